@@ -49,7 +49,7 @@
 //! # Ok::<(), CompactFiltersError>(())
 //! ```
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::path::Path;
 use std::sync::atomic::{AtomicUsize, Ordering};
@@ -67,6 +67,9 @@ mod peer;
 mod store;
 mod sync;
 
+use crate::blockchain::fee_estimator::{
+    BlockHistoryFeeEstimator, FallbackFeeEstimator, FeeEstimator, MempoolFeeEstimator,
+};
 use crate::blockchain::*;
 use crate::database::{BatchDatabase, BatchOperations, DatabaseUtils};
 use crate::error::Error;
@@ -139,6 +142,49 @@ impl CompactFiltersBlockchain {
         })
     }
 
+    /// Build a [`MempoolFeeEstimator`] from the transactions received by the peers
+    ///
+    /// The mempool is only populated if the first peer advertises the
+    /// [`BLOOM`](bitcoin::network::constants::ServiceFlags::BLOOM) service flag. The fee of a
+    /// transaction can only be computed when the outputs it spends are in the mempool or in the
+    /// blocks stored during the sync, the other transactions are ignored.
+    pub fn mempool_fee_estimator(&self) -> Result<MempoolFeeEstimator, CompactFiltersError> {
+        let prevouts = self
+            .headers
+            .iter_full_blocks()?
+            .into_iter()
+            .flat_map(|(_, block)| block.txdata)
+            .flat_map(|tx| {
+                let txid = tx.txid();
+                tx.output
+                    .into_iter()
+                    .enumerate()
+                    .map(move |(vout, txout)| (OutPoint::new(txid, vout as u32), txout))
+            })
+            .collect::<HashMap<_, _>>();
+        let txs = self.peers[0].get_mempool().iter_txs();
+
+        Ok(MempoolFeeEstimator::from_transactions(
+            txs.iter(),
+            |outpoint| prevouts.get(outpoint).cloned(),
+        ))
+    }
+
+    /// Build a [`BlockHistoryFeeEstimator`] from the blocks stored during the sync
+    ///
+    /// Only the blocks containing transactions relevant to the wallet are downloaded, and they are
+    /// deleted once they are buried, so the estimator is often based on a small sample.
+    pub fn block_fee_estimator(&self) -> Result<BlockHistoryFeeEstimator, CompactFiltersError> {
+        let network = self.peers[0].get_network();
+
+        let mut estimator = BlockHistoryFeeEstimator::new();
+        for (height, block) in self.headers.iter_full_blocks()? {
+            estimator.add_block(&block, height as u32, network);
+        }
+
+        Ok(estimator)
+    }
+
     /// Process a transaction by looking for inputs that spend from a UTXO in the database or
     /// outputs that send funds to a know script_pubkey.
     fn process_tx<D: BatchDatabase>(
@@ -147,8 +193,7 @@ impl CompactFiltersBlockchain {
         tx: &Transaction,
         height: Option<u32>,
         timestamp: Option<u64>,
-        internal_max_deriv: &mut Option<u32>,
-        external_max_deriv: &mut Option<u32>,
+        max_derivs: &mut HashMap<KeychainKind, u32>,
     ) -> Result<(), Error> {
         let mut updates = database.begin_batch();
 
@@ -197,14 +242,10 @@ impl CompactFiltersBlockchain {
                 })?;
                 incoming += output.value;
 
-                if keychain == KeychainKind::Internal
-                    && (internal_max_deriv.is_none() || child > internal_max_deriv.unwrap_or(0))
-                {
-                    *internal_max_deriv = Some(child);
-                } else if keychain == KeychainKind::External
-                    && (external_max_deriv.is_none() || child > external_max_deriv.unwrap_or(0))
-                {
-                    *external_max_deriv = Some(child);
+                // the index of imported scripts doesn't come from a derivation
+                if keychain != KeychainKind::Imported {
+                    let max_deriv = max_derivs.entry(keychain).or_insert(child);
+                    *max_deriv = std::cmp::max(*max_deriv, child);
                 }
             }
         }
@@ -240,9 +281,15 @@ impl Blockchain for CompactFiltersBlockchain {
         Ok(())
     }
 
-    fn estimate_fee(&self, _target: usize) -> Result<FeeRate, Error> {
-        // TODO
-        Ok(FeeRate::default())
+    fn estimate_fee(&self, target: usize) -> Result<FeeRate, Error> {
+        let estimator = FallbackFeeEstimator::new()
+            .add_source(self.mempool_fee_estimator()?)
+            .add_source(self.block_fee_estimator()?)
+            // without any data, fallback to the minimum relay fee
+            .add_source(FeeRate::default())
+            .min_fee_rate(FeeRate::default_min_relay_fee());
+
+        estimator.estimate_fee(target)
     }
 }
 
@@ -422,48 +469,24 @@ impl WalletSync for CompactFiltersBlockchain {
             e => e?,
         };
 
-        let mut internal_max_deriv = None;
-        let mut external_max_deriv = None;
+        let mut max_derivs = HashMap::new();
 
         for (height, block) in self.headers.iter_full_blocks()? {
             for tx in &block.txdata {
-                self.process_tx(
-                    database,
-                    tx,
-                    Some(height as u32),
-                    None,
-                    &mut internal_max_deriv,
-                    &mut external_max_deriv,
-                )?;
+                self.process_tx(database, tx, Some(height as u32), None, &mut max_derivs)?;
             }
         }
         for tx in first_peer.get_mempool().iter_txs().iter() {
-            self.process_tx(
-                database,
-                tx,
-                None,
-                None,
-                &mut internal_max_deriv,
-                &mut external_max_deriv,
-            )?;
-        }
-
-        let current_ext = database
-            .get_last_index(KeychainKind::External)?
-            .unwrap_or(0);
-        let first_ext_new = external_max_deriv.map(|x| x + 1).unwrap_or(0);
-        if first_ext_new > current_ext {
-            info!("Setting external index to {}", first_ext_new);
-            database.set_last_index(KeychainKind::External, first_ext_new)?;
+            self.process_tx(database, tx, None, None, &mut max_derivs)?;
         }
 
-        let current_int = database
-            .get_last_index(KeychainKind::Internal)?
-            .unwrap_or(0);
-        let first_int_new = internal_max_deriv.map(|x| x + 1).unwrap_or(0);
-        if first_int_new > current_int {
-            info!("Setting internal index to {}", first_int_new);
-            database.set_last_index(KeychainKind::Internal, first_int_new)?;
+        for (keychain, max_deriv) in max_derivs {
+            let current = database.get_last_index(keychain)?.unwrap_or(0);
+            let first_new = max_deriv + 1;
+            if first_new > current {
+                info!("Setting {:?} index to {}", keychain, first_new);
+                database.set_last_index(keychain, first_new)?;
+            }
         }
 
         info!("Dropping blocks until {}", buried_height);
@@ -0,0 +1,533 @@
+// Bitcoin Dev Kit
+//
+// Copyright (c) 2020-2022 Bitcoin Dev Kit Developers
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Fee estimation
+//!
+//! This module provides the [`FeeEstimator`] trait, implemented by the sources of fee rate
+//! estimates, along with a few generic implementations that don't depend on a specific backend:
+//!
+//! - [`MempoolFeeEstimator`] looks at the fee rates of the unconfirmed transactions, and estimates
+//!   what is needed to end up in the first blocks that will be mined out of the mempool;
+//! - [`BlockHistoryFeeEstimator`] looks at the fee rates paid in recently mined blocks;
+//! - [`FallbackFeeEstimator`] queries a list of estimators in order, returning the first estimate
+//!   available clamped between a minimum and a maximum value.
+//!
+//! A [`FeeRate`] is also a [`FeeEstimator`] that always returns itself, which is useful as the
+//! last resort of a [`FallbackFeeEstimator`].
+//!
+//! ## Example
+//!
+//! ```
+//! # use bdk::FeeRate;
+//! # use bdk::blockchain::fee_estimator::*;
+//! let mut mempool = MempoolFeeEstimator::new();
+//! // an unconfirmed transaction of 200 vbytes paying 2000 satoshi
+//! mempool.add_entry(2_000, 200);
+//!
+//! let estimator = FallbackFeeEstimator::new()
+//!     .add_source(mempool)
+//!     .add_source(FeeRate::from_sat_per_vb(5.0))
+//!     .max_fee_rate(FeeRate::from_sat_per_vb(100.0));
+//! let fee_rate = estimator.estimate_fee(6)?;
+//! # Ok::<(), bdk::Error>(())
+//! ```
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use bitcoin::{Block, Network, OutPoint, Transaction, TxOut, Txid};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace};
+
+use crate::error::Error;
+use crate::types::{FeeRate, Vbytes};
+
+/// Maximum size of a block in virtual bytes
+const MAX_BLOCK_VSIZE: usize = 1_000_000;
+/// Value of the block subsidy before the first halving, in satoshi
+const INITIAL_SUBSIDY: u64 = 50 * 100_000_000;
+
+/// Trait implemented by the sources of fee rate estimates
+pub trait FeeEstimator {
+    /// Estimate the fee rate required to confirm a transaction in a given `target` of blocks
+    ///
+    /// Returns [`Error::FeeRateUnavailable`] if the estimator doesn't have enough data.
+    fn estimate_fee(&self, target: usize) -> Result<FeeRate, Error>;
+}
+
+impl FeeEstimator for FeeRate {
+    fn estimate_fee(&self, _target: usize) -> Result<FeeRate, Error> {
+        Ok(*self)
+    }
+}
+
+/// Estimator based on the fee rates of the unconfirmed transactions
+///
+/// The transactions are sorted by fee rate, as a miner would do, and split in blocks: the estimate
+/// for a `target` is the lowest fee rate that would still be included in the first `target`
+/// blocks. If the transactions don't fill `target` blocks, the minimum relay fee is returned.
+///
+/// This doesn't take into account the transactions that will be broadcast before the next blocks
+/// are found, nor the dependencies between unconfirmed transactions.
+#[derive(Debug, Clone, Default)]
+pub struct MempoolFeeEstimator {
+    // Fee rate and size in vbytes of every transaction
+    histogram: Vec<(FeeRate, usize)>,
+}
+
+impl MempoolFeeEstimator {
+    /// Create a new estimator without any transaction
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create an estimator from a set of unconfirmed transactions
+    ///
+    /// Computing the fee of a transaction requires the outputs it spends: they are looked up
+    /// among `txs` first, and then with `get_prevout`. The transactions with some previous output
+    /// that can't be found are ignored.
+    pub fn from_transactions<'a, I, F>(txs: I, get_prevout: F) -> Self
+    where
+        I: IntoIterator<Item = &'a Transaction>,
+        F: Fn(&OutPoint) -> Option<TxOut>,
+    {
+        let txs = txs
+            .into_iter()
+            .map(|tx| (tx.txid(), tx))
+            .collect::<HashMap<Txid, _>>();
+
+        let mut estimator = Self::new();
+        for tx in txs.values() {
+            let inputs_sum = tx
+                .input
+                .iter()
+                .map(|txin| {
+                    let outpoint = &txin.previous_output;
+                    match txs.get(&outpoint.txid) {
+                        Some(parent) => parent
+                            .output
+                            .get(outpoint.vout as usize)
+                            .map(|txout| txout.value),
+                        None => get_prevout(outpoint).map(|txout| txout.value),
+                    }
+                })
+                .sum::<Option<u64>>();
+            let outputs_sum = tx.output.iter().map(|txout| txout.value).sum::<u64>();
+
+            match inputs_sum {
+                Some(inputs_sum) if inputs_sum >= outputs_sum => {
+                    estimator.add_entry(inputs_sum - outputs_sum, tx.weight().vbytes())
+                }
+                _ => trace!("Can't compute the fee of {}, ignoring it", tx.txid()),
+            }
+        }
+
+        estimator
+    }
+
+    /// Add an unconfirmed transaction of `vsize` virtual bytes paying `fee` satoshi
+    pub fn add_entry(&mut self, fee: u64, vsize: usize) {
+        if vsize > 0 {
+            self.histogram.push((FeeRate::from_vb(fee, vsize), vsize));
+        }
+    }
+
+    /// Return the number of transactions in the estimator
+    pub fn len(&self) -> usize {
+        self.histogram.len()
+    }
+
+    /// Return whether the estimator doesn't have any transaction
+    pub fn is_empty(&self) -> bool {
+        self.histogram.is_empty()
+    }
+}
+
+impl FeeEstimator for MempoolFeeEstimator {
+    fn estimate_fee(&self, target: usize) -> Result<FeeRate, Error> {
+        if self.histogram.is_empty() {
+            return Err(Error::FeeRateUnavailable);
+        }
+
+        let mut histogram = self.histogram.clone();
+        histogram.sort_by(|(a, _), (b, _)| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+
+        let capacity = target.max(1) * MAX_BLOCK_VSIZE;
+        let mut size = 0;
+        for (fee_rate, vsize) in histogram {
+            size += vsize;
+            if size >= capacity {
+                return Ok(max_fee_rate(fee_rate, FeeRate::default_min_relay_fee()));
+            }
+        }
+
+        Ok(FeeRate::default_min_relay_fee())
+    }
+}
+
+/// Estimator based on the fee rates paid in recently mined blocks
+///
+/// The fee rate of a block is the average fee rate of its transactions, computed from the value
+/// claimed by its coinbase. The estimate for a `target` is the lowest fee rate that matches or
+/// exceeds the fee rate of at least one block in every run of `target` consecutive blocks known to
+/// the estimator. Since the average fee rate of a block is higher than the lowest fee rate it
+/// includes, the estimates tend to be on the safe side.
+#[derive(Debug, Clone, Default)]
+pub struct BlockHistoryFeeEstimator {
+    blocks: BTreeMap<u32, FeeRate>,
+}
+
+impl BlockHistoryFeeEstimator {
+    /// Create a new estimator without any block
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the average fee rate of the block at `height`
+    pub fn add_block_fee_rate(&mut self, height: u32, fee_rate: FeeRate) {
+        self.blocks.insert(height, fee_rate);
+    }
+
+    /// Add the block at `height`, computing its average fee rate from the value of its coinbase
+    ///
+    /// Blocks that don't contain any transaction other than the coinbase are ignored.
+    pub fn add_block(&mut self, block: &Block, height: u32, network: Network) {
+        let coinbase = match block.txdata.first() {
+            Some(coinbase) if block.txdata.len() > 1 => coinbase,
+            _ => return,
+        };
+
+        let claimed = coinbase.output.iter().map(|txout| txout.value).sum::<u64>();
+        let fees = claimed.saturating_sub(block_subsidy(height, network));
+        let vsize = block.txdata[1..]
+            .iter()
+            .map(Transaction::weight)
+            .sum::<usize>()
+            .vbytes();
+
+        self.add_block_fee_rate(height, FeeRate::from_vb(fees, vsize));
+    }
+
+    /// Return the number of blocks in the estimator
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Return whether the estimator doesn't have any block
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+}
+
+impl FeeEstimator for BlockHistoryFeeEstimator {
+    fn estimate_fee(&self, target: usize) -> Result<FeeRate, Error> {
+        if self.blocks.is_empty() {
+            return Err(Error::FeeRateUnavailable);
+        }
+
+        let fee_rates = self.blocks.values().cloned().collect::<Vec<_>>();
+        let window = target.max(1).min(fee_rates.len());
+
+        fee_rates
+            .windows(window)
+            // paying the lowest fee rate of the run is enough to be included in one of its blocks
+            .map(|blocks| blocks.iter().cloned().fold(blocks[0], min_fee_rate))
+            .reduce(max_fee_rate)
+            .map(|fee_rate| max_fee_rate(fee_rate, FeeRate::default_min_relay_fee()))
+            .ok_or(Error::FeeRateUnavailable)
+    }
+}
+
+/// Estimator that queries other estimators in order, until one of them returns an estimate
+///
+/// The estimate returned is clamped between the optional minimum and maximum fee rates. If none
+/// of the sources has an estimate, the error of the last one is returned.
+#[derive(Default)]
+pub struct FallbackFeeEstimator {
+    sources: Vec<Box<dyn FeeEstimator>>,
+    min_fee_rate: Option<FeeRate>,
+    max_fee_rate: Option<FeeRate>,
+}
+
+impl FallbackFeeEstimator {
+    /// Create a new estimator without any source
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a source, queried after the ones already added
+    pub fn add_source<E: FeeEstimator + 'static>(mut self, source: E) -> Self {
+        self.sources.push(Box::new(source));
+        self
+    }
+
+    /// Set the lowest fee rate that can be returned
+    pub fn min_fee_rate(mut self, min_fee_rate: FeeRate) -> Self {
+        self.min_fee_rate = Some(min_fee_rate);
+        self
+    }
+
+    /// Set the highest fee rate that can be returned, it takes precedence over the minimum
+    pub fn max_fee_rate(mut self, max_fee_rate: FeeRate) -> Self {
+        self.max_fee_rate = Some(max_fee_rate);
+        self
+    }
+
+    fn clamp(&self, mut fee_rate: FeeRate) -> FeeRate {
+        if let Some(min_fee_rate) = self.min_fee_rate {
+            fee_rate = max_fee_rate(fee_rate, min_fee_rate);
+        }
+        match self.max_fee_rate {
+            Some(max_fee_rate) if fee_rate > max_fee_rate => max_fee_rate,
+            _ => fee_rate,
+        }
+    }
+}
+
+impl fmt::Debug for FallbackFeeEstimator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FallbackFeeEstimator")
+            .field("sources", &self.sources.len())
+            .field("min_fee_rate", &self.min_fee_rate)
+            .field("max_fee_rate", &self.max_fee_rate)
+            .finish()
+    }
+}
+
+impl FeeEstimator for FallbackFeeEstimator {
+    fn estimate_fee(&self, target: usize) -> Result<FeeRate, Error> {
+        let mut last_error = None;
+        for (index, source) in self.sources.iter().enumerate() {
+            match source.estimate_fee(target) {
+                Ok(fee_rate) => return Ok(self.clamp(fee_rate)),
+                Err(e) => {
+                    debug!("Fee estimator #{} failed: {:?}", index, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or(Error::FeeRateUnavailable))
+    }
+}
+
+fn max_fee_rate(a: FeeRate, b: FeeRate) -> FeeRate {
+    match a < b {
+        true => b,
+        false => a,
+    }
+}
+
+fn min_fee_rate(a: FeeRate, b: FeeRate) -> FeeRate {
+    match a < b {
+        true => a,
+        false => b,
+    }
+}
+
+fn block_subsidy(height: u32, network: Network) -> u64 {
+    let halving_interval = match network {
+        Network::Regtest => 150,
+        _ => 210_000,
+    };
+
+    match height / halving_interval {
+        halvings if halvings >= 64 => 0,
+        halvings => INITIAL_SUBSIDY >> halvings,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{PackedLockTime, Script, Sequence, TxIn, Witness};
+
+    fn tx(previous_outputs: &[OutPoint], values: &[u64]) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: PackedLockTime::ZERO,
+            input: previous_outputs
+                .iter()
+                .map(|previous_output| TxIn {
+                    previous_output: *previous_output,
+                    script_sig: Script::new(),
+                    sequence: Sequence::MAX,
+                    witness: Witness::new(),
+                })
+                .collect(),
+            output: values
+                .iter()
+                .map(|value| TxOut {
+                    value: *value,
+                    script_pubkey: Script::new(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_mempool_fee_estimator() {
+        let mut estimator = MempoolFeeEstimator::new();
+        assert!(matches!(
+            estimator.estimate_fee(1),
+            Err(Error::FeeRateUnavailable)
+        ));
+
+        // a block and a half of transactions at 20 sat/vbyte, and one more block at 10 sat/vbyte
+        for _ in 0..15 {
+            estimator.add_entry(2_000_000, 100_000);
+        }
+        for _ in 0..10 {
+            estimator.add_entry(1_000_000, 100_000);
+        }
+
+        assert_eq!(estimator.len(), 25);
+        assert_eq!(
+            estimator.estimate_fee(1).unwrap(),
+            FeeRate::from_sat_per_vb(20.0)
+        );
+        assert_eq!(
+            estimator.estimate_fee(2).unwrap(),
+            FeeRate::from_sat_per_vb(10.0)
+        );
+        assert_eq!(
+            estimator.estimate_fee(3).unwrap(),
+            FeeRate::default_min_relay_fee()
+        );
+    }
+
+    #[test]
+    fn test_mempool_fee_estimator_from_transactions() {
+        let confirmed = OutPoint::new(Txid::all_zeros(), 0);
+        let parent = tx(&[confirmed], &[10_000, 20_000]);
+        let child = tx(&[OutPoint::new(parent.txid(), 1)], &[15_000]);
+        let orphan = tx(&[OutPoint::new(Txid::all_zeros(), 1)], &[5_000]);
+
+        let estimator =
+            MempoolFeeEstimator::from_transactions(vec![&parent, &child, &orphan], |outpoint| {
+                match outpoint == &confirmed {
+                    true => Some(TxOut {
+                        value: 31_000,
+                        script_pubkey: Script::new(),
+                    }),
+                    false => None,
+                }
+            });
+
+        // the orphan is ignored
+        assert_eq!(estimator.len(), 2);
+        let mut fees = estimator
+            .histogram
+            .iter()
+            .map(|(fee_rate, vsize)| fee_rate.fee_vb(*vsize))
+            .collect::<Vec<_>>();
+        fees.sort_unstable();
+        assert_eq!(fees, vec![1_000, 5_000]);
+    }
+
+    #[test]
+    fn test_block_history_fee_estimator() {
+        let mut estimator = BlockHistoryFeeEstimator::new();
+        assert!(matches!(
+            estimator.estimate_fee(1),
+            Err(Error::FeeRateUnavailable)
+        ));
+
+        for (height, fee_rate) in [
+            (100, 30.0),
+            (101, 5.0),
+            (102, 20.0),
+            (103, 8.0),
+            (104, 12.0),
+        ] {
+            estimator.add_block_fee_rate(height, FeeRate::from_sat_per_vb(fee_rate));
+        }
+
+        assert_eq!(
+            estimator.estimate_fee(1).unwrap(),
+            FeeRate::from_sat_per_vb(30.0)
+        );
+        assert_eq!(
+            estimator.estimate_fee(2).unwrap(),
+            FeeRate::from_sat_per_vb(8.0)
+        );
+        assert_eq!(
+            estimator.estimate_fee(3).unwrap(),
+            FeeRate::from_sat_per_vb(8.0)
+        );
+        assert_eq!(
+            estimator.estimate_fee(10).unwrap(),
+            FeeRate::from_sat_per_vb(5.0)
+        );
+    }
+
+    #[test]
+    fn test_block_history_fee_estimator_add_block() {
+        let mut block = genesis_block(Network::Regtest);
+        let mut estimator = BlockHistoryFeeEstimator::new();
+
+        // only the coinbase
+        estimator.add_block(&block, 0, Network::Regtest);
+        assert!(estimator.is_empty());
+
+        let spend = tx(&[OutPoint::new(Txid::all_zeros(), 0)], &[10_000]);
+        let vsize = spend.weight().vbytes();
+        block.txdata[0].output[0].value = INITIAL_SUBSIDY / 2 + 10 * vsize as u64;
+        block.txdata.push(spend);
+        estimator.add_block(&block, 150, Network::Regtest);
+
+        assert_eq!(estimator.len(), 1);
+        assert_eq!(
+            estimator.estimate_fee(1).unwrap(),
+            FeeRate::from_sat_per_vb(10.0)
+        );
+    }
+
+    #[test]
+    fn test_fallback_fee_estimator() {
+        let estimator = FallbackFeeEstimator::new();
+        assert!(matches!(
+            estimator.estimate_fee(1),
+            Err(Error::FeeRateUnavailable)
+        ));
+
+        let mut mempool = MempoolFeeEstimator::new();
+        mempool.add_entry(5_000_000, 100_000);
+        mempool.add_entry(5_000_000, 1_000_000);
+        let estimator = FallbackFeeEstimator::new()
+            .add_source(BlockHistoryFeeEstimator::new())
+            .add_source(mempool)
+            .add_source(FeeRate::from_sat_per_vb(2.0));
+        assert_eq!(
+            estimator.estimate_fee(1).unwrap(),
+            FeeRate::from_sat_per_vb(5.0)
+        );
+
+        let estimator = estimator.max_fee_rate(FeeRate::from_sat_per_vb(3.0));
+        assert_eq!(
+            estimator.estimate_fee(1).unwrap(),
+            FeeRate::from_sat_per_vb(3.0)
+        );
+
+        let estimator = FallbackFeeEstimator::new()
+            .add_source(MempoolFeeEstimator::new())
+            .add_source(FeeRate::from_sat_per_vb(2.0))
+            .min_fee_rate(FeeRate::from_sat_per_vb(4.0));
+        assert_eq!(
+            estimator.estimate_fee(1).unwrap(),
+            FeeRate::from_sat_per_vb(4.0)
+        );
+    }
+}
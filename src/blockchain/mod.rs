@@ -35,6 +35,7 @@ use crate::{FeeRate, KeychainKind};
     feature = "rpc"
 ))]
 pub mod any;
+pub mod fee_estimator;
 mod script_sync;
 
 #[cfg(any(
@@ -299,8 +299,7 @@ struct DbState<'a, D> {
     params: &'a RpcSyncParams,
     prog: &'a dyn Progress,
 
-    ext_spks: Vec<Script>,
-    int_spks: Vec<Script>,
+    spks: Vec<Script>,
     txs: HashMap<Txid, TransactionDetails>,
     utxos: HashSet<LocalUtxo>,
     last_indexes: HashMap<KeychainKind, u32>,
@@ -314,12 +313,24 @@ struct DbState<'a, D> {
 impl<'a, D: BatchDatabase> DbState<'a, D> {
     /// Obtain [DbState] from [crate::database::Database].
     fn new(db: &'a D, params: &'a RpcSyncParams, prog: &'a dyn Progress) -> Result<Self, Error> {
-        let ext_spks = db.iter_script_pubkeys(Some(KeychainKind::External))?;
-        let int_spks = db.iter_script_pubkeys(Some(KeychainKind::Internal))?;
+        let mut keychains = vec![KeychainKind::External, KeychainKind::Internal];
+        keychains.extend(
+            db.iter_keychains()?
+                .into_iter()
+                .filter(|keychain| matches!(keychain, KeychainKind::Custom(_))),
+        );
 
-        // This is a hack to see whether atleast one of the keychains comes from a derivable
-        // descriptor. We assume that non-derivable descriptors always has a script count of 1.
-        let last_count = std::cmp::max(ext_spks.len(), int_spks.len());
+        let mut spks = vec![];
+        let mut last_count = 0;
+        for keychain in &keychains {
+            let keychain_spks = db.iter_script_pubkeys(Some(*keychain))?;
+            // This is a hack to see whether atleast one of the keychains comes from a derivable
+            // descriptor. We assume that non-derivable descriptors always has a script count of 1.
+            last_count = std::cmp::max(last_count, keychain_spks.len());
+            spks.extend(keychain_spks);
+        }
+        // Imported scripts don't come from a descriptor, so they are imported as they are
+        spks.extend(db.iter_script_pubkeys(Some(KeychainKind::Imported))?);
         let has_derivable = last_count > 1;
 
         // If at least one descriptor is derivable, we need to ensure scriptPubKeys are sufficiently
@@ -341,7 +352,7 @@ impl<'a, D: BatchDatabase> DbState<'a, D> {
 
         let utxos = db.iter_utxos()?.into_iter().collect::<HashSet<_>>();
 
-        let last_indexes = [KeychainKind::External, KeychainKind::Internal]
+        let last_indexes = keychains
             .iter()
             .filter_map(|keychain| match db.get_last_index(*keychain) {
                 Ok(li_opt) => li_opt.map(|li| Ok((*keychain, li))),
@@ -360,8 +371,7 @@ impl<'a, D: BatchDatabase> DbState<'a, D> {
             db,
             params,
             prog,
-            ext_spks,
-            int_spks,
+            spks,
             txs,
             utxos,
             last_indexes,
@@ -384,7 +394,7 @@ impl<'a, D: BatchDatabase> DbState<'a, D> {
         };
 
         // sync scriptPubKeys from Database to Core wallet
-        let scripts_iter = self.ext_spks.iter().chain(&self.int_spks);
+        let scripts_iter = self.spks.iter();
         if is_descriptor {
             import_descriptors(client, start_epoch, scripts_iter)?;
         } else {
@@ -590,6 +600,10 @@ impl<'a, D: BatchDatabase> DbState<'a, D> {
 
     // updates the db state's last_index for the given keychain (if larger than current last_index)
     fn update_last_index(&mut self, keychain: KeychainKind, index: u32) {
+        // the last index of imported scripts is only moved by `Wallet::import_script`
+        if keychain == KeychainKind::Imported {
+            return;
+        }
         self.last_indexes
             .entry(keychain)
             .and_modify(|last| {
@@ -28,7 +28,16 @@ pub enum Request<'a, D: BatchDatabase> {
 /// starts a sync
 pub fn start<D: BatchDatabase>(db: &D, stop_gap: usize) -> Result<Request<'_, D>, Error> {
     use rand::seq::SliceRandom;
-    let mut keychains = vec![KeychainKind::Internal, KeychainKind::External];
+    let mut keychains = vec![
+        KeychainKind::Internal,
+        KeychainKind::External,
+        KeychainKind::Imported,
+    ];
+    keychains.extend(
+        db.iter_keychains()?
+            .into_iter()
+            .filter(|keychain| matches!(keychain, KeychainKind::Custom(_))),
+    );
     // shuffling improve privacy, the server doesn't know my first request is from my internal or external addresses
     keychains.shuffle(&mut rand::thread_rng());
     let keychain = keychains.pop().unwrap();
@@ -139,7 +148,13 @@ impl<'a, D: BatchDatabase> ScriptReq<'a, D> {
             last, remaining, current_gap, self.stop_gap
         );
 
-        if is_derivable {
+        if self.keychain == KeychainKind::Imported {
+            // imported scripts are unrelated to each other, so all of them have to be checked
+            // and there's no gap to respect
+            if remaining > 0 {
+                return Ok(Request::Script(self));
+            }
+        } else if is_derivable {
             if remaining > 0 {
                 // we still have scriptPubKeys to do requests for
                 return Ok(Request::Script(self));
@@ -429,7 +444,7 @@ impl<'a, D: BatchDatabase> State<'a, D> {
 
         // apply index updates
         for (keychain, new_index) in index_updates {
-            debug!("updating index ({}, {})", keychain.as_byte(), new_index);
+            debug!("updating index ({:?}, {})", keychain, new_index);
             batch.set_last_index(keychain, new_index)?;
         }
 
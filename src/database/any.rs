@@ -148,6 +148,12 @@ impl BatchOperations for AnyDatabase {
     fn set_sync_time(&mut self, sync_time: SyncTime) -> Result<(), Error> {
         impl_inner_method!(AnyDatabase, self, set_sync_time, sync_time)
     }
+    fn set_label(&mut self, key: &LabelKey, label: &str) -> Result<(), Error> {
+        impl_inner_method!(AnyDatabase, self, set_label, key, label)
+    }
+    fn set_frozen(&mut self, outpoint: &OutPoint) -> Result<(), Error> {
+        impl_inner_method!(AnyDatabase, self, set_frozen, outpoint)
+    }
 
     fn del_script_pubkey_from_path(
         &mut self,
@@ -187,6 +193,12 @@ impl BatchOperations for AnyDatabase {
     fn del_sync_time(&mut self) -> Result<Option<SyncTime>, Error> {
         impl_inner_method!(AnyDatabase, self, del_sync_time)
     }
+    fn del_label(&mut self, key: &LabelKey) -> Result<Option<String>, Error> {
+        impl_inner_method!(AnyDatabase, self, del_label, key)
+    }
+    fn del_frozen(&mut self, outpoint: &OutPoint) -> Result<bool, Error> {
+        impl_inner_method!(AnyDatabase, self, del_frozen, outpoint)
+    }
 }
 
 impl Database for AnyDatabase {
@@ -216,6 +228,15 @@ impl Database for AnyDatabase {
     fn iter_txs(&self, include_raw: bool) -> Result<Vec<TransactionDetails>, Error> {
         impl_inner_method!(AnyDatabase, self, iter_txs, include_raw)
     }
+    fn iter_labels(&self) -> Result<Vec<(LabelKey, String)>, Error> {
+        impl_inner_method!(AnyDatabase, self, iter_labels)
+    }
+    fn iter_frozen(&self) -> Result<Vec<OutPoint>, Error> {
+        impl_inner_method!(AnyDatabase, self, iter_frozen)
+    }
+    fn iter_keychains(&self) -> Result<Vec<KeychainKind>, Error> {
+        impl_inner_method!(AnyDatabase, self, iter_keychains)
+    }
 
     fn get_script_pubkey_from_path(
         &self,
@@ -251,6 +272,12 @@ impl Database for AnyDatabase {
     fn get_sync_time(&self) -> Result<Option<SyncTime>, Error> {
         impl_inner_method!(AnyDatabase, self, get_sync_time)
     }
+    fn get_label(&self, key: &LabelKey) -> Result<Option<String>, Error> {
+        impl_inner_method!(AnyDatabase, self, get_label, key)
+    }
+    fn is_frozen(&self, outpoint: &OutPoint) -> Result<bool, Error> {
+        impl_inner_method!(AnyDatabase, self, is_frozen, outpoint)
+    }
 
     fn increment_last_index(&mut self, keychain: KeychainKind) -> Result<u32, Error> {
         impl_inner_method!(AnyDatabase, self, increment_last_index, keychain)
@@ -281,6 +308,12 @@ impl BatchOperations for AnyBatch {
     fn set_sync_time(&mut self, sync_time: SyncTime) -> Result<(), Error> {
         impl_inner_method!(AnyBatch, self, set_sync_time, sync_time)
     }
+    fn set_label(&mut self, key: &LabelKey, label: &str) -> Result<(), Error> {
+        impl_inner_method!(AnyBatch, self, set_label, key, label)
+    }
+    fn set_frozen(&mut self, outpoint: &OutPoint) -> Result<(), Error> {
+        impl_inner_method!(AnyBatch, self, set_frozen, outpoint)
+    }
 
     fn del_script_pubkey_from_path(
         &mut self,
@@ -314,6 +347,12 @@ impl BatchOperations for AnyBatch {
     fn del_sync_time(&mut self) -> Result<Option<SyncTime>, Error> {
         impl_inner_method!(AnyBatch, self, del_sync_time)
     }
+    fn del_label(&mut self, key: &LabelKey) -> Result<Option<String>, Error> {
+        impl_inner_method!(AnyBatch, self, del_label, key)
+    }
+    fn del_frozen(&mut self, outpoint: &OutPoint) -> Result<bool, Error> {
+        impl_inner_method!(AnyBatch, self, del_frozen, outpoint)
+    }
 }
 
 impl BatchDatabase for AnyDatabase {
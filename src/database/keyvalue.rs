@@ -18,7 +18,7 @@ use bitcoin::hash_types::Txid;
 use bitcoin::{OutPoint, Script, Transaction};
 
 use crate::database::memory::MapKey;
-use crate::database::{BatchDatabase, BatchOperations, Database, SyncTime};
+use crate::database::{BatchDatabase, BatchOperations, Database, LabelKey, SyncTime};
 use crate::error::Error;
 use crate::types::*;
 
@@ -90,6 +90,20 @@ macro_rules! impl_batch_operations {
             Ok(())
         }
 
+        fn set_label(&mut self, label_key: &LabelKey, label: &str) -> Result<(), Error> {
+            let key = MapKey::Label(Some(label_key)).as_map_key();
+            self.insert(key, serde_json::to_vec(label)?)$($after_insert)*;
+
+            Ok(())
+        }
+
+        fn set_frozen(&mut self, outpoint: &OutPoint) -> Result<(), Error> {
+            let key = MapKey::Frozen(Some(outpoint)).as_map_key();
+            self.insert(key, Vec::<u8>::new())$($after_insert)*;
+
+            Ok(())
+        }
+
         fn del_script_pubkey_from_path(&mut self, keychain: KeychainKind, path: u32) -> Result<Option<Script>, Error> {
             let key = MapKey::Path((Some(keychain), Some(path))).as_map_key();
             let res = self.remove(key);
@@ -178,6 +192,22 @@ macro_rules! impl_batch_operations {
 
             Ok(res.map(|b| serde_json::from_slice(&b)).transpose()?)
         }
+
+        fn del_label(&mut self, label_key: &LabelKey) -> Result<Option<String>, Error> {
+            let key = MapKey::Label(Some(label_key)).as_map_key();
+            let res = self.remove(key);
+            let res = $process_delete!(res);
+
+            Ok(res.map(|b| serde_json::from_slice(&b)).transpose()?)
+        }
+
+        fn del_frozen(&mut self, outpoint: &OutPoint) -> Result<bool, Error> {
+            let key = MapKey::Frozen(Some(outpoint)).as_map_key();
+            let res = self.remove(key);
+            let res = $process_delete!(res);
+
+            Ok(res.is_some())
+        }
     }
 }
 
@@ -282,6 +312,36 @@ impl Database for Tree {
             .collect()
     }
 
+    fn iter_labels(&self) -> Result<Vec<(LabelKey, String)>, Error> {
+        let key = MapKey::Label(None).as_map_key();
+        self.scan_prefix(key)
+            .map(|x| -> Result<_, Error> {
+                let (k, v) = x?;
+                let label_key = LabelKey::from_bytes(&k[1..])?;
+                Ok((label_key, serde_json::from_slice(&v)?))
+            })
+            .collect()
+    }
+
+    fn iter_frozen(&self) -> Result<Vec<OutPoint>, Error> {
+        let key = MapKey::Frozen(None).as_map_key();
+        self.scan_prefix(key)
+            .map(|x| -> Result<_, Error> {
+                let (k, _) = x?;
+                Ok(deserialize(&k[1..])?)
+            })
+            .collect()
+    }
+
+    fn iter_keychains(&self) -> Result<Vec<KeychainKind>, Error> {
+        self.scan_prefix(b"d")
+            .filter_map(|x| match x {
+                Ok((k, _)) => KeychainKind::from_bytes(&k[1..]).map(Ok),
+                Err(e) => Some(Err(e.into())),
+            })
+            .collect()
+    }
+
     fn get_script_pubkey_from_path(
         &self,
         keychain: KeychainKind,
@@ -361,6 +421,19 @@ impl Database for Tree {
             .transpose()?)
     }
 
+    fn get_label(&self, label_key: &LabelKey) -> Result<Option<String>, Error> {
+        let key = MapKey::Label(Some(label_key)).as_map_key();
+        Ok(self
+            .get(key)?
+            .map(|b| serde_json::from_slice(&b))
+            .transpose()?)
+    }
+
+    fn is_frozen(&self, outpoint: &OutPoint) -> Result<bool, Error> {
+        let key = MapKey::Frozen(Some(outpoint)).as_map_key();
+        Ok(self.contains_key(key)?)
+    }
+
     // inserts 0 if not present
     fn increment_last_index(&mut self, keychain: KeychainKind) -> Result<u32, Error> {
         let key = MapKey::LastIndex(keychain).as_map_key();
@@ -532,4 +605,19 @@ mod test {
     fn test_check_descriptor_checksum() {
         crate::database::test::test_check_descriptor_checksum(get_tree());
     }
+
+    #[test]
+    fn test_label() {
+        crate::database::test::test_label(get_tree());
+    }
+
+    #[test]
+    fn test_frozen() {
+        crate::database::test::test_frozen(get_tree());
+    }
+
+    #[test]
+    fn test_custom_keychains() {
+        crate::database::test::test_custom_keychains(get_tree());
+    }
 }
@@ -22,18 +22,22 @@ use bitcoin::consensus::encode::{deserialize, serialize};
 use bitcoin::hash_types::Txid;
 use bitcoin::{OutPoint, Script, Transaction};
 
-use crate::database::{BatchDatabase, BatchOperations, ConfigurableDatabase, Database, SyncTime};
+use crate::database::{
+    BatchDatabase, BatchOperations, ConfigurableDatabase, Database, LabelKey, SyncTime,
+};
 use crate::error::Error;
 use crate::types::*;
 
-// path -> script       p{i,e}<path> -> script
-// script -> path       s<script> -> {i,e}<path>
+// path -> script       p{i,e,k<id>,w}<path> -> script
+// script -> path       s<script> -> {i,e,k<id>,w}<path>
 // outpoint             u<outpoint> -> txout
 // rawtx                r<txid> -> tx
 // transactions         t<txid> -> tx details
-// deriv indexes        c{i,e} -> u32
-// descriptor checksum  d{i,e} -> vec<u8>
+// deriv indexes        c{i,e,k<id>,w} -> u32
+// descriptor checksum  d{i,e,k<id>} -> vec<u8>
 // last sync time       l -> { height, timestamp }
+// labels               n{u,a,t,i,x}<key> -> string
+// frozen outpoints     f<outpoint> -> ()
 
 pub(crate) enum MapKey<'a> {
     Path((Option<KeychainKind>, Option<u32>)),
@@ -44,6 +48,8 @@ pub(crate) enum MapKey<'a> {
     LastIndex(KeychainKind),
     SyncTime,
     DescriptorChecksum(KeychainKind),
+    Label(Option<&'a LabelKey>),
+    Frozen(Option<&'a OutPoint>),
 }
 
 impl MapKey<'_> {
@@ -52,7 +58,7 @@ impl MapKey<'_> {
             MapKey::Path((st, _)) => {
                 let mut v = b"p".to_vec();
                 if let Some(st) = st {
-                    v.push(st.as_byte());
+                    v.extend(st.as_bytes());
                 }
                 v
             }
@@ -60,9 +66,11 @@ impl MapKey<'_> {
             MapKey::Utxo(_) => b"u".to_vec(),
             MapKey::RawTx(_) => b"r".to_vec(),
             MapKey::Transaction(_) => b"t".to_vec(),
-            MapKey::LastIndex(st) => [b"c", st.as_ref()].concat(),
+            MapKey::LastIndex(st) => [b"c".to_vec(), st.as_bytes()].concat(),
             MapKey::SyncTime => b"l".to_vec(),
-            MapKey::DescriptorChecksum(st) => [b"d", st.as_ref()].concat(),
+            MapKey::DescriptorChecksum(st) => [b"d".to_vec(), st.as_bytes()].concat(),
+            MapKey::Label(_) => b"n".to_vec(),
+            MapKey::Frozen(_) => b"f".to_vec(),
         }
     }
 
@@ -73,6 +81,8 @@ impl MapKey<'_> {
             MapKey::Utxo(Some(s)) => serialize(*s),
             MapKey::RawTx(Some(s)) => serialize(*s),
             MapKey::Transaction(Some(s)) => serialize(*s),
+            MapKey::Label(Some(k)) => k.as_bytes(),
+            MapKey::Frozen(Some(s)) => serialize(*s),
             _ => vec![],
         }
     }
@@ -191,6 +201,18 @@ impl BatchOperations for MemoryDatabase {
 
         Ok(())
     }
+    fn set_label(&mut self, label_key: &LabelKey, label: &str) -> Result<(), Error> {
+        let key = MapKey::Label(Some(label_key)).as_map_key();
+        self.map.insert(key, Box::new(label.to_string()));
+
+        Ok(())
+    }
+    fn set_frozen(&mut self, outpoint: &OutPoint) -> Result<(), Error> {
+        let key = MapKey::Frozen(Some(outpoint)).as_map_key();
+        self.map.insert(key, Box::new(()));
+
+        Ok(())
+    }
 
     fn del_script_pubkey_from_path(
         &mut self,
@@ -289,6 +311,20 @@ impl BatchOperations for MemoryDatabase {
 
         Ok(res.map(|b| b.downcast_ref().cloned().unwrap()))
     }
+    fn del_label(&mut self, label_key: &LabelKey) -> Result<Option<String>, Error> {
+        let key = MapKey::Label(Some(label_key)).as_map_key();
+        let res = self.map.remove(&key);
+        self.deleted_keys.push(key);
+
+        Ok(res.map(|b| b.downcast_ref().cloned().unwrap()))
+    }
+    fn del_frozen(&mut self, outpoint: &OutPoint) -> Result<bool, Error> {
+        let key = MapKey::Frozen(Some(outpoint)).as_map_key();
+        let res = self.map.remove(&key);
+        self.deleted_keys.push(key);
+
+        Ok(res.is_some())
+    }
 }
 
 impl Database for MemoryDatabase {
@@ -364,6 +400,34 @@ impl Database for MemoryDatabase {
             .collect()
     }
 
+    fn iter_labels(&self) -> Result<Vec<(LabelKey, String)>, Error> {
+        let key = MapKey::Label(None).as_map_key();
+        self.map
+            .range::<Vec<u8>, _>((Included(&key), Excluded(&after(&key))))
+            .map(|(k, v)| {
+                let label_key = LabelKey::from_bytes(&k[1..])?;
+                Ok((label_key, v.downcast_ref().cloned().unwrap()))
+            })
+            .collect()
+    }
+
+    fn iter_frozen(&self) -> Result<Vec<OutPoint>, Error> {
+        let key = MapKey::Frozen(None).as_map_key();
+        self.map
+            .range::<Vec<u8>, _>((Included(&key), Excluded(&after(&key))))
+            .map(|(k, _)| Ok(deserialize(&k[1..])?))
+            .collect()
+    }
+
+    fn iter_keychains(&self) -> Result<Vec<KeychainKind>, Error> {
+        let key = b"d".to_vec();
+        Ok(self
+            .map
+            .range::<Vec<u8>, _>((Included(&key), Excluded(&after(&key))))
+            .filter_map(|(k, _)| KeychainKind::from_bytes(&k[1..]))
+            .collect())
+    }
+
     fn get_script_pubkey_from_path(
         &self,
         keychain: KeychainKind,
@@ -436,6 +500,19 @@ impl Database for MemoryDatabase {
             .map(|b| b.downcast_ref().cloned().unwrap()))
     }
 
+    fn get_label(&self, label_key: &LabelKey) -> Result<Option<String>, Error> {
+        let key = MapKey::Label(Some(label_key)).as_map_key();
+        Ok(self
+            .map
+            .get(&key)
+            .map(|b| b.downcast_ref().cloned().unwrap()))
+    }
+
+    fn is_frozen(&self, outpoint: &OutPoint) -> Result<bool, Error> {
+        let key = MapKey::Frozen(Some(outpoint)).as_map_key();
+        Ok(self.map.contains_key(&key))
+    }
+
     // inserts 0 if not present
     fn increment_last_index(&mut self, keychain: KeychainKind) -> Result<u32, Error> {
         let key = MapKey::LastIndex(keychain).as_map_key();
@@ -687,4 +764,19 @@ mod test {
     fn test_check_descriptor_checksum() {
         crate::database::test::test_check_descriptor_checksum(get_tree());
     }
+
+    #[test]
+    fn test_label() {
+        crate::database::test::test_label(get_tree());
+    }
+
+    #[test]
+    fn test_frozen() {
+        crate::database::test::test_frozen(get_tree());
+    }
+
+    #[test]
+    fn test_custom_keychains() {
+        crate::database::test::test_custom_keychains(get_tree());
+    }
 }
@@ -26,7 +26,9 @@
 
 use serde::{Deserialize, Serialize};
 
+use bitcoin::consensus::encode::{deserialize, serialize};
 use bitcoin::hash_types::Txid;
+use bitcoin::util::bip32::ExtendedPubKey;
 use bitcoin::{OutPoint, Script, Transaction, TxOut};
 
 use crate::error::Error;
@@ -55,6 +57,59 @@ pub struct SyncTime {
     pub block_time: BlockTime,
 }
 
+/// Object a user-defined label can be attached to
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum LabelKey {
+    /// Label attached to an unspent output
+    Utxo(OutPoint),
+    /// Label attached to an address, identified by its script_pubkey
+    Address(Script),
+    /// Label attached to a transaction
+    Transaction(Txid),
+    /// Label attached to a transaction input, identified by the txid of the spending transaction
+    /// and the index of the input in it
+    Input {
+        /// Txid of the transaction the input belongs to
+        txid: Txid,
+        /// Index of the input in the transaction
+        vin: u32,
+    },
+    /// Label attached to an extended public key
+    Xpub(ExtendedPubKey),
+}
+
+impl LabelKey {
+    /// Serialize the key as a one-byte tag followed by the consensus encoding of its content
+    pub(crate) fn as_bytes(&self) -> Vec<u8> {
+        match self {
+            LabelKey::Utxo(outpoint) => [b"u".to_vec(), serialize(outpoint)].concat(),
+            LabelKey::Address(script) => [b"a".to_vec(), serialize(script)].concat(),
+            LabelKey::Transaction(txid) => [b"t".to_vec(), serialize(txid)].concat(),
+            LabelKey::Input { txid, vin } => {
+                [b"i".to_vec(), serialize(txid), serialize(vin)].concat()
+            }
+            LabelKey::Xpub(xpub) => [b"x".to_vec(), xpub.encode().to_vec()].concat(),
+        }
+    }
+
+    /// Parse a key previously serialized with [`LabelKey::as_bytes`]
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        match bytes.split_first() {
+            Some((b'u', content)) => Ok(LabelKey::Utxo(deserialize(content)?)),
+            Some((b'a', content)) => Ok(LabelKey::Address(deserialize(content)?)),
+            Some((b't', content)) => Ok(LabelKey::Transaction(deserialize(content)?)),
+            Some((b'i', content)) => {
+                let (txid, vin) = deserialize(content)?;
+                Ok(LabelKey::Input { txid, vin })
+            }
+            Some((b'x', content)) => Ok(LabelKey::Xpub(ExtendedPubKey::decode(content)?)),
+            _ => Err(Error::Encode(
+                bitcoin::consensus::encode::Error::ParseFailed("invalid label key"),
+            )),
+        }
+    }
+}
+
 /// Trait for operations that can be batched
 ///
 /// This trait defines the list of operations that must be implemented on the [`Database`] type and
@@ -77,6 +132,11 @@ pub trait BatchOperations {
     fn set_last_index(&mut self, keychain: KeychainKind, value: u32) -> Result<(), Error>;
     /// Store the sync time
     fn set_sync_time(&mut self, sync_time: SyncTime) -> Result<(), Error>;
+    /// Store a label for a [`LabelKey`], replacing the previous one if present
+    fn set_label(&mut self, key: &LabelKey, label: &str) -> Result<(), Error>;
+    /// Mark an [`OutPoint`] as frozen, so that it's not automatically selected when creating
+    /// transactions
+    fn set_frozen(&mut self, outpoint: &OutPoint) -> Result<(), Error>;
 
     /// Delete a script_pubkey given the keychain and its child number.
     fn del_script_pubkey_from_path(
@@ -106,6 +166,12 @@ pub trait BatchOperations {
     ///
     /// Returns the removed value
     fn del_sync_time(&mut self) -> Result<Option<SyncTime>, Error>;
+    /// Delete the label attached to a [`LabelKey`]
+    fn del_label(&mut self, key: &LabelKey) -> Result<Option<String>, Error>;
+    /// Remove the frozen flag from an [`OutPoint`]
+    ///
+    /// Returns whether the outpoint was frozen
+    fn del_frozen(&mut self, outpoint: &OutPoint) -> Result<bool, Error>;
 }
 
 /// Trait for reading data from a database
@@ -131,6 +197,12 @@ pub trait Database: BatchOperations {
     fn iter_raw_txs(&self) -> Result<Vec<Transaction>, Error>;
     /// Return the list of transactions metadata
     fn iter_txs(&self, include_raw: bool) -> Result<Vec<TransactionDetails>, Error>;
+    /// Return the list of labels along with the object they are attached to
+    fn iter_labels(&self) -> Result<Vec<(LabelKey, String)>, Error>;
+    /// Return the list of frozen [`OutPoint`]s
+    fn iter_frozen(&self) -> Result<Vec<OutPoint>, Error>;
+    /// Return the list of keychains that have a descriptor checksum stored
+    fn iter_keychains(&self) -> Result<Vec<KeychainKind>, Error>;
 
     /// Fetch a script_pubkey given the child number of a keychain.
     fn get_script_pubkey_from_path(
@@ -153,6 +225,10 @@ pub trait Database: BatchOperations {
     fn get_last_index(&self, keychain: KeychainKind) -> Result<Option<u32>, Error>;
     /// Return the sync time, if present
     fn get_sync_time(&self) -> Result<Option<SyncTime>, Error>;
+    /// Fetch the label attached to a [`LabelKey`]
+    fn get_label(&self, key: &LabelKey) -> Result<Option<String>, Error>;
+    /// Return whether an [`OutPoint`] is frozen
+    fn is_frozen(&self, outpoint: &OutPoint) -> Result<bool, Error>;
 
     /// Increment the last derivation index for a keychain and return it
     ///
@@ -653,5 +729,139 @@ pub mod test {
         assert!(res.is_err());
     }
 
+    pub fn test_label<D: Database>(mut db: D) {
+        let outpoint = OutPoint::from_str(
+            "5df6e0e2761359d30a8275058e299fcc0381534545f55cf43e41983f5d4c9456:0",
+        )
+        .unwrap();
+        let script = Script::from(
+            Vec::<u8>::from_hex("76a91402306a7c23f3e8010de41e9e591348bb83f11daa88ac").unwrap(),
+        );
+        let utxo_key = LabelKey::Utxo(outpoint);
+        let address_key = LabelKey::Address(script);
+        let tx_key = LabelKey::Transaction(outpoint.txid);
+        let input_key = LabelKey::Input {
+            txid: outpoint.txid,
+            vin: 1,
+        };
+        let xpub_key = LabelKey::Xpub(ExtendedPubKey::from_str("tpubDD3ynpHgJQW8VvWRzQ5WFDCrs4jqVFGHB3vLC3r49XHJSqP8bHKdK4AriuUKLccK68zfzowx7YhmDN8SiSkgCDENUFx9qVw65YyqM78vyVe").unwrap());
+
+        assert!(db.get_label(&utxo_key).unwrap().is_none());
+
+        db.set_label(&utxo_key, "coffee change").unwrap();
+        db.set_label(&address_key, "donations").unwrap();
+        db.set_label(&tx_key, "payroll").unwrap();
+        db.set_label(&tx_key, "rent").unwrap();
+        db.set_label(&input_key, "from savings").unwrap();
+        db.set_label(&xpub_key, "cold storage").unwrap();
+
+        assert_eq!(
+            db.get_label(&utxo_key).unwrap(),
+            Some("coffee change".to_string())
+        );
+        assert_eq!(db.get_label(&tx_key).unwrap(), Some("rent".to_string()));
+
+        let mut labels = db.iter_labels().unwrap();
+        labels.sort();
+        assert_eq!(
+            labels,
+            vec![
+                (utxo_key.clone(), "coffee change".to_string()),
+                (address_key.clone(), "donations".to_string()),
+                (tx_key.clone(), "rent".to_string()),
+                (input_key.clone(), "from savings".to_string()),
+                (xpub_key.clone(), "cold storage".to_string()),
+            ]
+        );
+
+        assert_eq!(
+            db.del_label(&address_key).unwrap(),
+            Some("donations".to_string())
+        );
+        assert!(db.get_label(&address_key).unwrap().is_none());
+        assert!(db.del_label(&address_key).unwrap().is_none());
+        assert_eq!(db.iter_labels().unwrap().len(), 4);
+    }
+
+    pub fn test_frozen<D: Database>(mut db: D) {
+        let outpoint = OutPoint::from_str(
+            "5df6e0e2761359d30a8275058e299fcc0381534545f55cf43e41983f5d4c9456:0",
+        )
+        .unwrap();
+        let other = OutPoint::from_str(
+            "5df6e0e2761359d30a8275058e299fcc0381534545f55cf43e41983f5d4c9456:1",
+        )
+        .unwrap();
+
+        assert!(!db.is_frozen(&outpoint).unwrap());
+
+        db.set_frozen(&outpoint).unwrap();
+        db.set_frozen(&outpoint).unwrap();
+
+        assert!(db.is_frozen(&outpoint).unwrap());
+        assert!(!db.is_frozen(&other).unwrap());
+        assert_eq!(db.iter_frozen().unwrap(), vec![outpoint]);
+
+        assert!(db.del_frozen(&outpoint).unwrap());
+        assert!(!db.del_frozen(&outpoint).unwrap());
+        assert!(!db.is_frozen(&outpoint).unwrap());
+        assert!(db.iter_frozen().unwrap().is_empty());
+    }
+
+    pub fn test_custom_keychains<D: Database>(mut db: D) {
+        let first = Script::from(
+            Vec::<u8>::from_hex("76a91402306a7c23f3e8010de41e9e591348bb83f11daa88ac").unwrap(),
+        );
+        let second = Script::from(
+            Vec::<u8>::from_hex("0014a6e5ceeb2b9a50a7e1b5e6a5b9b8c6e33a8a4e4b").unwrap(),
+        );
+
+        db.set_script_pubkey(&first, KeychainKind::Custom(1), 0)
+            .unwrap();
+        db.set_script_pubkey(&second, KeychainKind::Custom(256), 0)
+            .unwrap();
+
+        assert_eq!(
+            db.iter_script_pubkeys(Some(KeychainKind::Custom(1)))
+                .unwrap(),
+            vec![first.clone()]
+        );
+        assert!(db
+            .iter_script_pubkeys(Some(KeychainKind::External))
+            .unwrap()
+            .is_empty());
+        assert_eq!(db.iter_script_pubkeys(None).unwrap().len(), 2);
+        assert_eq!(
+            db.get_path_from_script_pubkey(&second).unwrap(),
+            Some((KeychainKind::Custom(256), 0))
+        );
+
+        db.set_last_index(KeychainKind::Custom(1), 10).unwrap();
+        assert_eq!(
+            db.increment_last_index(KeychainKind::Custom(256)).unwrap(),
+            0
+        );
+        assert_eq!(
+            db.get_last_index(KeychainKind::Custom(1)).unwrap(),
+            Some(10)
+        );
+        assert_eq!(db.get_last_index(KeychainKind::External).unwrap(), None);
+
+        db.check_descriptor_checksum(KeychainKind::External, "1cead456")
+            .unwrap();
+        db.check_descriptor_checksum(KeychainKind::Custom(1), "1cead454")
+            .unwrap();
+        assert!(db
+            .check_descriptor_checksum(KeychainKind::Custom(1), "1cead456")
+            .is_err());
+
+        let mut keychains = db.iter_keychains().unwrap();
+        keychains.sort_by_key(|keychain| keychain.as_bytes());
+        assert_eq!(
+            keychains,
+            vec![KeychainKind::External, KeychainKind::Custom(1)]
+        );
+    }
+
     // TODO: more tests...
 }
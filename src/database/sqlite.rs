@@ -15,7 +15,7 @@ use bitcoin::consensus::encode::{deserialize, serialize};
 use bitcoin::hash_types::Txid;
 use bitcoin::{OutPoint, Script, Transaction, TxOut};
 
-use crate::database::{BatchDatabase, BatchOperations, Database, SyncTime};
+use crate::database::{BatchDatabase, BatchOperations, Database, LabelKey, SyncTime};
 use crate::error::Error;
 use crate::types::*;
 
@@ -57,7 +57,11 @@ static MIGRATIONS: &[&str] = &[
     "CREATE TABLE utxos (value INTEGER, keychain TEXT, vout INTEGER, txid BLOB, script BLOB, is_spent BOOLEAN DEFAULT 0);",
     "INSERT INTO utxos SELECT value, keychain, vout, txid, script, is_spent FROM utxos_old;",
     "DROP TABLE utxos_old;",
-    "CREATE UNIQUE INDEX idx_utxos_txid_vout ON utxos(txid, vout);"
+    "CREATE UNIQUE INDEX idx_utxos_txid_vout ON utxos(txid, vout);",
+    "CREATE TABLE labels (key BLOB, label TEXT);",
+    "CREATE UNIQUE INDEX idx_labels_key ON labels(key);",
+    "CREATE TABLE frozen_utxos (txid BLOB, vout INTEGER);",
+    "CREATE UNIQUE INDEX idx_frozen_utxos_txid_vout ON frozen_utxos(txid, vout);"
 ];
 
 /// Sqlite database stored on filesystem
@@ -244,6 +248,30 @@ impl SqliteDatabase {
         Ok(self.connection.last_insert_rowid())
     }
 
+    fn update_label(&self, key: &[u8], label: &str) -> Result<(), Error> {
+        let mut statement = self.connection.prepare_cached(
+            "INSERT INTO labels (key, label) VALUES (:key, :label) ON CONFLICT(key) DO UPDATE SET label=:label WHERE key=:key",
+        )?;
+        statement.execute(named_params! {
+            ":key": key,
+            ":label": label,
+        })?;
+
+        Ok(())
+    }
+
+    fn insert_frozen_utxo(&self, txid: &[u8], vout: u32) -> Result<(), Error> {
+        let mut statement = self.connection.prepare_cached(
+            "INSERT OR IGNORE INTO frozen_utxos (txid, vout) VALUES (:txid, :vout)",
+        )?;
+        statement.execute(named_params! {
+            ":txid": txid,
+            ":vout": vout,
+        })?;
+
+        Ok(())
+    }
+
     fn select_script_pubkeys(&self) -> Result<Vec<Script>, Error> {
         let mut statement = self
             .connection
@@ -560,6 +588,74 @@ impl SqliteDatabase {
         }
     }
 
+    fn select_labels(&self) -> Result<Vec<(LabelKey, String)>, Error> {
+        let mut statement = self
+            .connection
+            .prepare_cached("SELECT key, label FROM labels")?;
+        let mut labels = vec![];
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let key: Vec<u8> = row.get(0)?;
+            let label: String = row.get(1)?;
+            labels.push((LabelKey::from_bytes(&key)?, label));
+        }
+
+        Ok(labels)
+    }
+
+    fn select_label_by_key(&self, key: &[u8]) -> Result<Option<String>, Error> {
+        let mut statement = self
+            .connection
+            .prepare_cached("SELECT label FROM labels WHERE key=:key")?;
+        let mut rows = statement.query(named_params! {":key": key})?;
+
+        match rows.next()? {
+            Some(row) => {
+                let label: String = row.get(0)?;
+                Ok(Some(label))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn select_checksum_keychains(&self) -> Result<Vec<KeychainKind>, Error> {
+        let mut statement = self
+            .connection
+            .prepare_cached("SELECT keychain FROM checksums")?;
+        let mut keychains = vec![];
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let keychain: String = row.get(0)?;
+            keychains.push(serde_json::from_str(&keychain)?);
+        }
+
+        Ok(keychains)
+    }
+
+    fn select_frozen_utxos(&self) -> Result<Vec<OutPoint>, Error> {
+        let mut statement = self
+            .connection
+            .prepare_cached("SELECT txid, vout FROM frozen_utxos")?;
+        let mut outpoints = vec![];
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let txid: Vec<u8> = row.get(0)?;
+            let vout: u32 = row.get(1)?;
+            outpoints.push(OutPoint::new(deserialize(&txid)?, vout));
+        }
+
+        Ok(outpoints)
+    }
+
+    fn select_frozen_utxo_by_outpoint(&self, txid: &[u8], vout: u32) -> Result<bool, Error> {
+        let mut statement = self
+            .connection
+            .prepare_cached("SELECT vout FROM frozen_utxos WHERE txid=:txid AND vout=:vout")?;
+        let mut rows = statement.query(named_params! {":txid": txid, ":vout": vout})?;
+
+        Ok(rows.next()?.is_some())
+    }
+
     fn delete_script_pubkey_by_path(&self, keychain: String, child: u32) -> Result<(), Error> {
         let mut statement = self.connection.prepare_cached(
             "DELETE FROM script_pubkeys WHERE keychain=:keychain AND child=:child",
@@ -629,6 +725,26 @@ impl SqliteDatabase {
         statement.execute([])?;
         Ok(())
     }
+
+    fn delete_label_by_key(&self, key: &[u8]) -> Result<(), Error> {
+        let mut statement = self
+            .connection
+            .prepare_cached("DELETE FROM labels WHERE key=:key")?;
+        statement.execute(named_params! {":key": key})?;
+        Ok(())
+    }
+
+    fn delete_frozen_utxo_by_outpoint(&self, txid: &[u8], vout: u32) -> Result<(), Error> {
+        let mut statement = self
+            .connection
+            .prepare_cached("DELETE FROM frozen_utxos WHERE txid=:txid AND vout=:vout")?;
+        statement.execute(named_params! {
+            ":txid": txid,
+            ":vout": vout
+        })?;
+
+        Ok(())
+    }
 }
 
 impl BatchOperations for SqliteDatabase {
@@ -694,6 +810,14 @@ impl BatchOperations for SqliteDatabase {
         Ok(())
     }
 
+    fn set_label(&mut self, key: &LabelKey, label: &str) -> Result<(), Error> {
+        self.update_label(&key.as_bytes(), label)
+    }
+
+    fn set_frozen(&mut self, outpoint: &OutPoint) -> Result<(), Error> {
+        self.insert_frozen_utxo(&outpoint.txid, outpoint.vout)
+    }
+
     fn del_script_pubkey_from_path(
         &mut self,
         keychain: KeychainKind,
@@ -785,6 +909,27 @@ impl BatchOperations for SqliteDatabase {
             None => Ok(None),
         }
     }
+
+    fn del_label(&mut self, key: &LabelKey) -> Result<Option<String>, Error> {
+        let key = key.as_bytes();
+        match self.select_label_by_key(&key)? {
+            Some(label) => {
+                self.delete_label_by_key(&key)?;
+
+                Ok(Some(label))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn del_frozen(&mut self, outpoint: &OutPoint) -> Result<bool, Error> {
+        if self.select_frozen_utxo_by_outpoint(&outpoint.txid, outpoint.vout)? {
+            self.delete_frozen_utxo_by_outpoint(&outpoint.txid, outpoint.vout)?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
 }
 
 impl Database for SqliteDatabase {
@@ -835,6 +980,18 @@ impl Database for SqliteDatabase {
         }
     }
 
+    fn iter_labels(&self) -> Result<Vec<(LabelKey, String)>, Error> {
+        self.select_labels()
+    }
+
+    fn iter_frozen(&self) -> Result<Vec<OutPoint>, Error> {
+        self.select_frozen_utxos()
+    }
+
+    fn iter_keychains(&self) -> Result<Vec<KeychainKind>, Error> {
+        self.select_checksum_keychains()
+    }
+
     fn get_script_pubkey_from_path(
         &self,
         keychain: KeychainKind,
@@ -890,6 +1047,14 @@ impl Database for SqliteDatabase {
         self.select_sync_time()
     }
 
+    fn get_label(&self, key: &LabelKey) -> Result<Option<String>, Error> {
+        self.select_label_by_key(&key.as_bytes())
+    }
+
+    fn is_frozen(&self, outpoint: &OutPoint) -> Result<bool, Error> {
+        self.select_frozen_utxo_by_outpoint(&outpoint.txid, outpoint.vout)
+    }
+
     fn increment_last_index(&mut self, keychain: KeychainKind) -> Result<u32, Error> {
         let keychain_string = serde_json::to_string(&keychain)?;
         match self.get_last_index(keychain)? {
@@ -1096,4 +1261,19 @@ pub mod test {
     fn test_check_descriptor_checksum() {
         crate::database::test::test_check_descriptor_checksum(get_database());
     }
+
+    #[test]
+    fn test_label() {
+        crate::database::test::test_label(get_database());
+    }
+
+    #[test]
+    fn test_frozen() {
+        crate::database::test::test_frozen(get_database());
+    }
+
+    #[test]
+    fn test_custom_keychains() {
+        crate::database::test::test_custom_keychains(get_database());
+    }
 }
@@ -75,10 +75,19 @@ pub enum Error {
     ChecksumMismatch,
     /// Spending policy is not compatible with this [`KeychainKind`](crate::types::KeychainKind)
     SpendingPolicyRequired(crate::types::KeychainKind),
+    /// The [`KeychainKind`](crate::types::KeychainKind) hasn't been added to the wallet, or it
+    /// doesn't have a descriptor to spend its outputs
+    UnknownKeychain(crate::types::KeychainKind),
     /// Error while extracting and manipulating policies
     InvalidPolicyPathError(crate::descriptor::policy::PolicyError),
     /// Signing error
     Signer(crate::wallet::signer::SignerError),
+    /// Collaborative transaction error
+    Coinjoin(crate::wallet::coinjoin::CoinjoinError),
+    /// Payjoin error
+    Payjoin(crate::wallet::payjoin::PayjoinError),
+    /// Silent payment error
+    SilentPayment(crate::wallet::silent_payment::SilentPaymentError),
     /// Invalid network
     InvalidNetwork {
         /// requested network, for example what is given as bdk-cli option
@@ -191,6 +200,9 @@ macro_rules! impl_error {
 impl_error!(descriptor::error::Error, Descriptor);
 impl_error!(descriptor::policy::PolicyError, InvalidPolicyPathError);
 impl_error!(wallet::signer::SignerError, Signer);
+impl_error!(wallet::coinjoin::CoinjoinError, Coinjoin);
+impl_error!(wallet::payjoin::PayjoinError, Payjoin);
+impl_error!(wallet::silent_payment::SilentPaymentError, SilentPayment);
 
 impl From<crate::keys::KeyError> for Error {
     fn from(key_error: crate::keys::KeyError) -> Error {
@@ -9,7 +9,6 @@
 // You may not use this file except in accordance with one or both of these
 // licenses.
 
-use std::convert::AsRef;
 use std::ops::Sub;
 
 use bitcoin::blockdata::transaction::{OutPoint, Transaction, TxOut};
@@ -21,26 +20,51 @@ use serde::{Deserialize, Serialize};
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum KeychainKind {
     /// External
-    External = 0,
+    External,
     /// Internal, usually used for change outputs
-    Internal = 1,
+    Internal,
+    /// Additional keychain identified by a user-chosen id, see
+    /// [`Wallet::add_keychain`](crate::wallet::Wallet::add_keychain)
+    Custom(u32),
+    /// Watch-only scripts that don't belong to any descriptor, see
+    /// [`Wallet::import_script`](crate::wallet::Wallet::import_script)
+    Imported,
 }
 
 impl KeychainKind {
     /// Return [`KeychainKind`] as a byte
+    ///
+    /// Every [`KeychainKind::Custom`] keychain maps to the same byte, use
+    /// [`KeychainKind::as_bytes`] to get a unique representation.
     pub fn as_byte(&self) -> u8 {
         match self {
             KeychainKind::External => b'e',
             KeychainKind::Internal => b'i',
+            KeychainKind::Custom(_) => b'k',
+            KeychainKind::Imported => b'w',
         }
     }
-}
 
-impl AsRef<[u8]> for KeychainKind {
-    fn as_ref(&self) -> &[u8] {
-        match self {
-            KeychainKind::External => b"e",
-            KeychainKind::Internal => b"i",
+    /// Return [`KeychainKind`] as a unique sequence of bytes
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.as_byte()];
+        if let KeychainKind::Custom(id) = self {
+            bytes.extend_from_slice(&id.to_be_bytes());
+        }
+
+        bytes
+    }
+
+    /// Parse a [`KeychainKind`] previously serialized with [`KeychainKind::as_bytes`]
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [b'e'] => Some(KeychainKind::External),
+            [b'i'] => Some(KeychainKind::Internal),
+            [b'w'] => Some(KeychainKind::Imported),
+            [b'k', id @ ..] if id.len() == 4 => Some(KeychainKind::Custom(u32::from_be_bytes([
+                id[0], id[1], id[2], id[3],
+            ]))),
+            _ => None,
         }
     }
 }
@@ -331,6 +355,18 @@ impl std::iter::Sum for Balance {
     }
 }
 
+/// Balance of the wallet after subtracting the cost of spending every UTXO at a given fee rate
+///
+/// See [`Wallet::get_effective_balance`](crate::wallet::Wallet::get_effective_balance).
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+pub struct EffectiveBalance {
+    /// Value of the economical UTXOs net of the fee needed to spend them, in the same categories
+    /// as [`Balance`]
+    pub balance: Balance,
+    /// UTXOs whose value doesn't cover the fee needed to spend them
+    pub uneconomical: Vec<LocalUtxo>,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
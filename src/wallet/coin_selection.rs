@@ -75,6 +75,7 @@
 //!             selected: all_utxos_selected,
 //!             fee_amount: additional_fees,
 //!             excess,
+//!             waste: None,
 //!         })
 //!     }
 //! }
@@ -95,19 +96,21 @@
 //! ```
 
 use crate::types::FeeRate;
+use crate::wallet::tx_builder::ChangeSplit;
 use crate::wallet::utils::IsDust;
 use crate::{database::Database, WeightedUtxo};
 use crate::{error::Error, Utxo};
 
 use bitcoin::consensus::encode::serialize;
-use bitcoin::Script;
+use bitcoin::{OutPoint, Script};
 
 use rand::seq::SliceRandom;
 #[cfg(not(test))]
 use rand::thread_rng;
+use rand::Rng;
 #[cfg(test)]
 use rand::{rngs::StdRng, SeedableRng};
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::convert::TryInto;
 
 /// Default coin selection algorithm used by [`TxBuilder`](super::tx_builder::TxBuilder) if not
@@ -151,6 +154,9 @@ pub struct CoinSelectionResult {
     pub fee_amount: u64,
     /// Remaining amount after deducing fees and outgoing outputs
     pub excess: Excess,
+    /// Waste metric of the selection, for the algorithms that compute it (see
+    /// [`WasteMetricCoinSelection`])
+    pub waste: Option<i64>,
 }
 
 impl CoinSelectionResult {
@@ -324,6 +330,94 @@ pub fn decide_change(remaining_amount: u64, fee_rate: FeeRate, drain_script: &Sc
     }
 }
 
+/// Split the change of a selection into multiple outputs
+///
+/// - `excess`: the excess of the selection, as returned by [`decide_change`]
+/// - `fee_rate`: required fee rate for the current selection
+/// - `drain_script`: script to consider change creation, all the change outputs are assumed to
+///   have a script of the same length
+/// - `change_split`: how to split the change
+///
+/// Returns the values of the change outputs and the fee paid for the outputs added on top of the
+/// one already accounted for in `excess`. No outputs are returned if `excess` is
+/// [`Excess::NoChange`].
+pub fn split_change(
+    excess: &Excess,
+    fee_rate: FeeRate,
+    drain_script: &Script,
+    change_split: &ChangeSplit,
+) -> (Vec<u64>, u64) {
+    let amount = match excess {
+        Excess::Change { amount, .. } => *amount,
+        Excess::NoChange { .. } => return (vec![], 0),
+    };
+    // every additional output costs as much as the drain output considered in `decide_change`
+    let output_fee = fee_rate.fee_vb(serialize(drain_script).len() + 8usize);
+    let is_valid = |values: &[u64]| !values.iter().any(|v| v.is_dust(drain_script));
+
+    #[cfg(not(test))]
+    let mut rng = thread_rng();
+    #[cfg(test)]
+    let mut rng: StdRng = SeedableRng::from_seed([0; 32]);
+
+    let values = match *change_split {
+        ChangeSplit::Single => None,
+        ChangeSplit::Equal(outputs) => (2..=outputs).rev().find_map(|n| {
+            let available = amount.checked_sub((n as u64 - 1) * output_fee)?;
+            let mut values = vec![available / n as u64; n];
+            values[0] += available % n as u64;
+            Some(values).filter(|v| is_valid(v))
+        }),
+        ChangeSplit::Denomination { value, max_outputs } if value > 0 => {
+            let count = ((amount + output_fee) / (value + output_fee)).min(max_outputs as u64);
+            let mut values = vec![value; count as usize];
+            let remainder = (amount + output_fee).saturating_sub(count * (value + output_fee));
+            match remainder.checked_sub(output_fee) {
+                Some(change) if !change.is_dust(drain_script) => values.push(change),
+                // not worth an output on its own, add it to the last one
+                _ => {
+                    if let Some(last) = values.last_mut() {
+                        *last += remainder;
+                    }
+                }
+            }
+            Some(values).filter(|v| v.len() > 1 && is_valid(v))
+        }
+        ChangeSplit::Denomination { .. } => None,
+        ChangeSplit::Random(outputs) => (2..=outputs).rev().find_map(|n| {
+            let available = amount.checked_sub((n as u64 - 1) * output_fee)?;
+            let weights = (0..n)
+                .map(|_| rng.gen_range(1..=100u64))
+                .collect::<Vec<_>>();
+            let total_weight = weights.iter().sum::<u64>();
+            // the first output gets the remainder, the others are rounded like a payment amount
+            let mut values = weights
+                .iter()
+                .map(|w| round_payment_amount(available * w / total_weight))
+                .collect::<Vec<_>>();
+            values[0] = available - values[1..].iter().sum::<u64>();
+            Some(values).filter(|v| is_valid(v))
+        }),
+    };
+
+    match values {
+        Some(values) => {
+            let extra_fee = (values.len() as u64 - 1) * output_fee;
+            (values, extra_fee)
+        }
+        None => (vec![amount], 0),
+    }
+}
+
+// Round `amount` down to two significant digits
+fn round_payment_amount(amount: u64) -> u64 {
+    let mut magnitude = 1;
+    while amount / magnitude >= 100 {
+        magnitude *= 10;
+    }
+    amount / magnitude * magnitude
+}
+
 fn select_sorted_utxos(
     utxos: impl Iterator<Item = (bool, WeightedUtxo)>,
     fee_rate: FeeRate,
@@ -371,6 +465,7 @@ fn select_sorted_utxos(
         selected,
         fee_amount,
         excess,
+        waste: None,
     })
 }
 
@@ -715,14 +810,544 @@ impl BranchAndBoundCoinSelection {
             selected,
             fee_amount,
             excess,
+            waste: None,
+        }
+    }
+}
+
+/// Privacy preserving coin selection
+///
+/// This coin selection algorithm tries not to leak more information about the wallet than needed:
+///
+/// - UTXOs are grouped by `script_pubkey` and every group is spent as a whole (like Bitcoin Core's
+///   `avoid_partial_spends`), so that a reused address doesn't end up linking multiple
+///   transactions together;
+/// - selections spending a single script type are preferred over the ones mixing, for example,
+///   `sh(wpkh(...))` and `wpkh(...)` inputs. Only if no script type has enough funds (or the
+///   required UTXOs already mix them) every group is considered.
+///
+/// Among the groups that can be spent, the largest ones are picked first.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PrivacyCoinSelection;
+
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Hash, Clone, Copy)]
+enum ScriptType {
+    P2pkh,
+    P2sh,
+    P2wpkh,
+    P2wsh,
+    P2tr,
+    Other,
+}
+
+impl ScriptType {
+    fn of(utxo: &WeightedUtxo) -> Self {
+        let script = &utxo.utxo.txout().script_pubkey;
+        if script.is_p2pkh() {
+            ScriptType::P2pkh
+        } else if script.is_p2sh() {
+            ScriptType::P2sh
+        } else if script.is_v0_p2wpkh() {
+            ScriptType::P2wpkh
+        } else if script.is_v0_p2wsh() {
+            ScriptType::P2wsh
+        } else if script.is_v1_p2tr() {
+            ScriptType::P2tr
+        } else {
+            ScriptType::Other
+        }
+    }
+}
+
+impl<D: Database> CoinSelectionAlgorithm<D> for PrivacyCoinSelection {
+    fn coin_select(
+        &self,
+        _database: &D,
+        mut required_utxos: Vec<WeightedUtxo>,
+        optional_utxos: Vec<WeightedUtxo>,
+        fee_rate: FeeRate,
+        target_amount: u64,
+        drain_script: &Script,
+    ) -> Result<CoinSelectionResult, Error> {
+        // The optional UTXOs sharing an address with a required one are required as well
+        let required_scripts = required_utxos
+            .iter()
+            .map(|u| u.utxo.txout().script_pubkey.clone())
+            .collect::<HashSet<_>>();
+        let mut groups: HashMap<Script, Vec<WeightedUtxo>> = HashMap::new();
+        for utxo in optional_utxos {
+            let script_pubkey = utxo.utxo.txout().script_pubkey.clone();
+            if required_scripts.contains(&script_pubkey) {
+                required_utxos.push(utxo);
+            } else {
+                groups.entry(script_pubkey).or_default().push(utxo);
+            }
+        }
+
+        // Largest groups first, sorting by script to break ties deterministically
+        let mut groups = groups.into_iter().collect::<Vec<_>>();
+        groups.sort_unstable_by(|(a_script, a), (b_script, b)| {
+            let a_value = a.iter().map(|u| u.utxo.txout().value).sum::<u64>();
+            let b_value = b.iter().map(|u| u.utxo.txout().value).sum::<u64>();
+            b_value.cmp(&a_value).then_with(|| a_script.cmp(b_script))
+        });
+        let groups = groups
+            .into_iter()
+            .map(|(_, group)| group)
+            .collect::<Vec<_>>();
+
+        let required_types = required_utxos
+            .iter()
+            .map(ScriptType::of)
+            .collect::<BTreeSet<_>>();
+        let candidate_types = match required_types.len() {
+            0 => groups.iter().map(|g| ScriptType::of(&g[0])).collect(),
+            1 => required_types,
+            // Mixing script types can't be avoided anymore
+            _ => BTreeSet::new(),
+        };
+
+        let best_single_type = candidate_types
+            .into_iter()
+            .filter_map(|script_type| {
+                let groups = groups
+                    .iter()
+                    .filter(|g| ScriptType::of(&g[0]) == script_type)
+                    .cloned();
+                select_groups(
+                    required_utxos.clone(),
+                    groups,
+                    fee_rate,
+                    target_amount,
+                    drain_script,
+                )
+                .ok()
+            })
+            .min_by_key(|result| result.fee_amount);
+
+        match best_single_type {
+            Some(result) => Ok(result),
+            None => select_groups(
+                required_utxos,
+                groups.into_iter(),
+                fee_rate,
+                target_amount,
+                drain_script,
+            ),
+        }
+    }
+}
+
+fn select_groups(
+    required_utxos: Vec<WeightedUtxo>,
+    groups: impl Iterator<Item = Vec<WeightedUtxo>>,
+    fee_rate: FeeRate,
+    target_amount: u64,
+    drain_script: &Script,
+) -> Result<CoinSelectionResult, Error> {
+    let mut selected_amount = 0;
+    let mut fee_amount = 0;
+    let mut selected = vec![];
+    let groups = std::iter::once((true, required_utxos)).chain(groups.map(|g| (false, g)));
+    for (must_use, group) in groups {
+        if !must_use && selected_amount >= target_amount + fee_amount {
+            break;
+        }
+        for weighted_utxo in group {
+            fee_amount += fee_rate.fee_wu(TXIN_BASE_WEIGHT + weighted_utxo.satisfaction_weight);
+            selected_amount += weighted_utxo.utxo.txout().value;
+            selected.push(weighted_utxo.utxo);
+        }
+    }
+
+    let amount_needed_with_fees = target_amount + fee_amount;
+    if selected_amount < amount_needed_with_fees {
+        return Err(Error::InsufficientFunds {
+            needed: amount_needed_with_fees,
+            available: selected_amount,
+        });
+    }
+
+    let remaining_amount = selected_amount - amount_needed_with_fees;
+
+    let excess = decide_change(remaining_amount, fee_rate, drain_script);
+
+    Ok(CoinSelectionResult {
+        selected,
+        fee_amount,
+        excess,
+        waste: None,
+    })
+}
+
+/// Knapsack coin selection
+///
+/// Port of the stochastic approximation used by Bitcoin Core's `KnapsackSolver`. UTXOs whose
+/// effective value alone is larger than the target plus `min_change` are only considered as a
+/// whole, while the subset of the smaller ones closest to the target (or to the target plus
+/// `min_change`, to avoid creating tiny change outputs) is approximated by randomly including and
+/// excluding them over many iterations.
+#[derive(Debug, Clone, Copy)]
+pub struct KnapsackCoinSelection {
+    min_change: u64,
+}
+
+impl Default for KnapsackCoinSelection {
+    fn default() -> Self {
+        Self {
+            // Bitcoin Core's `MIN_CHANGE`, 0.01 BTC
+            min_change: 1_000_000,
+        }
+    }
+}
+
+impl KnapsackCoinSelection {
+    /// Create new instance with the minimum change amount to aim for when an exact match can't be
+    /// found
+    pub fn new(min_change: u64) -> Self {
+        Self { min_change }
+    }
+}
+
+const KNAPSACK_ITERATIONS: usize = 1_000;
+
+impl<D: Database> CoinSelectionAlgorithm<D> for KnapsackCoinSelection {
+    fn coin_select(
+        &self,
+        _database: &D,
+        required_utxos: Vec<WeightedUtxo>,
+        optional_utxos: Vec<WeightedUtxo>,
+        fee_rate: FeeRate,
+        target_amount: u64,
+        drain_script: &Script,
+    ) -> Result<CoinSelectionResult, Error> {
+        let required_utxos: Vec<OutputGroup> = required_utxos
+            .into_iter()
+            .map(|u| OutputGroup::new(u, fee_rate))
+            .collect();
+        let mut optional_utxos: Vec<OutputGroup> = optional_utxos
+            .into_iter()
+            .map(|u| OutputGroup::new(u, fee_rate))
+            .filter(|u| u.effective_value.is_positive())
+            .collect();
+
+        let curr_value = required_utxos
+            .iter()
+            .map(|u| u.effective_value)
+            .sum::<i64>();
+        let target_amount: i64 = target_amount
+            .try_into()
+            .expect("Bitcoin amount to fit into i64");
+        // what's left to select once the required utxos are spent
+        let target = target_amount - curr_value;
+
+        let finish = |selected: Vec<OutputGroup>, required: Vec<OutputGroup>| {
+            let selected_value = selected.iter().map(|u| u.effective_value).sum::<i64>();
+            // remaining_amount can't be negative as that would mean the
+            // selection wasn't successful
+            let remaining_amount = (curr_value + selected_value - target_amount) as u64;
+            let excess = decide_change(remaining_amount, fee_rate, drain_script);
+
+            BranchAndBoundCoinSelection::calculate_cs_result(selected, required, excess)
+        };
+
+        if target <= 0 {
+            return Ok(finish(vec![], required_utxos));
+        }
+
+        #[cfg(not(test))]
+        let mut rng = thread_rng();
+        #[cfg(test)]
+        let mut rng: StdRng = SeedableRng::from_seed([0; 32]);
+
+        optional_utxos.shuffle(&mut rng);
+
+        let min_change = self.min_change as i64;
+        let mut applicable_utxos = vec![];
+        let mut total_lower = 0;
+        let mut lowest_larger: Option<OutputGroup> = None;
+        // Assume we spend all the UTXOs we can, in case there aren't enough of them
+        let (utxo_fees, utxo_value) = required_utxos.iter().chain(optional_utxos.iter()).fold(
+            (0, 0),
+            |(fees, value), utxo| {
+                (
+                    fees + utxo.fee,
+                    value + utxo.weighted_utxo.utxo.txout().value,
+                )
+            },
+        );
+        for utxo in optional_utxos {
+            if utxo.effective_value == target {
+                return Ok(finish(vec![utxo], required_utxos));
+            } else if utxo.effective_value < target + min_change {
+                total_lower += utxo.effective_value;
+                applicable_utxos.push(utxo);
+            } else {
+                match lowest_larger {
+                    Some(ref larger) if larger.effective_value <= utxo.effective_value => {}
+                    _ => lowest_larger = Some(utxo),
+                }
+            }
+        }
+
+        if total_lower == target {
+            return Ok(finish(applicable_utxos, required_utxos));
+        }
+
+        if total_lower < target {
+            return match lowest_larger {
+                Some(larger) => Ok(finish(vec![larger], required_utxos)),
+                None => Err(Error::InsufficientFunds {
+                    needed: target_amount as u64 + utxo_fees,
+                    available: utxo_value,
+                }),
+            };
+        }
+
+        // Solve subset sum by stochastic approximation
+        applicable_utxos.sort_unstable_by_key(|u| std::cmp::Reverse(u.effective_value));
+        let values = applicable_utxos
+            .iter()
+            .map(|u| u.effective_value)
+            .collect::<Vec<_>>();
+        let (mut best, mut best_value) =
+            approximate_best_subset(&mut rng, &values, total_lower, target);
+        if best_value != target && total_lower >= target + min_change {
+            let (subset, subset_value) =
+                approximate_best_subset(&mut rng, &values, total_lower, target + min_change);
+            best = subset;
+            best_value = subset_value;
+        }
+
+        // If we have a bigger coin and (either the stochastic approximation didn't find a good
+        // solution, or the next bigger coin is closer), return the bigger coin
+        let selected = match lowest_larger {
+            Some(larger)
+                if (best_value != target && best_value < target + min_change)
+                    || larger.effective_value <= best_value =>
+            {
+                vec![larger]
+            }
+            _ => applicable_utxos
+                .into_iter()
+                .zip(best)
+                .filter_map(|(utxo, is_in_best)| if is_in_best { Some(utxo) } else { None })
+                .collect(),
+        };
+
+        Ok(finish(selected, required_utxos))
+    }
+}
+
+// Returns which of `values` to include to get as close as possible to `target` without going
+// below it, and the sum of the selected values
+fn approximate_best_subset<R: Rng>(
+    rng: &mut R,
+    values: &[i64],
+    total_lower: i64,
+    target: i64,
+) -> (Vec<bool>, i64) {
+    let mut best = vec![true; values.len()];
+    let mut best_value = total_lower;
+
+    for _ in 0..KNAPSACK_ITERATIONS {
+        if best_value == target {
+            break;
+        }
+
+        let mut included = vec![false; values.len()];
+        let mut total = 0;
+        let mut reached_target = false;
+        for pass in 0..2 {
+            if reached_target {
+                break;
+            }
+            for (i, value) in values.iter().enumerate() {
+                // The solver here uses a randomized algorithm, the randomness serves no real
+                // security purpose but is just needed to prevent degenerate behavior
+                let include = if pass == 0 {
+                    rng.gen_bool(0.5)
+                } else {
+                    !included[i]
+                };
+                if include {
+                    total += value;
+                    included[i] = true;
+                    if total >= target {
+                        reached_target = true;
+                        if total < best_value {
+                            best_value = total;
+                            best = included.clone();
+                        }
+                        total -= value;
+                        included[i] = false;
+                    }
+                }
+            }
+        }
+    }
+
+    (best, best_value)
+}
+
+/// Coin selection that minimizes the waste metric
+///
+/// This coin selection algorithm runs several other algorithms on the same UTXOs and picks the
+/// selection with the lowest waste, as defined in Bitcoin Core:
+///
+/// - for every input, the difference between the fee paid to spend it now at `fee_rate` and the
+///   fee it would cost to spend it in the future at `long_term_fee_rate`;
+/// - plus, if a change output is created, the cost of creating it now and spending it in the
+///   future at `long_term_fee_rate`;
+/// - or, if there's no change, the excess amount that goes to the miners.
+///
+/// When the current fee rate is lower than the long term one every input has a negative waste, so
+/// selections spending more inputs are preferred, and the other way around when fees are high.
+///
+/// The computed waste is available in [`CoinSelectionResult::waste`].
+#[derive(Debug, Clone, Copy)]
+pub struct WasteMetricCoinSelection {
+    long_term_fee_rate: FeeRate,
+    size_of_change: u64,
+    change_spend_weight: usize,
+}
+
+impl Default for WasteMetricCoinSelection {
+    fn default() -> Self {
+        Self::new(FeeRate::from_sat_per_vb(10.0))
+    }
+}
+
+impl WasteMetricCoinSelection {
+    /// Create a new instance given the fee rate expected to be paid in the long term to spend the
+    /// UTXOs (10 sat/vbyte by default)
+    pub fn new(long_term_fee_rate: FeeRate) -> Self {
+        Self {
+            long_term_fee_rate,
+            // P2WPKH cost of change -> value (8 bytes) + script len (1 bytes) + script (22 bytes)
+            size_of_change: 8 + 1 + 22,
+            // P2WPKH witness -> n. of items (1WU) + signature len (1WU) + signature and sighash
+            // (72WU) + pubkey len (1WU) + pubkey (33WU)
+            change_spend_weight: TXIN_BASE_WEIGHT + 1 + 1 + 72 + 1 + 33,
         }
     }
+
+    /// Set the size in bytes of the change output and the weight of the input that will
+    /// eventually spend it, if the change is not P2WPKH
+    pub fn change_weights(mut self, size_of_change: u64, change_spend_weight: usize) -> Self {
+        self.size_of_change = size_of_change;
+        self.change_spend_weight = change_spend_weight;
+        self
+    }
+
+    fn waste(
+        &self,
+        result: &CoinSelectionResult,
+        weights: &HashMap<OutPoint, usize>,
+        fee_rate: FeeRate,
+    ) -> i64 {
+        let inputs_waste = result
+            .selected
+            .iter()
+            .map(|utxo| {
+                let weight = TXIN_BASE_WEIGHT + weights[&utxo.outpoint()];
+                fee_rate.fee_wu(weight) as i64 - self.long_term_fee_rate.fee_wu(weight) as i64
+            })
+            .sum::<i64>();
+
+        let excess_waste = match result.excess {
+            Excess::Change { fee, .. } => {
+                fee + self.long_term_fee_rate.fee_wu(self.change_spend_weight)
+            }
+            Excess::NoChange {
+                remaining_amount, ..
+            } => remaining_amount,
+        };
+
+        inputs_waste + excess_waste as i64
+    }
+}
+
+impl<D: Database> CoinSelectionAlgorithm<D> for WasteMetricCoinSelection {
+    fn coin_select(
+        &self,
+        database: &D,
+        required_utxos: Vec<WeightedUtxo>,
+        optional_utxos: Vec<WeightedUtxo>,
+        fee_rate: FeeRate,
+        target_amount: u64,
+        drain_script: &Script,
+    ) -> Result<CoinSelectionResult, Error> {
+        let weights = required_utxos
+            .iter()
+            .chain(optional_utxos.iter())
+            .map(|u| (u.utxo.outpoint(), u.satisfaction_weight))
+            .collect::<HashMap<_, _>>();
+
+        let algorithms: Vec<Box<dyn CoinSelectionAlgorithm<D>>> = vec![
+            Box::new(BranchAndBoundCoinSelection::new(self.size_of_change)),
+            Box::new(KnapsackCoinSelection::default()),
+            Box::new(LargestFirstCoinSelection),
+            Box::new(OldestFirstCoinSelection),
+        ];
+        let mut candidates = algorithms
+            .iter()
+            .map(|algorithm| {
+                algorithm.coin_select(
+                    database,
+                    required_utxos.clone(),
+                    optional_utxos.clone(),
+                    fee_rate,
+                    target_amount,
+                    drain_script,
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Branch and bound only returns a random draw if it can't find an exact match, try one
+        // anyway since it might be less wasteful than what the other algorithms found
+        let required_groups = required_utxos
+            .into_iter()
+            .map(|u| OutputGroup::new(u, fee_rate))
+            .collect::<Vec<_>>();
+        let optional_groups = optional_utxos
+            .into_iter()
+            .map(|u| OutputGroup::new(u, fee_rate))
+            .filter(|u| u.effective_value.is_positive())
+            .collect::<Vec<_>>();
+        let curr_value = required_groups.iter().map(|u| u.effective_value).sum();
+        candidates.push(
+            BranchAndBoundCoinSelection::new(self.size_of_change).single_random_draw(
+                required_groups,
+                optional_groups,
+                curr_value,
+                target_amount as i64,
+                drain_script,
+                fee_rate,
+            ),
+        );
+
+        let (waste, mut result) = candidates
+            .into_iter()
+            .map(|result| (self.waste(&result, &weights, fee_rate), result))
+            .min_by_key(|(waste, _)| *waste)
+            .expect("there's always at least one candidate");
+        log::debug!(
+            "Selected {} inputs, waste = `{}`",
+            result.selected.len(),
+            waste
+        );
+
+        result.waste = Some(waste);
+        Ok(result)
+    }
 }
 
 #[cfg(test)]
 mod test {
     use std::str::FromStr;
 
+    use bitcoin::hashes::Hash;
     use bitcoin::{OutPoint, Script, TxOut};
 
     use super::*;
@@ -1597,4 +2222,573 @@ mod test {
             }
         ));
     }
+
+    fn utxo_with_script(value: u64, index: u32, script_pubkey: Script) -> WeightedUtxo {
+        let mut utxo = utxo(value, index);
+        if let Utxo::Local(local) = &mut utxo.utxo {
+            local.txout.script_pubkey = script_pubkey;
+        }
+        utxo
+    }
+
+    fn p2wpkh(index: u8) -> Script {
+        Script::new_v0_p2wpkh(&bitcoin::WPubkeyHash::hash(&[index]))
+    }
+
+    fn p2sh(index: u8) -> Script {
+        Script::new_p2sh(&bitcoin::ScriptHash::hash(&[index]))
+    }
+
+    fn selected_outpoints(result: &CoinSelectionResult) -> HashSet<OutPoint> {
+        result.selected.iter().map(|u| u.outpoint()).collect()
+    }
+
+    #[test]
+    fn test_privacy_coin_selection_single_script_type() {
+        // largest first would mix the p2sh utxo with one of the p2wpkh ones
+        let utxos = vec![
+            utxo_with_script(100_000, 0, p2sh(0)),
+            utxo_with_script(60_000, 1, p2wpkh(1)),
+            utxo_with_script(60_000, 2, p2wpkh(2)),
+        ];
+        let database = MemoryDatabase::default();
+        let drain_script = Script::default();
+
+        let result = PrivacyCoinSelection::default()
+            .coin_select(
+                &database,
+                vec![],
+                utxos.clone(),
+                FeeRate::from_sat_per_vb(1.0),
+                110_000,
+                &drain_script,
+            )
+            .unwrap();
+
+        assert_eq!(
+            selected_outpoints(&result),
+            vec![utxos[1].utxo.outpoint(), utxos[2].utxo.outpoint()]
+                .into_iter()
+                .collect()
+        );
+        assert_eq!(result.fee_amount, 136);
+    }
+
+    #[test]
+    fn test_privacy_coin_selection_spends_whole_address() {
+        let utxos = vec![
+            utxo_with_script(50_000, 0, p2wpkh(0)),
+            utxo_with_script(60_000, 1, p2wpkh(1)),
+            utxo_with_script(50_000, 2, p2wpkh(0)),
+        ];
+        let database = MemoryDatabase::default();
+        let drain_script = Script::default();
+
+        let result = PrivacyCoinSelection::default()
+            .coin_select(
+                &database,
+                vec![],
+                utxos.clone(),
+                FeeRate::from_sat_per_vb(1.0),
+                20_000,
+                &drain_script,
+            )
+            .unwrap();
+
+        assert_eq!(
+            selected_outpoints(&result),
+            vec![utxos[0].utxo.outpoint(), utxos[2].utxo.outpoint()]
+                .into_iter()
+                .collect()
+        );
+    }
+
+    #[test]
+    fn test_privacy_coin_selection_required_address() {
+        // the optional utxo sharing the address of the required one is spent too
+        let utxos = vec![
+            utxo_with_script(50_000, 0, p2wpkh(0)),
+            utxo_with_script(60_000, 1, p2wpkh(1)),
+            utxo_with_script(10_000, 2, p2wpkh(0)),
+        ];
+        let database = MemoryDatabase::default();
+        let drain_script = Script::default();
+
+        let result = PrivacyCoinSelection::default()
+            .coin_select(
+                &database,
+                vec![utxos[0].clone()],
+                utxos[1..].to_vec(),
+                FeeRate::from_sat_per_vb(1.0),
+                20_000,
+                &drain_script,
+            )
+            .unwrap();
+
+        assert_eq!(
+            selected_outpoints(&result),
+            vec![utxos[0].utxo.outpoint(), utxos[2].utxo.outpoint()]
+                .into_iter()
+                .collect()
+        );
+    }
+
+    #[test]
+    fn test_privacy_coin_selection_required_script_type() {
+        // the p2wpkh utxos would be enough, but the required p2sh one is already being spent
+        let utxos = vec![
+            utxo_with_script(10_000, 0, p2sh(0)),
+            utxo_with_script(100_000, 1, p2wpkh(1)),
+            utxo_with_script(50_000, 2, p2sh(2)),
+        ];
+        let database = MemoryDatabase::default();
+        let drain_script = Script::default();
+
+        let result = PrivacyCoinSelection::default()
+            .coin_select(
+                &database,
+                vec![utxos[0].clone()],
+                utxos[1..].to_vec(),
+                FeeRate::from_sat_per_vb(1.0),
+                40_000,
+                &drain_script,
+            )
+            .unwrap();
+
+        assert_eq!(
+            selected_outpoints(&result),
+            vec![utxos[0].utxo.outpoint(), utxos[2].utxo.outpoint()]
+                .into_iter()
+                .collect()
+        );
+    }
+
+    #[test]
+    fn test_privacy_coin_selection_mix_when_needed() {
+        let utxos = vec![
+            utxo_with_script(100_000, 0, p2sh(0)),
+            utxo_with_script(60_000, 1, p2wpkh(1)),
+            utxo_with_script(60_000, 2, p2wpkh(2)),
+        ];
+        let database = MemoryDatabase::default();
+        let drain_script = Script::default();
+
+        let result = PrivacyCoinSelection::default()
+            .coin_select(
+                &database,
+                vec![],
+                utxos,
+                FeeRate::from_sat_per_vb(1.0),
+                150_000,
+                &drain_script,
+            )
+            .unwrap();
+
+        assert_eq!(result.selected.len(), 2);
+        assert_eq!(result.selected_amount(), 160_000);
+    }
+
+    #[test]
+    fn test_privacy_coin_selection_insufficient_funds() {
+        let utxos = vec![
+            utxo_with_script(100_000, 0, p2sh(0)),
+            utxo_with_script(60_000, 1, p2wpkh(1)),
+        ];
+        let database = MemoryDatabase::default();
+        let drain_script = Script::default();
+
+        let err = PrivacyCoinSelection::default()
+            .coin_select(
+                &database,
+                vec![],
+                utxos,
+                FeeRate::from_sat_per_vb(1.0),
+                500_000,
+                &drain_script,
+            )
+            .unwrap_err();
+
+        assert!(matches!(
+            err,
+            Error::InsufficientFunds {
+                available: 160_000,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_knapsack_coin_selection_exact_match() {
+        let utxos = get_test_utxos();
+        let database = MemoryDatabase::default();
+        let drain_script = Script::default();
+        // 100_000 minus the fee to spend it at 1 sat/vb
+        let target_amount = 100_000 - 68;
+
+        let result = KnapsackCoinSelection::default()
+            .coin_select(
+                &database,
+                vec![],
+                utxos,
+                FeeRate::from_sat_per_vb(1.0),
+                target_amount,
+                &drain_script,
+            )
+            .unwrap();
+
+        assert_eq!(result.selected.len(), 1);
+        assert_eq!(result.selected_amount(), 100_000);
+        assert_eq!(result.fee_amount, 68);
+        assert!(matches!(
+            result.excess,
+            Excess::NoChange {
+                remaining_amount: 0,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_knapsack_coin_selection_lowest_larger() {
+        // the two small utxos are not enough, and there's no point in adding them to the large
+        // one
+        let utxos = vec![utxo(10_000, 0), utxo(20_000, 1), utxo(5_000_000, 2)];
+        let database = MemoryDatabase::default();
+        let drain_script = Script::default();
+
+        let result = KnapsackCoinSelection::default()
+            .coin_select(
+                &database,
+                vec![],
+                utxos,
+                FeeRate::from_sat_per_vb(1.0),
+                50_000,
+                &drain_script,
+            )
+            .unwrap();
+
+        assert_eq!(result.selected.len(), 1);
+        assert_eq!(result.selected_amount(), 5_000_000);
+    }
+
+    #[test]
+    fn test_knapsack_coin_selection_subset() {
+        let utxos = vec![
+            utxo(10_068, 0),
+            utxo(20_068, 1),
+            utxo(30_068, 2),
+            utxo(40_068, 3),
+            utxo(80_068, 4),
+        ];
+        let database = MemoryDatabase::default();
+        let drain_script = Script::default();
+
+        let result = KnapsackCoinSelection::new(1_000)
+            .coin_select(
+                &database,
+                vec![],
+                utxos,
+                FeeRate::from_sat_per_vb(1.0),
+                70_000,
+                &drain_script,
+            )
+            .unwrap();
+
+        // the effective value of every utxo is a multiple of 10_000, an exact match exists
+        assert_eq!(
+            result.selected_amount() - result.fee_amount,
+            70_000,
+            "{:?}",
+            result
+        );
+        assert!(matches!(
+            result.excess,
+            Excess::NoChange {
+                remaining_amount: 0,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_knapsack_coin_selection_required_are_enough() {
+        let utxos = get_test_utxos();
+        let database = MemoryDatabase::default();
+        let drain_script = Script::default();
+
+        let result = KnapsackCoinSelection::default()
+            .coin_select(
+                &database,
+                vec![utxos[0].clone()],
+                utxos[1..].to_vec(),
+                FeeRate::from_sat_per_vb(1.0),
+                50_000,
+                &drain_script,
+            )
+            .unwrap();
+
+        assert_eq!(result.selected.len(), 1);
+        assert_eq!(result.selected_amount(), 100_000);
+        assert!(matches!(result.excess, Excess::Change { .. }));
+    }
+
+    #[test]
+    fn test_knapsack_coin_selection_insufficient_funds() {
+        let utxos = get_test_utxos();
+        let database = MemoryDatabase::default();
+        let drain_script = Script::default();
+
+        let err = KnapsackCoinSelection::default()
+            .coin_select(
+                &database,
+                vec![],
+                utxos,
+                FeeRate::from_sat_per_vb(1.0),
+                500_000,
+                &drain_script,
+            )
+            .unwrap_err();
+
+        assert!(matches!(
+            err,
+            Error::InsufficientFunds {
+                available: 300_000,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_knapsack_coin_selection_is_reproducible() {
+        let mut rng: StdRng = SeedableRng::from_seed([0; 32]);
+        let utxos = generate_random_utxos(&mut rng, 50);
+        let database = MemoryDatabase::default();
+        let drain_script = Script::default();
+        let target_amount = sum_random_utxos(&mut rng, &mut utxos.clone());
+
+        let select = || {
+            KnapsackCoinSelection::default()
+                .coin_select(
+                    &database,
+                    vec![],
+                    utxos.clone(),
+                    FeeRate::from_sat_per_vb(1.0),
+                    target_amount,
+                    &drain_script,
+                )
+                .unwrap()
+                .selected
+                .into_iter()
+                .map(|u| u.outpoint())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(select(), select());
+    }
+
+    fn get_waste_test_utxos() -> Vec<WeightedUtxo> {
+        vec![utxo(200_000, 0), utxo(100_000, 1), utxo(50_000, 2)]
+    }
+
+    #[test]
+    fn test_waste_metric_coin_selection_high_fee_rate() {
+        // when fees are higher than in the long term, spending fewer inputs and creating change
+        // is less wasteful than the exact match found by branch and bound
+        let utxos = get_waste_test_utxos();
+        let database = MemoryDatabase::default();
+        let drain_script = Script::default();
+
+        let result = WasteMetricCoinSelection::new(FeeRate::from_sat_per_vb(1.0))
+            .coin_select(
+                &database,
+                vec![],
+                utxos,
+                FeeRate::from_sat_per_vb(10.0),
+                148_540,
+                &drain_script,
+            )
+            .unwrap();
+
+        assert_eq!(result.selected.len(), 1);
+        assert_eq!(result.selected_amount(), 200_000);
+        assert!(matches!(result.excess, Excess::Change { .. }));
+        // input: 680 - 68, change: 90 + 67
+        assert_eq!(result.waste, Some(769));
+    }
+
+    #[test]
+    fn test_waste_metric_coin_selection_low_fee_rate() {
+        // when fees are lower than in the long term, spending more inputs is less wasteful
+        let utxos = get_waste_test_utxos();
+        let database = MemoryDatabase::default();
+        let drain_script = Script::default();
+
+        let result = WasteMetricCoinSelection::new(FeeRate::from_sat_per_vb(10.0))
+            .coin_select(
+                &database,
+                vec![],
+                utxos,
+                FeeRate::from_sat_per_vb(1.0),
+                149_850,
+                &drain_script,
+            )
+            .unwrap();
+
+        assert_eq!(result.selected.len(), 2);
+        assert_eq!(result.selected_amount(), 150_000);
+        assert!(matches!(result.excess, Excess::NoChange { .. }));
+        // inputs: 2 * (68 - 680), excess: 14
+        assert_eq!(result.waste, Some(-1210));
+    }
+
+    #[test]
+    fn test_waste_metric_coin_selection_insufficient_funds() {
+        let utxos = get_waste_test_utxos();
+        let database = MemoryDatabase::default();
+        let drain_script = Script::default();
+
+        let err = WasteMetricCoinSelection::default()
+            .coin_select(
+                &database,
+                vec![],
+                utxos,
+                FeeRate::from_sat_per_vb(1.0),
+                500_000,
+                &drain_script,
+            )
+            .unwrap_err();
+
+        assert!(matches!(err, Error::InsufficientFunds { .. }));
+    }
+
+    #[test]
+    fn test_other_coin_selections_have_no_waste() {
+        let utxos = get_waste_test_utxos();
+        let database = MemoryDatabase::default();
+        let drain_script = Script::default();
+
+        let result = LargestFirstCoinSelection::default()
+            .coin_select(
+                &database,
+                vec![],
+                utxos,
+                FeeRate::from_sat_per_vb(1.0),
+                100_000,
+                &drain_script,
+            )
+            .unwrap();
+
+        assert_eq!(result.waste, None);
+    }
+
+    #[test]
+    fn test_split_change_equal() {
+        let drain_script = Script::default();
+        let fee_rate = FeeRate::from_sat_per_vb(1.0);
+        let excess = decide_change(30_009, fee_rate, &drain_script);
+
+        let (values, extra_fee) =
+            split_change(&excess, fee_rate, &drain_script, &ChangeSplit::Equal(3));
+        // every additional output costs 9 vbytes
+        assert_eq!(extra_fee, 18);
+        assert_eq!(values, vec![9_994, 9_994, 9_994]);
+        assert_eq!(values.iter().sum::<u64>() + extra_fee, 30_000);
+
+        let (values, extra_fee) =
+            split_change(&excess, fee_rate, &drain_script, &ChangeSplit::Single);
+        assert_eq!((values, extra_fee), (vec![30_000], 0));
+    }
+
+    #[test]
+    fn test_split_change_avoids_dust() {
+        let drain_script = Script::default();
+        let fee_rate = FeeRate::from_sat_per_vb(1.0);
+        let dust = drain_script.dust_value().to_sat();
+        let excess = Excess::Change {
+            amount: 2 * dust + 100,
+            fee: 9,
+        };
+
+        // only two outputs above the dust limit can be afforded
+        let (values, extra_fee) =
+            split_change(&excess, fee_rate, &drain_script, &ChangeSplit::Equal(10));
+        assert_eq!(values.len(), 2);
+        assert_eq!(values.iter().sum::<u64>() + extra_fee, 2 * dust + 100);
+
+        let excess = Excess::NoChange {
+            dust_threshold: dust,
+            remaining_amount: 10,
+            change_fee: 9,
+        };
+        let (values, extra_fee) =
+            split_change(&excess, fee_rate, &drain_script, &ChangeSplit::Equal(10));
+        assert!(values.is_empty());
+        assert_eq!(extra_fee, 0);
+    }
+
+    #[test]
+    fn test_split_change_denomination() {
+        let drain_script = Script::default();
+        let fee_rate = FeeRate::from_sat_per_vb(1.0);
+        let excess = Excess::Change {
+            amount: 35_000,
+            fee: 9,
+        };
+
+        let split = ChangeSplit::Denomination {
+            value: 10_000,
+            max_outputs: 5,
+        };
+        let (values, extra_fee) = split_change(&excess, fee_rate, &drain_script, &split);
+        assert_eq!(values, vec![10_000, 10_000, 10_000, 4_973]);
+        assert_eq!(extra_fee, 27);
+
+        let split = ChangeSplit::Denomination {
+            value: 10_000,
+            max_outputs: 2,
+        };
+        let (values, extra_fee) = split_change(&excess, fee_rate, &drain_script, &split);
+        assert_eq!(values, vec![10_000, 10_000, 14_982]);
+        assert_eq!(extra_fee, 18);
+
+        // the remainder is too small for an output of its own
+        let excess = Excess::Change {
+            amount: 20_100,
+            fee: 9,
+        };
+        let split = ChangeSplit::Denomination {
+            value: 10_000,
+            max_outputs: 5,
+        };
+        let (values, extra_fee) = split_change(&excess, fee_rate, &drain_script, &split);
+        assert_eq!(values, vec![10_000, 10_091]);
+        assert_eq!(extra_fee, 9);
+    }
+
+    #[test]
+    fn test_split_change_random() {
+        let drain_script = Script::default();
+        let fee_rate = FeeRate::from_sat_per_vb(1.0);
+        let excess = Excess::Change {
+            amount: 1_000_000,
+            fee: 9,
+        };
+
+        let (values, extra_fee) =
+            split_change(&excess, fee_rate, &drain_script, &ChangeSplit::Random(4));
+        assert_eq!(values.len(), 4);
+        assert_eq!(extra_fee, 27);
+        assert_eq!(values.iter().sum::<u64>() + extra_fee, 1_000_000);
+        for value in &values[1..] {
+            assert_eq!(*value, round_payment_amount(*value));
+        }
+    }
+
+    #[test]
+    fn test_round_payment_amount() {
+        assert_eq!(round_payment_amount(0), 0);
+        assert_eq!(round_payment_amount(99), 99);
+        assert_eq!(round_payment_amount(123_456), 120_000);
+        assert_eq!(round_payment_amount(1_999), 1_900);
+    }
 }
@@ -0,0 +1,422 @@
+// Bitcoin Dev Kit
+//
+// Copyright (c) 2020-2022 Bitcoin Dev Kit Developers
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Collaborative transactions
+//!
+//! This module contains a [`CoinjoinBuilder`], used to assemble a transaction where every
+//! participant contributes some inputs and receives an output of the same value (the
+//! *denomination*) plus its own change. Every participant pays the fees for its own inputs and
+//! outputs, and an equal share of the fees for the transaction header.
+//!
+//! A participant can be created from a [`Wallet`](super::Wallet) with
+//! [`Wallet::coinjoin_participant`](super::Wallet::coinjoin_participant). Once the coordinator
+//! has built the PSBT, every participant checks that it's paying no more than its share with
+//! [`Wallet::verify_coinjoin`](super::Wallet::verify_coinjoin), signs it with
+//! [`Wallet::sign`](super::Wallet::sign) and sends it back to the coordinator, which combines
+//! the signatures.
+//!
+//! The transaction is sorted following [BIP69], so participants can build it independently.
+//!
+//! ## Example
+//!
+//! ```
+//! # use bdk::*;
+//! # use bdk::database::*;
+//! # use bdk::wallet::coinjoin::CoinjoinBuilder;
+//! # use bdk::wallet::get_funded_wallet;
+//! # let (alice, _, alice_txid) = get_funded_wallet("wpkh(tprv8ZgxMBicQKsPd3EupYiPRhaMooHKUHJxNsTfYuScep13go8QFfHdtkG9nRkFGb7busX4isf6X9dURGCoKgitaApQ6MupRhZMcELAxTBRJgS/*)");
+//! # let (bob, _, bob_txid) = get_funded_wallet("wpkh(cVpPVruEDdmutPzisEsYvtST1usBR3ntr8pXSyt6D2YYqXRyPcFW)");
+//! let fee_rate = FeeRate::from_sat_per_vb(2.0);
+//!
+//! let mut builder = CoinjoinBuilder::new(30_000, fee_rate);
+//! builder
+//!     .add_participant(alice.coinjoin_participant(&[bitcoin::OutPoint::new(alice_txid, 0)])?)
+//!     .add_participant(bob.coinjoin_participant(&[bitcoin::OutPoint::new(bob_txid, 0)])?);
+//! let psbt = builder.finish()?;
+//!
+//! let mut alice_psbt = psbt.clone();
+//! alice.verify_coinjoin(&alice_psbt, 30_000, fee_rate)?;
+//! alice.sign(&mut alice_psbt, SignOptions::default())?;
+//!
+//! let mut bob_psbt = psbt;
+//! bob.verify_coinjoin(&bob_psbt, 30_000, fee_rate)?;
+//! bob.sign(&mut bob_psbt, SignOptions::default())?;
+//!
+//! alice_psbt.combine(bob_psbt)?;
+//! let tx = alice_psbt.extract_tx();
+//! # Ok::<(), bdk::Error>(())
+//! ```
+//!
+//! [BIP69]: https://github.com/bitcoin/bips/blob/master/bip-0069.mediawiki
+
+use std::collections::HashMap;
+use std::fmt;
+
+use bitcoin::consensus::encode::serialize;
+use bitcoin::util::psbt::{self, PartiallySignedTransaction as Psbt};
+use bitcoin::{OutPoint, PackedLockTime, Script, Sequence, Transaction, TxIn, TxOut, Witness};
+
+use crate::wallet::coin_selection::TXIN_BASE_WEIGHT;
+use crate::wallet::tx_builder::{check_foreign_utxo, TxOrdering};
+use crate::wallet::utils::IsDust;
+use crate::{Error, FeeRate, Utxo, WeightedUtxo};
+
+// version, locktime, number of inputs and outputs, segwit marker and flag
+pub(crate) const TX_HEADER_WEIGHT: usize = (4 + 4 + 1 + 1) * 4 + 2;
+
+/// Errors that can be thrown while building or verifying a collaborative transaction
+#[derive(Debug)]
+pub enum CoinjoinError {
+    /// The transaction has less than two participants
+    NotEnoughParticipants,
+    /// A participant doesn't contribute any input
+    NoInputs,
+    /// The PSBT is missing the previous output of an input
+    MissingUtxo(OutPoint),
+    /// None of our outputs has the value of the denomination
+    MissingOutput,
+    /// We would pay more than our share of the fees
+    ShareTooHigh {
+        /// Maximum amount we expect to pay
+        max: u64,
+        /// Amount we would pay
+        actual: u64,
+    },
+}
+
+impl fmt::Display for CoinjoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotEnoughParticipants => write!(f, "At least two participants are required"),
+            Self::NoInputs => write!(f, "Participant without inputs"),
+            Self::MissingUtxo(outpoint) => write!(f, "Missing previous output for {}", outpoint),
+            Self::MissingOutput => write!(f, "Missing output with the denomination value"),
+            Self::ShareTooHigh { max, actual } => write!(
+                f,
+                "Paying {} sats for the transaction, at most {} expected",
+                actual, max
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CoinjoinError {}
+
+// Weight of `txout` once serialized in a transaction
+pub(crate) fn output_weight(txout: &TxOut) -> usize {
+    serialize(txout).len() * 4
+}
+
+// Share of the transaction header paid by each of the `participants`
+pub(crate) fn header_weight_share(participants: usize) -> usize {
+    (TX_HEADER_WEIGHT as f32 / participants as f32).ceil() as usize
+}
+
+/// A participant of a collaborative transaction
+#[derive(Debug, Clone)]
+pub struct CoinjoinParticipant {
+    inputs: Vec<WeightedUtxo>,
+    output: Script,
+    change: Script,
+}
+
+impl CoinjoinParticipant {
+    /// Create a new participant, receiving the denomination on `output` and its change on
+    /// `change`
+    pub fn new(output: Script, change: Script) -> Self {
+        CoinjoinParticipant {
+            inputs: vec![],
+            output,
+            change,
+        }
+    }
+
+    /// Add an input of the participant
+    ///
+    /// The same considerations of
+    /// [`TxBuilder::add_foreign_utxo`](super::tx_builder::TxBuilder::add_foreign_utxo) about the
+    /// value and the `satisfaction_weight` of the input apply here. Note that every participant
+    /// signing with [`Wallet::sign`](super::Wallet::sign) needs the `non_witness_utxo` of the
+    /// non-taproot inputs, unless it signs with
+    /// [`SignOptions::trust_witness_utxo`](super::signer::SignOptions::trust_witness_utxo).
+    pub fn add_input(
+        &mut self,
+        outpoint: OutPoint,
+        psbt_input: psbt::Input,
+        satisfaction_weight: usize,
+    ) -> Result<&mut Self, Error> {
+        check_foreign_utxo(outpoint, &psbt_input)?;
+
+        self.inputs.push(WeightedUtxo {
+            satisfaction_weight,
+            utxo: Utxo::Foreign {
+                outpoint,
+                psbt_input: Box::new(psbt_input),
+            },
+        });
+
+        Ok(self)
+    }
+
+    /// Return the script receiving the denomination
+    pub fn output(&self) -> &Script {
+        &self.output
+    }
+
+    /// Return the script receiving the change
+    pub fn change(&self) -> &Script {
+        &self.change
+    }
+}
+
+/// Builder for a collaborative transaction with equal-value outputs
+#[derive(Debug, Clone)]
+pub struct CoinjoinBuilder {
+    denomination: u64,
+    fee_rate: FeeRate,
+    participants: Vec<CoinjoinParticipant>,
+}
+
+impl CoinjoinBuilder {
+    /// Create a new builder, paying `denomination` to every participant
+    pub fn new(denomination: u64, fee_rate: FeeRate) -> Self {
+        CoinjoinBuilder {
+            denomination,
+            fee_rate,
+            participants: vec![],
+        }
+    }
+
+    /// Add a participant
+    pub fn add_participant(&mut self, participant: CoinjoinParticipant) -> &mut Self {
+        self.participants.push(participant);
+        self
+    }
+
+    /// Build the transaction
+    ///
+    /// Every participant receives its change if it's not dust, otherwise the remaining value is
+    /// added to the fees.
+    pub fn finish(&self) -> Result<Psbt, Error> {
+        if self.participants.len() < 2 {
+            return Err(CoinjoinError::NotEnoughParticipants.into());
+        }
+        let header_weight = header_weight_share(self.participants.len());
+
+        let mut tx = Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: vec![],
+            output: vec![],
+        };
+        let mut psbt_inputs = HashMap::new();
+        for participant in &self.participants {
+            if participant.inputs.is_empty() {
+                return Err(CoinjoinError::NoInputs.into());
+            }
+
+            let output = TxOut {
+                value: self.denomination,
+                script_pubkey: participant.output.clone(),
+            };
+            let mut change = TxOut {
+                value: 0,
+                script_pubkey: participant.change.clone(),
+            };
+
+            let weight = header_weight
+                + output_weight(&output)
+                + participant
+                    .inputs
+                    .iter()
+                    .map(|u| TXIN_BASE_WEIGHT + u.satisfaction_weight)
+                    .sum::<usize>();
+            let available = participant
+                .inputs
+                .iter()
+                .map(|u| u.utxo.txout().value)
+                .sum::<u64>();
+            let needed = self.denomination + self.fee_rate.fee_wu(weight);
+            if available < needed {
+                return Err(Error::InsufficientFunds { needed, available });
+            }
+
+            change.value = available.saturating_sub(
+                self.denomination + self.fee_rate.fee_wu(weight + output_weight(&change)),
+            );
+            if !change.value.is_dust(&change.script_pubkey) {
+                tx.output.push(change);
+            }
+            tx.output.push(output);
+
+            for weighted in &participant.inputs {
+                if let Utxo::Foreign {
+                    outpoint,
+                    psbt_input,
+                } = &weighted.utxo
+                {
+                    tx.input.push(TxIn {
+                        previous_output: *outpoint,
+                        script_sig: Script::default(),
+                        sequence: Sequence::MAX,
+                        witness: Witness::new(),
+                    });
+                    psbt_inputs.insert(*outpoint, psbt_input.as_ref().clone());
+                }
+            }
+        }
+
+        TxOrdering::Bip69Lexicographic.sort_tx(&mut tx);
+
+        let mut psbt = Psbt::from_unsigned_tx(tx)?;
+        for (psbt_input, txin) in psbt.inputs.iter_mut().zip(psbt.unsigned_tx.input.iter()) {
+            if let Some(input) = psbt_inputs.remove(&txin.previous_output) {
+                *psbt_input = input;
+            }
+        }
+
+        Ok(psbt)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::signer::SignOptions;
+    use crate::wallet::get_funded_wallet;
+    use crate::wallet::test::get_test_wpkh;
+
+    const ALICE_DESC: &str = "wpkh(tprv8ZgxMBicQKsPd3EupYiPRhaMooHKUHJxNsTfYuScep13go8QFfHdtkG9nRkFGb7busX4isf6X9dURGCoKgitaApQ6MupRhZMcELAxTBRJgS/*)";
+    const DENOMINATION: u64 = 30_000;
+
+    #[test]
+    fn test_coinjoin() {
+        let (alice, _, alice_txid) = get_funded_wallet(ALICE_DESC);
+        let (bob, _, bob_txid) = get_funded_wallet(get_test_wpkh());
+        let fee_rate = FeeRate::from_sat_per_vb(2.0);
+
+        let mut builder = CoinjoinBuilder::new(DENOMINATION, fee_rate);
+        builder
+            .add_participant(
+                alice
+                    .coinjoin_participant(&[OutPoint::new(alice_txid, 0)])
+                    .unwrap(),
+            )
+            .add_participant(
+                bob.coinjoin_participant(&[OutPoint::new(bob_txid, 0)])
+                    .unwrap(),
+            );
+        let psbt = builder.finish().unwrap();
+        assert_eq!(psbt.unsigned_tx.input.len(), 2);
+        assert_eq!(
+            psbt.unsigned_tx
+                .output
+                .iter()
+                .filter(|txout| txout.value == DENOMINATION)
+                .count(),
+            2
+        );
+
+        let mut alice_psbt = psbt.clone();
+        alice
+            .verify_coinjoin(&alice_psbt, DENOMINATION, fee_rate)
+            .unwrap();
+        assert!(!alice.sign(&mut alice_psbt, SignOptions::default()).unwrap());
+
+        let mut bob_psbt = psbt;
+        bob.verify_coinjoin(&bob_psbt, DENOMINATION, fee_rate)
+            .unwrap();
+        assert!(!bob.sign(&mut bob_psbt, SignOptions::default()).unwrap());
+
+        alice_psbt.combine(bob_psbt).unwrap();
+        let tx = alice_psbt.clone().extract_tx();
+        assert!(tx.input.iter().all(|txin| !txin.witness.is_empty()));
+
+        // Both participants pay the same fee, since they have the same inputs and outputs
+        let fee = 2 * 50_000 - tx.output.iter().map(|txout| txout.value).sum::<u64>();
+        assert!(fee % 2 == 0);
+        assert!(FeeRate::from_wu(fee, tx.weight()) >= fee_rate);
+    }
+
+    #[test]
+    fn test_coinjoin_not_enough_participants() {
+        let (alice, _, alice_txid) = get_funded_wallet(ALICE_DESC);
+
+        let mut builder = CoinjoinBuilder::new(DENOMINATION, FeeRate::default());
+        builder.add_participant(
+            alice
+                .coinjoin_participant(&[OutPoint::new(alice_txid, 0)])
+                .unwrap(),
+        );
+        assert!(matches!(
+            builder.finish(),
+            Err(Error::Coinjoin(CoinjoinError::NotEnoughParticipants))
+        ));
+    }
+
+    #[test]
+    fn test_coinjoin_insufficient_funds() {
+        let (alice, _, alice_txid) = get_funded_wallet(ALICE_DESC);
+        let (bob, _, bob_txid) = get_funded_wallet(get_test_wpkh());
+
+        let mut builder = CoinjoinBuilder::new(50_000, FeeRate::default());
+        builder
+            .add_participant(
+                alice
+                    .coinjoin_participant(&[OutPoint::new(alice_txid, 0)])
+                    .unwrap(),
+            )
+            .add_participant(
+                bob.coinjoin_participant(&[OutPoint::new(bob_txid, 0)])
+                    .unwrap(),
+            );
+        assert!(matches!(
+            builder.finish(),
+            Err(Error::InsufficientFunds {
+                available: 50_000,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_coinjoin_verify_share_too_high() {
+        let (alice, _, alice_txid) = get_funded_wallet(ALICE_DESC);
+        let (bob, _, bob_txid) = get_funded_wallet(get_test_wpkh());
+        let fee_rate = FeeRate::from_sat_per_vb(2.0);
+
+        let alice_participant = alice
+            .coinjoin_participant(&[OutPoint::new(alice_txid, 0)])
+            .unwrap();
+        let mut builder = CoinjoinBuilder::new(DENOMINATION, fee_rate);
+        builder
+            .add_participant(alice_participant.clone())
+            .add_participant(
+                bob.coinjoin_participant(&[OutPoint::new(bob_txid, 0)])
+                    .unwrap(),
+            );
+        let mut psbt = builder.finish().unwrap();
+
+        // The coordinator steals from alice's change
+        psbt.unsigned_tx
+            .output
+            .iter_mut()
+            .find(|txout| &txout.script_pubkey == alice_participant.change())
+            .unwrap()
+            .value -= 1_000;
+
+        assert!(matches!(
+            alice.verify_coinjoin(&psbt, DENOMINATION, fee_rate),
+            Err(Error::Coinjoin(CoinjoinError::ShareTooHigh { .. }))
+        ));
+        // Bob is not affected
+        bob.verify_coinjoin(&psbt, DENOMINATION, fee_rate).unwrap();
+    }
+}
@@ -0,0 +1,404 @@
+// Bitcoin Dev Kit
+//
+// Copyright (c) 2020-2022 Bitcoin Dev Kit Developers
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Wallet labels export
+//!
+//! This module implements the [BIP-329](https://github.com/bitcoin/bips/blob/master/bip-0329.mediawiki)
+//! label export format, which allows moving transaction, address, input, output and xpub labels
+//! between wallets.
+//!
+//! Labels are read from and written to the wallet's [`Database`](crate::database::Database),
+//! see [`Wallet::set_label`] and [`Wallet::freeze_utxo`]. Outputs with `"spendable": false` are
+//! imported as frozen.
+//!
+//! ## Example
+//!
+//! ```
+//! # use std::str::FromStr;
+//! # use bitcoin::*;
+//! # use bdk::database::*;
+//! # use bdk::wallet::labels::*;
+//! # use bdk::*;
+//! let wallet = Wallet::new(
+//!     "wpkh([c258d2e4/84h/1h/0h]tpubDD3ynpHgJQW8VvWRzQ5WFDCrs4jqVFGHB3vLC3r49XHJSqP8bHKdK4AriuUKLccK68zfzowx7YhmDN8SiSkgCDENUFx9qVw65YyqM78vyVe/0/*)",
+//!     None,
+//!     Network::Testnet,
+//!     MemoryDatabase::default(),
+//! )?;
+//!
+//! let import = r#"{"type":"tx","ref":"f91d0a8a78462bc59398f2c5d7a84fcff491c26ba54c4833478b202796c8aafd","label":"Transaction"}
+//! {"type":"xpub","ref":"tpubDD3ynpHgJQW8VvWRzQ5WFDCrs4jqVFGHB3vLC3r49XHJSqP8bHKdK4AriuUKLccK68zfzowx7YhmDN8SiSkgCDENUFx9qVw65YyqM78vyVe","label":"Testnet account"}"#;
+//! let import = Bip329Export::from_str(import)?;
+//! assert_eq!(import.import_into_wallet(&wallet)?, 2);
+//!
+//! let export = Bip329Export::export_wallet(&wallet)?;
+//! println!("Exported: {}", export.to_string());
+//! # Ok::<_, bdk::Error>(())
+//! ```
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use bitcoin::util::bip32::ExtendedPubKey;
+use bitcoin::{Address, OutPoint, Txid};
+
+use crate::database::{BatchDatabase, BatchOperations, LabelKey};
+use crate::error::Error;
+use crate::wallet::Wallet;
+
+/// Type of the object referenced by a [`Label`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LabelType {
+    /// Transaction, referenced by its txid
+    Tx,
+    /// Address
+    Addr,
+    /// Public key, referenced by its hex encoding
+    Pubkey,
+    /// Transaction input, referenced as `txid:vin` of the transaction spending it
+    Input,
+    /// Transaction output, referenced by its outpoint
+    Output,
+    /// Extended public key
+    Xpub,
+}
+
+/// A single BIP-329 label record
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Label {
+    /// Type of the referenced object
+    #[serde(rename = "type")]
+    pub label_type: LabelType,
+    /// Reference to the object, encoded according to its type
+    #[serde(rename = "ref")]
+    pub reference: String,
+    /// The label itself
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Key origin of the descriptor the object belongs to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+    /// Whether an output can be spent, only meaningful for [`LabelType::Output`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spendable: Option<bool>,
+}
+
+/// Structure that contains the BIP-329 labels of a wallet
+///
+/// It's serialized as JSON Lines, with one [`Label`] per line.
+///
+/// For a usage example see [this module](crate::wallet::labels)'s documentation.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bip329Export {
+    /// The list of labels
+    pub labels: Vec<Label>,
+}
+
+impl fmt::Display for Bip329Export {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, label) in self.labels.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(
+                f,
+                "{}",
+                serde_json::to_string(label).map_err(|_| fmt::Error)?
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Bip329Export {
+    type Err = serde_json::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let labels = s
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+
+        Ok(Bip329Export { labels })
+    }
+}
+
+fn parse_reference<T: FromStr>(reference: &str) -> Result<T, Error> {
+    T::from_str(reference)
+        .map_err(|_| Error::Generic(format!("Invalid BIP-329 reference `{}`", reference)))
+}
+
+impl Bip329Export {
+    /// Export the labels and frozen outputs stored in the `wallet`'s database
+    ///
+    /// Address labels whose script can't be represented as an address are skipped.
+    pub fn export_wallet<D: BatchDatabase>(wallet: &Wallet<D>) -> Result<Self, Error> {
+        let database = wallet.database.borrow();
+        let mut frozen = database.iter_frozen()?.into_iter().collect::<BTreeSet<_>>();
+
+        let mut labels = vec![];
+        for (key, label) in database.iter_labels()? {
+            let spendable = match key {
+                LabelKey::Utxo(outpoint) if frozen.remove(&outpoint) => Some(false),
+                _ => None,
+            };
+            let (label_type, reference) = match key {
+                LabelKey::Transaction(txid) => (LabelType::Tx, txid.to_string()),
+                LabelKey::Address(script) => {
+                    match Address::from_script(&script, wallet.network()) {
+                        Ok(address) => (LabelType::Addr, address.to_string()),
+                        Err(_) => continue,
+                    }
+                }
+                LabelKey::Input { txid, vin } => (LabelType::Input, format!("{}:{}", txid, vin)),
+                LabelKey::Utxo(outpoint) => (LabelType::Output, outpoint.to_string()),
+                LabelKey::Xpub(xpub) => (LabelType::Xpub, xpub.to_string()),
+            };
+
+            labels.push(Label {
+                label_type,
+                reference,
+                label: Some(label),
+                origin: None,
+                spendable,
+            });
+        }
+
+        // frozen outputs without a label
+        labels.extend(frozen.into_iter().map(|outpoint| Label {
+            label_type: LabelType::Output,
+            reference: outpoint.to_string(),
+            label: None,
+            origin: None,
+            spendable: Some(false),
+        }));
+
+        Ok(Bip329Export { labels })
+    }
+
+    /// Import the labels into the `wallet`'s database, returning the number of labels imported
+    ///
+    /// `addr` labels are only imported if the address belongs to the wallet and `output` labels
+    /// only if the output is in the wallet's UTXO set, so the wallet should be synced before
+    /// importing. `pubkey` labels are not supported and are always skipped.
+    ///
+    /// Returns an error if any of the references can't be parsed.
+    pub fn import_into_wallet<D: BatchDatabase>(&self, wallet: &Wallet<D>) -> Result<usize, Error> {
+        let mut database = wallet.database.borrow_mut();
+        let mut batch = database.begin_batch();
+
+        let mut imported = 0;
+        for label in &self.labels {
+            let key = match label.label_type {
+                LabelType::Tx => LabelKey::Transaction(parse_reference::<Txid>(&label.reference)?),
+                LabelType::Addr => {
+                    let address = parse_reference::<Address>(&label.reference)?;
+                    let script = address.script_pubkey();
+                    if !address.is_valid_for_network(wallet.network())
+                        || database.get_path_from_script_pubkey(&script)?.is_none()
+                    {
+                        continue;
+                    }
+
+                    LabelKey::Address(script)
+                }
+                LabelType::Pubkey => continue,
+                LabelType::Input => {
+                    // same `txid:index` format as an outpoint, but the txid is the one of the
+                    // spending transaction
+                    let OutPoint { txid, vout } = parse_reference(&label.reference)?;
+                    LabelKey::Input { txid, vin: vout }
+                }
+                LabelType::Output => {
+                    let outpoint = parse_reference::<OutPoint>(&label.reference)?;
+                    if database.get_utxo(&outpoint)?.is_none() {
+                        continue;
+                    }
+
+                    match label.spendable {
+                        Some(false) => batch.set_frozen(&outpoint)?,
+                        Some(true) => {
+                            batch.del_frozen(&outpoint)?;
+                        }
+                        None => {}
+                    }
+
+                    LabelKey::Utxo(outpoint)
+                }
+                LabelType::Xpub => {
+                    LabelKey::Xpub(parse_reference::<ExtendedPubKey>(&label.reference)?)
+                }
+            };
+
+            if let Some(label) = &label.label {
+                batch.set_label(&key, label)?;
+            }
+            imported += 1;
+        }
+
+        database.commit_batch(batch)?;
+
+        Ok(imported)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use bitcoin::{OutPoint, Txid};
+
+    use super::*;
+    use crate::wallet::get_funded_wallet;
+    use crate::wallet::test::get_test_wpkh;
+    use crate::wallet::AddressIndex::New;
+
+    #[test]
+    fn test_labels_roundtrip() {
+        let (wallet, _, funding_txid) = get_funded_wallet(get_test_wpkh());
+        let address = wallet.get_address(New).unwrap();
+        let outpoint = OutPoint::new(funding_txid, 0);
+
+        let import = format!(
+            r#"{{"type":"tx","ref":"{txid}","label":"Funding"}}
+{{"type":"addr","ref":"{address}","label":"Donations"}}
+{{"type":"output","ref":"{outpoint}","label":"Cold coin","spendable":false}}
+{{"type":"input","ref":"{outpoint}","label":"Spent coin"}}
+
+{{"type":"pubkey","ref":"0283409659355b6d1cc3c32decd5d561abaac86c37a353b52895a5e6c196d6f448","label":"Unsupported"}}"#,
+            txid = funding_txid,
+            address = address,
+            outpoint = outpoint,
+        );
+        let import = Bip329Export::from_str(&import).unwrap();
+        assert_eq!(import.labels.len(), 5);
+        assert_eq!(import.import_into_wallet(&wallet).unwrap(), 4);
+
+        assert_eq!(
+            wallet
+                .get_label(&LabelKey::Address(address.script_pubkey()))
+                .unwrap(),
+            Some("Donations".to_string())
+        );
+        assert_eq!(wallet.list_frozen().unwrap(), vec![outpoint]);
+
+        let export = Bip329Export::export_wallet(&wallet).unwrap();
+        assert_eq!(export.labels.len(), 4);
+        let output = export
+            .labels
+            .iter()
+            .find(|l| l.label_type == LabelType::Output)
+            .unwrap();
+        assert_eq!(output.reference, outpoint.to_string());
+        assert_eq!(output.label, Some("Cold coin".to_string()));
+        assert_eq!(output.spendable, Some(false));
+
+        let reimported = Bip329Export::from_str(&export.to_string()).unwrap();
+        assert_eq!(reimported, export);
+    }
+
+    #[test]
+    fn test_labels_bip329_example() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let txid =
+            Txid::from_str("f91d0a8a78462bc59398f2c5d7a84fcff491c26ba54c4833478b202796c8aafd")
+                .unwrap();
+
+        // records from the BIP-329 test vectors: the `input` is the first input of the
+        // transaction `f91d0a...`, not an output of it
+        let import = r#"{ "type": "tx", "ref": "f91d0a8a78462bc59398f2c5d7a84fcff491c26ba54c4833478b202796c8aafd", "label": "Transaction", "origin": "wpkh([d34db33f/84'/0'/0'])" }
+{ "type": "addr", "ref": "bc1q34aq5drpuwy3wgl9lhup9892qp6svr8ldzyy7c", "label": "Address" }
+{ "type": "pubkey", "ref": "0283409659355b6d1cc3c32decd5d561abaac86c37a353b52895a5e6c196d6f448", "label": "Public Key" }
+{ "type": "input", "ref": "f91d0a8a78462bc59398f2c5d7a84fcff491c26ba54c4833478b202796c8aafd:0", "label": "Input" }
+{ "type": "output", "ref": "f91d0a8a78462bc59398f2c5d7a84fcff491c26ba54c4833478b202796c8aafd:1", "label": "Output", "spendable": false }
+{ "type": "xpub", "ref": "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8", "label": "Extended Public Key" }"#;
+        let import = Bip329Export::from_str(import).unwrap();
+        assert_eq!(import.labels.len(), 6);
+        // the address and the output don't belong to the wallet, pubkeys are not supported
+        assert_eq!(import.import_into_wallet(&wallet).unwrap(), 3);
+
+        assert_eq!(
+            wallet.get_label(&LabelKey::Input { txid, vin: 0 }).unwrap(),
+            Some("Input".to_string())
+        );
+        assert_eq!(
+            wallet.get_label(&LabelKey::Transaction(txid)).unwrap(),
+            Some("Transaction".to_string())
+        );
+        assert!(wallet
+            .get_label(&LabelKey::Utxo(OutPoint::new(txid, 0)))
+            .unwrap()
+            .is_none());
+
+        let export = Bip329Export::export_wallet(&wallet).unwrap();
+        let input = export
+            .labels
+            .iter()
+            .find(|l| l.label_type == LabelType::Input)
+            .unwrap();
+        assert_eq!(
+            input.reference,
+            "f91d0a8a78462bc59398f2c5d7a84fcff491c26ba54c4833478b202796c8aafd:0"
+        );
+        assert_eq!(input.label, Some("Input".to_string()));
+    }
+
+    #[test]
+    fn test_labels_skip_foreign_objects() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let txid =
+            Txid::from_str("f91d0a8a78462bc59398f2c5d7a84fcff491c26ba54c4833478b202796c8aafd")
+                .unwrap();
+
+        let import = format!(
+            r#"{{"type":"addr","ref":"2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX","label":"Not mine"}}
+{{"type":"output","ref":"{}:1","label":"Not mine either","spendable":false}}"#,
+            txid
+        );
+        let import = Bip329Export::from_str(&import).unwrap();
+        assert_eq!(import.import_into_wallet(&wallet).unwrap(), 0);
+        assert!(wallet.list_labels().unwrap().is_empty());
+        assert!(wallet.list_frozen().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_labels_frozen_without_label() {
+        let (wallet, _, funding_txid) = get_funded_wallet(get_test_wpkh());
+        let outpoint = OutPoint::new(funding_txid, 0);
+        wallet.freeze_utxo(outpoint).unwrap();
+
+        let export = Bip329Export::export_wallet(&wallet).unwrap();
+        assert_eq!(
+            export.to_string(),
+            format!(
+                r#"{{"type":"output","ref":"{}","spendable":false}}"#,
+                outpoint
+            )
+        );
+    }
+
+    #[test]
+    fn test_labels_invalid_reference() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let import =
+            Bip329Export::from_str(r#"{"type":"tx","ref":"nope","label":"Broken"}"#).unwrap();
+        assert!(matches!(
+            import.import_into_wallet(&wallet),
+            Err(Error::Generic(_))
+        ));
+    }
+}
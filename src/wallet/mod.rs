@@ -30,14 +30,22 @@ use bitcoin::{
     Transaction, TxOut, Txid, Witness,
 };
 
+use miniscript::descriptor::DescriptorSecretKey;
 use miniscript::psbt::{PsbtExt, PsbtInputExt, PsbtInputSatisfier};
 
 #[allow(unused_imports)]
 use log::{debug, error, info, trace};
 
+use rand::thread_rng;
+
 pub mod coin_selection;
+pub mod coinjoin;
 pub mod export;
+pub mod labels;
+pub mod payjoin;
+pub mod recovery;
 pub mod signer;
+pub mod silent_payment;
 pub mod time;
 pub mod tx_builder;
 pub(crate) mod utils;
@@ -51,21 +59,32 @@ pub mod hardwaresigner;
 
 pub use utils::IsDust;
 
-use coin_selection::DefaultCoinSelectionAlgorithm;
+use coin_selection::{split_change, DefaultCoinSelectionAlgorithm, TXIN_BASE_WEIGHT};
+use coinjoin::{CoinjoinError, CoinjoinParticipant};
 use signer::{SignOptions, SignerOrdering, SignersContainer, TransactionSigner};
-use tx_builder::{BumpFee, CreateTx, FeePolicy, TxBuilder, TxParams};
-use utils::{check_nsequence_rbf, After, Older, SecpCtx};
+use silent_payment::{
+    SilentPaymentAddress, SilentPaymentError, SilentPaymentKeychain, SilentPaymentOutput,
+};
+use tx_builder::{
+    AncestorFeePolicy, BumpFee, ChangeSplit, CreateTx, FeePolicy, PackageFee, TxBuilder, TxParams,
+    UtxoFilter,
+};
+use utils::{apply_anti_fee_sniping, check_nsequence_rbf, After, Older, SecpCtx};
 
-use crate::blockchain::{GetHeight, NoopProgress, Progress, WalletSync};
+use crate::blockchain::{Blockchain, GetHeight, NoopProgress, Progress, WalletSync};
 use crate::database::memory::MemoryDatabase;
-use crate::database::{AnyDatabase, BatchDatabase, BatchOperations, DatabaseUtils, SyncTime};
+use crate::database::{
+    AnyDatabase, BatchDatabase, BatchOperations, DatabaseUtils, LabelKey, SyncTime,
+};
 use crate::descriptor::checksum::calc_checksum_bytes_internal;
-use crate::descriptor::policy::BuildSatisfaction;
+use crate::descriptor::policy::{BuildSatisfaction, Condition};
+use crate::descriptor::template::{Bip44, Bip49, Bip84, P2Pkh, P2Wpkh, P2Wpkh_P2Sh};
 use crate::descriptor::{
     calc_checksum, into_wallet_descriptor_checked, DerivedDescriptor, DescriptorMeta,
-    ExtendedDescriptor, ExtractPolicy, IntoWalletDescriptor, Policy, XKeyUtils,
+    ExtendedDescriptor, ExtractPolicy, IntoWalletDescriptor, Legacy, Policy, Segwitv0, XKeyUtils,
 };
 use crate::error::{Error, MiniscriptPsbtError};
+use crate::keys::{DescriptorKey, IntoDescriptorKey};
 use crate::psbt::PsbtUtils;
 use crate::signer::SignerError;
 use crate::testutils;
@@ -94,10 +113,14 @@ pub struct Wallet<D> {
     signers: Arc<SignersContainer>,
     change_signers: Arc<SignersContainer>,
 
+    custom_keychains: BTreeMap<u32, (ExtendedDescriptor, Arc<SignersContainer>)>,
+
     network: Network,
 
     database: RefCell<D>,
 
+    silent_payment: Option<SilentPaymentKeychain>,
+
     secp: SecpCtx,
 }
 
@@ -167,6 +190,40 @@ pub struct SyncOptions {
     pub progress: Option<Box<dyn Progress>>,
 }
 
+/// Options to a [`build_migration`].
+///
+/// [`build_migration`]: Wallet::build_migration
+#[derive(Debug, Clone)]
+pub struct MigrationOptions {
+    /// The fee rate of the migration transactions
+    ///
+    /// Defaults to [`FeeRate::default`].
+    pub fee_rate: FeeRate,
+    /// The number of transactions to split the migration into, each one sending to a different
+    /// address of the target wallet
+    ///
+    /// UTXOs sent to the same address are already linked, so they always end up in the same
+    /// transaction, which means that fewer transactions may be created.
+    ///
+    /// Defaults to `1`.
+    pub transactions: usize,
+    /// Whether to copy the labels of the migrated UTXOs and of their addresses to the address of
+    /// the target wallet that receives them
+    ///
+    /// Defaults to `false`.
+    pub preserve_labels: bool,
+}
+
+impl Default for MigrationOptions {
+    fn default() -> Self {
+        MigrationOptions {
+            fee_rate: FeeRate::default(),
+            transactions: 1,
+            preserve_labels: false,
+        }
+    }
+}
+
 impl<D> Wallet<D>
 where
     D: BatchDatabase,
@@ -226,8 +283,10 @@ where
             change_descriptor,
             signers,
             change_signers,
+            custom_keychains: BTreeMap::new(),
             network,
             database: RefCell::new(database),
+            silent_payment: None,
             secp,
         })
     }
@@ -245,6 +304,78 @@ where
         db.check_descriptor_checksum(kind, checksum_inception)
     }
 
+    /// Add a keychain to the wallet, identified by `id` as [`KeychainKind::Custom`]
+    ///
+    /// The addresses of the new keychain are tracked along with the external and internal ones,
+    /// and its UTXOs can be spent in the transactions created by the wallet. This can be used,
+    /// for instance, to keep spending the coins received by a legacy descriptor after migrating
+    /// the wallet to a new one, or to give every customer their own deposit descriptor.
+    ///
+    /// Like the descriptors passed to [`Wallet::new`], the keychain is not persisted in the
+    /// database (only its checksum is), so it has to be added again every time the wallet is
+    /// created. This fails if `descriptor` doesn't match the checksum stored in the database for
+    /// `id`.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use bdk::{Wallet, KeychainKind};
+    /// # use bdk::bitcoin::Network;
+    /// # use bdk::database::MemoryDatabase;
+    /// # use bdk::wallet::AddressIndex;
+    /// let mut wallet = Wallet::new("tr(tprv8ZgxMBicQKsPe73PBRSmNbTfbcsZnwWhz5eVmhHpi31HW29Z7mc9B4cWGRQzopNUzZUT391DeDJxL2PefNunWyLgqCKRMDkU1s2s8bAfoSk/86'/1'/0'/0/*)", None, Network::Testnet, MemoryDatabase::new())?;
+    /// wallet.add_keychain(1, "sh(wpkh(tprv8ZgxMBicQKsPe73PBRSmNbTfbcsZnwWhz5eVmhHpi31HW29Z7mc9B4cWGRQzopNUzZUT391DeDJxL2PefNunWyLgqCKRMDkU1s2s8bAfoSk/49'/1'/0'/0/*))")?;
+    ///
+    /// let legacy_address = wallet.get_keychain_address(KeychainKind::Custom(1), AddressIndex::New)?;
+    /// assert_eq!(legacy_address.keychain, KeychainKind::Custom(1));
+    /// # Ok::<(), bdk::Error>(())
+    /// ```
+    pub fn add_keychain<E: IntoWalletDescriptor>(
+        &mut self,
+        id: u32,
+        descriptor: E,
+    ) -> Result<(), Error> {
+        let (descriptor, keymap) =
+            into_wallet_descriptor_checked(descriptor, &self.secp, self.network)?;
+        Self::db_checksum(
+            self.database.get_mut(),
+            &descriptor.to_string(),
+            KeychainKind::Custom(id),
+        )?;
+        let signers = Arc::new(SignersContainer::build(keymap, &descriptor, &self.secp));
+        self.custom_keychains.insert(id, (descriptor, signers));
+
+        Ok(())
+    }
+
+    /// Return the keychains tracked by the wallet
+    ///
+    /// The internal keychain is only included if the wallet has a change descriptor.
+    pub fn keychains(&self) -> Vec<KeychainKind> {
+        let mut keychains = vec![KeychainKind::External];
+        if self.change_descriptor.is_some() {
+            keychains.push(KeychainKind::Internal);
+        }
+        keychains.extend(
+            self.custom_keychains
+                .keys()
+                .map(|id| KeychainKind::Custom(*id)),
+        );
+
+        keychains
+    }
+
+    // Whether `keychain` is one of the keychains tracked by the wallet. The internal keychain is
+    // always considered tracked, since it falls back to the external descriptor, while imported
+    // scripts don't have a descriptor at all.
+    fn has_keychain(&self, keychain: KeychainKind) -> bool {
+        match keychain {
+            KeychainKind::Custom(id) => self.custom_keychains.contains_key(&id),
+            KeychainKind::Imported => false,
+            _ => true,
+        }
+    }
+
     /// Get the Bitcoin network the wallet is using.
     pub fn network(&self) -> Network {
         self.network
@@ -347,6 +478,23 @@ where
         self._get_address(address_index, KeychainKind::Internal)
     }
 
+    /// Return a derived address using the descriptor of `keychain`, see [`AddressIndex`] for
+    /// available address index selection strategies.
+    ///
+    /// Returns an error if `keychain` is a [`KeychainKind::Custom`] keychain that hasn't been added
+    /// with [`Wallet::add_keychain`].
+    pub fn get_keychain_address(
+        &self,
+        keychain: KeychainKind,
+        address_index: AddressIndex,
+    ) -> Result<AddressInfo, Error> {
+        if !self.has_keychain(keychain) {
+            return Err(Error::UnknownKeychain(keychain));
+        }
+
+        self._get_address(address_index, keychain)
+    }
+
     fn _get_address(
         &self,
         address_index: AddressIndex,
@@ -401,14 +549,110 @@ where
                 self.cache_addresses(KeychainKind::Internal, 0, max_address)?;
             }
         }
+
+        for (id, (descriptor, _)) in &self.custom_keychains {
+            let keychain = KeychainKind::Custom(*id);
+            let max_address = match descriptor.has_wildcard() {
+                false => 0,
+                true => max_addresses,
+            };
+
+            if self
+                .database
+                .borrow()
+                .get_script_pubkey_from_path(keychain, max_address.saturating_sub(1))?
+                .is_none()
+            {
+                debug!("caching {:?} addresses", keychain);
+                new_addresses_cached = true;
+                self.cache_addresses(keychain, 0, max_address)?;
+            }
+        }
         Ok(new_addresses_cached)
     }
 
-    /// Return whether or not a `script` is part of this wallet (either internal or external)
+    /// Return whether or not a `script` is part of this wallet (in any of its keychains)
     pub fn is_mine(&self, script: &Script) -> Result<bool, Error> {
         self.database.borrow().is_mine(script)
     }
 
+    /// Import a watch-only `script` into the wallet
+    ///
+    /// Imported scripts are stored in the database with the [`KeychainKind::Imported`] keychain,
+    /// so they are tracked by [`Wallet::sync`] and their UTXOs are part of the balance like the
+    /// ones of the wallet's descriptors. Since the wallet doesn't know how to spend them, they are
+    /// never selected when creating a transaction and they can't be added with
+    /// [`TxBuilder::add_utxo`]: they can only be spent as foreign UTXOs, with
+    /// [`TxBuilder::add_foreign_utxo`].
+    ///
+    /// Importing a script that already belongs to the wallet does nothing.
+    pub fn import_script(&self, script: Script) -> Result<(), Error> {
+        let mut database = self.database.borrow_mut();
+        if database.is_mine(&script)? {
+            return Ok(());
+        }
+
+        let index = database.increment_last_index(KeychainKind::Imported)?;
+        database.set_script_pubkey(&script, KeychainKind::Imported, index)
+    }
+
+    /// Import a watch-only `address` into the wallet, see [`Wallet::import_script`]
+    pub fn import_address(&self, address: &Address) -> Result<(), Error> {
+        if !address.is_valid_for_network(self.network) {
+            return Err(Error::InvalidNetwork {
+                requested: self.network,
+                found: address.network,
+            });
+        }
+
+        self.import_script(address.script_pubkey())
+    }
+
+    /// Import the script of an `addr()` or `raw()` descriptor into the wallet, see
+    /// [`Wallet::import_script`]
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use bdk::*;
+    /// # let descriptor = "wpkh(tpubD6NzVbkrYhZ4Xferm7Pz4VnjdcDPFyjVu5K4iZXQ4pVN8Cks4pHVowTBXBKRhX64pkRyJZJN5xAKj4UDNnLPb5p2sSKXhewoYx5GbTdUFWq/*)";
+    /// # let wallet = doctest_wallet!();
+    /// wallet.import_descriptor("addr(bcrt1qj08ys4ct2hzzc2hcz6h2hgrvlmsjynawhcf2xa)")?;
+    /// wallet.import_descriptor("raw(6a0474657374)")?;
+    /// assert_eq!(wallet.list_imported_scripts()?.len(), 2);
+    /// # Ok::<(), bdk::Error>(())
+    /// ```
+    pub fn import_descriptor(&self, descriptor: &str) -> Result<(), Error> {
+        // make sure the checksum is valid, if present
+        calc_checksum(descriptor)?;
+        let descriptor = descriptor.split('#').next().unwrap_or_default();
+
+        if let Some(address) = descriptor
+            .strip_prefix("addr(")
+            .and_then(|inner| inner.strip_suffix(')'))
+        {
+            let address = Address::from_str(address)
+                .map_err(|e| Error::Generic(format!("Invalid address in descriptor: {}", e)))?;
+            self.import_address(&address)
+        } else if let Some(hex) = descriptor
+            .strip_prefix("raw(")
+            .and_then(|inner| inner.strip_suffix(')'))
+        {
+            self.import_script(Script::from_str(hex)?)
+        } else {
+            Err(Error::Generic(
+                "Only `addr()` and `raw()` descriptors can be imported".into(),
+            ))
+        }
+    }
+
+    /// Return the list of scripts imported with [`Wallet::import_script`]
+    pub fn list_imported_scripts(&self) -> Result<Vec<Script>, Error> {
+        self.database
+            .borrow()
+            .iter_script_pubkeys(Some(KeychainKind::Imported))
+    }
+
     /// Return the list of unspent outputs of this wallet
     ///
     /// Note that this method only operates on the internal database, which first needs to be
@@ -423,12 +667,66 @@ where
             .collect())
     }
 
+    /// Return the list of unspent outputs of this wallet that belong to `keychain`
+    ///
+    /// Like [`Wallet::list_unspent`], this method only operates on the internal database.
+    pub fn list_unspent_for_keychain(
+        &self,
+        keychain: KeychainKind,
+    ) -> Result<Vec<LocalUtxo>, Error> {
+        Ok(self
+            .list_unspent()?
+            .into_iter()
+            .filter(|utxo| utxo.keychain == keychain)
+            .collect())
+    }
+
     /// Returns the `UTXO` owned by this wallet corresponding to `outpoint` if it exists in the
     /// wallet's database.
     pub fn get_utxo(&self, outpoint: OutPoint) -> Result<Option<LocalUtxo>, Error> {
         self.database.borrow().get_utxo(&outpoint)
     }
 
+    /// Mark a `UTXO` as frozen
+    ///
+    /// Frozen outputs are persisted in the database and are never selected automatically when
+    /// creating a transaction, which makes this a permanent alternative to
+    /// [`TxBuilder::add_unspendable`]. They can still be spent by explicitly adding them with
+    /// [`TxBuilder::add_utxo`].
+    pub fn freeze_utxo(&self, outpoint: OutPoint) -> Result<(), Error> {
+        self.database.borrow_mut().set_frozen(&outpoint)
+    }
+
+    /// Remove the frozen flag from a `UTXO`, returning whether it was frozen
+    pub fn unfreeze_utxo(&self, outpoint: OutPoint) -> Result<bool, Error> {
+        self.database.borrow_mut().del_frozen(&outpoint)
+    }
+
+    /// Return the list of frozen outputs
+    pub fn list_frozen(&self) -> Result<Vec<OutPoint>, Error> {
+        self.database.borrow().iter_frozen()
+    }
+
+    /// Attach a label to a `UTXO`, an address or a transaction, replacing the previous one
+    pub fn set_label(&self, key: LabelKey, label: &str) -> Result<(), Error> {
+        self.database.borrow_mut().set_label(&key, label)
+    }
+
+    /// Return the label attached to a `UTXO`, an address or a transaction, if any
+    pub fn get_label(&self, key: &LabelKey) -> Result<Option<String>, Error> {
+        self.database.borrow().get_label(key)
+    }
+
+    /// Remove the label attached to a `UTXO`, an address or a transaction, returning it
+    pub fn remove_label(&self, key: &LabelKey) -> Result<Option<String>, Error> {
+        self.database.borrow_mut().del_label(key)
+    }
+
+    /// Return the list of all the labels stored in the database
+    pub fn list_labels(&self) -> Result<Vec<(LabelKey, String)>, Error> {
+        self.database.borrow().iter_labels()
+    }
+
     /// Return a single transactions made and received by the wallet
     ///
     /// Optionally fill the [`TransactionDetails::transaction`] field with the raw transaction if
@@ -472,50 +770,68 @@ where
     /// Note that this method only operates on the internal database, which first needs to be
     /// [`Wallet::sync`] manually.
     pub fn get_balance(&self) -> Result<Balance, Error> {
-        let mut immature = 0;
-        let mut trusted_pending = 0;
-        let mut untrusted_pending = 0;
-        let mut confirmed = 0;
-        let utxos = self.list_unspent()?;
-        let database = self.database.borrow();
-        let last_sync_height = match database
-            .get_sync_time()?
-            .map(|sync_time| sync_time.block_time.height)
-        {
-            Some(height) => height,
-            // None means database was never synced
-            None => return Ok(Balance::default()),
-        };
-        for u in utxos {
-            // Unwrap used since utxo set is created from database
-            let tx = database
-                .get_tx(&u.outpoint.txid, true)?
-                .expect("Transaction not found in database");
-            if let Some(tx_conf_time) = &tx.confirmation_time {
-                if tx.transaction.expect("No transaction").is_coin_base()
-                    && (last_sync_height - tx_conf_time.height) < COINBASE_MATURITY
-                {
-                    immature += u.txout.value;
-                } else {
-                    confirmed += u.txout.value;
-                }
-            } else if u.keychain == KeychainKind::Internal {
-                trusted_pending += u.txout.value;
-            } else {
-                untrusted_pending += u.txout.value;
+        let utxos = self
+            .list_unspent()?
+            .into_iter()
+            .map(|utxo| {
+                let value = utxo.txout.value;
+                (utxo, value)
+            })
+            .collect();
+
+        self.compute_balance(utxos)
+    }
+
+    /// Return the balance of the UTXOs that belong to `keychain`
+    ///
+    /// Like [`Wallet::get_balance`], this method only operates on the internal database.
+    pub fn get_balance_for_keychain(&self, keychain: KeychainKind) -> Result<Balance, Error> {
+        let utxos = self
+            .list_unspent_for_keychain(keychain)?
+            .into_iter()
+            .map(|utxo| {
+                let value = utxo.txout.value;
+                (utxo, value)
+            })
+            .collect();
+
+        self.compute_balance(utxos)
+    }
+
+    /// Return the balance net of the fee needed to spend every UTXO at `fee_rate`, and the UTXOs
+    /// that aren't worth spending at that fee rate
+    ///
+    /// The cost of a UTXO is the fee for the weight of the input spending it, computed from the
+    /// maximum satisfaction weight of its descriptor. UTXOs whose value doesn't cover their cost
+    /// are listed as [uneconomical](EffectiveBalance::uneconomical) and don't contribute to the
+    /// balance.
+    ///
+    /// Like [`Wallet::get_balance`], this method only operates on the internal database.
+    pub fn get_effective_balance(&self, fee_rate: FeeRate) -> Result<EffectiveBalance, Error> {
+        let mut utxos = vec![];
+        let mut uneconomical = vec![];
+        for utxo in self.list_unspent()? {
+            let satisfaction_weight = self
+                .get_descriptor_for_keychain(utxo.keychain)
+                .max_satisfaction_weight()?;
+            let cost = fee_rate.fee_wu(TXIN_BASE_WEIGHT + satisfaction_weight);
+            match utxo.txout.value.checked_sub(cost) {
+                Some(value) if value > 0 => utxos.push((utxo, value)),
+                _ => uneconomical.push(utxo),
             }
         }
 
-        Ok(Balance {
-            immature,
-            trusted_pending,
-            untrusted_pending,
-            confirmed,
+        Ok(EffectiveBalance {
+            balance: self.compute_balance(utxos)?,
+            uneconomical,
         })
     }
 
     /// Add an external signer
     ///
+    /// Signers for a [`KeychainKind::Custom`] keychain that hasn't been added with
+    /// [`Wallet::add_keychain`] are ignored.
+    ///
     /// See [the `signer` module](signer) for an example.
     pub fn add_signer(
         &mut self,
@@ -526,6 +842,11 @@ where
         let signers = match keychain {
             KeychainKind::External => Arc::make_mut(&mut self.signers),
             KeychainKind::Internal => Arc::make_mut(&mut self.change_signers),
+            KeychainKind::Custom(id) => match self.custom_keychains.get_mut(&id) {
+                Some((_, signers)) => Arc::make_mut(signers),
+                None => return,
+            },
+            KeychainKind::Imported => return,
         };
 
         signers.add_external(signer.id(&self.secp), ordering, signer);
@@ -551,7 +872,73 @@ where
         match keychain {
             KeychainKind::External => Arc::clone(&self.signers),
             KeychainKind::Internal => Arc::clone(&self.change_signers),
+            KeychainKind::Custom(id) => self
+                .custom_keychains
+                .get(&id)
+                .map(|(_, signers)| Arc::clone(signers))
+                .unwrap_or_else(|| Arc::new(SignersContainer::new())),
+            KeychainKind::Imported => Arc::new(SignersContainer::new()),
+        }
+    }
+
+    /// Set the keychain used to receive [silent payments](silent_payment)
+    ///
+    /// The keychain is not persisted in the database, so it has to be set again every time the
+    /// wallet is created.
+    pub fn set_silent_payment_keychain(&mut self, keychain: SilentPaymentKeychain) {
+        self.silent_payment = Some(keychain);
+    }
+
+    /// Return the [silent payment](silent_payment) address of this wallet
+    ///
+    /// Returns an error if no keychain has been set with
+    /// [`Wallet::set_silent_payment_keychain`].
+    pub fn get_silent_payment_address(&self) -> Result<SilentPaymentAddress, Error> {
+        let keychain = self
+            .silent_payment
+            .as_ref()
+            .ok_or(SilentPaymentError::MissingKeychain)?;
+
+        Ok(keychain.address(self.network, &self.secp))
+    }
+
+    /// Scan the transactions in the database for [silent payments](silent_payment) to this
+    /// wallet
+    ///
+    /// Transactions whose previous outputs are not all in the database are skipped, since the
+    /// shared secret can't be computed without them.
+    ///
+    /// **Note**: [`Wallet::sync`] only fetches the transactions of the scripts the wallet already
+    /// knows, while silent payments are made to fresh scripts. This means that the only payments
+    /// this method can find are the ones this wallet made to itself. Payments from third parties
+    /// have to be found by fetching their transactions, along with the outputs they spend, from
+    /// a backend and passing them to [`silent_payment::scan_transaction`].
+    pub fn scan_silent_payments(&self) -> Result<Vec<SilentPaymentOutput>, Error> {
+        let keychain = self
+            .silent_payment
+            .as_ref()
+            .ok_or(SilentPaymentError::MissingKeychain)?;
+        let database = self.database.borrow();
+
+        let mut found = vec![];
+        for tx in database.iter_raw_txs()? {
+            if tx.is_coin_base() {
+                continue;
+            }
+
+            let prevouts = tx
+                .input
+                .iter()
+                .map(|txin| database.get_previous_output(&txin.previous_output))
+                .collect::<Result<Option<Vec<_>>, _>>()?;
+            if let Some(prevouts) = prevouts {
+                found.extend(silent_payment::scan_transaction(
+                    keychain, &tx, &prevouts, &self.secp,
+                )?);
+            }
         }
+
+        Ok(found)
     }
 
     /// Start building a transaction.
@@ -589,11 +976,121 @@ where
         }
     }
 
-    pub(crate) fn create_tx<Cs: coin_selection::CoinSelectionAlgorithm<D>>(
+    /// Create a [`CoinjoinParticipant`] spending the given `utxos`
+    ///
+    /// The participant receives the denomination on a new external address, and the change on a
+    /// new internal address.
+    ///
+    /// Only the previous outputs are included in the inputs of the participant, the rest of the
+    /// metadata is added back by [`Wallet::sign`].
+    pub fn coinjoin_participant(&self, utxos: &[OutPoint]) -> Result<CoinjoinParticipant, Error> {
+        let mut participant = CoinjoinParticipant::new(
+            self.get_address(AddressIndex::New)?.script_pubkey(),
+            self.get_internal_address(AddressIndex::New)?
+                .script_pubkey(),
+        );
+
+        for outpoint in utxos {
+            let utxo = match self.database.borrow().get_utxo(outpoint)? {
+                Some(utxo) if !utxo.is_spent => utxo,
+                _ => return Err(Error::UnknownUtxo),
+            };
+            let satisfaction_weight = self
+                .get_descriptor_for_keychain(utxo.keychain)
+                .max_satisfaction_weight()?;
+            let psbt_input = self.get_psbt_input(utxo, None, false)?;
+
+            participant.add_input(
+                *outpoint,
+                psbt::Input {
+                    witness_utxo: psbt_input.witness_utxo,
+                    non_witness_utxo: psbt_input.non_witness_utxo,
+                    ..Default::default()
+                },
+                satisfaction_weight,
+            )?;
+        }
+
+        Ok(participant)
+    }
+
+    /// Check that our share of a collaborative transaction is correct
+    ///
+    /// This makes sure that at least two outputs have the value of the `denomination`, that one of
+    /// them pays this wallet and that, except for dust change, we're not paying more than the
+    /// fees at `fee_rate` for our inputs, our outputs and our share of the transaction header.
+    ///
+    /// See the [`coinjoin`] module for an example.
+    pub fn verify_coinjoin(
         &self,
-        coin_selection: Cs,
-        params: TxParams,
-    ) -> Result<(psbt::PartiallySignedTransaction, TransactionDetails), Error> {
+        psbt: &psbt::PartiallySignedTransaction,
+        denomination: u64,
+        fee_rate: FeeRate,
+    ) -> Result<(), Error> {
+        let database = self.database.borrow();
+
+        let mut input_value = 0;
+        let mut weight = 0;
+        for (index, txin) in psbt.unsigned_tx.input.iter().enumerate() {
+            let utxo = psbt
+                .get_utxo_for(index)
+                .ok_or(CoinjoinError::MissingUtxo(txin.previous_output))?;
+            if let Some((keychain, _)) =
+                database.get_path_from_script_pubkey(&utxo.script_pubkey)?
+            {
+                input_value += utxo.value;
+                weight += coin_selection::TXIN_BASE_WEIGHT
+                    + self
+                        .get_descriptor_for_keychain(keychain)
+                        .max_satisfaction_weight()?;
+            }
+        }
+        if input_value == 0 {
+            return Err(CoinjoinError::NoInputs.into());
+        }
+
+        let outputs = &psbt.unsigned_tx.output;
+        let participants = outputs
+            .iter()
+            .filter(|txout| txout.value == denomination)
+            .count();
+        if participants < 2 {
+            return Err(CoinjoinError::NotEnoughParticipants.into());
+        }
+
+        let mut output_value = 0;
+        let mut our_outputs = 0;
+        let mut denomination_output = None;
+        for txout in outputs {
+            if self.is_mine(&txout.script_pubkey)? {
+                output_value += txout.value;
+                our_outputs += 1;
+                weight += coinjoin::output_weight(txout);
+                if txout.value == denomination && denomination_output.is_none() {
+                    denomination_output = Some(txout);
+                }
+            }
+        }
+        let denomination_output = denomination_output.ok_or(CoinjoinError::MissingOutput)?;
+
+        let mut max = fee_rate.fee_wu(weight + coinjoin::header_weight_share(participants));
+        // Without change we may have lost some dust, plus the fees for the change output
+        if our_outputs == 1 {
+            max += fee_rate.fee_wu(coinjoin::output_weight(denomination_output))
+                + denomination_output.script_pubkey.dust_value().to_sat();
+        }
+
+        let actual = input_value.saturating_sub(output_value);
+        if actual > max {
+            return Err(CoinjoinError::ShareTooHigh { max, actual }.into());
+        }
+
+        Ok(())
+    }
+
+    // Get the timelock requirements of the policy paths set in `params`, making sure that a path
+    // was provided for every keychain that can be spent and needs one
+    fn get_policy_requirements(&self, params: &TxParams) -> Result<Condition, Error> {
         let external_policy = self
             .descriptor
             .extract_policy(&self.signers, BuildSatisfaction::None, &self.secp)?
@@ -626,6 +1123,23 @@ where
                 return Err(Error::SpendingPolicyRequired(KeychainKind::Internal));
             };
         }
+        // And for every custom keychain, whose outputs are never considered change
+        let mut custom_requirements = Condition::default();
+        for (id, (descriptor, signers)) in &self.custom_keychains {
+            let policy = descriptor
+                .extract_policy(signers, BuildSatisfaction::None, &self.secp)?
+                .unwrap();
+            let policy_path = params.custom_policy_paths.get(id);
+            if params.change_policy != tx_builder::ChangeSpendPolicy::OnlyChange
+                && policy.requires_path()
+                && policy_path.is_none()
+            {
+                return Err(Error::SpendingPolicyRequired(KeychainKind::Custom(*id)));
+            }
+
+            let requirements = policy.get_condition(policy_path.unwrap_or(&BTreeMap::new()))?;
+            custom_requirements = custom_requirements.merge(&requirements)?;
+        }
 
         let external_requirements = external_policy.get_condition(
             params
@@ -646,8 +1160,17 @@ where
             })
             .transpose()?;
 
-        let requirements =
-            external_requirements.merge(&internal_requirements.unwrap_or_default())?;
+        Ok(external_requirements
+            .merge(&internal_requirements.unwrap_or_default())?
+            .merge(&custom_requirements)?)
+    }
+
+    pub(crate) fn create_tx<Cs: coin_selection::CoinSelectionAlgorithm<D>>(
+        &self,
+        coin_selection: Cs,
+        params: TxParams,
+    ) -> Result<(psbt::PartiallySignedTransaction, TransactionDetails), Error> {
+        let requirements = self.get_policy_requirements(&params)?;
         debug!("Policy requirements: {:?}", requirements);
 
         let version = match params.version {
@@ -761,7 +1284,11 @@ where
                         });
                     }
                 }
-                (*rate, 0)
+                // make up for the ancestors paying less than the requested rate
+                let package_deficit = params.package_fee.map_or(0, |package| {
+                    rate.fee_wu(package.weight).saturating_sub(package.fee)
+                });
+                (*rate, package_deficit)
             }
         };
 
@@ -780,9 +1307,20 @@ where
         let mut outgoing: u64 = 0;
         let mut received: u64 = 0;
 
-        let recipients = params.recipients.iter().map(|(r, v)| (r, *v));
-
-        for (index, (script_pubkey, value)) in recipients.enumerate() {
+        // Silent payment outputs depend on the inputs, so until those are selected we use a
+        // placeholder with the same size
+        let silent_payment_placeholders = params
+            .silent_payment_recipients
+            .iter()
+            .map(|(address, value)| (address.placeholder_script(), *value))
+            .collect::<Vec<_>>();
+        let recipients = params
+            .recipients
+            .iter()
+            .chain(silent_payment_placeholders.iter())
+            .map(|(r, v)| (r, *v));
+
+        for (index, (script_pubkey, value)) in recipients.enumerate() {
             if !params.allow_dust
                 && value.is_dust(script_pubkey)
                 && !script_pubkey.is_provably_unspendable()
@@ -825,15 +1363,22 @@ where
             ));
         }
 
+        let unspendable = self.apply_utxo_filters(&params.unspendable, &params.utxo_filters)?;
         let (required_utxos, optional_utxos) = self.preselect_utxos(
             params.change_policy,
-            &params.unspendable,
+            &unspendable,
             params.utxos.clone(),
             params.drain_wallet,
             params.manually_selected_only,
             params.bumping_fee.is_some(), // we mandate confirmed transactions if we're bumping the fee
             current_height.map(LockTime::to_consensus_u32),
         )?;
+        let (required_utxos, optional_utxos) = self.apply_ancestor_fee_policy(
+            params.ancestor_fee_policy,
+            fee_rate,
+            required_utxos,
+            optional_utxos,
+        )?;
 
         // get drain script
         let drain_script = match params.drain_to {
@@ -844,17 +1389,57 @@ where
                 .script_pubkey(),
         };
 
+        // When the recipients pay for the fee the coins only need to cover the outgoing amount:
+        // the selection is made without fees, which are computed and subtracted afterwards
+        let subtract_fee = !params.fee_paying_recipients.is_empty();
+        let satisfaction_weights = required_utxos
+            .iter()
+            .chain(optional_utxos.iter())
+            .map(|u| (u.utxo.outpoint(), u.satisfaction_weight))
+            .collect::<HashMap<_, _>>();
+        let (selection_fee_rate, selection_fee_amount) = match subtract_fee {
+            true => (FeeRate::from_sat_per_vb(0.0), 0),
+            false => (fee_rate, fee_amount),
+        };
+
         let coin_selection = coin_selection.coin_select(
             self.database.borrow().deref(),
             required_utxos,
             optional_utxos,
-            fee_rate,
-            outgoing + fee_amount,
+            selection_fee_rate,
+            outgoing + selection_fee_amount,
             &drain_script,
         )?;
         fee_amount += coin_selection.fee_amount;
         let excess = &coin_selection.excess;
 
+        let change_split = match params.drain_to {
+            Some(_) => ChangeSplit::Single,
+            None => params.change_split,
+        };
+        let (change_values, change_split_fee) =
+            split_change(excess, selection_fee_rate, &drain_script, &change_split);
+
+        if subtract_fee {
+            fee_amount += coin_selection
+                .selected
+                .iter()
+                .map(|u| fee_rate.fee_wu(TXIN_BASE_WEIGHT + satisfaction_weights[&u.outpoint()]))
+                .sum::<u64>();
+            fee_amount += change_values
+                .iter()
+                .map(|value| {
+                    let drain_output = TxOut {
+                        value: *value,
+                        script_pubkey: drain_script.clone(),
+                    };
+                    fee_rate.fee_vb(serialize(&drain_output).len())
+                })
+                .sum::<u64>();
+
+            received -= self.subtract_fee_from_recipients(&mut tx, &params, fee_amount)?;
+        }
+
         tx.input = coin_selection
             .selected
             .iter()
@@ -866,6 +1451,37 @@ where
             })
             .collect();
 
+        // When spending only taproot inputs, follow BIP-326 to prevent fee sniping. Requirements
+        // and explicit values for the nLockTime or the nSequence take precedence.
+        //
+        // Setting the nSequence of an input makes the transaction replaceable (BIP-125), so that
+        // variant is only used when RBF is enabled with the default value: with an explicit
+        // nSequence, or when RBF is disabled, only the nLockTime is set.
+        let all_taproot = coin_selection
+            .selected
+            .iter()
+            .all(|u| u.txout().script_pubkey.is_v1_p2tr());
+        match current_height {
+            Some(height)
+                if all_taproot
+                    && height.is_block_height()
+                    && params.locktime.is_none()
+                    && requirements.timelock.is_none()
+                    && requirements.csv.is_none() =>
+            {
+                let height = height.to_consensus_u32();
+                let confirmations = match (params.version, &params.rbf) {
+                    (Some(tx_builder::Version(1)), _) => None,
+                    (_, Some(tx_builder::RbfValue::Default)) => {
+                        self.get_confirmations(&coin_selection.selected, height)?
+                    }
+                    _ => None,
+                };
+                apply_anti_fee_sniping(&mut thread_rng(), &mut tx, height, confirmations);
+            }
+            _ => {}
+        }
+
         if tx.output.is_empty() {
             // Uh oh, our transaction has no outputs.
             // We allow this when:
@@ -895,31 +1511,64 @@ where
             NoChange {
                 remaining_amount, ..
             } => fee_amount += remaining_amount,
-            Change { amount, fee } => {
-                if self.is_mine(&drain_script)? {
-                    received += amount;
-                }
-                fee_amount += fee;
+            Change { fee, .. } => {
+                fee_amount += fee + change_split_fee;
+
+                for (index, value) in change_values.into_iter().enumerate() {
+                    // the first output goes to the drain script, the others to new change addresses
+                    let script_pubkey = match index {
+                        0 => drain_script.clone(),
+                        _ => self
+                            .get_internal_address(AddressIndex::New)?
+                            .address
+                            .script_pubkey(),
+                    };
+                    if self.is_mine(&script_pubkey)? {
+                        received += value;
+                    }
 
-                // create drain output
-                let drain_output = TxOut {
-                    value: *amount,
-                    script_pubkey: drain_script,
-                };
+                    // create drain output
+                    let drain_output = TxOut {
+                        value,
+                        script_pubkey,
+                    };
 
-                // TODO: We should pay attention when adding a new output: this might increase
-                // the lenght of the "number of vouts" parameter by 2 bytes, potentially making
-                // our feerate too low
-                tx.output.push(drain_output);
+                    // TODO: We should pay attention when adding a new output: this might increase
+                    // the lenght of the "number of vouts" parameter by 2 bytes, potentially making
+                    // our feerate too low
+                    tx.output.push(drain_output);
+                }
             }
         };
 
         // sort input/outputs according to the chosen algorithm
         params.ordering.sort_tx(&mut tx);
 
-        let txid = tx.txid();
         let sent = coin_selection.local_selected_amount();
-        let psbt = self.complete_transaction(tx, coin_selection.selected, params)?;
+        let silent_payment_recipients = params.silent_payment_recipients.clone();
+        let ordering = params.ordering;
+        let mut psbt = self.complete_transaction(tx, coin_selection.selected, params)?;
+        if !silent_payment_recipients.is_empty() {
+            self.add_silent_payment_outputs(&mut psbt, &silent_payment_recipients)?;
+
+            // BIP69 also sorts the outputs by script, so they have to be sorted again now that
+            // the placeholders have been replaced. The order of the inputs is not affected.
+            if ordering == tx_builder::TxOrdering::Bip69Lexicographic {
+                let mut outputs = psbt
+                    .unsigned_tx
+                    .output
+                    .drain(..)
+                    .zip(psbt.outputs.drain(..))
+                    .collect::<Vec<_>>();
+                outputs.sort_unstable_by(|(a, _), (b, _)| {
+                    (a.value, &a.script_pubkey).cmp(&(b.value, &b.script_pubkey))
+                });
+                let (txouts, psbt_outputs): (Vec<_>, Vec<_>) = outputs.into_iter().unzip();
+                psbt.unsigned_tx.output = txouts;
+                psbt.outputs = psbt_outputs;
+            }
+        }
+        let txid = psbt.unsigned_tx.txid();
 
         let transaction_details = TransactionDetails {
             transaction: None,
@@ -933,11 +1582,115 @@ where
         Ok((psbt, transaction_details))
     }
 
+    pub(crate) fn max_sendable(
+        &self,
+        params: &TxParams,
+        script_pubkey: &Script,
+    ) -> Result<u64, Error> {
+        self.get_policy_requirements(params)?;
+
+        let (fee_rate, mut fee_amount) = match params
+            .fee_policy
+            .as_ref()
+            .unwrap_or(&FeePolicy::FeeRate(FeeRate::default()))
+        {
+            FeePolicy::FeeAmount(fee) => (FeeRate::from_sat_per_vb(0.0), *fee),
+            FeePolicy::FeeRate(rate) => {
+                let package_deficit = params.package_fee.map_or(0, |package| {
+                    rate.fee_wu(package.weight).saturating_sub(package.fee)
+                });
+                (*rate, package_deficit)
+            }
+        };
+
+        if params.manually_selected_only && params.utxos.is_empty() {
+            return Err(Error::NoUtxosSelected);
+        }
+        if params.change_policy != tx_builder::ChangeSpendPolicy::ChangeAllowed
+            && self.change_descriptor.is_none()
+        {
+            return Err(Error::Generic(
+                "The `change_policy` can be set only if the wallet has a change_descriptor".into(),
+            ));
+        }
+
+        // the fee is computed the same way as in `create_tx`, with `script_pubkey` taking the
+        // place of the drain output
+        let silent_payment_placeholders = params
+            .silent_payment_recipients
+            .iter()
+            .map(|(address, value)| (address.placeholder_script(), *value));
+        let tx = Transaction {
+            version: 1,
+            lock_time: bitcoin::PackedLockTime::ZERO,
+            input: vec![],
+            output: params
+                .recipients
+                .iter()
+                .cloned()
+                .chain(silent_payment_placeholders)
+                .map(|(script_pubkey, value)| TxOut {
+                    script_pubkey,
+                    value,
+                })
+                .collect(),
+        };
+        let outgoing = tx.output.iter().map(|txout| txout.value).sum::<u64>();
+        fee_amount += fee_rate.fee_wu(tx.weight()) + fee_rate.fee_wu(2);
+        fee_amount += fee_rate.fee_vb(serialize(script_pubkey).len() + 8);
+
+        let current_height = match params.current_height {
+            None => self.database().get_sync_time()?.map(|sync_time| {
+                LockTime::from_height(sync_time.block_time.height).expect("Invalid height")
+            }),
+            h => h,
+        };
+        let unspendable = self.apply_utxo_filters(&params.unspendable, &params.utxo_filters)?;
+        let (required_utxos, optional_utxos) = self.preselect_utxos(
+            params.change_policy,
+            &unspendable,
+            params.utxos.clone(),
+            false,
+            params.manually_selected_only,
+            false,
+            current_height.map(LockTime::to_consensus_u32),
+        )?;
+        let (required_utxos, optional_utxos) = self.apply_ancestor_fee_policy(
+            params.ancestor_fee_policy,
+            fee_rate,
+            required_utxos,
+            optional_utxos,
+        )?;
+
+        // the manually selected UTXOs are always spent, the others only if they're worth it
+        let effective_value = |u: &WeightedUtxo| {
+            u.utxo.txout().value as i64
+                - fee_rate.fee_wu(TXIN_BASE_WEIGHT + u.satisfaction_weight) as i64
+        };
+        let available = required_utxos.iter().map(effective_value).sum::<i64>()
+            + optional_utxos
+                .iter()
+                .map(effective_value)
+                .filter(|value| *value > 0)
+                .sum::<i64>();
+
+        let max = available - fee_amount as i64 - outgoing as i64;
+        if max <= 0 || (!params.allow_dust && (max as u64).is_dust(script_pubkey)) {
+            return Err(Error::InsufficientFunds {
+                needed: script_pubkey.dust_value().to_sat(),
+                available: max.max(0) as u64,
+            });
+        }
+
+        Ok(max as u64)
+    }
+
     /// Bump the fee of a transaction previously created with this wallet.
     ///
     /// Returns an error if the transaction is already confirmed or doesn't explicitly signal
     /// *replace by fee* (RBF). If the transaction can be fee bumped then it returns a [`TxBuilder`]
-    /// pre-populated with the inputs and outputs of the original transaction.
+    /// pre-populated with the inputs and outputs of the original transaction. More recipients can
+    /// be batched into the replacement by calling `add_recipient` on the returned builder.
     ///
     /// ## Example
     ///
@@ -1040,6 +1793,7 @@ where
             })
             .collect::<Result<Vec<_>, _>>()?;
 
+        let mut bumping_change = None;
         if tx.output.len() > 1 {
             let mut change_index = None;
             for (index, txout) in tx.output.iter().enumerate() {
@@ -1055,7 +1809,7 @@ where
             }
 
             if let Some(change_index) = change_index {
-                tx.output.remove(change_index);
+                bumping_change = Some(tx.output.remove(change_index).script_pubkey);
             }
         }
 
@@ -1072,6 +1826,7 @@ where
                 absolute: details.fee.ok_or(Error::FeeRateUnavailable)?,
                 rate: feerate.as_sat_per_vb(),
             }),
+            bumping_change,
             ..Default::default()
         };
 
@@ -1083,131 +1838,570 @@ where
         })
     }
 
-    /// Sign a transaction with all the wallet's signers, in the order specified by every signer's
-    /// [`SignerOrdering`]
+    /// Cancel a transaction previously created with this wallet by replacing it with a transaction
+    /// that spends the same inputs back to a new internal address.
     ///
-    /// The [`SignOptions`] can be used to tweak the behavior of the software signers, and the way
-    /// the transaction is finalized at the end. Note that it can't be guaranteed that *every*
-    /// signers will follow the options, but the "software signers" (WIF keys and `xprv`) defined
-    /// in this library will.
+    /// The replacement pays the minimum fee accepted by nodes following BIP125: the absolute fee of
+    /// the original transaction plus the [minimum relay fee](FeeRate::default_min_relay_fee) for its
+    /// own size. As with [`build_fee_bump`](Self::build_fee_bump), an error is returned if the
+    /// transaction is already confirmed or doesn't signal RBF.
     ///
     /// ## Example
     ///
-    /// ```
+    /// ```no_run
     /// # use std::str::FromStr;
     /// # use bitcoin::*;
     /// # use bdk::*;
-    /// # use bdk::database::*;
     /// # let descriptor = "wpkh(tpubD6NzVbkrYhZ4Xferm7Pz4VnjdcDPFyjVu5K4iZXQ4pVN8Cks4pHVowTBXBKRhX64pkRyJZJN5xAKj4UDNnLPb5p2sSKXhewoYx5GbTdUFWq/*)";
     /// # let wallet = doctest_wallet!();
     /// # let to_address = Address::from_str("2N4eQYCbKUHCCTUjBJeHcJp9ok6J2GZsTDt").unwrap();
     /// let (mut psbt, _) = {
     ///     let mut builder = wallet.build_tx();
-    ///     builder.add_recipient(to_address.script_pubkey(), 50_000);
+    ///     builder
+    ///         .add_recipient(to_address.script_pubkey(), 50_000)
+    ///         .enable_rbf();
     ///     builder.finish()?
     /// };
-    /// let  finalized = wallet.sign(&mut psbt, SignOptions::default())?;
-    /// assert!(finalized, "we should have signed all the inputs");
+    /// let _ = wallet.sign(&mut psbt, SignOptions::default())?;
+    /// let tx = psbt.extract_tx();
+    /// // broadcast tx but we changed our mind before it confirmed
+    /// let (mut psbt, _) = wallet.build_cancel_tx(tx.txid())?.finish()?;
+    /// let _ = wallet.sign(&mut psbt, SignOptions::default())?;
+    /// let cancel_tx = psbt.extract_tx();
+    /// // broadcast cancel_tx to replace the original
     /// # Ok::<(), bdk::Error>(())
-    pub fn sign(
+    /// ```
+    pub fn build_cancel_tx(
         &self,
-        psbt: &mut psbt::PartiallySignedTransaction,
-        sign_options: SignOptions,
-    ) -> Result<bool, Error> {
-        // This adds all the PSBT metadata for the inputs, which will help us later figure out how
-        // to derive our keys
-        self.update_psbt_with_descriptor(psbt)?;
-
-        // If we aren't allowed to use `witness_utxo`, ensure that every input (except p2tr and finalized ones)
-        // has the `non_witness_utxo`
-        if !sign_options.trust_witness_utxo
-            && psbt
-                .inputs
+        txid: Txid,
+    ) -> Result<TxBuilder<'_, D, DefaultCoinSelectionAlgorithm, BumpFee>, Error> {
+        let mut builder = self.build_fee_bump(txid)?;
+        let previous_fee = builder
+            .params
+            .bumping_fee
+            .expect("build_fee_bump always sets the previous fee");
+
+        let drain_script = self
+            .get_internal_address(AddressIndex::New)?
+            .address
+            .script_pubkey();
+
+        // estimate the size of the replacement, which has the same inputs and a single output
+        let tx = Transaction {
+            version: 1,
+            lock_time: bitcoin::PackedLockTime::ZERO,
+            input: builder
+                .params
+                .utxos
                 .iter()
-                .filter(|i| i.final_script_witness.is_none() && i.final_script_sig.is_none())
-                .filter(|i| i.tap_internal_key.is_none() && i.tap_merkle_root.is_none())
-                .any(|i| i.non_witness_utxo.is_none())
-        {
-            return Err(Error::Signer(signer::SignerError::MissingNonWitnessUtxo));
-        }
-
-        // If the user hasn't explicitly opted-in, refuse to sign the transaction unless every input
-        // is using `SIGHASH_ALL` or `SIGHASH_DEFAULT` for taproot
-        if !sign_options.allow_all_sighashes
-            && !psbt.inputs.iter().all(|i| {
-                i.sighash_type.is_none()
-                    || i.sighash_type == Some(EcdsaSighashType::All.into())
-                    || i.sighash_type == Some(SchnorrSighashType::All.into())
-                    || i.sighash_type == Some(SchnorrSighashType::Default.into())
-            })
-        {
-            return Err(Error::Signer(signer::SignerError::NonStandardSighash));
-        }
-
-        for signer in self
-            .signers
-            .signers()
+                .map(|u| bitcoin::TxIn {
+                    previous_output: u.utxo.outpoint(),
+                    ..Default::default()
+                })
+                .collect(),
+            output: vec![TxOut {
+                value: 0,
+                script_pubkey: drain_script.clone(),
+            }],
+        };
+        let satisfaction_weight = builder
+            .params
+            .utxos
             .iter()
-            .chain(self.change_signers.signers().iter())
-        {
-            signer.sign_transaction(psbt, &sign_options, &self.secp)?;
-        }
-
-        // attempt to finalize
-        if sign_options.try_finalize {
-            self.finalize_psbt(psbt, sign_options)
-        } else {
-            Ok(false)
-        }
-    }
+            .map(|u| u.satisfaction_weight)
+            .sum::<usize>();
+        // add 2WU for the segwit marker and flag, like `create_tx` does
+        let weight = tx.weight() + satisfaction_weight + 2;
+
+        builder.params.recipients.clear();
+        builder.params.drain_to = Some(drain_script);
+        builder.params.manually_selected_only = true;
+        builder.params.fee_policy = Some(FeePolicy::FeeAmount(
+            previous_fee.absolute + FeeRate::default_min_relay_fee().fee_wu(weight),
+        ));
 
-    /// Return the spending policies for the wallet's descriptor
-    pub fn policies(&self, keychain: KeychainKind) -> Result<Option<Policy>, Error> {
-        match (keychain, self.change_descriptor.as_ref()) {
-            (KeychainKind::External, _) => Ok(self.descriptor.extract_policy(
-                &self.signers,
-                BuildSatisfaction::None,
-                &self.secp,
-            )?),
-            (KeychainKind::Internal, None) => Ok(None),
-            (KeychainKind::Internal, Some(desc)) => Ok(desc.extract_policy(
-                &self.change_signers,
-                BuildSatisfaction::None,
-                &self.secp,
-            )?),
-        }
+        Ok(builder)
     }
 
-    /// Return the "public" version of the wallet's descriptor, meaning a new descriptor that has
-    /// the same structure but with every secret key removed
+    /// Bump the fee of an unconfirmed transaction by spending one of its outputs (*child pays for
+    /// parent*, CPFP).
     ///
-    /// This can be used to build a watch-only version of a wallet
-    pub fn public_descriptor(
+    /// Unlike [`build_fee_bump`](Self::build_fee_bump) this also works for transactions that don't
+    /// signal RBF or that were created by somebody else, as long as they pay to this wallet. The
+    /// returned [`TxBuilder`] spends every unspent output of `txid` owned by the wallet and sends
+    /// them back to a new internal address. The fee of the child is computed so that the child and
+    /// all of its unconfirmed ancestors, taken together as a package, pay at least `fee_rate`.
+    ///
+    /// Returns an error if the transaction is already confirmed, if its fee is unknown or if none
+    /// of its outputs can be spent by the wallet.
+    ///
+    /// ## Example
+    ///
+    /// ```no_run
+    /// # use bitcoin::*;
+    /// # use bitcoin::hashes::Hash;
+    /// # use bdk::*;
+    /// # let descriptor = "wpkh(tpubD6NzVbkrYhZ4Xferm7Pz4VnjdcDPFyjVu5K4iZXQ4pVN8Cks4pHVowTBXBKRhX64pkRyJZJN5xAKj4UDNnLPb5p2sSKXhewoYx5GbTdUFWq/*)";
+    /// # let wallet = doctest_wallet!();
+    /// # let stuck_txid = Txid::all_zeros();
+    /// // an incoming payment is stuck in the mempool with a very low fee rate
+    /// let (mut psbt, _) = {
+    ///     let builder = wallet.build_cpfp(stuck_txid, FeeRate::from_sat_per_vb(10.0))?;
+    ///     builder.finish()?
+    /// };
+    /// let _ = wallet.sign(&mut psbt, SignOptions::default())?;
+    /// let child_tx = psbt.extract_tx();
+    /// // broadcast child_tx so that miners are incentivized to include both transactions
+    /// # Ok::<(), bdk::Error>(())
+    /// ```
+    pub fn build_cpfp(
         &self,
-        keychain: KeychainKind,
-    ) -> Result<Option<ExtendedDescriptor>, Error> {
-        match (keychain, self.change_descriptor.as_ref()) {
-            (KeychainKind::External, _) => Ok(Some(self.descriptor.clone())),
-            (KeychainKind::Internal, None) => Ok(None),
-            (KeychainKind::Internal, Some(desc)) => Ok(Some(desc.clone())),
+        txid: Txid,
+        fee_rate: FeeRate,
+    ) -> Result<TxBuilder<'_, D, DefaultCoinSelectionAlgorithm, CreateTx>, Error> {
+        let details = match self.database.borrow().get_tx(&txid, true)? {
+            None => return Err(Error::TransactionNotFound),
+            Some(tx) if tx.transaction.is_none() => return Err(Error::TransactionNotFound),
+            Some(tx) if tx.confirmation_time.is_some() => return Err(Error::TransactionConfirmed),
+            Some(tx) => tx,
+        };
+        if details.fee.is_none() {
+            return Err(Error::FeeRateUnavailable);
+        }
+
+        let utxos = self
+            .get_available_utxos()?
+            .into_iter()
+            .filter(|(utxo, _)| utxo.outpoint.txid == txid)
+            .map(|(utxo, satisfaction_weight)| WeightedUtxo {
+                satisfaction_weight,
+                utxo: Utxo::Local(utxo),
+            })
+            .collect::<Vec<_>>();
+        if utxos.is_empty() {
+            return Err(Error::Generic(
+                "The transaction doesn't have any unspent output owned by the wallet".into(),
+            ));
         }
+
+        let params = TxParams {
+            utxos,
+            drain_to: Some(
+                self.get_internal_address(AddressIndex::New)?
+                    .address
+                    .script_pubkey(),
+            ),
+            fee_policy: Some(FeePolicy::FeeRate(fee_rate)),
+            package_fee: self.get_unconfirmed_ancestors(txid)?,
+            ..Default::default()
+        };
+
+        Ok(TxBuilder {
+            wallet: self,
+            params,
+            coin_selection: DefaultCoinSelectionAlgorithm::default(),
+            phantom: core::marker::PhantomData,
+        })
     }
 
-    /// Finalize a PSBT, i.e., for each input determine if sufficient data is available to pass
-    /// validation and construct the respective `scriptSig` or `scriptWitness`. Please refer to
-    /// [BIP174](https://github.com/bitcoin/bips/blob/master/bip-0174.mediawiki#Input_Finalizer)
-    /// for further information.
+    /// Plan the consolidation of the wallet's UTXOs into the internal keychain
     ///
-    /// Returns `true` if the PSBT could be finalized, and `false` otherwise.
+    /// The available UTXOs are grouped into one or more transactions, each one spending as many of
+    /// them as fit in `max_weight` and sending everything to a new internal address at the given
+    /// `fee_rate`. UTXOs whose value doesn't cover the cost of spending them at `fee_rate` are
+    /// left out, as are frozen ones and the UTXOs that would end up alone in their transaction.
     ///
-    /// The [`SignOptions`] can be used to tweak the behavior of the finalizer.
-    pub fn finalize_psbt(
+    /// The transactions signal RBF and are returned as PSBTs ready to be [signed](Self::sign).
+    /// They don't conflict with each other, so they can all be broadcast.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use bdk::*;
+    /// # let descriptor = "wpkh(tpubD6NzVbkrYhZ4Xferm7Pz4VnjdcDPFyjVu5K4iZXQ4pVN8Cks4pHVowTBXBKRhX64pkRyJZJN5xAKj4UDNnLPb5p2sSKXhewoYx5GbTdUFWq/*)";
+    /// # let wallet = doctest_wallet!();
+    /// // consolidate at 1 sat/vbyte in standard-sized transactions
+    /// let consolidation = wallet.plan_consolidation(FeeRate::from_sat_per_vb(1.0), 400_000)?;
+    /// for (mut psbt, details) in consolidation {
+    ///     let _ = wallet.sign(&mut psbt, SignOptions::default())?;
+    ///     // broadcast psbt.extract_tx() ...
+    /// }
+    /// # Ok::<(), bdk::Error>(())
+    /// ```
+    pub fn plan_consolidation(
         &self,
-        psbt: &mut psbt::PartiallySignedTransaction,
-        sign_options: SignOptions,
-    ) -> Result<bool, Error> {
-        let tx = &psbt.unsigned_tx;
-        let mut finished = true;
+        fee_rate: FeeRate,
+        max_weight: usize,
+    ) -> Result<Vec<(psbt::PartiallySignedTransaction, TransactionDetails)>, Error> {
+        // all the internal addresses have the same script length
+        let drain_script = self
+            .get_internal_address(AddressIndex::Peek(0))?
+            .address
+            .script_pubkey();
+        let tx = Transaction {
+            version: 1,
+            lock_time: bitcoin::PackedLockTime::ZERO,
+            input: vec![],
+            output: vec![TxOut {
+                value: 0,
+                script_pubkey: drain_script,
+            }],
+        };
+        // account for the segwit marker and flag and for the number of inputs growing to 3 bytes
+        let base_weight = tx.weight() + 2 + 8;
+
+        let mut utxos = self
+            .get_available_utxos()?
+            .into_iter()
+            .filter(|(utxo, satisfaction_weight)| {
+                utxo.txout.value > fee_rate.fee_wu(TXIN_BASE_WEIGHT + satisfaction_weight)
+            })
+            .collect::<Vec<_>>();
+        utxos.sort_by_key(|(utxo, _)| std::cmp::Reverse(utxo.txout.value));
+
+        let mut groups: Vec<Vec<WeightedUtxo>> = vec![];
+        let mut weight = 0;
+        for (utxo, satisfaction_weight) in utxos {
+            let input_weight = TXIN_BASE_WEIGHT + satisfaction_weight;
+            if base_weight + input_weight > max_weight {
+                continue;
+            }
+            if groups.is_empty() || weight + input_weight > max_weight {
+                groups.push(vec![]);
+                weight = base_weight;
+            }
+            weight += input_weight;
+            groups.last_mut().unwrap().push(WeightedUtxo {
+                satisfaction_weight,
+                utxo: Utxo::Local(utxo),
+            });
+        }
+
+        groups
+            .into_iter()
+            .filter(|utxos| utxos.len() > 1)
+            .map(|utxos| {
+                let params = TxParams {
+                    utxos,
+                    manually_selected_only: true,
+                    drain_to: Some(
+                        self.get_internal_address(AddressIndex::New)?
+                            .address
+                            .script_pubkey(),
+                    ),
+                    fee_policy: Some(FeePolicy::FeeRate(fee_rate)),
+                    rbf: Some(tx_builder::RbfValue::Default),
+                    ..Default::default()
+                };
+
+                self.create_tx(DefaultCoinSelectionAlgorithm::default(), params)
+            })
+            .collect()
+    }
+
+    /// Build the transactions that move all the funds of this wallet to `target`
+    ///
+    /// This can be used to rotate the keys of a wallet or to migrate it to a new descriptor: the
+    /// available UTXOs are swept to new external addresses of the `target` wallet, optionally
+    /// split into several transactions to limit the linkage between them (see
+    /// [`MigrationOptions`]). UTXOs whose value doesn't cover the cost of spending them at the
+    /// given fee rate are left out, as are frozen ones.
+    ///
+    /// The transactions signal RBF and are returned as PSBTs ready to be [signed](Self::sign).
+    /// They don't conflict with each other, so they can all be broadcast. The addresses of
+    /// `target` are only marked as used, and the labels only copied, once every transaction has
+    /// been built, so nothing is changed in `target` if this method fails.
+    ///
+    /// To migrate to a descriptor, wrap it in a [`Wallet`] as shown below: with an empty
+    /// database its addresses are used starting from index `0`. Use the database of the new
+    /// wallet to keep the labels with `preserve_labels`.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use bdk::*;
+    /// # use bdk::bitcoin::Network;
+    /// # use bdk::database::MemoryDatabase;
+    /// # use bdk::wallet::MigrationOptions;
+    /// # let descriptor = "wpkh(tpubD6NzVbkrYhZ4Xferm7Pz4VnjdcDPFyjVu5K4iZXQ4pVN8Cks4pHVowTBXBKRhX64pkRyJZJN5xAKj4UDNnLPb5p2sSKXhewoYx5GbTdUFWq/*)";
+    /// # let wallet = doctest_wallet!();
+    /// let new_wallet = Wallet::new("tr(tpubD6NzVbkrYhZ4Xferm7Pz4VnjdcDPFyjVu5K4iZXQ4pVN8Cks4pHVowTBXBKRhX64pkRyJZJN5xAKj4UDNnLPb5p2sSKXhewoYx5GbTdUFWq/86/*)", None, Network::Testnet, MemoryDatabase::new())?;
+    /// let options = MigrationOptions {
+    ///     fee_rate: FeeRate::from_sat_per_vb(2.0),
+    ///     transactions: 3,
+    ///     preserve_labels: true,
+    /// };
+    /// let migration = wallet.build_migration(&new_wallet, options)?;
+    /// // sign every PSBT with `wallet` and broadcast them ...
+    /// # Ok::<(), bdk::Error>(())
+    /// ```
+    pub fn build_migration<Dt: BatchDatabase>(
+        &self,
+        target: &Wallet<Dt>,
+        options: MigrationOptions,
+    ) -> Result<Vec<(psbt::PartiallySignedTransaction, TransactionDetails)>, Error> {
+        let mut by_script: BTreeMap<Script, Vec<WeightedUtxo>> = BTreeMap::new();
+        for (utxo, satisfaction_weight) in self.get_available_utxos()? {
+            if utxo.txout.value
+                > options
+                    .fee_rate
+                    .fee_wu(TXIN_BASE_WEIGHT + satisfaction_weight)
+            {
+                by_script
+                    .entry(utxo.txout.script_pubkey.clone())
+                    .or_default()
+                    .push(WeightedUtxo {
+                        satisfaction_weight,
+                        utxo: Utxo::Local(utxo),
+                    });
+            }
+        }
+
+        // Spread the UTXOs of every address over the transactions, largest first, always adding
+        // them to the transaction that has the lowest value so far
+        let mut by_script = by_script.into_values().collect::<Vec<_>>();
+        by_script.sort_by_key(|utxos| {
+            std::cmp::Reverse(utxos.iter().map(|u| u.utxo.txout().value).sum::<u64>())
+        });
+        let transactions = std::cmp::min(std::cmp::max(options.transactions, 1), by_script.len());
+        let mut groups: Vec<(u64, Vec<WeightedUtxo>)> = vec![(0, vec![]); transactions];
+        for utxos in by_script {
+            let (value, group) = groups.iter_mut().min_by_key(|(value, _)| *value).unwrap();
+            *value += utxos.iter().map(|u| u.utxo.txout().value).sum::<u64>();
+            group.extend(utxos);
+        }
+
+        // The addresses of the target are only peeked while building the transactions, and they
+        // are reserved, together with the labels, once all of them have been built: this way a
+        // failure doesn't leave any change in the target
+        let first_index = target
+            .database()
+            .get_last_index(KeychainKind::External)?
+            .map_or(0, |index| index + 1);
+        let mut migration = Vec::with_capacity(groups.len());
+        for (n, (_, utxos)) in groups.iter().enumerate() {
+            let drain_to = target
+                .get_address(AddressIndex::Peek(first_index + n as u32))?
+                .address
+                .script_pubkey();
+
+            let params = TxParams {
+                utxos: utxos.clone(),
+                manually_selected_only: true,
+                drain_to: Some(drain_to),
+                fee_policy: Some(FeePolicy::FeeRate(options.fee_rate)),
+                rbf: Some(tx_builder::RbfValue::Default),
+                ..Default::default()
+            };
+
+            migration.push(self.create_tx(DefaultCoinSelectionAlgorithm::default(), params)?);
+        }
+
+        for (_, utxos) in &groups {
+            let drain_to = target
+                .get_address(AddressIndex::New)?
+                .address
+                .script_pubkey();
+            if options.preserve_labels {
+                self.migrate_labels(utxos, target, &drain_to)?;
+            }
+        }
+
+        Ok(migration)
+    }
+
+    /// Build a transaction that sweeps the funds controlled by some private `keys` into this wallet
+    ///
+    /// This can be used to import the funds of paper wallets: every key is looked up as a
+    /// `pkh()`, `sh(wpkh())` and `wpkh()` script, and their UTXOs are fetched from `blockchain`
+    /// without storing anything in this wallet's database. All of them are then drained to a new
+    /// external address of this wallet, at the fee rate estimated by `blockchain` for
+    /// confirmation within 6 blocks.
+    ///
+    /// A master extended private key (with no origin nor derivation path) is not used directly:
+    /// the external and internal keychains of account #0 are scanned instead, following
+    /// [`Bip44`], [`Bip49`] and [`Bip84`]. Other accounts can be found with a
+    /// [`RecoveryScanner`](recovery::RecoveryScanner).
+    ///
+    /// The transaction signals RBF and is returned already signed and finalized, ready to be
+    /// broadcast.
+    ///
+    /// **Note**: keys encrypted with BIP-38 are not supported, they have to be decrypted into
+    /// WIF beforehand.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use bdk::*;
+    /// # use bdk::bitcoin::PrivateKey;
+    /// # use bdk::blockchain::Blockchain;
+    /// # use bdk::database::MemoryDatabase;
+    /// fn sweep<B: Blockchain>(wallet: &Wallet<MemoryDatabase>, blockchain: &B) -> Result<(), bdk::Error> {
+    ///     let key = PrivateKey::from_wif("cTc4vURSzdx6QE6KVynWGomDbLaA75dNALMNyfjh3p8DRRar84Um").unwrap();
+    ///     let (psbt, _) = wallet.build_sweep(vec![key], blockchain)?;
+    ///     blockchain.broadcast(&psbt.extract_tx())
+    /// }
+    /// ```
+    #[maybe_async]
+    pub fn build_sweep<K, B>(
+        &self,
+        keys: Vec<K>,
+        blockchain: &B,
+    ) -> Result<(psbt::PartiallySignedTransaction, TransactionDetails), Error>
+    where
+        K: IntoDescriptorKey<Legacy> + IntoDescriptorKey<Segwitv0> + Clone,
+        B: Blockchain,
+    {
+        let sweep_wallets = self.sweep_wallets(keys)?;
+        for wallet in &sweep_wallets {
+            maybe_await!(wallet.sync(blockchain, SyncOptions::default()))?;
+        }
+        let fee_rate = maybe_await!(blockchain.estimate_fee(6))?;
+
+        self.create_sweep(&sweep_wallets, fee_rate)
+    }
+
+    /// Sign a transaction with all the wallet's signers, in the order specified by every signer's
+    /// [`SignerOrdering`]
+    ///
+    /// The [`SignOptions`] can be used to tweak the behavior of the software signers, and the way
+    /// the transaction is finalized at the end. Note that it can't be guaranteed that *every*
+    /// signers will follow the options, but the "software signers" (WIF keys and `xprv`) defined
+    /// in this library will.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use std::str::FromStr;
+    /// # use bitcoin::*;
+    /// # use bdk::*;
+    /// # use bdk::database::*;
+    /// # let descriptor = "wpkh(tpubD6NzVbkrYhZ4Xferm7Pz4VnjdcDPFyjVu5K4iZXQ4pVN8Cks4pHVowTBXBKRhX64pkRyJZJN5xAKj4UDNnLPb5p2sSKXhewoYx5GbTdUFWq/*)";
+    /// # let wallet = doctest_wallet!();
+    /// # let to_address = Address::from_str("2N4eQYCbKUHCCTUjBJeHcJp9ok6J2GZsTDt").unwrap();
+    /// let (mut psbt, _) = {
+    ///     let mut builder = wallet.build_tx();
+    ///     builder.add_recipient(to_address.script_pubkey(), 50_000);
+    ///     builder.finish()?
+    /// };
+    /// let  finalized = wallet.sign(&mut psbt, SignOptions::default())?;
+    /// assert!(finalized, "we should have signed all the inputs");
+    /// # Ok::<(), bdk::Error>(())
+    pub fn sign(
+        &self,
+        psbt: &mut psbt::PartiallySignedTransaction,
+        sign_options: SignOptions,
+    ) -> Result<bool, Error> {
+        // This adds all the PSBT metadata for the inputs, which will help us later figure out how
+        // to derive our keys
+        self.update_psbt_with_descriptor(psbt)?;
+
+        // If we aren't allowed to use `witness_utxo`, ensure that every input (except p2tr and finalized ones)
+        // has the `non_witness_utxo`
+        if !sign_options.trust_witness_utxo
+            && psbt
+                .inputs
+                .iter()
+                .filter(|i| i.final_script_witness.is_none() && i.final_script_sig.is_none())
+                .filter(|i| i.tap_internal_key.is_none() && i.tap_merkle_root.is_none())
+                .any(|i| i.non_witness_utxo.is_none())
+        {
+            return Err(Error::Signer(signer::SignerError::MissingNonWitnessUtxo));
+        }
+
+        // If the user hasn't explicitly opted-in, refuse to sign the transaction unless every input
+        // is using `SIGHASH_ALL` or `SIGHASH_DEFAULT` for taproot
+        if !sign_options.allow_all_sighashes
+            && !psbt.inputs.iter().all(|i| {
+                i.sighash_type.is_none()
+                    || i.sighash_type == Some(EcdsaSighashType::All.into())
+                    || i.sighash_type == Some(SchnorrSighashType::All.into())
+                    || i.sighash_type == Some(SchnorrSighashType::Default.into())
+            })
+        {
+            return Err(Error::Signer(signer::SignerError::NonStandardSighash));
+        }
+
+        for signer in self
+            .signers
+            .signers()
+            .into_iter()
+            .chain(self.change_signers.signers())
+            .chain(
+                self.custom_keychains
+                    .values()
+                    .flat_map(|(_, signers)| signers.signers()),
+            )
+        {
+            signer.sign_transaction(psbt, &sign_options, &self.secp)?;
+        }
+
+        // attempt to finalize
+        if sign_options.try_finalize {
+            self.finalize_psbt(psbt, sign_options)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Return the spending policies for the wallet's descriptor
+    pub fn policies(&self, keychain: KeychainKind) -> Result<Option<Policy>, Error> {
+        match (keychain, self.change_descriptor.as_ref()) {
+            (KeychainKind::External, _) => Ok(self.descriptor.extract_policy(
+                &self.signers,
+                BuildSatisfaction::None,
+                &self.secp,
+            )?),
+            (KeychainKind::Internal, None) => Ok(None),
+            (KeychainKind::Internal, Some(desc)) => Ok(desc.extract_policy(
+                &self.change_signers,
+                BuildSatisfaction::None,
+                &self.secp,
+            )?),
+            (KeychainKind::Custom(id), _) => match self.custom_keychains.get(&id) {
+                Some((desc, signers)) => {
+                    Ok(desc.extract_policy(signers, BuildSatisfaction::None, &self.secp)?)
+                }
+                None => Ok(None),
+            },
+            (KeychainKind::Imported, _) => Ok(None),
+        }
+    }
+
+    /// Return the "public" version of the wallet's descriptor, meaning a new descriptor that has
+    /// the same structure but with every secret key removed
+    ///
+    /// This can be used to build a watch-only version of a wallet
+    pub fn public_descriptor(
+        &self,
+        keychain: KeychainKind,
+    ) -> Result<Option<ExtendedDescriptor>, Error> {
+        match (keychain, self.change_descriptor.as_ref()) {
+            (KeychainKind::External, _) => Ok(Some(self.descriptor.clone())),
+            (KeychainKind::Internal, None) => Ok(None),
+            (KeychainKind::Internal, Some(desc)) => Ok(Some(desc.clone())),
+            (KeychainKind::Custom(id), _) => {
+                Ok(self.custom_keychains.get(&id).map(|(desc, _)| desc.clone()))
+            }
+            (KeychainKind::Imported, _) => Ok(None),
+        }
+    }
+
+    /// Finalize a PSBT, i.e., for each input determine if sufficient data is available to pass
+    /// validation and construct the respective `scriptSig` or `scriptWitness`. Please refer to
+    /// [BIP174](https://github.com/bitcoin/bips/blob/master/bip-0174.mediawiki#Input_Finalizer)
+    /// for further information.
+    ///
+    /// Returns `true` if the PSBT could be finalized, and `false` otherwise.
+    ///
+    /// The [`SignOptions`] can be used to tweak the behavior of the finalizer.
+    pub fn finalize_psbt(
+        &self,
+        psbt: &mut psbt::PartiallySignedTransaction,
+        sign_options: SignOptions,
+    ) -> Result<bool, Error> {
+        let tx = &psbt.unsigned_tx;
+        let mut finished = true;
 
         for (n, input) in tx.input.iter().enumerate() {
             let psbt_input = &psbt
@@ -1240,7 +2434,8 @@ where
             // - If that fails, try to derive it by looking at the psbt input: the complete logic
             //   is in `src/descriptor/mod.rs`, but it will basically look at `bip32_derivation`,
             //   `redeem_script` and `witness_script` to determine the right derivation
-            // - If that also fails, it will try it on the internal descriptor, if present
+            // - If that also fails, it will try it on the internal descriptor, if present, and
+            //   then on the custom ones
             let desc = psbt
                 .get_utxo_for(n)
                 .map(|txout| self.get_descriptor_for_txout(&txout))
@@ -1257,6 +2452,11 @@ where
                     self.change_descriptor.as_ref().and_then(|desc| {
                         desc.derive_from_psbt_input(psbt_input, psbt.get_utxo_for(n), &self.secp)
                     })
+                })
+                .or_else(|| {
+                    self.custom_keychains.values().find_map(|(desc, _)| {
+                        desc.derive_from_psbt_input(psbt_input, psbt.get_utxo_for(n), &self.secp)
+                    })
                 });
 
             match desc {
@@ -1313,15 +2513,147 @@ where
                 self.change_descriptor.as_ref().unwrap(),
                 KeychainKind::Internal,
             ),
+            KeychainKind::Custom(id) if self.custom_keychains.contains_key(&id) => {
+                (&self.custom_keychains[&id].0, keychain)
+            }
             _ => (&self.descriptor, KeychainKind::External),
         }
     }
 
+    // Replace the placeholders added by `create_tx` with the real silent payment outputs
+    fn add_silent_payment_outputs(
+        &self,
+        psbt: &mut psbt::PartiallySignedTransaction,
+        recipients: &[(SilentPaymentAddress, u64)],
+    ) -> Result<(), Error> {
+        let mut input_keys = vec![];
+        for (psbt_input, txin) in psbt.inputs.iter().zip(psbt.unsigned_tx.input.iter()) {
+            let prevout = psbt_input
+                .witness_utxo
+                .clone()
+                .or_else(|| {
+                    psbt_input
+                        .non_witness_utxo
+                        .as_ref()
+                        .and_then(|tx| tx.output.get(txin.previous_output.vout as usize))
+                        .cloned()
+                })
+                .ok_or(Error::UnknownUtxo)?;
+            if !silent_payment::is_eligible_input(psbt_input, &prevout) {
+                continue;
+            }
+
+            let is_taproot = prevout.script_pubkey.is_v1_p2tr();
+            let secret_key = self
+                .get_silent_payment_input_key(psbt_input, is_taproot)
+                .ok_or(SilentPaymentError::MissingInputKey(txin.previous_output))?;
+            input_keys.push((secret_key, is_taproot));
+        }
+
+        let addresses = recipients
+            .iter()
+            .map(|(address, _)| *address)
+            .collect::<Vec<_>>();
+        let outpoints = psbt
+            .unsigned_tx
+            .input
+            .iter()
+            .map(|txin| txin.previous_output)
+            .collect::<Vec<_>>();
+        let scripts =
+            silent_payment::output_scripts(&addresses, &input_keys, &outpoints, &self.secp)?;
+
+        let mut replaced = HashSet::new();
+        for ((address, value), script) in recipients.iter().zip(scripts) {
+            let placeholder = address.placeholder_script();
+            let vout = (0..psbt.unsigned_tx.output.len())
+                .find(|vout| {
+                    let output = &psbt.unsigned_tx.output[*vout];
+                    !replaced.contains(vout)
+                        && output.script_pubkey == placeholder
+                        && output.value == *value
+                })
+                .ok_or_else(|| Error::Generic("Missing silent payment output".into()))?;
+            replaced.insert(vout);
+            psbt.unsigned_tx.output[vout].script_pubkey = script;
+        }
+
+        Ok(())
+    }
+
+    // Look for the private key that signs for `psbt_input` in the wallet's signers. For taproot
+    // inputs this is the tweaked internal key.
+    fn get_silent_payment_input_key(
+        &self,
+        psbt_input: &psbt::Input,
+        is_taproot: bool,
+    ) -> Option<bitcoin::secp256k1::SecretKey> {
+        let matches_input = |secret_key: &bitcoin::secp256k1::SecretKey| {
+            let public_key = secret_key.public_key(&self.secp);
+            match is_taproot {
+                true => psbt_input.tap_internal_key == Some(public_key.x_only_public_key().0),
+                false => psbt_input.bip32_derivation.contains_key(&public_key),
+            }
+        };
+
+        let secret_key = self
+            .signers
+            .signers()
+            .into_iter()
+            .chain(self.change_signers.signers())
+            .chain(
+                self.custom_keychains
+                    .values()
+                    .flat_map(|(_, signers)| signers.signers()),
+            )
+            .filter_map(|signer| signer.descriptor_secret_key())
+            .find_map(|key| match key {
+                DescriptorSecretKey::Single(single) => match is_taproot || single.key.compressed {
+                    true => Some(single.key.inner).filter(matches_input),
+                    false => None,
+                },
+                DescriptorSecretKey::XPrv(xkey) => {
+                    let tap_key_origins = psbt_input
+                        .tap_key_origins
+                        .iter()
+                        .filter(|(pk, _)| Some(**pk) == psbt_input.tap_internal_key)
+                        .map(|(_, (_, keysource))| keysource);
+                    psbt_input
+                        .bip32_derivation
+                        .values()
+                        .chain(tap_key_origins)
+                        .filter(|keysource| xkey.matches(keysource, &self.secp).is_some())
+                        .filter_map(|(_, full_path)| {
+                            let path = match &xkey.origin {
+                                Some((_, origin_path)) => full_path
+                                    .into_iter()
+                                    .skip(origin_path.len())
+                                    .cloned()
+                                    .collect::<Vec<_>>()
+                                    .into(),
+                                None => full_path.clone(),
+                            };
+                            xkey.xkey.derive_priv(&self.secp, &path).ok()
+                        })
+                        .map(|derived| derived.private_key)
+                        .find(matches_input)
+                }
+            })?;
+
+        Some(silent_payment::input_secret_key(
+            secret_key,
+            psbt_input.tap_merkle_root,
+            is_taproot,
+            &self.secp,
+        ))
+    }
+
     fn get_descriptor_for_txout(&self, txout: &TxOut) -> Result<Option<DerivedDescriptor>, Error> {
         Ok(self
             .database
             .borrow()
             .get_path_from_script_pubkey(&txout.script_pubkey)?
+            .filter(|(keychain, _)| self.has_keychain(*keychain))
             .map(|(keychain, child)| (self.get_descriptor_for_keychain(keychain), child))
             .map(|(desc, child)| desc.at_derivation_index(child)))
     }
@@ -1402,13 +2734,123 @@ where
         Ok(())
     }
 
-    fn get_available_utxos(&self) -> Result<Vec<(LocalUtxo, usize)>, Error> {
-        Ok(self
-            .list_unspent()?
-            .into_iter()
-            .map(|utxo| {
-                let keychain = utxo.keychain;
-                (
+    /// Returns the total weight and fee of the unconfirmed transactions that have to be mined
+    /// before a transaction spending one of the outputs of `txid`, including `txid` itself.
+    ///
+    /// Returns `None` if `txid` is confirmed or not in the database.
+    fn get_unconfirmed_ancestors(&self, txid: Txid) -> Result<Option<PackageFee>, Error> {
+        let database = self.database.borrow();
+        let mut package: Option<PackageFee> = None;
+        let mut visited = HashSet::new();
+        let mut to_visit = vec![txid];
+        while let Some(txid) = to_visit.pop() {
+            if !visited.insert(txid) {
+                continue;
+            }
+            let details = match database.get_tx(&txid, true)? {
+                Some(details) if details.confirmation_time.is_none() => details,
+                _ => continue,
+            };
+            let tx = match details.transaction {
+                Some(tx) => tx,
+                None => continue,
+            };
+
+            let package = package.get_or_insert_with(PackageFee::default);
+            package.weight += tx.weight();
+            package.fee += details.fee.unwrap_or(0);
+            to_visit.extend(tx.input.iter().map(|txin| txin.previous_output.txid));
+        }
+
+        Ok(package)
+    }
+
+    /// Applies the [`AncestorFeePolicy`] to the preselected utxos.
+    ///
+    /// When bumping, the fee missing from every unconfirmed package is converted into extra
+    /// satisfaction weight so that coin selection can take it into account.
+    fn apply_ancestor_fee_policy(
+        &self,
+        policy: AncestorFeePolicy,
+        fee_rate: FeeRate,
+        mut required_utxos: Vec<WeightedUtxo>,
+        mut optional_utxos: Vec<WeightedUtxo>,
+    ) -> Result<(Vec<WeightedUtxo>, Vec<WeightedUtxo>), Error> {
+        let sat_per_wu = fee_rate.as_sat_per_vb() / 4.0;
+        if policy == AncestorFeePolicy::Ignore || sat_per_wu <= 0.0 {
+            return Ok((required_utxos, optional_utxos));
+        }
+
+        let mut packages = HashMap::new();
+        let mut missing_weight = |txid: Txid| -> Result<usize, Error> {
+            if let Some(weight) = packages.get(&txid) {
+                return Ok(*weight);
+            }
+            let weight = match self.get_unconfirmed_ancestors(txid)? {
+                Some(package) => package
+                    .weight
+                    .saturating_sub((package.fee as f32 / sat_per_wu) as usize),
+                None => 0,
+            };
+            packages.insert(txid, weight);
+            Ok(weight)
+        };
+
+        match policy {
+            AncestorFeePolicy::Ignore => {}
+            AncestorFeePolicy::Bump => {
+                // the required utxos are all going to be spent, so every package is paid once
+                let mut paid = HashSet::new();
+                for weighted_utxo in &mut required_utxos {
+                    if let Utxo::Local(utxo) = &weighted_utxo.utxo {
+                        if paid.insert(utxo.outpoint.txid) {
+                            weighted_utxo.satisfaction_weight +=
+                                missing_weight(utxo.outpoint.txid)?;
+                        }
+                    }
+                }
+                for weighted_utxo in &mut optional_utxos {
+                    if let Utxo::Local(utxo) = &weighted_utxo.utxo {
+                        if !paid.contains(&utxo.outpoint.txid) {
+                            weighted_utxo.satisfaction_weight +=
+                                missing_weight(utxo.outpoint.txid)?;
+                        }
+                    }
+                }
+            }
+            AncestorFeePolicy::Avoid => {
+                let mut low_fee = HashSet::new();
+                for weighted_utxo in &optional_utxos {
+                    if let Utxo::Local(utxo) = &weighted_utxo.utxo {
+                        if missing_weight(utxo.outpoint.txid)? > 0 {
+                            low_fee.insert(utxo.outpoint);
+                        }
+                    }
+                }
+                optional_utxos.retain(|u| !low_fee.contains(&u.utxo.outpoint()));
+            }
+        }
+
+        Ok((required_utxos, optional_utxos))
+    }
+
+    fn get_available_utxos(&self) -> Result<Vec<(LocalUtxo, usize)>, Error> {
+        let frozen = self
+            .database
+            .borrow()
+            .iter_frozen()?
+            .into_iter()
+            .collect::<HashSet<_>>();
+
+        Ok(self
+            .list_unspent()?
+            .into_iter()
+            .filter(|utxo| !frozen.contains(&utxo.outpoint))
+            // The UTXOs of keychains that haven't been added to the wallet can't be spent
+            .filter(|utxo| self.has_keychain(utxo.keychain))
+            .map(|utxo| {
+                let keychain = utxo.keychain;
+                (
                     utxo,
                     self.get_descriptor_for_keychain(keychain)
                         .max_satisfaction_weight()
@@ -1418,6 +2860,286 @@ where
             .collect())
     }
 
+    // Get the number of confirmations of every UTXO at `height`, unless some of them are foreign,
+    // unconfirmed or too old to be used as a relative timelock
+    fn get_confirmations(&self, utxos: &[Utxo], height: u32) -> Result<Option<Vec<u32>>, Error> {
+        let database = self.database.borrow();
+        let mut confirmations = Vec::with_capacity(utxos.len());
+        for utxo in utxos {
+            let confirmation_height = match utxo {
+                Utxo::Local(local) => database
+                    .get_tx(&local.outpoint.txid, false)?
+                    .and_then(|details| details.confirmation_time)
+                    .map(|time| time.height),
+                Utxo::Foreign { .. } => None,
+            };
+            match confirmation_height {
+                Some(confirmation_height) if confirmation_height <= height => {
+                    let count = height - confirmation_height + 1;
+                    if count > 0xFFFF {
+                        return Ok(None);
+                    }
+                    confirmations.push(count);
+                }
+                _ => return Ok(None),
+            }
+        }
+
+        Ok(Some(confirmations))
+    }
+
+    // Sum up the given value of every UTXO in its balance category
+    fn compute_balance(&self, utxos: Vec<(LocalUtxo, u64)>) -> Result<Balance, Error> {
+        let mut immature = 0;
+        let mut trusted_pending = 0;
+        let mut untrusted_pending = 0;
+        let mut confirmed = 0;
+        let database = self.database.borrow();
+        let last_sync_height = match database
+            .get_sync_time()?
+            .map(|sync_time| sync_time.block_time.height)
+        {
+            Some(height) => height,
+            // None means database was never synced
+            None => return Ok(Balance::default()),
+        };
+        for (u, value) in utxos {
+            // Unwrap used since utxo set is created from database
+            let tx = database
+                .get_tx(&u.outpoint.txid, true)?
+                .expect("Transaction not found in database");
+            if let Some(tx_conf_time) = &tx.confirmation_time {
+                if tx.transaction.expect("No transaction").is_coin_base()
+                    && (last_sync_height - tx_conf_time.height) < COINBASE_MATURITY
+                {
+                    immature += value;
+                } else {
+                    confirmed += value;
+                }
+            } else if u.keychain == KeychainKind::Internal {
+                trusted_pending += value;
+            } else {
+                untrusted_pending += value;
+            }
+        }
+
+        Ok(Balance {
+            immature,
+            trusted_pending,
+            untrusted_pending,
+            confirmed,
+        })
+    }
+
+    // Label `script` in `target` with the labels of the given UTXOs and of their addresses
+    fn migrate_labels<Dt: BatchDatabase>(
+        &self,
+        utxos: &[WeightedUtxo],
+        target: &Wallet<Dt>,
+        script: &Script,
+    ) -> Result<(), Error> {
+        let database = self.database.borrow();
+        let mut labels: Vec<String> = vec![];
+        for utxo in utxos {
+            let keys = [
+                LabelKey::Address(utxo.utxo.txout().script_pubkey.clone()),
+                LabelKey::Utxo(utxo.utxo.outpoint()),
+            ];
+            for key in &keys {
+                if let Some(label) = database.get_label(key)? {
+                    if !labels.contains(&label) {
+                        labels.push(label);
+                    }
+                }
+            }
+        }
+
+        if !labels.is_empty() {
+            target.set_label(LabelKey::Address(script.clone()), &labels.join(", "))?;
+        }
+
+        Ok(())
+    }
+
+    /// Create a temporary wallet for every standard single-key script type of every key
+    ///
+    /// Master extended keys are derived following BIP44, BIP49 and BIP84 for account #0,
+    /// including their change keychain, while any other key is used as it is.
+    fn sweep_wallets<K>(&self, keys: Vec<K>) -> Result<Vec<Wallet<MemoryDatabase>>, Error>
+    where
+        K: IntoDescriptorKey<Legacy> + IntoDescriptorKey<Segwitv0> + Clone,
+    {
+        let mut wallets = vec![];
+        for key in keys {
+            match IntoDescriptorKey::<Legacy>::into_descriptor_key(key.clone())? {
+                DescriptorKey::Secret(DescriptorSecretKey::XPrv(xprv), _, _)
+                    if xprv.xkey.depth == 0
+                        && xprv.origin.is_none()
+                        && xprv.derivation_path.is_empty() =>
+                {
+                    let xkey = xprv.xkey;
+                    wallets.push(Wallet::new(
+                        Bip44(xkey, KeychainKind::External),
+                        Some(Bip44(xkey, KeychainKind::Internal)),
+                        self.network,
+                        MemoryDatabase::new(),
+                    )?);
+                    wallets.push(Wallet::new(
+                        Bip49(xkey, KeychainKind::External),
+                        Some(Bip49(xkey, KeychainKind::Internal)),
+                        self.network,
+                        MemoryDatabase::new(),
+                    )?);
+                    wallets.push(Wallet::new(
+                        Bip84(xkey, KeychainKind::External),
+                        Some(Bip84(xkey, KeychainKind::Internal)),
+                        self.network,
+                        MemoryDatabase::new(),
+                    )?);
+                }
+                _ => {
+                    wallets.push(Wallet::new(
+                        P2Pkh(key.clone()),
+                        None,
+                        self.network,
+                        MemoryDatabase::new(),
+                    )?);
+                    wallets.push(Wallet::new(
+                        P2Wpkh_P2Sh(key.clone()),
+                        None,
+                        self.network,
+                        MemoryDatabase::new(),
+                    )?);
+                    wallets.push(Wallet::new(
+                        P2Wpkh(key),
+                        None,
+                        self.network,
+                        MemoryDatabase::new(),
+                    )?);
+                }
+            }
+        }
+
+        Ok(wallets)
+    }
+
+    /// Drain the UTXOs of the synced `sweep_wallets` to a new external address and sign the
+    /// transaction with their keys
+    fn create_sweep(
+        &self,
+        sweep_wallets: &[Wallet<MemoryDatabase>],
+        fee_rate: FeeRate,
+    ) -> Result<(psbt::PartiallySignedTransaction, TransactionDetails), Error> {
+        let mut utxos = vec![];
+        for wallet in sweep_wallets {
+            let satisfaction_weight = wallet
+                .get_descriptor_for_keychain(KeychainKind::External)
+                .max_satisfaction_weight()?;
+            for utxo in wallet.list_unspent()? {
+                let psbt_input = wallet.get_psbt_input(utxo.clone(), None, false)?;
+                utxos.push(WeightedUtxo {
+                    satisfaction_weight,
+                    utxo: Utxo::Foreign {
+                        outpoint: utxo.outpoint,
+                        psbt_input: Box::new(psbt_input),
+                    },
+                });
+            }
+        }
+        if utxos.is_empty() {
+            return Err(Error::NoUtxosSelected);
+        }
+
+        let params = TxParams {
+            utxos,
+            manually_selected_only: true,
+            drain_to: Some(self.get_address(AddressIndex::New)?.address.script_pubkey()),
+            fee_policy: Some(FeePolicy::FeeRate(fee_rate)),
+            rbf: Some(tx_builder::RbfValue::Default),
+            ..Default::default()
+        };
+        let (mut psbt, details) =
+            self.create_tx(DefaultCoinSelectionAlgorithm::default(), params)?;
+
+        for wallet in sweep_wallets {
+            // The signers of single keys sign every input they are given, even the ones of the
+            // other script types, so only the inputs each wallet manages to finalize are kept
+            let mut signed = psbt.clone();
+            wallet.sign(&mut signed, SignOptions::default())?;
+            for (input, signed_input) in psbt.inputs.iter_mut().zip(signed.inputs) {
+                if signed_input.final_script_sig.is_some()
+                    || signed_input.final_script_witness.is_some()
+                {
+                    *input = signed_input;
+                }
+            }
+        }
+
+        Ok((psbt, details))
+    }
+
+    /// Subtract `fee` from the fee-paying recipients of `tx`, returning the amount subtracted
+    /// from the outputs that belong to this wallet
+    fn subtract_fee_from_recipients(
+        &self,
+        tx: &mut Transaction,
+        params: &TxParams,
+        fee: u64,
+    ) -> Result<u64, Error> {
+        let values = params
+            .fee_paying_recipients
+            .iter()
+            .map(|index| tx.output[*index].value)
+            .collect::<Vec<_>>();
+        let shares = params.fee_split.split(fee, &values);
+
+        let mut subtracted_from_mine = 0;
+        for (index, share) in params.fee_paying_recipients.iter().zip(shares) {
+            let output = &mut tx.output[*index];
+            if share >= output.value {
+                return Err(Error::OutputBelowDustLimit(*index));
+            }
+            output.value -= share;
+            if !params.allow_dust
+                && output.value.is_dust(&output.script_pubkey)
+                && !output.script_pubkey.is_provably_unspendable()
+            {
+                return Err(Error::OutputBelowDustLimit(*index));
+            }
+
+            if self.is_mine(&output.script_pubkey)? {
+                subtracted_from_mine += share;
+            }
+        }
+
+        Ok(subtracted_from_mine)
+    }
+
+    /// Extend the `unspendable` set with the available utxos rejected by any of the `filters`
+    fn apply_utxo_filters(
+        &self,
+        unspendable: &HashSet<OutPoint>,
+        filters: &[UtxoFilter],
+    ) -> Result<HashSet<OutPoint>, Error> {
+        let mut unspendable = unspendable.clone();
+        if filters.is_empty() {
+            return Ok(unspendable);
+        }
+
+        let database = self.database.borrow();
+        for (utxo, _) in self.get_available_utxos()? {
+            let accepted = match database.get_path_from_script_pubkey(&utxo.txout.script_pubkey)? {
+                Some((_, child)) => filters.iter().all(|filter| (filter.0)(&utxo, child)),
+                None => false,
+            };
+            if !accepted {
+                unspendable.insert(utxo.outpoint);
+            }
+        }
+
+        Ok(unspendable)
+    }
+
     /// Given the options returns the list of utxos that must be used to form the
     /// transaction and any further that may be used if needed.
     #[allow(clippy::type_complexity)]
@@ -1525,6 +3247,9 @@ where
             if let Some(change_descriptor) = &self.change_descriptor {
                 all_xpubs.extend(change_descriptor.get_extended_keys()?);
             }
+            for (descriptor, _) in self.custom_keychains.values() {
+                all_xpubs.extend(descriptor.get_extended_keys()?);
+            }
 
             for xpub in all_xpubs {
                 let origin = match xpub.origin {
@@ -1607,6 +3332,9 @@ where
             .borrow()
             .get_path_from_script_pubkey(&utxo.txout.script_pubkey)?
             .ok_or(Error::UnknownUtxo)?;
+        if !self.has_keychain(keychain) {
+            return Err(Error::UnknownKeychain(keychain));
+        }
 
         let mut psbt_input = psbt::Input {
             sighash_type,
@@ -1658,6 +3386,7 @@ where
                 .database
                 .borrow()
                 .get_path_from_script_pubkey(&out.script_pubkey)?
+                .filter(|(keychain, _)| self.has_keychain(*keychain))
             {
                 debug!(
                     "Found descriptor for input #{} {:?}/{}",
@@ -1713,7 +3442,11 @@ where
         // end up with an infinite loop
         let has_wildcard = self.descriptor.has_wildcard()
             && (self.change_descriptor.is_none()
-                || self.change_descriptor.as_ref().unwrap().has_wildcard());
+                || self.change_descriptor.as_ref().unwrap().has_wildcard())
+            && self
+                .custom_keychains
+                .values()
+                .all(|(descriptor, _)| descriptor.has_wildcard());
 
         // Restrict max rounds in case of faulty "missing cache" implementation by blockchain
         let max_rounds = if has_wildcard { 100 } else { 1 };
@@ -1914,79 +3647,294 @@ pub(crate) mod test {
     }
 
     #[test]
-    fn test_cache_addresses_fixed() {
-        let db = MemoryDatabase::new();
-        let wallet = Wallet::new(
-            "wpkh(L5EZftvrYaSudiozVRzTqLcHLNDoVn7H5HSfM9BAN6tMJX8oTWz6)",
-            None,
-            Network::Testnet,
-            db,
-        )
-        .unwrap();
-
-        assert_eq!(
-            wallet.get_address(New).unwrap().to_string(),
-            "tb1qj08ys4ct2hzzc2hcz6h2hgrvlmsjynaw43s835"
+    fn test_get_effective_balance() {
+        let (wallet, descriptors, _) = get_funded_wallet(get_test_wpkh());
+        let small_txid = crate::populate_test_db!(
+            wallet.database.borrow_mut(),
+            testutils! (@tx ( (@external descriptors, 0) => 300 ) (@confirmations 1)),
+            Some(100),
         );
+        crate::populate_test_db!(
+            wallet.database.borrow_mut(),
+            testutils! (@tx ( (@external descriptors, 0) => 10_000 ) (@confirmations 0)),
+            Some(100),
+        );
+
+        // spending a wpkh input costs 68 vbytes
+        let effective = wallet
+            .get_effective_balance(FeeRate::from_sat_per_vb(5.0))
+            .unwrap();
+        assert_eq!(effective.balance.confirmed, 50_000 - 340);
+        assert_eq!(effective.balance.untrusted_pending, 10_000 - 340);
+        assert_eq!(effective.uneconomical.len(), 1);
         assert_eq!(
-            wallet.get_address(New).unwrap().to_string(),
-            "tb1qj08ys4ct2hzzc2hcz6h2hgrvlmsjynaw43s835"
+            effective.uneconomical[0].outpoint,
+            OutPoint::new(small_txid, 0)
         );
 
-        assert!(wallet
-            .database
-            .borrow_mut()
-            .get_script_pubkey_from_path(KeychainKind::External, 0)
-            .unwrap()
-            .is_some());
-        assert!(wallet
-            .database
-            .borrow_mut()
-            .get_script_pubkey_from_path(KeychainKind::Internal, 0)
-            .unwrap()
-            .is_none());
+        let effective = wallet
+            .get_effective_balance(FeeRate::from_sat_per_vb(1.0))
+            .unwrap();
+        assert_eq!(effective.balance.confirmed, 50_000 + 300 - 2 * 68);
+        assert!(effective.uneconomical.is_empty());
+        assert_eq!(wallet.get_balance().unwrap().confirmed, 50_300);
     }
 
     #[test]
-    fn test_cache_addresses() {
-        let db = MemoryDatabase::new();
-        let wallet = Wallet::new("wpkh(tpubEBr4i6yk5nf5DAaJpsi9N2pPYBeJ7fZ5Z9rmN4977iYLCGco1VyjB9tvvuvYtfZzjD5A8igzgw3HeWeeKFmanHYqksqZXYXGsw5zjnj7KM9/*)", None, Network::Testnet, db).unwrap();
+    fn test_custom_keychain() {
+        let (mut wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        wallet
+            .add_keychain(1, "sh(wpkh(tprv8ZgxMBicQKsPd3EupYiPRhaMooHKUHJxNsTfYuScep13go8QFfHdtkG9nRkFGb7busX4isf6X9dURGCoKgitaApQ6MupRhZMcELAxTBRJgS/1/*))")
+            .unwrap();
+        assert_eq!(
+            wallet.keychains(),
+            vec![KeychainKind::External, KeychainKind::Custom(1)]
+        );
+        assert!(matches!(
+            wallet.get_keychain_address(KeychainKind::Custom(2), New),
+            Err(Error::UnknownKeychain(KeychainKind::Custom(2)))
+        ));
+
+        let addr = wallet
+            .get_keychain_address(KeychainKind::Custom(1), New)
+            .unwrap();
+        assert_eq!(addr.index, 0);
+        assert_eq!(addr.keychain, KeychainKind::Custom(1));
+        assert!(wallet.is_mine(&addr.script_pubkey()).unwrap());
 
+        let txid = crate::populate_test_db!(
+            wallet.database.borrow_mut(),
+            testutils! (@tx ( (@addr addr.address.clone()) => 25_000 ) (@confirmations 1)),
+            Some(100),
+        );
+        let mut utxo = wallet.get_utxo(OutPoint::new(txid, 0)).unwrap().unwrap();
+        utxo.keychain = KeychainKind::Custom(1);
+        wallet.database.borrow_mut().set_utxo(&utxo).unwrap();
+
+        assert_eq!(wallet.get_balance().unwrap().confirmed, 75_000);
         assert_eq!(
-            wallet.get_address(New).unwrap().to_string(),
-            "tb1q6yn66vajcctph75pvylgkksgpp6nq04ppwct9a"
+            wallet
+                .get_balance_for_keychain(KeychainKind::Custom(1))
+                .unwrap()
+                .confirmed,
+            25_000
         );
         assert_eq!(
-            wallet.get_address(New).unwrap().to_string(),
-            "tb1q4er7kxx6sssz3q7qp7zsqsdx4erceahhax77d7"
+            wallet
+                .list_unspent_for_keychain(KeychainKind::External)
+                .unwrap()
+                .len(),
+            1
         );
 
-        assert!(wallet
-            .database
-            .borrow_mut()
-            .get_script_pubkey_from_path(KeychainKind::External, CACHE_ADDR_BATCH_SIZE - 1)
-            .unwrap()
-            .is_some());
-        assert!(wallet
-            .database
-            .borrow_mut()
-            .get_script_pubkey_from_path(KeychainKind::External, CACHE_ADDR_BATCH_SIZE)
-            .unwrap()
-            .is_none());
+        // spending both keychains requires signing with both of them
+        let to_addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let mut builder = wallet.build_tx();
+        builder.add_recipient(to_addr.script_pubkey(), 60_000);
+        let (mut psbt, _) = builder.finish().unwrap();
+        assert_eq!(psbt.inputs.len(), 2);
+        let custom_input = psbt
+            .unsigned_tx
+            .input
+            .iter()
+            .position(|txin| txin.previous_output.txid == txid)
+            .unwrap();
+        assert!(psbt.inputs[custom_input].redeem_script.is_some());
+
+        let finalized = wallet.sign(&mut psbt, SignOptions::default()).unwrap();
+        assert!(finalized);
+
+        // the checksum of the keychain is stored in the database
+        assert!(matches!(
+            wallet.add_keychain(1, "wpkh(tprv8ZgxMBicQKsPd3EupYiPRhaMooHKUHJxNsTfYuScep13go8QFfHdtkG9nRkFGb7busX4isf6X9dURGCoKgitaApQ6MupRhZMcELAxTBRJgS/1/*)"),
+            Err(Error::ChecksumMismatch)
+        ));
     }
 
     #[test]
-    fn test_cache_addresses_refill() {
-        let db = MemoryDatabase::new();
-        let wallet = Wallet::new("wpkh(tpubEBr4i6yk5nf5DAaJpsi9N2pPYBeJ7fZ5Z9rmN4977iYLCGco1VyjB9tvvuvYtfZzjD5A8igzgw3HeWeeKFmanHYqksqZXYXGsw5zjnj7KM9/*)", None, Network::Testnet, db).unwrap();
-
+    fn test_import_script() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let (other, _, _) = get_funded_wallet(get_test_tr_single_sig());
+        let addr = other.get_address(New).unwrap();
+        assert!(!wallet.is_mine(&addr.script_pubkey()).unwrap());
+
+        wallet.import_address(&addr).unwrap();
+        // importing the same script twice is a no-op
+        wallet.import_script(addr.script_pubkey()).unwrap();
+        // scripts that are already tracked by a descriptor are not imported
+        wallet
+            .import_script(wallet.get_address(Peek(0)).unwrap().script_pubkey())
+            .unwrap();
+        assert!(wallet.is_mine(&addr.script_pubkey()).unwrap());
         assert_eq!(
-            wallet.get_address(New).unwrap().to_string(),
-            "tb1q6yn66vajcctph75pvylgkksgpp6nq04ppwct9a"
+            wallet.list_imported_scripts().unwrap(),
+            vec![addr.script_pubkey()]
         );
-        assert!(wallet
-            .database
-            .borrow_mut()
+
+        let mainnet_addr = Address::from_str("1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2").unwrap();
+        assert!(matches!(
+            wallet.import_address(&mainnet_addr),
+            Err(Error::InvalidNetwork {
+                requested: Network::Regtest,
+                found: Network::Bitcoin
+            })
+        ));
+
+        let txid = crate::populate_test_db!(
+            wallet.database.borrow_mut(),
+            testutils! (@tx ( (@addr addr.address.clone()) => 25_000 ) (@confirmations 1)),
+            Some(100),
+        );
+        let mut utxo = wallet.get_utxo(OutPoint::new(txid, 0)).unwrap().unwrap();
+        utxo.keychain = KeychainKind::Imported;
+        wallet.database.borrow_mut().set_utxo(&utxo).unwrap();
+
+        assert_eq!(wallet.get_balance().unwrap().confirmed, 75_000);
+        assert_eq!(
+            wallet
+                .get_balance_for_keychain(KeychainKind::Imported)
+                .unwrap()
+                .confirmed,
+            25_000
+        );
+
+        // imported utxos are never selected by the wallet
+        let to_addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let mut builder = wallet.build_tx();
+        builder.add_recipient(to_addr.script_pubkey(), 60_000);
+        assert!(matches!(
+            builder.finish(),
+            Err(Error::InsufficientFunds { .. })
+        ));
+
+        let mut builder = wallet.build_tx();
+        assert!(matches!(
+            builder.add_utxo(utxo.outpoint),
+            Err(Error::UnknownKeychain(KeychainKind::Imported))
+        ));
+
+        // but they can still be spent as foreign utxos
+        let satisfaction_weight = other
+            .get_descriptor_for_keychain(KeychainKind::External)
+            .max_satisfaction_weight()
+            .unwrap();
+        let psbt_input = psbt::Input {
+            witness_utxo: Some(utxo.txout.clone()),
+            ..Default::default()
+        };
+        let mut builder = wallet.build_tx();
+        builder
+            .add_recipient(to_addr.script_pubkey(), 60_000)
+            .only_witness_utxo()
+            .add_foreign_utxo(utxo.outpoint, psbt_input, satisfaction_weight)
+            .unwrap();
+        let (psbt, _) = builder.finish().unwrap();
+        assert!(psbt
+            .unsigned_tx
+            .input
+            .iter()
+            .any(|txin| txin.previous_output == utxo.outpoint));
+    }
+
+    #[test]
+    fn test_import_descriptor() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let (other, _, _) = get_funded_wallet(get_test_tr_single_sig());
+        let addr = other.get_address(New).unwrap();
+
+        wallet
+            .import_descriptor(&format!("addr({})", addr))
+            .unwrap();
+        wallet.import_descriptor("raw(6a0474657374)").unwrap();
+        assert_eq!(wallet.list_imported_scripts().unwrap().len(), 2);
+        assert!(wallet
+            .is_mine(&Script::from_str("6a0474657374").unwrap())
+            .unwrap());
+
+        assert!(matches!(
+            wallet.import_descriptor(get_test_wpkh()),
+            Err(Error::Generic(_))
+        ));
+        assert!(matches!(
+            wallet.import_descriptor("raw(6a0474657374)#00000000"),
+            Err(_)
+        ));
+    }
+
+    #[test]
+    fn test_cache_addresses_fixed() {
+        let db = MemoryDatabase::new();
+        let wallet = Wallet::new(
+            "wpkh(L5EZftvrYaSudiozVRzTqLcHLNDoVn7H5HSfM9BAN6tMJX8oTWz6)",
+            None,
+            Network::Testnet,
+            db,
+        )
+        .unwrap();
+
+        assert_eq!(
+            wallet.get_address(New).unwrap().to_string(),
+            "tb1qj08ys4ct2hzzc2hcz6h2hgrvlmsjynaw43s835"
+        );
+        assert_eq!(
+            wallet.get_address(New).unwrap().to_string(),
+            "tb1qj08ys4ct2hzzc2hcz6h2hgrvlmsjynaw43s835"
+        );
+
+        assert!(wallet
+            .database
+            .borrow_mut()
+            .get_script_pubkey_from_path(KeychainKind::External, 0)
+            .unwrap()
+            .is_some());
+        assert!(wallet
+            .database
+            .borrow_mut()
+            .get_script_pubkey_from_path(KeychainKind::Internal, 0)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_cache_addresses() {
+        let db = MemoryDatabase::new();
+        let wallet = Wallet::new("wpkh(tpubEBr4i6yk5nf5DAaJpsi9N2pPYBeJ7fZ5Z9rmN4977iYLCGco1VyjB9tvvuvYtfZzjD5A8igzgw3HeWeeKFmanHYqksqZXYXGsw5zjnj7KM9/*)", None, Network::Testnet, db).unwrap();
+
+        assert_eq!(
+            wallet.get_address(New).unwrap().to_string(),
+            "tb1q6yn66vajcctph75pvylgkksgpp6nq04ppwct9a"
+        );
+        assert_eq!(
+            wallet.get_address(New).unwrap().to_string(),
+            "tb1q4er7kxx6sssz3q7qp7zsqsdx4erceahhax77d7"
+        );
+
+        assert!(wallet
+            .database
+            .borrow_mut()
+            .get_script_pubkey_from_path(KeychainKind::External, CACHE_ADDR_BATCH_SIZE - 1)
+            .unwrap()
+            .is_some());
+        assert!(wallet
+            .database
+            .borrow_mut()
+            .get_script_pubkey_from_path(KeychainKind::External, CACHE_ADDR_BATCH_SIZE)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_cache_addresses_refill() {
+        let db = MemoryDatabase::new();
+        let wallet = Wallet::new("wpkh(tpubEBr4i6yk5nf5DAaJpsi9N2pPYBeJ7fZ5Z9rmN4977iYLCGco1VyjB9tvvuvYtfZzjD5A8igzgw3HeWeeKFmanHYqksqZXYXGsw5zjnj7KM9/*)", None, Network::Testnet, db).unwrap();
+
+        assert_eq!(
+            wallet.get_address(New).unwrap().to_string(),
+            "tb1q6yn66vajcctph75pvylgkksgpp6nq04ppwct9a"
+        );
+        assert!(wallet
+            .database
+            .borrow_mut()
             .get_script_pubkey_from_path(KeychainKind::External, CACHE_ADDR_BATCH_SIZE - 1)
             .unwrap()
             .is_some());
@@ -2252,6 +4200,87 @@ pub(crate) mod test {
         assert_eq!(psbt.unsigned_tx.lock_time, PackedLockTime(100_000));
     }
 
+    #[test]
+    fn test_create_tx_taproot_anti_fee_sniping() {
+        let (wallet, _, _) = get_funded_wallet(get_test_tr_single_sig());
+        let addr = wallet.get_address(New).unwrap();
+
+        let mut used_sequence = false;
+        let mut used_locktime = false;
+        for _ in 0..32 {
+            let mut builder = wallet.build_tx();
+            builder
+                .add_recipient(addr.script_pubkey(), 25_000)
+                .enable_rbf();
+            let (psbt, _) = builder.finish().unwrap();
+            let tx = psbt.unsigned_tx;
+
+            if tx.lock_time == PackedLockTime::ZERO {
+                // the input has a single confirmation at the sync height of 100
+                assert_eq!(tx.version, 2);
+                assert_eq!(tx.input[0].sequence, Sequence(1));
+                used_sequence = true;
+            } else {
+                assert!(tx.lock_time.0 <= 100 && tx.lock_time.0 > 0);
+                assert_eq!(tx.input[0].sequence, Sequence::ENABLE_RBF_NO_LOCKTIME);
+                used_locktime = true;
+            }
+        }
+
+        assert!(used_sequence && used_locktime);
+    }
+
+    #[test]
+    fn test_create_tx_taproot_anti_fee_sniping_no_rbf() {
+        let (wallet, _, _) = get_funded_wallet(get_test_tr_single_sig());
+        let addr = wallet.get_address(New).unwrap();
+
+        // without RBF only the nLockTime is used, so the transaction isn't made replaceable
+        for _ in 0..32 {
+            let mut builder = wallet.build_tx();
+            builder.add_recipient(addr.script_pubkey(), 25_000);
+            let (psbt, _) = builder.finish().unwrap();
+            let tx = psbt.unsigned_tx;
+
+            assert!(tx.lock_time.0 <= 100 && tx.lock_time.0 > 0);
+            assert_eq!(tx.input[0].sequence, Sequence::ENABLE_LOCKTIME_NO_RBF);
+            assert!(!tx.input[0].sequence.is_rbf());
+        }
+    }
+
+    #[test]
+    fn test_create_tx_taproot_anti_fee_sniping_explicit_values() {
+        let (wallet, _, _) = get_funded_wallet(get_test_tr_single_sig());
+        let addr = wallet.get_address(New).unwrap();
+
+        for _ in 0..16 {
+            let mut builder = wallet.build_tx();
+            builder
+                .add_recipient(addr.script_pubkey(), 25_000)
+                .nlocktime(LockTime::from_height(50).unwrap());
+            let (psbt, _) = builder.finish().unwrap();
+            assert_eq!(psbt.unsigned_tx.lock_time, PackedLockTime(50));
+
+            // relative timelocks can't be used with version 1
+            let mut builder = wallet.build_tx();
+            builder
+                .add_recipient(addr.script_pubkey(), 25_000)
+                .version(1);
+            let (psbt, _) = builder.finish().unwrap();
+            assert_ne!(psbt.unsigned_tx.lock_time, PackedLockTime::ZERO);
+            assert_eq!(psbt.unsigned_tx.version, 1);
+
+            // an explicit nSequence is never replaced
+            let mut builder = wallet.build_tx();
+            builder
+                .add_recipient(addr.script_pubkey(), 25_000)
+                .enable_rbf_with_sequence(Sequence(0xFFFFFFF0));
+            let (psbt, _) = builder.finish().unwrap();
+            assert_ne!(psbt.unsigned_tx.lock_time, PackedLockTime::ZERO);
+            assert_eq!(psbt.unsigned_tx.input[0].sequence, Sequence(0xFFFFFFF0));
+        }
+    }
+
     #[test]
     fn test_create_tx_custom_locktime() {
         let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
@@ -3989,171 +6018,1435 @@ pub(crate) mod test {
             details.received
         );
 
-        assert_fee_rate!(psbt, details.fee.unwrap_or(0), FeeRate::from_sat_per_vb(5.0), @add_signature);
-    }
+        assert_fee_rate!(psbt, details.fee.unwrap_or(0), FeeRate::from_sat_per_vb(5.0), @add_signature);
+    }
+
+    #[test]
+    fn test_bump_fee_absolute_force_add_input() {
+        let (wallet, descriptors, _) = get_funded_wallet(get_test_wpkh());
+        let incoming_txid = crate::populate_test_db!(
+            wallet.database.borrow_mut(),
+            testutils! (@tx ( (@external descriptors, 0) => 25_000 ) (@confirmations 1)),
+            Some(100),
+        );
+
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let mut builder = wallet.build_tx();
+        builder
+            .add_recipient(addr.script_pubkey(), 45_000)
+            .enable_rbf();
+        let (psbt, mut original_details) = builder.finish().unwrap();
+        let mut tx = psbt.extract_tx();
+        let txid = tx.txid();
+        // skip saving the new utxos, we know they can't be used anyways
+        for txin in &mut tx.input {
+            txin.witness.push([0x00; P2WPKH_FAKE_WITNESS_SIZE]); // fake signature
+            wallet
+                .database
+                .borrow_mut()
+                .del_utxo(&txin.previous_output)
+                .unwrap();
+        }
+        original_details.transaction = Some(tx);
+        wallet
+            .database
+            .borrow_mut()
+            .set_tx(&original_details)
+            .unwrap();
+
+        // the new fee_rate is low enough that just reducing the change would be fine, but we force
+        // the addition of an extra input with `add_utxo()`
+        let mut builder = wallet.build_fee_bump(txid).unwrap();
+        builder
+            .add_utxo(OutPoint {
+                txid: incoming_txid,
+                vout: 0,
+            })
+            .unwrap()
+            .fee_absolute(250);
+        let (psbt, details) = builder.finish().unwrap();
+
+        assert_eq!(details.sent, original_details.sent + 25_000);
+        assert_eq!(details.fee.unwrap_or(0) + details.received, 30_000);
+
+        let tx = &psbt.unsigned_tx;
+        assert_eq!(tx.input.len(), 2);
+        assert_eq!(tx.output.len(), 2);
+        assert_eq!(
+            tx.output
+                .iter()
+                .find(|txout| txout.script_pubkey == addr.script_pubkey())
+                .unwrap()
+                .value,
+            45_000
+        );
+        assert_eq!(
+            tx.output
+                .iter()
+                .find(|txout| txout.script_pubkey != addr.script_pubkey())
+                .unwrap()
+                .value,
+            details.received
+        );
+
+        assert_eq!(details.fee.unwrap_or(0), 250);
+    }
+
+    #[test]
+    #[should_panic(expected = "InsufficientFunds")]
+    fn test_bump_fee_unconfirmed_inputs_only() {
+        // We try to bump the fee, but:
+        // - We can't reduce the change, as we have no change
+        // - All our UTXOs are unconfirmed
+        // So, we fail with "InsufficientFunds", as per RBF rule 2:
+        // The replacement transaction may only include an unconfirmed input
+        // if that input was included in one of the original transactions.
+        let (wallet, descriptors, _) = get_funded_wallet(get_test_wpkh());
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let mut builder = wallet.build_tx();
+        builder
+            .drain_wallet()
+            .drain_to(addr.script_pubkey())
+            .enable_rbf();
+        let (psbt, mut original_details) = builder.finish().unwrap();
+        // Now we receive one transaction with 0 confirmations. We won't be able to use that for
+        // fee bumping, as it's still unconfirmed!
+        crate::populate_test_db!(
+            wallet.database.borrow_mut(),
+            testutils! (@tx ( (@external descriptors, 0) => 25_000 ) (@confirmations 0)),
+            Some(100),
+        );
+        let mut tx = psbt.extract_tx();
+        let txid = tx.txid();
+        for txin in &mut tx.input {
+            txin.witness.push([0x00; P2WPKH_FAKE_WITNESS_SIZE]); // fake signature
+            wallet
+                .database
+                .borrow_mut()
+                .del_utxo(&txin.previous_output)
+                .unwrap();
+        }
+        original_details.transaction = Some(tx);
+        wallet
+            .database
+            .borrow_mut()
+            .set_tx(&original_details)
+            .unwrap();
+
+        let mut builder = wallet.build_fee_bump(txid).unwrap();
+        builder.fee_rate(FeeRate::from_sat_per_vb(25.0));
+        builder.finish().unwrap();
+    }
+
+    #[test]
+    fn test_bump_fee_unconfirmed_input() {
+        // We create a tx draining the wallet and spending one confirmed
+        // and one unconfirmed UTXO. We check that we can fee bump normally
+        // (BIP125 rule 2 only apply to newly added unconfirmed input, you can
+        // always fee bump with an unconfirmed input if it was included in the
+        // original transaction)
+        let (wallet, descriptors, _) = get_funded_wallet(get_test_wpkh());
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        // We receive a tx with 0 confirmations, which will be used as an input
+        // in the drain tx.
+        crate::populate_test_db!(
+            wallet.database.borrow_mut(),
+            testutils! (@tx ( (@external descriptors, 0) => 25_000 ) (@confirmations 0)),
+            Some(100),
+        );
+        let mut builder = wallet.build_tx();
+        builder
+            .drain_wallet()
+            .drain_to(addr.script_pubkey())
+            .enable_rbf();
+        let (psbt, mut original_details) = builder.finish().unwrap();
+        let mut tx = psbt.extract_tx();
+        let txid = tx.txid();
+        for txin in &mut tx.input {
+            txin.witness.push([0x00; P2WPKH_FAKE_WITNESS_SIZE]); // fake signature
+            wallet
+                .database
+                .borrow_mut()
+                .del_utxo(&txin.previous_output)
+                .unwrap();
+        }
+        original_details.transaction = Some(tx);
+        wallet
+            .database
+            .borrow_mut()
+            .set_tx(&original_details)
+            .unwrap();
+
+        let mut builder = wallet.build_fee_bump(txid).unwrap();
+        builder
+            .fee_rate(FeeRate::from_sat_per_vb(15.0))
+            .allow_shrinking(addr.script_pubkey())
+            .unwrap();
+        builder.finish().unwrap();
+    }
+
+    #[test]
+    fn test_bump_fee_add_recipient() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let new_addr = Address::from_str("2N4eQYCbKUHCCTUjBJeHcJp9ok6J2GZsTDt").unwrap();
+        let mut builder = wallet.build_tx();
+        builder
+            .add_recipient(addr.script_pubkey(), 25_000)
+            .fee_rate(FeeRate::from_sat_per_vb(2.0))
+            .enable_rbf();
+        let (psbt, mut original_details) = builder.finish().unwrap();
+        let mut tx = psbt.extract_tx();
+        let txid = tx.txid();
+        for txin in &mut tx.input {
+            txin.witness.push([0x00; P2WPKH_FAKE_WITNESS_SIZE]); // fake signature
+            wallet
+                .database
+                .borrow_mut()
+                .del_utxo(&txin.previous_output)
+                .unwrap();
+        }
+        let original_change = tx
+            .output
+            .iter()
+            .find(|txout| txout.script_pubkey != addr.script_pubkey())
+            .unwrap()
+            .script_pubkey
+            .clone();
+        original_details.transaction = Some(tx);
+        wallet
+            .database
+            .borrow_mut()
+            .set_tx(&original_details)
+            .unwrap();
+
+        let mut builder = wallet.build_fee_bump(txid).unwrap();
+        builder
+            .add_recipient(new_addr.script_pubkey(), 10_000)
+            .fee_rate(FeeRate::from_sat_per_vb(5.0));
+        let (psbt, details) = builder.finish().unwrap();
+
+        assert_eq!(details.sent, original_details.sent);
+        assert_eq!(details.received + details.fee.unwrap(), 15_000);
+
+        let tx = &psbt.unsigned_tx;
+        assert_eq!(tx.input.len(), 1);
+        assert_eq!(tx.output.len(), 3);
+        let value_of = |script: &Script| {
+            tx.output
+                .iter()
+                .find(|txout| txout.script_pubkey == *script)
+                .map(|txout| txout.value)
+        };
+        assert_eq!(value_of(&addr.script_pubkey()), Some(25_000));
+        assert_eq!(value_of(&new_addr.script_pubkey()), Some(10_000));
+        assert_eq!(value_of(&original_change), Some(details.received));
+
+        assert_fee_rate!(psbt, details.fee.unwrap_or(0), FeeRate::from_sat_per_vb(5.0), @add_signature);
+    }
+
+    #[test]
+    fn test_bump_fee_add_recipient_add_input() {
+        let (wallet, descriptors, funding_txid) = get_funded_wallet(get_test_wpkh());
+        crate::populate_test_db!(
+            wallet.database.borrow_mut(),
+            testutils! (@tx ( (@external descriptors, 0) => 25_000 ) (@confirmations 1)),
+            Some(100),
+        );
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let new_addr = Address::from_str("2N4eQYCbKUHCCTUjBJeHcJp9ok6J2GZsTDt").unwrap();
+        let mut builder = wallet.build_tx();
+        builder
+            .add_recipient(addr.script_pubkey(), 45_000)
+            .add_utxo(OutPoint::new(funding_txid, 0))
+            .unwrap()
+            .manually_selected_only()
+            .enable_rbf();
+        let (psbt, mut original_details) = builder.finish().unwrap();
+        let mut tx = psbt.extract_tx();
+        let txid = tx.txid();
+        for txin in &mut tx.input {
+            txin.witness.push([0x00; P2WPKH_FAKE_WITNESS_SIZE]); // fake signature
+            wallet
+                .database
+                .borrow_mut()
+                .del_utxo(&txin.previous_output)
+                .unwrap();
+        }
+        original_details.transaction = Some(tx);
+        wallet
+            .database
+            .borrow_mut()
+            .set_tx(&original_details)
+            .unwrap();
+
+        let mut builder = wallet.build_fee_bump(txid).unwrap();
+        builder
+            .add_recipient(new_addr.script_pubkey(), 10_000)
+            .fee_rate(FeeRate::from_sat_per_vb(5.0));
+        let (psbt, details) = builder.finish().unwrap();
+
+        assert_eq!(details.sent, 75_000);
+        let tx = &psbt.unsigned_tx;
+        assert_eq!(tx.input.len(), 2);
+        assert!(tx
+            .output
+            .iter()
+            .any(|txout| txout.script_pubkey == addr.script_pubkey() && txout.value == 45_000));
+        assert!(tx
+            .output
+            .iter()
+            .any(|txout| txout.script_pubkey == new_addr.script_pubkey() && txout.value == 10_000));
+
+        assert_fee_rate!(psbt, details.fee.unwrap_or(0), FeeRate::from_sat_per_vb(5.0), @add_signature);
+    }
+
+    #[test]
+    fn test_cancel_tx() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let mut builder = wallet.build_tx();
+        builder
+            .add_recipient(addr.script_pubkey(), 25_000)
+            .fee_rate(FeeRate::from_sat_per_vb(5.0))
+            .enable_rbf();
+        let (psbt, mut original_details) = builder.finish().unwrap();
+        let mut tx = psbt.extract_tx();
+        let txid = tx.txid();
+        for txin in &mut tx.input {
+            txin.witness.push([0x00; P2WPKH_FAKE_WITNESS_SIZE]); // fake signature
+            wallet
+                .database
+                .borrow_mut()
+                .del_utxo(&txin.previous_output)
+                .unwrap();
+        }
+        let original_inputs = tx
+            .input
+            .iter()
+            .map(|txin| txin.previous_output)
+            .collect::<Vec<_>>();
+        original_details.transaction = Some(tx);
+        wallet
+            .database
+            .borrow_mut()
+            .set_tx(&original_details)
+            .unwrap();
+
+        let (mut psbt, details) = wallet.build_cancel_tx(txid).unwrap().finish().unwrap();
+
+        assert_eq!(
+            psbt.unsigned_tx
+                .input
+                .iter()
+                .map(|txin| txin.previous_output)
+                .collect::<Vec<_>>(),
+            original_inputs
+        );
+        assert_eq!(psbt.unsigned_tx.output.len(), 1);
+        assert!(wallet
+            .is_mine(&psbt.unsigned_tx.output[0].script_pubkey)
+            .unwrap());
+        assert_eq!(details.sent, original_details.sent);
+        assert_eq!(details.received + details.fee.unwrap(), details.sent);
+
+        assert!(wallet.sign(&mut psbt, Default::default()).unwrap());
+        let cancel_tx = psbt.extract_tx();
+        // BIP125 rules 3 and 4
+        let min_fee = original_details.fee.unwrap()
+            + FeeRate::default_min_relay_fee().fee_vb(cancel_tx.vsize());
+        assert!(details.fee.unwrap() >= min_fee);
+        assert!(details.fee.unwrap() <= min_fee + 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "IrreplaceableTransaction")]
+    fn test_cancel_tx_irreplaceable_tx() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let addr = wallet.get_address(New).unwrap();
+        let mut builder = wallet.build_tx();
+        builder.add_recipient(addr.script_pubkey(), 25_000);
+        let (psbt, mut details) = builder.finish().unwrap();
+
+        let tx = psbt.extract_tx();
+        let txid = tx.txid();
+        details.transaction = Some(tx);
+        wallet.database.borrow_mut().set_tx(&details).unwrap();
+
+        wallet.build_cancel_tx(txid).unwrap().finish().unwrap();
+    }
+
+    #[test]
+    fn test_cpfp() {
+        let (wallet, descriptors, _) = get_funded_wallet(get_test_wpkh());
+        // An incoming payment stuck in the mempool with no fee at all
+        let parent_txid = crate::populate_test_db!(
+            wallet.database.borrow_mut(),
+            testutils! (@tx ( (@external descriptors, 0) => 25_000 ) (@confirmations 0)),
+            Some(100),
+        );
+        let parent = wallet
+            .get_tx(&parent_txid, true)
+            .unwrap()
+            .unwrap()
+            .transaction
+            .unwrap();
+
+        let fee_rate = FeeRate::from_sat_per_vb(10.0);
+        let builder = wallet.build_cpfp(parent_txid, fee_rate).unwrap();
+        let (mut psbt, details) = builder.finish().unwrap();
+
+        assert_eq!(psbt.unsigned_tx.input.len(), 1);
+        assert_eq!(psbt.unsigned_tx.input[0].previous_output.txid, parent_txid);
+        assert_eq!(psbt.unsigned_tx.output.len(), 1);
+        assert_eq!(details.sent, 25_000);
+        assert_eq!(details.received + details.fee.unwrap(), 25_000);
+
+        assert!(wallet.sign(&mut psbt, Default::default()).unwrap());
+        let child = psbt.extract_tx();
+        let package_rate = FeeRate::from_wu(details.fee.unwrap(), parent.weight() + child.weight());
+        assert!(package_rate >= fee_rate);
+        // the child alone pays much more than the target
+        assert!(FeeRate::from_wu(details.fee.unwrap(), child.weight()) > fee_rate);
+    }
+
+    #[test]
+    fn test_cpfp_parent_fee_is_accounted_for() {
+        let (wallet, descriptors, _) = get_funded_wallet(get_test_wpkh());
+        let parent_txid = crate::populate_test_db!(
+            wallet.database.borrow_mut(),
+            testutils! (@tx ( (@external descriptors, 0) => 25_000 ) (@confirmations 0)),
+            Some(100),
+        );
+        let mut parent_details = wallet.get_tx(&parent_txid, true).unwrap().unwrap();
+        let parent_weight = parent_details.transaction.as_ref().unwrap().weight();
+        // the parent already pays the target rate, the child only has to pay for itself
+        let fee_rate = FeeRate::from_sat_per_vb(10.0);
+        parent_details.fee = Some(fee_rate.fee_wu(parent_weight));
+        wallet
+            .database
+            .borrow_mut()
+            .set_tx(&parent_details)
+            .unwrap();
+
+        let builder = wallet.build_cpfp(parent_txid, fee_rate).unwrap();
+        let (psbt, details) = builder.finish().unwrap();
+
+        assert_fee_rate!(psbt, details.fee.unwrap_or(0), fee_rate, @add_signature);
+    }
+
+    #[test]
+    fn test_cpfp_confirmed_tx() {
+        let (wallet, _, funding_txid) = get_funded_wallet(get_test_wpkh());
+        assert!(matches!(
+            wallet.build_cpfp(funding_txid, FeeRate::from_sat_per_vb(10.0)),
+            Err(Error::TransactionConfirmed)
+        ));
+    }
+
+    #[test]
+    fn test_cpfp_unknown_tx() {
+        use bitcoin::hashes::Hash;
+
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        assert!(matches!(
+            wallet.build_cpfp(Txid::all_zeros(), FeeRate::from_sat_per_vb(10.0)),
+            Err(Error::TransactionNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_cpfp_no_owned_outputs() {
+        let (wallet, descriptors, _) = get_funded_wallet(get_test_wpkh());
+        let parent_txid = crate::populate_test_db!(
+            wallet.database.borrow_mut(),
+            testutils! (@tx ( (@external descriptors, 0) => 25_000 ) (@confirmations 0)),
+            Some(100),
+        );
+        wallet
+            .database
+            .borrow_mut()
+            .del_utxo(&OutPoint::new(parent_txid, 0))
+            .unwrap();
+
+        assert!(matches!(
+            wallet.build_cpfp(parent_txid, FeeRate::from_sat_per_vb(10.0)),
+            Err(Error::Generic(_))
+        ));
+    }
+
+    #[test]
+    fn test_ancestor_fee_policy_bump() {
+        let (wallet, descriptors, _) = get_funded_wallet(get_test_wpkh());
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let parent_txid = crate::populate_test_db!(
+            wallet.database.borrow_mut(),
+            testutils! (@tx ( (@external descriptors, 0) => 25_000 ) (@confirmations 0)),
+            Some(100),
+        );
+        let parent_weight = wallet
+            .get_tx(&parent_txid, true)
+            .unwrap()
+            .unwrap()
+            .transaction
+            .unwrap()
+            .weight();
+        let fee_rate = FeeRate::from_sat_per_vb(10.0);
+
+        let mut builder = wallet.build_tx();
+        builder
+            .add_recipient(addr.script_pubkey(), 20_000)
+            .add_utxo(OutPoint::new(parent_txid, 0))
+            .unwrap()
+            .manually_selected_only()
+            .fee_rate(fee_rate);
+        let (_, ignore_details) = builder.finish().unwrap();
+
+        let mut builder = wallet.build_tx();
+        builder
+            .add_recipient(addr.script_pubkey(), 20_000)
+            .add_utxo(OutPoint::new(parent_txid, 0))
+            .unwrap()
+            .manually_selected_only()
+            .fee_rate(fee_rate)
+            .ancestor_fee_policy(AncestorFeePolicy::Bump);
+        let (mut psbt, details) = builder.finish().unwrap();
+
+        assert!(details.fee.unwrap() > ignore_details.fee.unwrap());
+        assert!(wallet.sign(&mut psbt, Default::default()).unwrap());
+        let child_weight = psbt.extract_tx().weight();
+        let package_rate = FeeRate::from_wu(details.fee.unwrap(), parent_weight + child_weight);
+        assert!(package_rate >= fee_rate);
+    }
+
+    #[test]
+    fn test_ancestor_fee_policy_bump_confirmed() {
+        // confirmed utxos don't have any ancestor to pay for
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let fee_rate = FeeRate::from_sat_per_vb(10.0);
+
+        let mut builder = wallet.build_tx();
+        builder
+            .add_recipient(addr.script_pubkey(), 20_000)
+            .fee_rate(fee_rate)
+            .ancestor_fee_policy(AncestorFeePolicy::Bump);
+        let (psbt, details) = builder.finish().unwrap();
+
+        assert_fee_rate!(psbt, details.fee.unwrap_or(0), fee_rate, @add_signature);
+    }
+
+    #[test]
+    fn test_ancestor_fee_policy_avoid() {
+        let (wallet, descriptors, funding_txid) = get_funded_wallet(get_test_wpkh());
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let low_fee_txid = crate::populate_test_db!(
+            wallet.database.borrow_mut(),
+            testutils! (@tx ( (@external descriptors, 0) => 100_000 ) (@confirmations 0)),
+            Some(100),
+        );
+
+        let mut builder = wallet
+            .build_tx()
+            .coin_selection(coin_selection::LargestFirstCoinSelection);
+        builder
+            .add_recipient(addr.script_pubkey(), 20_000)
+            .fee_rate(FeeRate::from_sat_per_vb(10.0));
+        let (psbt, _) = builder.finish().unwrap();
+        assert_eq!(psbt.unsigned_tx.input.len(), 1);
+        assert_eq!(psbt.unsigned_tx.input[0].previous_output.txid, low_fee_txid);
+
+        let mut builder = wallet
+            .build_tx()
+            .coin_selection(coin_selection::LargestFirstCoinSelection);
+        builder
+            .add_recipient(addr.script_pubkey(), 20_000)
+            .fee_rate(FeeRate::from_sat_per_vb(10.0))
+            .ancestor_fee_policy(AncestorFeePolicy::Avoid);
+        let (psbt, _) = builder.finish().unwrap();
+        assert_eq!(psbt.unsigned_tx.input.len(), 1);
+        assert_eq!(psbt.unsigned_tx.input[0].previous_output.txid, funding_txid);
+
+        // once the parent pays enough it can be spent again
+        let mut parent_details = wallet.get_tx(&low_fee_txid, true).unwrap().unwrap();
+        parent_details.fee = Some(10_000);
+        wallet
+            .database
+            .borrow_mut()
+            .set_tx(&parent_details)
+            .unwrap();
+
+        let mut builder = wallet
+            .build_tx()
+            .coin_selection(coin_selection::LargestFirstCoinSelection);
+        builder
+            .add_recipient(addr.script_pubkey(), 20_000)
+            .fee_rate(FeeRate::from_sat_per_vb(10.0))
+            .ancestor_fee_policy(AncestorFeePolicy::Avoid);
+        let (psbt, _) = builder.finish().unwrap();
+        assert_eq!(psbt.unsigned_tx.input[0].previous_output.txid, low_fee_txid);
+    }
+
+    #[test]
+    fn test_frozen_utxos_are_not_selected() {
+        let (wallet, descriptors, funding_txid) = get_funded_wallet(get_test_wpkh());
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let small_txid = crate::populate_test_db!(
+            wallet.database.borrow_mut(),
+            testutils! (@tx ( (@external descriptors, 0) => 30_000 ) (@confirmations 1)),
+            Some(100),
+        );
+        let frozen = OutPoint::new(funding_txid, 0);
+        wallet.freeze_utxo(frozen).unwrap();
+        assert_eq!(wallet.list_frozen().unwrap(), vec![frozen]);
+
+        let mut builder = wallet.build_tx();
+        builder.drain_to(addr.script_pubkey()).drain_wallet();
+        let (psbt, _) = builder.finish().unwrap();
+        assert_eq!(psbt.unsigned_tx.input.len(), 1);
+        assert_eq!(psbt.unsigned_tx.input[0].previous_output.txid, small_txid);
+
+        let mut builder = wallet.build_tx();
+        builder.add_recipient(addr.script_pubkey(), 40_000);
+        assert!(matches!(
+            builder.finish(),
+            Err(Error::InsufficientFunds { .. })
+        ));
+
+        // frozen utxos can still be spent explicitly
+        let mut builder = wallet.build_tx();
+        builder
+            .add_utxo(frozen)
+            .unwrap()
+            .add_recipient(addr.script_pubkey(), 40_000);
+        let (psbt, _) = builder.finish().unwrap();
+        assert!(psbt
+            .unsigned_tx
+            .input
+            .iter()
+            .any(|txin| txin.previous_output == frozen));
+
+        assert!(wallet.unfreeze_utxo(frozen).unwrap());
+        let mut builder = wallet.build_tx();
+        builder.add_recipient(addr.script_pubkey(), 40_000);
+        assert!(builder.finish().is_ok());
+    }
+
+    #[test]
+    fn test_labels() {
+        let (wallet, _, funding_txid) = get_funded_wallet(get_test_wpkh());
+        let addr = wallet.get_address(LastUnused).unwrap();
+        let utxo_key = LabelKey::Utxo(OutPoint::new(funding_txid, 0));
+        let address_key = LabelKey::Address(addr.script_pubkey());
+
+        wallet.set_label(utxo_key.clone(), "salary").unwrap();
+        wallet.set_label(address_key.clone(), "exchange").unwrap();
+
+        assert_eq!(
+            wallet.get_label(&utxo_key).unwrap(),
+            Some("salary".to_string())
+        );
+        assert_eq!(wallet.list_labels().unwrap().len(), 2);
+        assert_eq!(
+            wallet.remove_label(&address_key).unwrap(),
+            Some("exchange".to_string())
+        );
+        assert_eq!(
+            wallet.list_labels().unwrap(),
+            vec![(utxo_key, "salary".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_filter_utxos_by_child_number() {
+        let (wallet, descriptors, funding_txid) = get_funded_wallet("wpkh(tprv8ZgxMBicQKsPd3EupYiPRhaMooHKUHJxNsTfYuScep13go8QFfHdtkG9nRkFGb7busX4isf6X9dURGCoKgitaApQ6MupRhZMcELAxTBRJgS/*)");
+        wallet.ensure_addresses_cached(10).unwrap();
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let far_txid = crate::populate_test_db!(
+            wallet.database.borrow_mut(),
+            testutils! (@tx ( (@external descriptors, 5) => 80_000 ) (@confirmations 1)),
+            Some(100),
+        );
+
+        let mut builder = wallet.build_tx();
+        builder
+            .add_recipient(addr.script_pubkey(), 10_000)
+            .filter_utxos(|_, child| child < 5);
+        let (psbt, _) = builder.finish().unwrap();
+        assert_eq!(psbt.unsigned_tx.input.len(), 1);
+        assert_eq!(psbt.unsigned_tx.input[0].previous_output.txid, funding_txid);
+
+        // filters are combined
+        let mut builder = wallet.build_tx();
+        builder
+            .add_recipient(addr.script_pubkey(), 10_000)
+            .filter_utxos(|_, child| child >= 5)
+            .filter_utxos(move |utxo, _| utxo.outpoint.txid != far_txid);
+        assert!(matches!(
+            builder.finish(),
+            Err(Error::InsufficientFunds { .. })
+        ));
+
+        // manually selected utxos are always spent
+        let mut builder = wallet.build_tx();
+        builder
+            .add_recipient(addr.script_pubkey(), 10_000)
+            .add_utxo(OutPoint::new(far_txid, 0))
+            .unwrap()
+            .filter_utxos(|_, _| false);
+        let (psbt, _) = builder.finish().unwrap();
+        assert_eq!(psbt.unsigned_tx.input.len(), 1);
+        assert_eq!(psbt.unsigned_tx.input[0].previous_output.txid, far_txid);
+    }
+
+    #[test]
+    fn test_filter_by_label() {
+        let (wallet, descriptors, funding_txid) = get_funded_wallet("wpkh(tprv8ZgxMBicQKsPd3EupYiPRhaMooHKUHJxNsTfYuScep13go8QFfHdtkG9nRkFGb7busX4isf6X9dURGCoKgitaApQ6MupRhZMcELAxTBRJgS/*)");
+        wallet.ensure_addresses_cached(10).unwrap();
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let labeled_txid = crate::populate_test_db!(
+            wallet.database.borrow_mut(),
+            testutils! (@tx ( (@external descriptors, 1) => 30_000 ) (@confirmations 1)),
+            Some(100),
+        );
+        let other_txid = crate::populate_test_db!(
+            wallet.database.borrow_mut(),
+            testutils! (@tx ( (@external descriptors, 2) => 20_000 ) (@confirmations 1)),
+            Some(100),
+        );
+        wallet
+            .set_label(
+                LabelKey::Utxo(OutPoint::new(labeled_txid, 0)),
+                "cold-storage-batch-7",
+            )
+            .unwrap();
+        let labeled_address = wallet.get_address(Peek(2)).unwrap();
+        wallet
+            .set_label(
+                LabelKey::Address(labeled_address.script_pubkey()),
+                "cold-storage-batch-7",
+            )
+            .unwrap();
+        wallet
+            .set_label(LabelKey::Utxo(OutPoint::new(funding_txid, 0)), "hot")
+            .unwrap();
+
+        let mut builder = wallet.build_tx();
+        builder
+            .drain_to(addr.script_pubkey())
+            .drain_wallet()
+            .filter_by_label("cold-storage-batch-7")
+            .unwrap();
+        let (psbt, _) = builder.finish().unwrap();
+        let mut spent = psbt
+            .unsigned_tx
+            .input
+            .iter()
+            .map(|txin| txin.previous_output.txid)
+            .collect::<Vec<_>>();
+        spent.sort();
+        let mut expected = vec![labeled_txid, other_txid];
+        expected.sort();
+        assert_eq!(spent, expected);
+    }
+
+    #[test]
+    fn test_filter_utxos_with_change_policy() {
+        let descriptors = testutils!(@descriptors ("wpkh(tprv8ZgxMBicQKsPd3EupYiPRhaMooHKUHJxNsTfYuScep13go8QFfHdtkG9nRkFGb7busX4isf6X9dURGCoKgitaApQ6MupRhZMcELAxTBRJgS/0/*)") ("wpkh(tprv8ZgxMBicQKsPd3EupYiPRhaMooHKUHJxNsTfYuScep13go8QFfHdtkG9nRkFGb7busX4isf6X9dURGCoKgitaApQ6MupRhZMcELAxTBRJgS/1/*)"));
+        let wallet = Wallet::new(
+            &descriptors.0,
+            descriptors.1.as_ref(),
+            Network::Regtest,
+            AnyDatabase::Memory(MemoryDatabase::new()),
+        )
+        .unwrap();
+        wallet.ensure_addresses_cached(10).unwrap();
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let external_txid = crate::populate_test_db!(
+            wallet.database.borrow_mut(),
+            testutils! (@tx ( (@external descriptors, 0) => 50_000 ) (@confirmations 1)),
+            Some(100),
+        );
+        crate::populate_test_db!(
+            wallet.database.borrow_mut(),
+            testutils! (@tx ( (@external descriptors, 3) => 20_000 ) (@confirmations 1)),
+            Some(100),
+        );
+        let change_txid = crate::populate_test_db!(
+            wallet.database.borrow_mut(),
+            testutils! (@tx ( (@internal descriptors, 0) => 30_000 ) (@confirmations 1)),
+            Some(100),
+        );
+        let mut change_utxo = wallet
+            .get_utxo(OutPoint::new(change_txid, 0))
+            .unwrap()
+            .unwrap();
+        change_utxo.keychain = KeychainKind::Internal;
+        wallet.database.borrow_mut().set_utxo(&change_utxo).unwrap();
+
+        let mut builder = wallet.build_tx();
+        builder
+            .drain_to(addr.script_pubkey())
+            .drain_wallet()
+            .filter_utxos(|_, child| child == 0)
+            .do_not_spend_change();
+        let (psbt, _) = builder.finish().unwrap();
+        assert_eq!(psbt.unsigned_tx.input.len(), 1);
+        assert_eq!(
+            psbt.unsigned_tx.input[0].previous_output.txid,
+            external_txid
+        );
+
+        let mut builder = wallet.build_tx();
+        builder
+            .drain_to(addr.script_pubkey())
+            .drain_wallet()
+            .filter_utxos(|_, child| child == 0)
+            .only_spend_change();
+        let (psbt, _) = builder.finish().unwrap();
+        assert_eq!(psbt.unsigned_tx.input.len(), 1);
+        assert_eq!(psbt.unsigned_tx.input[0].previous_output.txid, change_txid);
+    }
+
+    #[test]
+    fn test_subtract_fee_from_recipient() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let mut builder = wallet.build_tx();
+        builder
+            .add_fee_paying_recipient(addr.script_pubkey(), 20_000)
+            .fee_rate(FeeRate::from_sat_per_vb(5.0))
+            .ordering(super::tx_builder::TxOrdering::Untouched);
+        let (psbt, details) = builder.finish().unwrap();
+        let fee = details.fee.unwrap_or(0);
+
+        assert_eq!(psbt.unsigned_tx.output.len(), 2);
+        assert_eq!(psbt.unsigned_tx.output[0].value, 20_000 - fee);
+        assert_eq!(psbt.unsigned_tx.output[1].value, 30_000);
+        assert_eq!(details.received, 30_000);
+        assert_fee_rate!(psbt, fee, FeeRate::from_sat_per_vb(5.0), @add_signature);
+    }
+
+    #[test]
+    fn test_subtract_fee_send_whole_balance() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let mut builder = wallet.build_tx();
+        builder
+            .add_fee_paying_recipient(addr.script_pubkey(), 50_000)
+            .fee_rate(FeeRate::from_sat_per_vb(5.0));
+        let (psbt, details) = builder.finish().unwrap();
+        let fee = details.fee.unwrap_or(0);
+
+        assert_eq!(psbt.unsigned_tx.output.len(), 1);
+        assert_eq!(psbt.unsigned_tx.output[0].value, 50_000 - fee);
+        assert_fee_rate!(psbt, fee, FeeRate::from_sat_per_vb(5.0), @add_signature);
+    }
+
+    #[test]
+    fn test_subtract_fee_from_multiple_recipients() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let addr1 = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let addr2 = Address::from_str("2N4eQYCbKUHCCTUjBJeHcJp9ok6J2GZsTDt").unwrap();
+        let addr3 = Address::from_str("mkHS9ne12qx9pS9VojpwU5xtRd4T7X7ZUt").unwrap();
+
+        let mut builder = wallet.build_tx();
+        builder
+            .add_fee_paying_recipient(addr1.script_pubkey(), 10_000)
+            .add_recipient(addr2.script_pubkey(), 5_000)
+            .add_fee_paying_recipient(addr3.script_pubkey(), 30_000)
+            .fee_absolute(1_001)
+            .ordering(super::tx_builder::TxOrdering::Untouched);
+        let (psbt, details) = builder.finish().unwrap();
+        assert_eq!(details.fee, Some(1_001));
+        assert_eq!(psbt.unsigned_tx.output[0].value, 10_000 - 501);
+        assert_eq!(psbt.unsigned_tx.output[1].value, 5_000);
+        assert_eq!(psbt.unsigned_tx.output[2].value, 30_000 - 500);
+
+        let mut builder = wallet.build_tx();
+        builder
+            .add_fee_paying_recipient(addr1.script_pubkey(), 10_000)
+            .add_recipient(addr2.script_pubkey(), 5_000)
+            .add_fee_paying_recipient(addr3.script_pubkey(), 30_000)
+            .fee_split(super::tx_builder::FeeSplit::Proportional)
+            .fee_absolute(1_000)
+            .ordering(super::tx_builder::TxOrdering::Untouched);
+        let (psbt, _) = builder.finish().unwrap();
+        assert_eq!(psbt.unsigned_tx.output[0].value, 10_000 - 250);
+        assert_eq!(psbt.unsigned_tx.output[1].value, 5_000);
+        assert_eq!(psbt.unsigned_tx.output[2].value, 30_000 - 750);
+    }
+
+    #[test]
+    #[should_panic(expected = "OutputBelowDustLimit(0)")]
+    fn test_subtract_fee_output_below_dust() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let mut builder = wallet.build_tx();
+        builder
+            .add_fee_paying_recipient(addr.script_pubkey(), 1_000)
+            .fee_rate(FeeRate::from_sat_per_vb(5.0));
+        builder.finish().unwrap();
+    }
+
+    #[test]
+    fn test_create_tx_change_split() {
+        let (wallet, _, _) = get_funded_wallet("wpkh(tprv8ZgxMBicQKsPd3EupYiPRhaMooHKUHJxNsTfYuScep13go8QFfHdtkG9nRkFGb7busX4isf6X9dURGCoKgitaApQ6MupRhZMcELAxTBRJgS/*)");
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let mut builder = wallet.build_tx();
+        builder
+            .add_recipient(addr.script_pubkey(), 20_000)
+            .change_split(ChangeSplit::Equal(3))
+            .fee_rate(FeeRate::from_sat_per_vb(5.0))
+            .ordering(super::tx_builder::TxOrdering::Untouched);
+        let (psbt, details) = builder.finish().unwrap();
+        let fee = details.fee.unwrap_or(0);
+
+        let change = &psbt.unsigned_tx.output[1..];
+        assert_eq!(change.len(), 3);
+        assert!(change
+            .iter()
+            .all(|txout| wallet.is_mine(&txout.script_pubkey).unwrap()));
+        assert_eq!(
+            change
+                .iter()
+                .map(|txout| &txout.script_pubkey)
+                .collect::<HashSet<_>>()
+                .len(),
+            3
+        );
+        assert!(change.windows(2).all(|w| w[0].value >= w[1].value));
+        assert!(change[0].value - change[2].value < 3);
+        assert_eq!(details.received, 30_000 - fee);
+        assert_fee_rate!(psbt, fee, FeeRate::from_sat_per_vb(5.0), @add_signature);
+    }
+
+    #[test]
+    fn test_create_tx_change_split_with_drain_to() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let drain_addr = Address::from_str("2N4eQYCbKUHCCTUjBJeHcJp9ok6J2GZsTDt").unwrap();
+        let mut builder = wallet.build_tx();
+        builder
+            .add_recipient(addr.script_pubkey(), 20_000)
+            .drain_to(drain_addr.script_pubkey())
+            .change_split(ChangeSplit::Equal(3));
+        let (psbt, _) = builder.finish().unwrap();
+
+        assert_eq!(psbt.unsigned_tx.output.len(), 2);
+    }
+
+    #[test]
+    fn test_subtract_fee_with_change_split() {
+        let (wallet, _, _) = get_funded_wallet("wpkh(tprv8ZgxMBicQKsPd3EupYiPRhaMooHKUHJxNsTfYuScep13go8QFfHdtkG9nRkFGb7busX4isf6X9dURGCoKgitaApQ6MupRhZMcELAxTBRJgS/*)");
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let mut builder = wallet.build_tx();
+        builder
+            .add_fee_paying_recipient(addr.script_pubkey(), 20_000)
+            .change_split(ChangeSplit::Equal(2))
+            .fee_rate(FeeRate::from_sat_per_vb(5.0))
+            .ordering(super::tx_builder::TxOrdering::Untouched);
+        let (psbt, details) = builder.finish().unwrap();
+        let fee = details.fee.unwrap_or(0);
+
+        assert_eq!(psbt.unsigned_tx.output.len(), 3);
+        assert_eq!(psbt.unsigned_tx.output[0].value, 20_000 - fee);
+        assert_eq!(psbt.unsigned_tx.output[1].value, 15_000);
+        assert_eq!(psbt.unsigned_tx.output[2].value, 15_000);
+        assert_fee_rate!(psbt, fee, FeeRate::from_sat_per_vb(5.0), @add_signature);
+    }
+
+    #[test]
+    fn test_plan_consolidation() {
+        let (wallet, descriptors, _) = get_funded_wallet(get_test_wpkh());
+        let mut deposits = vec![];
+        for value in 10_000..10_004 {
+            deposits.push(crate::populate_test_db!(
+                wallet.database.borrow_mut(),
+                testutils! (@tx ( (@external descriptors, 0) => value ) (@confirmations 1)),
+                Some(100),
+            ));
+        }
+        // spending this one at 5 sat/vbyte costs more than its value
+        let uneconomical = crate::populate_test_db!(
+            wallet.database.borrow_mut(),
+            testutils! (@tx ( (@external descriptors, 0) => 300 ) (@confirmations 1)),
+            Some(100),
+        );
+        let frozen = crate::populate_test_db!(
+            wallet.database.borrow_mut(),
+            testutils! (@tx ( (@external descriptors, 0) => 20_000 ) (@confirmations 1)),
+            Some(100),
+        );
+        wallet.freeze_utxo(OutPoint::new(frozen, 0)).unwrap();
+
+        // room for two inputs per transaction
+        let satisfaction_weight = wallet
+            .get_descriptor_for_keychain(KeychainKind::External)
+            .max_satisfaction_weight()
+            .unwrap();
+        let max_weight = 174 + 2 * (TXIN_BASE_WEIGHT + satisfaction_weight);
+        let fee_rate = FeeRate::from_sat_per_vb(5.0);
+        let consolidation = wallet.plan_consolidation(fee_rate, max_weight).unwrap();
+
+        // the 50k UTXO plus the four deposits make two pairs, the last deposit is left alone
+        assert_eq!(consolidation.len(), 2);
+        let mut spent = HashSet::new();
+        for (mut psbt, details) in consolidation {
+            assert_eq!(psbt.unsigned_tx.input.len(), 2);
+            assert_eq!(psbt.unsigned_tx.output.len(), 1);
+            assert!(wallet
+                .is_mine(&psbt.unsigned_tx.output[0].script_pubkey)
+                .unwrap());
+            assert!(psbt
+                .unsigned_tx
+                .input
+                .iter()
+                .all(|txin| txin.sequence.is_rbf()));
+            assert_eq!(details.received, details.sent - details.fee.unwrap_or(0));
+            spent.extend(
+                psbt.unsigned_tx
+                    .input
+                    .iter()
+                    .map(|txin| txin.previous_output),
+            );
+
+            assert_fee_rate!(psbt, details.fee.unwrap_or(0), fee_rate, @add_signature);
+            let finalized = wallet.sign(&mut psbt, SignOptions::default()).unwrap();
+            assert!(finalized);
+            assert!(psbt.extract_tx().weight() <= max_weight);
+        }
+        assert_eq!(spent.len(), 4);
+        assert!(!spent.contains(&OutPoint::new(uneconomical, 0)));
+        assert!(!spent.contains(&OutPoint::new(frozen, 0)));
+        assert_eq!(
+            deposits
+                .iter()
+                .filter(|txid| spent.contains(&OutPoint::new(**txid, 0)))
+                .count(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_build_migration() {
+        let (wallet, descriptors, _) = get_funded_wallet("wpkh(tprv8ZgxMBicQKsPd3EupYiPRhaMooHKUHJxNsTfYuScep13go8QFfHdtkG9nRkFGb7busX4isf6X9dURGCoKgitaApQ6MupRhZMcELAxTBRJgS/*)");
+        wallet.ensure_addresses_cached(3).unwrap();
+        let deposit = crate::populate_test_db!(
+            wallet.database.borrow_mut(),
+            testutils! (@tx ( (@external descriptors, 1) => 10_000 ) (@confirmations 1)),
+            Some(100),
+        );
+        crate::populate_test_db!(
+            wallet.database.borrow_mut(),
+            testutils! (@tx ( (@external descriptors, 1) => 10_001 ) (@confirmations 1)),
+            Some(100),
+        );
+        let savings = crate::populate_test_db!(
+            wallet.database.borrow_mut(),
+            testutils! (@tx ( (@external descriptors, 2) => 30_000 ) (@confirmations 1)),
+            Some(100),
+        );
+        let savings_script = wallet
+            .get_utxo(OutPoint::new(savings, 0))
+            .unwrap()
+            .unwrap()
+            .txout
+            .script_pubkey;
+        wallet
+            .set_label(LabelKey::Utxo(OutPoint::new(deposit, 0)), "deposit")
+            .unwrap();
+        wallet
+            .set_label(LabelKey::Address(savings_script), "savings")
+            .unwrap();
+
+        let target = Wallet::new(
+            get_test_tr_single_sig_xprv(),
+            None,
+            Network::Regtest,
+            MemoryDatabase::new(),
+        )
+        .unwrap();
+        let options = MigrationOptions {
+            fee_rate: FeeRate::from_sat_per_vb(2.0),
+            transactions: 2,
+            preserve_labels: true,
+        };
+        let migration = wallet.build_migration(&target, options).unwrap();
+
+        // the 50k UTXO goes alone, the other addresses are moved together
+        assert_eq!(migration.len(), 2);
+        let mut inputs = migration
+            .iter()
+            .map(|(psbt, _)| psbt.unsigned_tx.input.len())
+            .collect::<Vec<_>>();
+        inputs.sort_unstable();
+        assert_eq!(inputs, vec![1, 3]);
+
+        for (mut psbt, details) in migration {
+            assert_eq!(psbt.unsigned_tx.output.len(), 1);
+            let script = &psbt.unsigned_tx.output[0].script_pubkey;
+            assert!(target.is_mine(script).unwrap());
+            assert!(!wallet.is_mine(script).unwrap());
+            assert_eq!(details.received, 0);
+
+            let label = target
+                .get_label(&LabelKey::Address(script.clone()))
+                .unwrap();
+            match psbt.unsigned_tx.input.len() {
+                1 => assert_eq!(label, None),
+                _ => assert_eq!(label.as_deref(), Some("savings, deposit")),
+            }
+
+            assert_fee_rate!(psbt, details.fee.unwrap_or(0), FeeRate::from_sat_per_vb(2.0), @add_signature);
+            let finalized = wallet.sign(&mut psbt, SignOptions::default()).unwrap();
+            assert!(finalized);
+        }
+    }
+
+    #[test]
+    fn test_build_migration_failure_leaves_target_unchanged() {
+        let (wallet, descriptors, _) = get_funded_wallet("wpkh(tprv8ZgxMBicQKsPd3EupYiPRhaMooHKUHJxNsTfYuScep13go8QFfHdtkG9nRkFGb7busX4isf6X9dURGCoKgitaApQ6MupRhZMcELAxTBRJgS/*)");
+        wallet.ensure_addresses_cached(2).unwrap();
+        let deposit = crate::populate_test_db!(
+            wallet.database.borrow_mut(),
+            testutils! (@tx ( (@external descriptors, 1) => 10_000 ) (@confirmations 1)),
+            Some(100),
+        );
+        wallet
+            .set_label(LabelKey::Utxo(OutPoint::new(deposit, 0)), "deposit")
+            .unwrap();
+
+        let target = Wallet::new(
+            get_test_tr_single_sig_xprv(),
+            None,
+            Network::Regtest,
+            MemoryDatabase::new(),
+        )
+        .unwrap();
+        // the 10k UTXO is worth spending on its own, but it can't pay for a whole transaction
+        let options = MigrationOptions {
+            fee_rate: FeeRate::from_sat_per_vb(140.0),
+            transactions: 2,
+            preserve_labels: true,
+        };
+        assert!(matches!(
+            wallet.build_migration(&target, options),
+            Err(Error::InsufficientFunds { .. })
+        ));
+
+        assert_eq!(
+            target
+                .database()
+                .get_last_index(KeychainKind::External)
+                .unwrap(),
+            None
+        );
+        let first_address = target.get_address(Peek(0)).unwrap();
+        assert_eq!(
+            target
+                .get_label(&LabelKey::Address(first_address.script_pubkey()))
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_create_sweep() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let key =
+            bitcoin::PrivateKey::from_wif("cTc4vURSzdx6QE6KVynWGomDbLaA75dNALMNyfjh3p8DRRar84Um")
+                .unwrap();
+        let sweep_wallets = wallet.sweep_wallets(vec![key]).unwrap();
+        assert_eq!(sweep_wallets.len(), 3);
+
+        assert!(matches!(
+            wallet.create_sweep(&sweep_wallets, FeeRate::from_sat_per_vb(2.0)),
+            Err(Error::NoUtxosSelected)
+        ));
+
+        // fund the `pkh()` and `wpkh()` scripts of the key
+        for (sweep_wallet, amount) in [(&sweep_wallets[0], 20_000), (&sweep_wallets[2], 30_000)] {
+            sweep_wallet.ensure_addresses_cached(1).unwrap();
+            let addr = sweep_wallet.get_address(Peek(0)).unwrap();
+            crate::populate_test_db!(
+                sweep_wallet.database.borrow_mut(),
+                testutils! (@tx ( (@addr addr.address.clone()) => amount ) (@confirmations 1)),
+                Some(100),
+            );
+        }
+
+        let (psbt, details) = wallet
+            .create_sweep(&sweep_wallets, FeeRate::from_sat_per_vb(2.0))
+            .unwrap();
+        assert_eq!(psbt.inputs.len(), 2);
+        assert!(psbt
+            .inputs
+            .iter()
+            .all(|input| input.final_script_sig.is_some() || input.final_script_witness.is_some()));
+        assert_eq!(psbt.unsigned_tx.output.len(), 1);
+        assert!(wallet
+            .is_mine(&psbt.unsigned_tx.output[0].script_pubkey)
+            .unwrap());
+        assert_eq!(details.sent, 0);
+        assert_eq!(details.received, 50_000 - details.fee.unwrap_or(0));
+        // nothing is stored in the database of the wallet
+        assert_eq!(wallet.list_unspent().unwrap().len(), 1);
+
+        #[cfg(feature = "verify")]
+        {
+            let tx = psbt.extract_tx();
+            let serialized_tx = serialize(&tx);
+            for (n, input) in tx.input.iter().enumerate() {
+                let prev_tx = sweep_wallets
+                    .iter()
+                    .find_map(|w| {
+                        w.database()
+                            .get_raw_tx(&input.previous_output.txid)
+                            .unwrap()
+                    })
+                    .unwrap();
+                let prevout = &prev_tx.output[input.previous_output.vout as usize];
+                bitcoinconsensus::verify(
+                    &prevout.script_pubkey.to_bytes(),
+                    prevout.value,
+                    &serialized_tx,
+                    n,
+                )
+                .unwrap();
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "async-interface"))]
+    fn test_build_sweep() {
+        use crate::blockchain::{Capability, GetBlockHash, GetTx, Progress};
+        use crate::database::BatchDatabase;
+        use bitcoin::util::bip32::{DerivationPath, ExtendedPrivKey};
+        use bitcoin::BlockHash;
+
+        struct FundedScripts(Vec<(Script, u64)>);
+
+        impl Blockchain for FundedScripts {
+            fn get_capabilities(&self) -> HashSet<Capability> {
+                HashSet::new()
+            }
+            fn broadcast(&self, _tx: &Transaction) -> Result<(), Error> {
+                Ok(())
+            }
+            fn estimate_fee(&self, _target: usize) -> Result<FeeRate, Error> {
+                Ok(FeeRate::from_sat_per_vb(3.0))
+            }
+        }
+
+        impl GetHeight for FundedScripts {
+            fn get_height(&self) -> Result<u32, Error> {
+                Ok(100)
+            }
+        }
 
-    #[test]
-    fn test_bump_fee_absolute_force_add_input() {
-        let (wallet, descriptors, _) = get_funded_wallet(get_test_wpkh());
-        let incoming_txid = crate::populate_test_db!(
-            wallet.database.borrow_mut(),
-            testutils! (@tx ( (@external descriptors, 0) => 25_000 ) (@confirmations 1)),
-            Some(100),
-        );
+        impl GetTx for FundedScripts {
+            fn get_tx(&self, _txid: &Txid) -> Result<Option<Transaction>, Error> {
+                Ok(None)
+            }
+        }
 
-        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
-        let mut builder = wallet.build_tx();
-        builder
-            .add_recipient(addr.script_pubkey(), 45_000)
-            .enable_rbf();
-        let (psbt, mut original_details) = builder.finish().unwrap();
-        let mut tx = psbt.extract_tx();
-        let txid = tx.txid();
-        // skip saving the new utxos, we know they can't be used anyways
-        for txin in &mut tx.input {
-            txin.witness.push([0x00; P2WPKH_FAKE_WITNESS_SIZE]); // fake signature
-            wallet
-                .database
-                .borrow_mut()
-                .del_utxo(&txin.previous_output)
-                .unwrap();
+        impl GetBlockHash for FundedScripts {
+            fn get_block_hash(&self, _height: u64) -> Result<BlockHash, Error> {
+                Err(Error::Generic("not supported".into()))
+            }
         }
-        original_details.transaction = Some(tx);
-        wallet
-            .database
-            .borrow_mut()
-            .set_tx(&original_details)
-            .unwrap();
 
-        // the new fee_rate is low enough that just reducing the change would be fine, but we force
-        // the addition of an extra input with `add_utxo()`
-        let mut builder = wallet.build_fee_bump(txid).unwrap();
-        builder
-            .add_utxo(OutPoint {
-                txid: incoming_txid,
-                vout: 0,
-            })
-            .unwrap()
-            .fee_absolute(250);
-        let (psbt, details) = builder.finish().unwrap();
+        impl WalletSync for FundedScripts {
+            fn wallet_setup<D: BatchDatabase>(
+                &self,
+                database: &mut D,
+                _progress_update: Box<dyn Progress>,
+            ) -> Result<(), Error> {
+                for script in database.iter_script_pubkeys(None)? {
+                    if let Some((_, value)) = self.0.iter().find(|(s, _)| s == &script) {
+                        let txout = TxOut {
+                            value: *value,
+                            script_pubkey: script.clone(),
+                        };
+                        let transaction = Transaction {
+                            version: 1,
+                            lock_time: PackedLockTime(0),
+                            input: vec![],
+                            output: vec![txout.clone()],
+                        };
+                        let (keychain, _) = database.get_path_from_script_pubkey(&script)?.unwrap();
+                        database.set_utxo(&LocalUtxo {
+                            outpoint: OutPoint::new(transaction.txid(), 0),
+                            txout,
+                            keychain,
+                            is_spent: false,
+                        })?;
+                        database.set_tx(&TransactionDetails {
+                            txid: transaction.txid(),
+                            transaction: Some(transaction),
+                            received: *value,
+                            sent: 0,
+                            fee: Some(0),
+                            confirmation_time: Some(BlockTime {
+                                height: 100,
+                                timestamp: 0,
+                            }),
+                        })?;
+                    }
+                }
 
-        assert_eq!(details.sent, original_details.sent + 25_000);
-        assert_eq!(details.fee.unwrap_or(0) + details.received, 30_000);
+                Ok(())
+            }
+        }
 
-        let tx = &psbt.unsigned_tx;
-        assert_eq!(tx.input.len(), 2);
-        assert_eq!(tx.output.len(), 2);
-        assert_eq!(
-            tx.output
-                .iter()
-                .find(|txout| txout.script_pubkey == addr.script_pubkey())
+        fn script_at<T: IntoWalletDescriptor>(descriptor: T, index: u32) -> Script {
+            Wallet::new(descriptor, None, Network::Regtest, MemoryDatabase::new())
                 .unwrap()
-                .value,
-            45_000
-        );
-        assert_eq!(
-            tx.output
-                .iter()
-                .find(|txout| txout.script_pubkey != addr.script_pubkey())
+                .get_address(Peek(index))
                 .unwrap()
-                .value,
-            details.received
-        );
+                .script_pubkey()
+        }
 
-        assert_eq!(details.fee.unwrap_or(0), 250);
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let key = ExtendedPrivKey::from_str("tprv8ZgxMBicQKsPeZRHk4rTG6orPS2CRNFX3njhUXx5vj9qGog5ZMH4uGReDWN5kCkY3jmWEtWause41CDvBRXD1shKknAMKxT99o9qUTRVC6m").unwrap();
+        let blockchain = FundedScripts(vec![
+            (script_at(Bip44(key, KeychainKind::External), 0), 20_000),
+            (script_at(Bip84(key, KeychainKind::Internal), 3), 30_000),
+        ]);
+
+        let (psbt, details) = wallet
+            .build_sweep(vec![(key, DerivationPath::master())], &blockchain)
+            .unwrap();
+        assert_eq!(psbt.inputs.len(), 2);
+        assert!(psbt
+            .inputs
+            .iter()
+            .all(|input| input.final_script_sig.is_some() || input.final_script_witness.is_some()));
+        assert_eq!(psbt.unsigned_tx.output.len(), 1);
+        assert!(wallet
+            .is_mine(&psbt.unsigned_tx.output[0].script_pubkey)
+            .unwrap());
+        assert_eq!(details.received, 50_000 - details.fee.unwrap_or(0));
+
+        let tx = psbt.extract_tx();
+        let fee_rate = FeeRate::from_wu(details.fee.unwrap_or(0), tx.weight());
+        assert!(fee_rate.as_sat_per_vb() >= 3.0);
+
+        #[cfg(feature = "verify")]
+        {
+            let serialized_tx = serialize(&tx);
+            for (n, input) in tx.input.iter().enumerate() {
+                let (script_pubkey, value) = blockchain
+                    .0
+                    .iter()
+                    .find(|(script_pubkey, value)| {
+                        let prev_tx = Transaction {
+                            version: 1,
+                            lock_time: PackedLockTime(0),
+                            input: vec![],
+                            output: vec![TxOut {
+                                value: *value,
+                                script_pubkey: script_pubkey.clone(),
+                            }],
+                        };
+                        prev_tx.txid() == input.previous_output.txid
+                    })
+                    .unwrap();
+                bitcoinconsensus::verify(&script_pubkey.to_bytes(), *value, &serialized_tx, n)
+                    .unwrap();
+            }
+        }
     }
 
     #[test]
-    #[should_panic(expected = "InsufficientFunds")]
-    fn test_bump_fee_unconfirmed_inputs_only() {
-        // We try to bump the fee, but:
-        // - We can't reduce the change, as we have no change
-        // - All our UTXOs are unconfirmed
-        // So, we fail with "InsufficientFunds", as per RBF rule 2:
-        // The replacement transaction may only include an unconfirmed input
-        // if that input was included in one of the original transactions.
+    fn test_max_sendable() {
         let (wallet, descriptors, _) = get_funded_wallet(get_test_wpkh());
-        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
-        let mut builder = wallet.build_tx();
-        builder
-            .drain_wallet()
-            .drain_to(addr.script_pubkey())
-            .enable_rbf();
-        let (psbt, mut original_details) = builder.finish().unwrap();
-        // Now we receive one transaction with 0 confirmations. We won't be able to use that for
-        // fee bumping, as it's still unconfirmed!
+        let txid = crate::populate_test_db!(
+            wallet.database.borrow_mut(),
+            testutils! (@tx ( (@external descriptors, 0) => 20_000 ) (@confirmations 1)),
+            Some(100),
+        );
+        // not worth spending at 5 sat/vbyte
         crate::populate_test_db!(
             wallet.database.borrow_mut(),
-            testutils! (@tx ( (@external descriptors, 0) => 25_000 ) (@confirmations 0)),
+            testutils! (@tx ( (@external descriptors, 0) => 300 ) (@confirmations 1)),
             Some(100),
         );
-        let mut tx = psbt.extract_tx();
-        let txid = tx.txid();
-        for txin in &mut tx.input {
-            txin.witness.push([0x00; P2WPKH_FAKE_WITNESS_SIZE]); // fake signature
-            wallet
-                .database
-                .borrow_mut()
-                .del_utxo(&txin.previous_output)
-                .unwrap();
-        }
-        original_details.transaction = Some(tx);
-        wallet
-            .database
-            .borrow_mut()
-            .set_tx(&original_details)
-            .unwrap();
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let fee_rate = FeeRate::from_sat_per_vb(5.0);
 
-        let mut builder = wallet.build_fee_bump(txid).unwrap();
-        builder.fee_rate(FeeRate::from_sat_per_vb(25.0));
-        builder.finish().unwrap();
+        let mut builder = wallet.build_tx();
+        builder.fee_rate(fee_rate);
+        let max = builder.max_sendable(&addr.script_pubkey()).unwrap();
+        assert!(max > 69_000);
+
+        // the whole amount can actually be sent
+        builder.add_recipient(addr.script_pubkey(), max);
+        let (psbt, details) = builder.finish().unwrap();
+        assert_eq!(psbt.unsigned_tx.output.len(), 1);
+        assert_eq!(psbt.unsigned_tx.output[0].value, max);
+        assert_eq!(psbt.unsigned_tx.input.len(), 2);
+        assert_fee_rate!(psbt, details.fee.unwrap_or(0), fee_rate, @add_signature);
+
+        // excluding a UTXO lowers the maximum
+        let mut builder = wallet.build_tx();
+        builder
+            .fee_rate(fee_rate)
+            .add_unspendable(OutPoint::new(txid, 0));
+        let max_without = builder.max_sendable(&addr.script_pubkey()).unwrap();
+        assert_eq!(max_without, max - 20_000 + 340);
+
+        // the recipients already in the builder are taken into account
+        let mut builder = wallet.build_tx();
+        builder
+            .fee_rate(fee_rate)
+            .add_recipient(addr.script_pubkey(), 10_000);
+        let max_with_recipient = builder.max_sendable(&addr.script_pubkey()).unwrap();
+        // a p2sh output is 32 vbytes
+        assert_eq!(max_with_recipient, max - 10_000 - 160);
     }
 
     #[test]
-    fn test_bump_fee_unconfirmed_input() {
-        // We create a tx draining the wallet and spending one confirmed
-        // and one unconfirmed UTXO. We check that we can fee bump normally
-        // (BIP125 rule 2 only apply to newly added unconfirmed input, you can
-        // always fee bump with an unconfirmed input if it was included in the
-        // original transaction)
+    fn test_max_sendable_manually_selected() {
         let (wallet, descriptors, _) = get_funded_wallet(get_test_wpkh());
-        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
-        // We receive a tx with 0 confirmations, which will be used as an input
-        // in the drain tx.
-        crate::populate_test_db!(
+        let txid = crate::populate_test_db!(
             wallet.database.borrow_mut(),
-            testutils! (@tx ( (@external descriptors, 0) => 25_000 ) (@confirmations 0)),
+            testutils! (@tx ( (@external descriptors, 0) => 20_000 ) (@confirmations 1)),
             Some(100),
         );
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+
         let mut builder = wallet.build_tx();
         builder
-            .drain_wallet()
-            .drain_to(addr.script_pubkey())
-            .enable_rbf();
-        let (psbt, mut original_details) = builder.finish().unwrap();
-        let mut tx = psbt.extract_tx();
-        let txid = tx.txid();
-        for txin in &mut tx.input {
-            txin.witness.push([0x00; P2WPKH_FAKE_WITNESS_SIZE]); // fake signature
-            wallet
-                .database
-                .borrow_mut()
-                .del_utxo(&txin.previous_output)
-                .unwrap();
-        }
-        original_details.transaction = Some(tx);
-        wallet
-            .database
-            .borrow_mut()
-            .set_tx(&original_details)
-            .unwrap();
+            .add_utxo(OutPoint::new(txid, 0))
+            .unwrap()
+            .manually_selected_only()
+            .fee_absolute(1_000);
+        assert_eq!(builder.max_sendable(&addr.script_pubkey()).unwrap(), 19_000);
 
-        let mut builder = wallet.build_fee_bump(txid).unwrap();
+        let mut builder = wallet.build_tx();
         builder
-            .fee_rate(FeeRate::from_sat_per_vb(15.0))
-            .allow_shrinking(addr.script_pubkey())
+            .add_utxo(OutPoint::new(txid, 0))
+            .unwrap()
+            .manually_selected_only()
+            .fee_absolute(19_800);
+        assert!(matches!(
+            builder.max_sendable(&addr.script_pubkey()),
+            Err(Error::InsufficientFunds { .. })
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "SpendingPolicyRequired(External)")]
+    fn test_max_sendable_policy_path_required() {
+        let (wallet, _, _) = get_funded_wallet(get_test_a_or_b_plus_csv());
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        wallet
+            .build_tx()
+            .max_sendable(&addr.script_pubkey())
             .unwrap();
-        builder.finish().unwrap();
     }
 
     #[test]
@@ -5582,4 +8875,130 @@ pub(crate) mod test {
             "bcrt1pvysh4nmh85ysrkpwtrr8q8gdadhgdejpy6f9v424a8v9htjxjhyqw9c5s5"
         );
     }
+
+    fn get_test_silent_payment_keychain() -> SilentPaymentKeychain {
+        let secp = Secp256k1::new();
+        SilentPaymentKeychain::new(
+            bitcoin::secp256k1::SecretKey::from_slice(&[0x11; 32]).unwrap(),
+            bitcoin::secp256k1::SecretKey::from_slice(&[0x22; 32])
+                .unwrap()
+                .public_key(&secp),
+        )
+    }
+
+    fn test_silent_payment_send_and_scan(descriptor: &str) {
+        let (mut wallet, _, _) = get_funded_wallet(descriptor);
+        wallet.set_silent_payment_keychain(get_test_silent_payment_keychain());
+        let address = wallet.get_silent_payment_address().unwrap();
+
+        let mut builder = wallet.build_tx();
+        builder.add_silent_payment_recipient(address, 25_000);
+        let (mut psbt, mut details) = builder.finish().unwrap();
+        assert_eq!(details.txid, psbt.unsigned_tx.txid());
+        assert!(!psbt
+            .unsigned_tx
+            .output
+            .iter()
+            .any(|txout| txout.script_pubkey == address.placeholder_script()));
+
+        let finalized = wallet.sign(&mut psbt, Default::default()).unwrap();
+        assert!(finalized);
+
+        details.transaction = Some(psbt.extract_tx());
+        wallet.database.borrow_mut().set_tx(&details).unwrap();
+
+        let found = wallet.scan_silent_payments().unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].outpoint.txid, details.txid);
+        assert_eq!(found[0].txout.value, 25_000);
+    }
+
+    #[test]
+    fn test_silent_payment_wpkh() {
+        test_silent_payment_send_and_scan(get_test_wpkh());
+    }
+
+    #[test]
+    fn test_silent_payment_taproot() {
+        test_silent_payment_send_and_scan(get_test_tr_single_sig_xprv());
+    }
+
+    #[test]
+    fn test_silent_payment_taproot_with_taptree() {
+        test_silent_payment_send_and_scan(get_test_tr_with_taptree_xprv());
+    }
+
+    #[test]
+    fn test_silent_payment_bip69_ordering() {
+        let (mut wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        wallet.set_silent_payment_keychain(get_test_silent_payment_keychain());
+        let address = wallet.get_silent_payment_address().unwrap();
+
+        let mut builder = wallet.build_tx();
+        builder
+            .add_silent_payment_recipient(address, 10_000)
+            .ordering(super::tx_builder::TxOrdering::Bip69Lexicographic);
+        let (psbt, _) = builder.finish().unwrap();
+        let silent_payment_script = psbt
+            .unsigned_tx
+            .output
+            .iter()
+            .find(|txout| txout.value == 10_000)
+            .unwrap()
+            .script_pubkey
+            .clone();
+
+        // a script that sorts between the placeholder and the real output, so the two outputs
+        // with the same value end up in the wrong order if they are only sorted once
+        let placeholder = address.placeholder_script();
+        let mut other_script = std::cmp::min(&placeholder, &silent_payment_script).to_bytes();
+        other_script.push(0x00);
+        let other_script = Script::from(other_script);
+
+        let mut builder = wallet.build_tx();
+        builder
+            .add_recipient(other_script.clone(), 10_000)
+            .add_silent_payment_recipient(address, 10_000)
+            .ordering(super::tx_builder::TxOrdering::Bip69Lexicographic);
+        let (psbt, _) = builder.finish().unwrap();
+
+        let outputs = &psbt.unsigned_tx.output;
+        assert_eq!(outputs.len(), 3);
+        assert!(outputs
+            .iter()
+            .any(|txout| txout.script_pubkey == silent_payment_script));
+        assert!(outputs
+            .windows(2)
+            .all(|w| (w[0].value, &w[0].script_pubkey) <= (w[1].value, &w[1].script_pubkey)));
+        // the psbt outputs are sorted along with the transaction outputs
+        for (txout, psbt_output) in outputs.iter().zip(&psbt.outputs) {
+            assert_eq!(
+                wallet.is_mine(&txout.script_pubkey).unwrap(),
+                !psbt_output.bip32_derivation.is_empty()
+            );
+        }
+    }
+
+    #[test]
+    fn test_silent_payment_missing_input_key() {
+        let (wallet, _, _) = get_funded_wallet("wpkh(tpubD6NzVbkrYhZ4Xferm7Pz4VnjdcDPFyjVu5K4iZXQ4pVN8Cks4pHVowTBXBKRhX64pkRyJZJN5xAKj4UDNnLPb5p2sSKXhewoYx5GbTdUFWq/*)");
+        let secp = Secp256k1::new();
+        let address = get_test_silent_payment_keychain().address(Network::Regtest, &secp);
+
+        let mut builder = wallet.build_tx();
+        builder.add_silent_payment_recipient(address, 25_000);
+        assert!(matches!(
+            builder.finish(),
+            Err(Error::SilentPayment(SilentPaymentError::MissingInputKey(_)))
+        ));
+    }
+
+    #[test]
+    fn test_silent_payment_missing_keychain() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        assert!(matches!(
+            wallet.get_silent_payment_address(),
+            Err(Error::SilentPayment(SilentPaymentError::MissingKeychain))
+        ));
+    }
 }
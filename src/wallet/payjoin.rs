@@ -0,0 +1,1033 @@
+// Bitcoin Dev Kit
+//
+// Copyright (c) 2020-2022 Bitcoin Dev Kit Developers
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Payjoin
+//!
+//! This module implements both sides of a [BIP78] payjoin, where the receiver of a payment adds
+//! one of its own inputs to the transaction, breaking the common-input-ownership heuristic.
+//!
+//! The sender builds and signs a transaction as usual, then wraps it in a [`PayjoinSender`] and
+//! sends it to the receiver's endpoint through a [`PayjoinTransport`]. The receiver answers
+//! with a *proposal*, built by a [`PayjoinReceiver`], that the sender validates, signs again and
+//! broadcasts. If anything goes wrong the sender can still broadcast the original transaction.
+//!
+//! The transport is left to the caller: an HTTP client in production, or an in-process stand-in
+//! that calls [`PayjoinReceiver::handle_request`] directly.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! # use std::str::FromStr;
+//! # use bitcoin::*;
+//! # use bdk::*;
+//! # use bdk::database::*;
+//! # use bdk::wallet::payjoin::*;
+//! # let descriptor = "wpkh(tpubD6NzVbkrYhZ4Xferm7Pz4VnjdcDPFyjVu5K4iZXQ4pVN8Cks4pHVowTBXBKRhX64pkRyJZJN5xAKj4UDNnLPb5p2sSKXhewoYx5GbTdUFWq/*)";
+//! # let wallet = doctest_wallet!();
+//! # let to_address = Address::from_str("2N4eQYCbKUHCCTUjBJeHcJp9ok6J2GZsTDt").unwrap();
+//! struct HttpTransport;
+//!
+//! impl PayjoinTransport for HttpTransport {
+//!     fn post(&self, url: &str, body: &str) -> Result<String, PayjoinError> {
+//!         // POST `body` to `url` with your favourite HTTP client
+//!         # Err(PayjoinError::Transport("no HTTP client".into()))
+//!     }
+//! }
+//!
+//! let (mut psbt, _) = {
+//!     let mut builder = wallet.build_tx();
+//!     builder.add_recipient(to_address.script_pubkey(), 50_000);
+//!     builder.finish()?
+//! };
+//! wallet.sign(&mut psbt, SignOptions::default())?;
+//!
+//! let sender = PayjoinSender::new(psbt, to_address.script_pubkey(), PayjoinParams::default())?;
+//! let mut proposal = sender.send(&wallet, &HttpTransport, "https://example.com/payjoin")?;
+//! wallet.sign(&mut proposal, SignOptions::default())?;
+//!
+//! // broadcast the proposal ...
+//! # Ok::<(), bdk::Error>(())
+//! ```
+//!
+//! [BIP78]: https://github.com/bitcoin/bips/blob/master/bip-0078.mediawiki
+
+use std::cmp;
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+
+use bitcoin::util::psbt::{self, PartiallySignedTransaction as Psbt};
+use bitcoin::{OutPoint, Script, TxIn, TxOut, Witness};
+use rand::Rng;
+
+use crate::database::BatchDatabase;
+use crate::psbt::PsbtUtils;
+use crate::wallet::coin_selection::TXIN_BASE_WEIGHT;
+use crate::wallet::signer::SignOptions;
+use crate::{Error, FeeRate, Wallet};
+
+/// Errors that can be thrown while building or validating a payjoin
+#[derive(Debug)]
+pub enum PayjoinError {
+    /// Error returned by the [`PayjoinTransport`]
+    Transport(String),
+    /// The query parameters of the request are invalid
+    InvalidParams(String),
+    /// The original PSBT can't be used for a payjoin
+    OriginalPsbtRejected(String),
+    /// The receiver doesn't have any UTXO that can be added to the transaction
+    NoAvailableUtxo,
+    /// The proposal changes the version or the locktime of the original transaction
+    TransactionChanged,
+    /// An input of the original PSBT is missing from the proposal
+    MissingSenderInput(OutPoint),
+    /// A sender input of the proposal has a different sequence or contains signatures or key
+    /// paths
+    SenderInputChanged(OutPoint),
+    /// The proposal adds an input owned by the sender
+    NewSenderInput(OutPoint),
+    /// An input added by the receiver is not finalized or is missing its previous output
+    InvalidReceiverInput(OutPoint),
+    /// The proposal has inputs of different script types
+    MixedInputTypes,
+    /// An output of the sender was removed or reduced
+    SenderOutputChanged(Script),
+    /// The receiver changed the payee output, but output substitution was disabled
+    OutputSubstitutionDisabled,
+    /// The receiver took more than allowed from the sender to pay for its inputs
+    FeeContributionTooHigh {
+        /// Maximum contribution allowed
+        max: u64,
+        /// Contribution in the proposal
+        actual: u64,
+    },
+    /// The fee rate of the proposal is lower than [`PayjoinParams::min_fee_rate`]
+    FeeRateTooLow {
+        /// Required fee rate
+        required: FeeRate,
+    },
+}
+
+impl fmt::Display for PayjoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Transport(e) => write!(f, "Transport error: {}", e),
+            Self::InvalidParams(e) => write!(f, "Invalid payjoin parameters: {}", e),
+            Self::OriginalPsbtRejected(e) => write!(f, "Original PSBT rejected: {}", e),
+            Self::NoAvailableUtxo => write!(f, "No UTXO available for the payjoin"),
+            Self::TransactionChanged => {
+                write!(
+                    f,
+                    "The proposal changes the transaction version or locktime"
+                )
+            }
+            Self::MissingSenderInput(outpoint) => {
+                write!(f, "Sender input {} missing from the proposal", outpoint)
+            }
+            Self::SenderInputChanged(outpoint) => {
+                write!(f, "Sender input {} changed in the proposal", outpoint)
+            }
+            Self::NewSenderInput(outpoint) => {
+                write!(f, "The proposal adds the sender input {}", outpoint)
+            }
+            Self::InvalidReceiverInput(outpoint) => {
+                write!(f, "Invalid receiver input {}", outpoint)
+            }
+            Self::MixedInputTypes => write!(f, "The proposal mixes different input types"),
+            Self::SenderOutputChanged(script) => {
+                write!(f, "Sender output {} changed in the proposal", script)
+            }
+            Self::OutputSubstitutionDisabled => {
+                write!(
+                    f,
+                    "The payee output was substituted, but substitution is disabled"
+                )
+            }
+            Self::FeeContributionTooHigh { max, actual } => write!(
+                f,
+                "Fee contribution too high: {} sats taken, at most {} allowed",
+                actual, max
+            ),
+            Self::FeeRateTooLow { required } => write!(
+                f,
+                "Proposal fee rate too low: at least {} sat/vbyte required",
+                required.as_sat_per_vb()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PayjoinError {}
+
+/// Transport used to send the original PSBT to the receiver
+///
+/// In BIP78 this is an HTTP `POST` request, with the base64-encoded PSBT as body and the
+/// [`PayjoinParams`] in the query string of `url`.
+pub trait PayjoinTransport {
+    /// Send `body` to `url` and return the body of the response
+    fn post(&self, url: &str, body: &str) -> Result<String, PayjoinError>;
+}
+
+/// Parameters of a payjoin request, sent by the sender in the query string
+#[derive(Debug, Clone, Default)]
+pub struct PayjoinParams {
+    /// Index of the sender output that can be reduced to pay for the receiver's input
+    pub additional_fee_output_index: Option<usize>,
+    /// Maximum amount that can be taken from the output at `additional_fee_output_index`
+    pub max_additional_fee_contribution: u64,
+    /// Forbid the receiver from changing the script of the payee output or lowering its value
+    pub disable_output_substitution: bool,
+    /// Minimum fee rate of the proposal
+    pub min_fee_rate: Option<FeeRate>,
+}
+
+impl PayjoinParams {
+    /// Encode the parameters as a query string
+    pub fn to_query(&self) -> String {
+        let mut query = vec!["v=1".to_string()];
+        if let Some(index) = self.additional_fee_output_index {
+            query.push(format!("additionalfeeoutputindex={}", index));
+            query.push(format!(
+                "maxadditionalfeecontribution={}",
+                self.max_additional_fee_contribution
+            ));
+        }
+        if self.disable_output_substitution {
+            query.push("disableoutputsubstitution=true".to_string());
+        }
+        if let Some(rate) = self.min_fee_rate {
+            query.push(format!("minfeerate={}", rate.as_sat_per_vb()));
+        }
+
+        query.join("&")
+    }
+
+    /// Parse the parameters from a query string
+    ///
+    /// Unknown parameters are ignored.
+    pub fn from_query(query: &str) -> Result<Self, PayjoinError> {
+        let mut params = PayjoinParams::default();
+        let mut max_contribution = None;
+
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let invalid = || PayjoinError::InvalidParams(pair.to_string());
+
+            match key {
+                "v" if value == "1" => {}
+                "v" => return Err(invalid()),
+                "additionalfeeoutputindex" => {
+                    params.additional_fee_output_index = Some(value.parse().map_err(|_| invalid())?)
+                }
+                "maxadditionalfeecontribution" => {
+                    max_contribution = Some(value.parse().map_err(|_| invalid())?)
+                }
+                "disableoutputsubstitution" => params.disable_output_substitution = value == "true",
+                "minfeerate" => {
+                    let rate = value
+                        .parse::<f32>()
+                        .ok()
+                        .filter(|rate| {
+                            (rate.is_normal() || *rate == 0.0) && rate.is_sign_positive()
+                        })
+                        .ok_or_else(invalid)?;
+                    params.min_fee_rate = Some(FeeRate::from_sat_per_vb(rate));
+                }
+                _ => {}
+            }
+        }
+
+        // The fee output can only be used if both parameters are present
+        match max_contribution {
+            Some(max) if params.additional_fee_output_index.is_some() => {
+                params.max_additional_fee_contribution = max
+            }
+            _ => params.additional_fee_output_index = None,
+        }
+
+        Ok(params)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InputType {
+    P2pkh,
+    P2shP2wpkh,
+    P2wpkh,
+    P2tr,
+}
+
+impl InputType {
+    fn from_input(psbt_input: &psbt::Input, utxo: &TxOut) -> Option<Self> {
+        let script_pubkey = &utxo.script_pubkey;
+        if script_pubkey.is_p2pkh() {
+            Some(InputType::P2pkh)
+        } else if script_pubkey.is_v0_p2wpkh() {
+            Some(InputType::P2wpkh)
+        } else if script_pubkey.is_v1_p2tr() {
+            Some(InputType::P2tr)
+        } else if script_pubkey.is_p2sh() {
+            let redeem_script = psbt_input.redeem_script.clone().or_else(|| {
+                psbt_input
+                    .final_script_sig
+                    .as_ref()
+                    .and_then(|script_sig| script_sig.as_bytes().get(1..))
+                    .map(|bytes| Script::from(bytes.to_vec()))
+            });
+            redeem_script
+                .filter(Script::is_v0_p2wpkh)
+                .map(|_| InputType::P2shP2wpkh)
+        } else {
+            None
+        }
+    }
+
+    // Weight of a signed input of this type, assuming 72 bytes ECDSA signatures
+    fn expected_weight(&self) -> usize {
+        TXIN_BASE_WEIGHT
+            + match self {
+                InputType::P2pkh => 4 * (1 + 1 + 72 + 1 + 33),
+                InputType::P2shP2wpkh => 4 * (1 + 23) + (1 + 1 + 72 + 1 + 33),
+                InputType::P2wpkh => 4 + (1 + 1 + 72 + 1 + 33),
+                InputType::P2tr => 4 + (1 + 1 + 64),
+            }
+    }
+}
+
+fn is_finalized(psbt_input: &psbt::Input) -> bool {
+    psbt_input.final_script_sig.is_some() || psbt_input.final_script_witness.is_some()
+}
+
+struct OriginalInfo {
+    input_type: InputType,
+    utxos: Vec<TxOut>,
+    fee: u64,
+    weight: usize,
+}
+
+impl OriginalInfo {
+    fn fee_rate(&self) -> FeeRate {
+        FeeRate::from_wu(self.fee, self.weight)
+    }
+}
+
+// Make sure the original PSBT is a finalized transaction with inputs of the same, supported type
+fn check_original(original: &Psbt) -> Result<OriginalInfo, PayjoinError> {
+    let rejected = |reason: &str| PayjoinError::OriginalPsbtRejected(reason.to_string());
+
+    let mut input_type = None;
+    let mut utxos = vec![];
+    for (index, (psbt_input, txin)) in original
+        .inputs
+        .iter()
+        .zip(original.unsigned_tx.input.iter())
+        .enumerate()
+    {
+        if !is_finalized(psbt_input) {
+            return Err(rejected("not all the inputs are finalized"));
+        }
+        let utxo = original
+            .get_utxo_for(index)
+            .ok_or_else(|| rejected("missing previous output"))?;
+        let this_type = InputType::from_input(psbt_input, &utxo).ok_or_else(|| {
+            PayjoinError::OriginalPsbtRejected(format!(
+                "unsupported script type for input {}",
+                txin.previous_output
+            ))
+        })?;
+        if *input_type.get_or_insert(this_type) != this_type {
+            return Err(rejected("mixed input types"));
+        }
+
+        utxos.push(utxo);
+    }
+    let input_type = input_type.ok_or_else(|| rejected("no inputs"))?;
+
+    let input_value = utxos.iter().map(|utxo| utxo.value).sum::<u64>();
+    let output_value = original
+        .unsigned_tx
+        .output
+        .iter()
+        .map(|txout| txout.value)
+        .sum::<u64>();
+    let fee = input_value
+        .checked_sub(output_value)
+        .ok_or_else(|| rejected("outputs exceed inputs"))?;
+
+    Ok(OriginalInfo {
+        input_type,
+        utxos,
+        fee,
+        weight: original.clone().extract_tx().weight(),
+    })
+}
+
+/// The sending side of a payjoin
+#[derive(Debug, Clone)]
+pub struct PayjoinSender {
+    original: Psbt,
+    payee: Script,
+    params: PayjoinParams,
+}
+
+impl PayjoinSender {
+    /// Create a new payjoin request
+    ///
+    /// `original` must be a signed and finalized PSBT, usually created with
+    /// [`TxBuilder::finish`](crate::wallet::tx_builder::TxBuilder::finish) and
+    /// [`Wallet::sign`]. `payee` is the script of the output paying the receiver.
+    pub fn new(original: Psbt, payee: Script, params: PayjoinParams) -> Result<Self, PayjoinError> {
+        check_original(&original)?;
+
+        let outputs = &original.unsigned_tx.output;
+        if !outputs.iter().any(|txout| txout.script_pubkey == payee) {
+            return Err(PayjoinError::OriginalPsbtRejected(
+                "missing payee output".into(),
+            ));
+        }
+        if let Some(index) = params.additional_fee_output_index {
+            if outputs.get(index).map(|txout| &txout.script_pubkey) == Some(&payee)
+                || index >= outputs.len()
+            {
+                return Err(PayjoinError::InvalidParams(
+                    "invalid additional fee output".into(),
+                ));
+            }
+        }
+
+        Ok(PayjoinSender {
+            original,
+            payee,
+            params,
+        })
+    }
+
+    /// Return the original PSBT, which can be broadcast if the payjoin fails
+    pub fn original_psbt(&self) -> &Psbt {
+        &self.original
+    }
+
+    /// Return the URL and the body of the request to send to `endpoint`
+    pub fn request(&self, endpoint: &str) -> (String, String) {
+        let separator = if endpoint.contains('?') { '&' } else { '?' };
+        let url = format!("{}{}{}", endpoint, separator, self.params.to_query());
+
+        (url, self.original.to_string())
+    }
+
+    /// Send the request through `transport` and validate the response
+    ///
+    /// See [`PayjoinSender::process_proposal`].
+    pub fn send<D: BatchDatabase, T: PayjoinTransport>(
+        &self,
+        wallet: &Wallet<D>,
+        transport: &T,
+        endpoint: &str,
+    ) -> Result<Psbt, Error> {
+        let (url, body) = self.request(endpoint);
+        let response = transport.post(&url, &body)?;
+
+        self.process_proposal(wallet, Psbt::from_str(&response)?)
+    }
+
+    /// Validate the proposal sent back by the receiver
+    ///
+    /// On success the metadata of the sender's inputs and outputs is restored from the original
+    /// PSBT, so the returned PSBT can be signed with [`Wallet::sign`].
+    pub fn process_proposal<D: BatchDatabase>(
+        &self,
+        wallet: &Wallet<D>,
+        mut proposal: Psbt,
+    ) -> Result<Psbt, Error> {
+        let original = check_original(&self.original)?;
+        let original_tx = &self.original.unsigned_tx;
+
+        if proposal.unsigned_tx.version != original_tx.version
+            || proposal.unsigned_tx.lock_time != original_tx.lock_time
+        {
+            return Err(PayjoinError::TransactionChanged.into());
+        }
+
+        let mut sender_inputs = HashSet::new();
+        let mut receiver_inputs = vec![];
+        for (index, txin) in proposal.unsigned_tx.input.iter().enumerate() {
+            let outpoint = txin.previous_output;
+            let psbt_input = &mut proposal.inputs[index];
+
+            match original_tx
+                .input
+                .iter()
+                .position(|original_txin| original_txin.previous_output == outpoint)
+            {
+                Some(original_index) => {
+                    if txin.sequence != original_tx.input[original_index].sequence
+                        || is_finalized(psbt_input)
+                        || !psbt_input.partial_sigs.is_empty()
+                        || !psbt_input.bip32_derivation.is_empty()
+                        || psbt_input.tap_key_sig.is_some()
+                        || !psbt_input.tap_script_sigs.is_empty()
+                        || !psbt_input.tap_key_origins.is_empty()
+                    {
+                        return Err(PayjoinError::SenderInputChanged(outpoint).into());
+                    }
+
+                    // Restore the metadata of the original PSBT, without the signatures
+                    *psbt_input = psbt::Input {
+                        final_script_sig: None,
+                        final_script_witness: None,
+                        ..self.original.inputs[original_index].clone()
+                    };
+                    sender_inputs.insert(outpoint);
+                }
+                None => {
+                    let utxo = match (
+                        psbt_input.witness_utxo.clone(),
+                        &psbt_input.non_witness_utxo,
+                    ) {
+                        (Some(utxo), _) => Some(utxo),
+                        (None, Some(prev_tx)) => {
+                            prev_tx.output.get(outpoint.vout as usize).cloned()
+                        }
+                        (None, None) => None,
+                    };
+                    let utxo = match utxo {
+                        Some(utxo) if is_finalized(psbt_input) => utxo,
+                        _ => return Err(PayjoinError::InvalidReceiverInput(outpoint).into()),
+                    };
+                    if InputType::from_input(psbt_input, &utxo) != Some(original.input_type) {
+                        return Err(PayjoinError::MixedInputTypes.into());
+                    }
+
+                    receiver_inputs.push((outpoint, utxo));
+                }
+            }
+        }
+
+        if let Some(txin) = original_tx
+            .input
+            .iter()
+            .find(|txin| !sender_inputs.contains(&txin.previous_output))
+        {
+            return Err(PayjoinError::MissingSenderInput(txin.previous_output).into());
+        }
+        for (outpoint, utxo) in &receiver_inputs {
+            if wallet.is_mine(&utxo.script_pubkey)? {
+                return Err(PayjoinError::NewSenderInput(*outpoint).into());
+            }
+        }
+
+        let proposal_outputs = &proposal.unsigned_tx.output;
+        let mut restored_outputs = vec![psbt::Output::default(); proposal_outputs.len()];
+        let mut used_outputs = HashSet::new();
+        let mut contribution = 0;
+        for (index, original_txout) in original_tx.output.iter().enumerate() {
+            if original_txout.script_pubkey == self.payee {
+                if self.params.disable_output_substitution
+                    && !proposal_outputs.iter().any(|txout| {
+                        txout.script_pubkey == self.payee && txout.value >= original_txout.value
+                    })
+                {
+                    return Err(PayjoinError::OutputSubstitutionDisabled.into());
+                }
+                continue;
+            }
+
+            let changed =
+                || PayjoinError::SenderOutputChanged(original_txout.script_pubkey.clone());
+            let vout = (0..proposal_outputs.len())
+                .find(|vout| {
+                    proposal_outputs[*vout].script_pubkey == original_txout.script_pubkey
+                        && !used_outputs.contains(vout)
+                })
+                .ok_or_else(changed)?;
+            used_outputs.insert(vout);
+            restored_outputs[vout] = self.original.outputs[index].clone();
+
+            let value = proposal_outputs[vout].value;
+            if value < original_txout.value {
+                if self.params.additional_fee_output_index != Some(index) {
+                    return Err(changed().into());
+                }
+                contribution = original_txout.value - value;
+            }
+        }
+
+        // The sender only pays for the receiver's inputs, at the original fee rate
+        let added_weight = receiver_inputs.len() * original.input_type.expected_weight();
+        let max_contribution = match self.params.additional_fee_output_index {
+            Some(_) => cmp::min(
+                self.params.max_additional_fee_contribution,
+                original.fee_rate().fee_wu(added_weight),
+            ),
+            None => 0,
+        };
+        if contribution > max_contribution {
+            return Err(PayjoinError::FeeContributionTooHigh {
+                max: max_contribution,
+                actual: contribution,
+            }
+            .into());
+        }
+
+        if let Some(min_fee_rate) = self.params.min_fee_rate {
+            let input_value = original
+                .utxos
+                .iter()
+                .chain(receiver_inputs.iter().map(|(_, utxo)| utxo))
+                .map(|utxo| utxo.value)
+                .sum::<u64>();
+            let output_value = proposal_outputs
+                .iter()
+                .map(|txout| txout.value)
+                .sum::<u64>();
+            // Receiver inputs are already counted in the unsigned transaction, except for their
+            // signatures
+            let weight = original.weight + proposal.unsigned_tx.weight() - original_tx.weight()
+                + receiver_inputs.len()
+                    * (original.input_type.expected_weight() - TXIN_BASE_WEIGHT - 4);
+
+            match input_value.checked_sub(output_value) {
+                Some(fee) if fee >= min_fee_rate.fee_wu(weight) => {}
+                _ => {
+                    return Err(PayjoinError::FeeRateTooLow {
+                        required: min_fee_rate,
+                    }
+                    .into())
+                }
+            }
+        }
+
+        proposal.outputs = restored_outputs;
+        proposal.xpub = self.original.xpub.clone();
+
+        Ok(proposal)
+    }
+}
+
+/// The receiving side of a payjoin
+#[derive(Debug)]
+pub struct PayjoinReceiver<'w, D> {
+    wallet: &'w Wallet<D>,
+}
+
+impl<'w, D: BatchDatabase> PayjoinReceiver<'w, D> {
+    /// Create a new receiver that contributes inputs from `wallet`
+    pub fn new(wallet: &'w Wallet<D>) -> Self {
+        PayjoinReceiver { wallet }
+    }
+
+    /// Handle a request, with the [`PayjoinParams`] encoded in `query` and the base64-encoded
+    /// original PSBT in `body`, and return the base64-encoded proposal
+    pub fn handle_request(&self, query: &str, body: &str) -> Result<String, Error> {
+        let params = PayjoinParams::from_query(query)?;
+        let original = Psbt::from_str(body)?;
+
+        Ok(self.process_psbt(original, &params)?.to_string())
+    }
+
+    /// Turn the original PSBT into a proposal
+    ///
+    /// One of the wallet's unspent outputs, of the same type as the sender's inputs, is added to
+    /// the transaction and its value is added to the output paying the wallet, minus the fees for
+    /// the new input that are not covered by the sender. The new input is signed and finalized,
+    /// while the signatures of the sender are removed.
+    pub fn process_psbt(&self, original: Psbt, params: &PayjoinParams) -> Result<Psbt, Error> {
+        let rejected = |reason: &str| PayjoinError::OriginalPsbtRejected(reason.to_string());
+
+        let info = check_original(&original)?;
+        for utxo in &info.utxos {
+            if self.wallet.is_mine(&utxo.script_pubkey)? {
+                return Err(rejected("the original PSBT spends an output of the receiver").into());
+            }
+        }
+
+        let outputs = &original.unsigned_tx.output;
+        let mut receiver_vout = None;
+        for (vout, txout) in outputs.iter().enumerate() {
+            if self.wallet.is_mine(&txout.script_pubkey)? {
+                receiver_vout = Some(vout);
+                break;
+            }
+        }
+        let receiver_vout =
+            receiver_vout.ok_or_else(|| rejected("the original PSBT doesn't pay the receiver"))?;
+        if let Some(index) = params.additional_fee_output_index {
+            if index >= outputs.len() || index == receiver_vout {
+                return Err(
+                    PayjoinError::InvalidParams("invalid additional fee output".into()).into(),
+                );
+            }
+        }
+
+        let original_outpoints = original
+            .unsigned_tx
+            .input
+            .iter()
+            .map(|txin| txin.previous_output)
+            .collect::<HashSet<_>>();
+        let mut contributed = None;
+        for utxo in self.wallet.list_unspent()? {
+            if original_outpoints.contains(&utxo.outpoint) {
+                continue;
+            }
+            let psbt_input = self.wallet.get_psbt_input(utxo.clone(), None, false)?;
+            if (psbt_input.witness_utxo.is_some() || psbt_input.non_witness_utxo.is_some())
+                && InputType::from_input(&psbt_input, &utxo.txout) == Some(info.input_type)
+            {
+                contributed = Some((utxo, psbt_input));
+                break;
+            }
+        }
+        let (utxo, receiver_input) = contributed.ok_or(PayjoinError::NoAvailableUtxo)?;
+
+        let input_weight = info.input_type.expected_weight();
+        let mut additional_fee = info.fee_rate().fee_wu(input_weight);
+        if let Some(min_fee_rate) = params.min_fee_rate {
+            additional_fee = cmp::max(
+                additional_fee,
+                min_fee_rate
+                    .fee_wu(info.weight + input_weight)
+                    .saturating_sub(info.fee),
+            );
+        }
+
+        let mut proposal = original;
+        let sender_contribution = match params.additional_fee_output_index {
+            Some(index) => {
+                let txout = &mut proposal.unsigned_tx.output[index];
+                let contribution = cmp::min(
+                    cmp::min(
+                        info.fee_rate().fee_wu(input_weight),
+                        params.max_additional_fee_contribution,
+                    ),
+                    // Don't turn the sender's output into dust
+                    txout
+                        .value
+                        .saturating_sub(txout.script_pubkey.dust_value().to_sat()),
+                );
+                txout.value -= contribution;
+                contribution
+            }
+            None => 0,
+        };
+        let receiver_txout = &mut proposal.unsigned_tx.output[receiver_vout];
+        receiver_txout.value = (receiver_txout.value + utxo.txout.value)
+            .checked_sub(additional_fee - sender_contribution)
+            .ok_or(PayjoinError::NoAvailableUtxo)?;
+
+        let receiver_index = rand::thread_rng().gen_range(0..=proposal.inputs.len());
+        let sequence = proposal.unsigned_tx.input[0].sequence;
+        proposal.unsigned_tx.input.insert(
+            receiver_index,
+            TxIn {
+                previous_output: utxo.outpoint,
+                script_sig: Script::default(),
+                sequence,
+                witness: Witness::new(),
+            },
+        );
+        proposal.inputs.insert(receiver_index, receiver_input);
+
+        // The sender's inputs are still finalized at this point, so only ours is signed
+        self.wallet.sign(&mut proposal, SignOptions::default())?;
+        if !is_finalized(&proposal.inputs[receiver_index]) {
+            return Err(Error::Generic(
+                "Unable to finalize the payjoin input".into(),
+            ));
+        }
+
+        // Only keep the previous outputs and the finalized receiver input
+        for (index, psbt_input) in proposal.inputs.iter_mut().enumerate() {
+            let (final_script_sig, final_script_witness) = match index == receiver_index {
+                true => (
+                    psbt_input.final_script_sig.take(),
+                    psbt_input.final_script_witness.take(),
+                ),
+                false => (None, None),
+            };
+            *psbt_input = psbt::Input {
+                witness_utxo: psbt_input.witness_utxo.take(),
+                non_witness_utxo: psbt_input.non_witness_utxo.take(),
+                final_script_sig,
+                final_script_witness,
+                ..psbt::Input::default()
+            };
+        }
+        proposal.outputs = vec![psbt::Output::default(); proposal.unsigned_tx.output.len()];
+        proposal.xpub.clear();
+
+        Ok(proposal)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::Address;
+
+    use super::*;
+    use crate::database::AnyDatabase;
+    use crate::wallet::test::get_test_wpkh;
+    use crate::wallet::{get_funded_wallet, AddressIndex};
+
+    const RECEIVER_DESC: &str = "wpkh(tprv8ZgxMBicQKsPd3EupYiPRhaMooHKUHJxNsTfYuScep13go8QFfHdtkG9nRkFGb7busX4isf6X9dURGCoKgitaApQ6MupRhZMcELAxTBRJgS/*)";
+    const ENDPOINT: &str = "https://example.com/payjoin";
+
+    struct InProcessTransport<'w> {
+        receiver: PayjoinReceiver<'w, AnyDatabase>,
+    }
+
+    impl<'w> PayjoinTransport for InProcessTransport<'w> {
+        fn post(&self, url: &str, body: &str) -> Result<String, PayjoinError> {
+            let query = url.split_once('?').map(|(_, query)| query).unwrap_or("");
+            self.receiver
+                .handle_request(query, body)
+                .map_err(|e| PayjoinError::Transport(e.to_string()))
+        }
+    }
+
+    fn original_psbt(
+        sender: &Wallet<AnyDatabase>,
+        receiver: &Wallet<AnyDatabase>,
+    ) -> (Psbt, Script, usize) {
+        let payee = receiver
+            .get_address(AddressIndex::New)
+            .unwrap()
+            .script_pubkey();
+        let mut builder = sender.build_tx();
+        builder
+            .add_recipient(payee.clone(), 20_000)
+            .fee_rate(FeeRate::from_sat_per_vb(2.0));
+        let (mut psbt, _) = builder.finish().unwrap();
+        assert!(sender.sign(&mut psbt, SignOptions::default()).unwrap());
+
+        let change_index = psbt
+            .unsigned_tx
+            .output
+            .iter()
+            .position(|txout| txout.script_pubkey != payee)
+            .unwrap();
+        (psbt, payee, change_index)
+    }
+
+    #[test]
+    fn test_params_query() {
+        let params = PayjoinParams {
+            additional_fee_output_index: Some(1),
+            max_additional_fee_contribution: 182,
+            disable_output_substitution: true,
+            min_fee_rate: Some(FeeRate::from_sat_per_vb(2.5)),
+        };
+        let query = params.to_query();
+        assert_eq!(query, "v=1&additionalfeeoutputindex=1&maxadditionalfeecontribution=182&disableoutputsubstitution=true&minfeerate=2.5");
+
+        let parsed = PayjoinParams::from_query(&query).unwrap();
+        assert_eq!(parsed.additional_fee_output_index, Some(1));
+        assert_eq!(parsed.max_additional_fee_contribution, 182);
+        assert!(parsed.disable_output_substitution);
+        assert_eq!(parsed.min_fee_rate, Some(FeeRate::from_sat_per_vb(2.5)));
+
+        // The fee output index is ignored without a max contribution
+        let parsed = PayjoinParams::from_query("v=1&additionalfeeoutputindex=1&foo=bar").unwrap();
+        assert_eq!(parsed.additional_fee_output_index, None);
+
+        assert!(matches!(
+            PayjoinParams::from_query("v=2"),
+            Err(PayjoinError::InvalidParams(_))
+        ));
+        assert!(matches!(
+            PayjoinParams::from_query("v=1&minfeerate=-1"),
+            Err(PayjoinError::InvalidParams(_))
+        ));
+    }
+
+    #[test]
+    fn test_payjoin() {
+        let (sender_wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let (receiver_wallet, _, _) = get_funded_wallet(RECEIVER_DESC);
+        let (original, payee, change_index) = original_psbt(&sender_wallet, &receiver_wallet);
+        let original_fee_rate = original.fee_rate().unwrap();
+        let original_change = original.unsigned_tx.output[change_index].value;
+
+        let params = PayjoinParams {
+            additional_fee_output_index: Some(change_index),
+            max_additional_fee_contribution: 1_000,
+            ..Default::default()
+        };
+        let sender = PayjoinSender::new(original.clone(), payee.clone(), params).unwrap();
+        let transport = InProcessTransport {
+            receiver: PayjoinReceiver::new(&receiver_wallet),
+        };
+        let mut proposal = sender.send(&sender_wallet, &transport, ENDPOINT).unwrap();
+        assert!(sender_wallet
+            .sign(&mut proposal, SignOptions::default())
+            .unwrap());
+
+        let receiver_utxo = receiver_wallet.list_unspent().unwrap()[0].clone();
+        let tx = proposal.clone().extract_tx();
+        assert_eq!(tx.input.len(), 2);
+        assert!(tx
+            .input
+            .iter()
+            .any(|txin| txin.previous_output == receiver_utxo.outpoint));
+
+        // The receiver pays for part of its input, the sender pays the rest from its change
+        let payee_value = tx
+            .output
+            .iter()
+            .find(|txout| txout.script_pubkey == payee)
+            .unwrap()
+            .value;
+        let change_value = tx.output[change_index].value;
+        assert!(payee_value <= 20_000 + receiver_utxo.txout.value);
+        assert!(change_value < original_change);
+        assert!(original_change - change_value <= 1_000);
+        assert!(proposal.fee_rate().unwrap() >= original_fee_rate);
+    }
+
+    #[test]
+    fn test_payjoin_min_fee_rate() {
+        let (sender_wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let (receiver_wallet, _, _) = get_funded_wallet(RECEIVER_DESC);
+        let (original, payee, _) = original_psbt(&sender_wallet, &receiver_wallet);
+
+        let params = PayjoinParams {
+            min_fee_rate: Some(FeeRate::from_sat_per_vb(5.0)),
+            ..Default::default()
+        };
+        let sender = PayjoinSender::new(original, payee, params).unwrap();
+        let transport = InProcessTransport {
+            receiver: PayjoinReceiver::new(&receiver_wallet),
+        };
+        let mut proposal = sender.send(&sender_wallet, &transport, ENDPOINT).unwrap();
+        assert!(sender_wallet
+            .sign(&mut proposal, SignOptions::default())
+            .unwrap());
+        assert!(proposal.fee_rate().unwrap() >= FeeRate::from_sat_per_vb(5.0));
+    }
+
+    #[test]
+    fn test_payjoin_receiver_rejects_unsigned_original() {
+        let (sender_wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let (receiver_wallet, _, _) = get_funded_wallet(RECEIVER_DESC);
+        let payee = receiver_wallet
+            .get_address(AddressIndex::New)
+            .unwrap()
+            .script_pubkey();
+        let mut builder = sender_wallet.build_tx();
+        builder.add_recipient(payee, 20_000);
+        let (psbt, _) = builder.finish().unwrap();
+
+        let receiver = PayjoinReceiver::new(&receiver_wallet);
+        assert!(matches!(
+            receiver.process_psbt(psbt, &PayjoinParams::default()),
+            Err(Error::Payjoin(PayjoinError::OriginalPsbtRejected(_)))
+        ));
+    }
+
+    #[test]
+    fn test_payjoin_sender_rejects_changed_output() {
+        let (sender_wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let (receiver_wallet, _, _) = get_funded_wallet(RECEIVER_DESC);
+        let (original, payee, change_index) = original_psbt(&sender_wallet, &receiver_wallet);
+
+        let sender = PayjoinSender::new(original.clone(), payee, PayjoinParams::default()).unwrap();
+        let mut proposal = PayjoinReceiver::new(&receiver_wallet)
+            .process_psbt(original, &PayjoinParams::default())
+            .unwrap();
+        proposal.unsigned_tx.output[change_index].value -= 100;
+
+        assert!(matches!(
+            sender.process_proposal(&sender_wallet, proposal),
+            Err(Error::Payjoin(PayjoinError::SenderOutputChanged(_)))
+        ));
+    }
+
+    #[test]
+    fn test_payjoin_sender_rejects_high_contribution() {
+        let (sender_wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let (receiver_wallet, _, _) = get_funded_wallet(RECEIVER_DESC);
+        let (original, payee, change_index) = original_psbt(&sender_wallet, &receiver_wallet);
+
+        let params = PayjoinParams {
+            additional_fee_output_index: Some(change_index),
+            max_additional_fee_contribution: 100,
+            ..Default::default()
+        };
+        let sender = PayjoinSender::new(original.clone(), payee, params.clone()).unwrap();
+        let mut proposal = PayjoinReceiver::new(&receiver_wallet)
+            .process_psbt(original, &params)
+            .unwrap();
+        proposal.unsigned_tx.output[change_index].value -= 1;
+
+        assert!(matches!(
+            sender.process_proposal(&sender_wallet, proposal),
+            Err(Error::Payjoin(PayjoinError::FeeContributionTooHigh {
+                max: 100,
+                actual: 101
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_payjoin_sender_rejects_output_substitution() {
+        let (sender_wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let (receiver_wallet, _, _) = get_funded_wallet(RECEIVER_DESC);
+        let (original, payee, _) = original_psbt(&sender_wallet, &receiver_wallet);
+
+        let params = PayjoinParams {
+            disable_output_substitution: true,
+            ..Default::default()
+        };
+        let sender = PayjoinSender::new(original.clone(), payee.clone(), params.clone()).unwrap();
+        let mut proposal = PayjoinReceiver::new(&receiver_wallet)
+            .process_psbt(original, &params)
+            .unwrap();
+        let other = Address::from_str("bcrt1q3qtze4ys45tgdvguj66zrk4fu6hq3a3v9pfly5")
+            .unwrap()
+            .script_pubkey();
+        proposal
+            .unsigned_tx
+            .output
+            .iter_mut()
+            .filter(|txout| txout.script_pubkey == payee)
+            .for_each(|txout| txout.script_pubkey = other.clone());
+
+        assert!(matches!(
+            sender.process_proposal(&sender_wallet, proposal),
+            Err(Error::Payjoin(PayjoinError::OutputSubstitutionDisabled))
+        ));
+    }
+
+    #[test]
+    fn test_payjoin_sender_rejects_missing_input() {
+        let (sender_wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let (receiver_wallet, _, _) = get_funded_wallet(RECEIVER_DESC);
+        let (original, payee, _) = original_psbt(&sender_wallet, &receiver_wallet);
+
+        let sender = PayjoinSender::new(original.clone(), payee, PayjoinParams::default()).unwrap();
+        let mut proposal = PayjoinReceiver::new(&receiver_wallet)
+            .process_psbt(original.clone(), &PayjoinParams::default())
+            .unwrap();
+        let sender_input = original.unsigned_tx.input[0].previous_output;
+        let index = proposal
+            .unsigned_tx
+            .input
+            .iter()
+            .position(|txin| txin.previous_output == sender_input)
+            .unwrap();
+        proposal.unsigned_tx.input.remove(index);
+        proposal.inputs.remove(index);
+
+        assert!(matches!(
+            sender.process_proposal(&sender_wallet, proposal),
+            Err(Error::Payjoin(PayjoinError::MissingSenderInput(outpoint))) if outpoint == sender_input
+        ));
+    }
+}
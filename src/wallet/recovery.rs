@@ -0,0 +1,382 @@
+// Bitcoin Dev Kit
+//
+// Copyright (c) 2020-2022 Bitcoin Dev Kit Developers
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Wallet recovery
+//!
+//! When a wallet is restored from a key (generally a [BIP39](crate::keys::bip39) mnemonic) it's
+//! not always known which of the standard derivation schemes, and which accounts, were used to
+//! receive funds. This module implements a [`RecoveryScanner`] that tries the [`Bip44`],
+//! [`Bip49`], [`Bip84`] and [`Bip86`] derivations over multiple accounts, and reports the ones
+//! that have some history on the blockchain.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! # use std::str::FromStr;
+//! # use bdk::bitcoin::Network;
+//! # use bdk::bitcoin::util::bip32::ExtendedPrivKey;
+//! # use bdk::blockchain::ElectrumBlockchain;
+//! # use bdk::database::MemoryDatabase;
+//! # use bdk::electrum_client::Client;
+//! use bdk::wallet::recovery::RecoveryScanner;
+//!
+//! // with the `keys-bip39` feature a `Mnemonic` can be used directly
+//! let key = ExtendedPrivKey::from_str("tprv8ZgxMBicQKsPeZRHk4rTG6orPS2CRNFX3njhUXx5vj9qGog5ZMH4uGReDWN5kCkY3jmWEtWause41CDvBRXD1shKknAMKxT99o9qUTRVC6m")?;
+//! let blockchain = ElectrumBlockchain::from(Client::new("ssl://electrum.blockstream.info:60002")?);
+//!
+//! let recovered = RecoveryScanner::new(key, Network::Testnet)
+//!     .max_accounts(5)
+//!     .scan(&blockchain)?;
+//! for account in &recovered {
+//!     println!("{:?} account #{} has history", account.standard, account.account);
+//!     let wallet = account.build_wallet(MemoryDatabase::new())?;
+//!     // ...
+//! }
+//! # Ok::<_, Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! [`Bip44`]: crate::descriptor::template::Bip44
+//! [`Bip49`]: crate::descriptor::template::Bip49
+//! [`Bip84`]: crate::descriptor::template::Bip84
+//! [`Bip86`]: crate::descriptor::template::Bip86
+
+use bitcoin::Network;
+
+use log::debug;
+use miniscript::{Legacy, Segwitv0, Tap};
+
+use crate::blockchain::{GetHeight, WalletSync};
+use crate::database::{BatchDatabase, MemoryDatabase};
+use crate::descriptor::template::{
+    legacy, segwit_v0, segwit_v1, DescriptorTemplate, DescriptorTemplateOut, P2Pkh, P2Wpkh,
+    P2Wpkh_P2Sh, P2TR,
+};
+use crate::descriptor::{DescriptorError, ExtendedDescriptor};
+use crate::error::Error;
+use crate::keys::DerivableKey;
+use crate::types::KeychainKind;
+use crate::wallet::{SyncOptions, Wallet};
+
+/// Default number of accounts scanned for every [`DerivationStandard`]
+pub const DEFAULT_MAX_ACCOUNTS: u32 = 10;
+
+/// Standard derivation schemes that can be scanned by the [`RecoveryScanner`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum DerivationStandard {
+    /// BIP44, `pkh(key/44'/{0,1}'/account'/{0,1}/*)`
+    Bip44,
+    /// BIP49, `sh(wpkh(key/49'/{0,1}'/account'/{0,1}/*))`
+    Bip49,
+    /// BIP84, `wpkh(key/84'/{0,1}'/account'/{0,1}/*)`
+    Bip84,
+    /// BIP86, `tr(key/86'/{0,1}'/account'/{0,1}/*)`
+    Bip86,
+}
+
+impl DerivationStandard {
+    /// Return every standard, in the order they were introduced
+    pub fn all() -> Vec<DerivationStandard> {
+        vec![
+            DerivationStandard::Bip44,
+            DerivationStandard::Bip49,
+            DerivationStandard::Bip84,
+            DerivationStandard::Bip86,
+        ]
+    }
+
+    fn descriptor<K>(
+        &self,
+        key: K,
+        account: u32,
+        keychain: KeychainKind,
+        network: Network,
+    ) -> Result<DescriptorTemplateOut, DescriptorError>
+    where
+        K: DerivableKey<Legacy> + DerivableKey<Segwitv0> + DerivableKey<Tap>,
+    {
+        match self {
+            DerivationStandard::Bip44 => P2Pkh(legacy::make_bipxx_private(
+                44, key, account, keychain, network,
+            )?)
+            .build(network),
+            DerivationStandard::Bip49 => P2Wpkh_P2Sh(segwit_v0::make_bipxx_private(
+                49, key, account, keychain, network,
+            )?)
+            .build(network),
+            DerivationStandard::Bip84 => P2Wpkh(segwit_v0::make_bipxx_private(
+                84, key, account, keychain, network,
+            )?)
+            .build(network),
+            DerivationStandard::Bip86 => P2TR(segwit_v1::make_bipxx_private(
+                86, key, account, keychain, network,
+            )?)
+            .build(network),
+        }
+    }
+}
+
+/// An account found by the [`RecoveryScanner`] that has some history
+#[derive(Clone)]
+pub struct RecoveredAccount {
+    /// Derivation standard of the account
+    pub standard: DerivationStandard,
+    /// Account number, the hardened derivation step after the coin type
+    pub account: u32,
+    /// Number of transactions found for the account
+    pub transactions: usize,
+
+    descriptor: ExtendedDescriptor,
+    change_descriptor: ExtendedDescriptor,
+    // descriptors with the private keys, used to build the wallet
+    secret_descriptors: (String, String),
+    network: Network,
+}
+
+impl RecoveredAccount {
+    /// Return the public descriptor of the account for `keychain`
+    ///
+    /// Only [`KeychainKind::External`] and [`KeychainKind::Internal`] are available, any other
+    /// keychain returns `None`.
+    pub fn descriptor(&self, keychain: KeychainKind) -> Option<&ExtendedDescriptor> {
+        match keychain {
+            KeychainKind::External => Some(&self.descriptor),
+            KeychainKind::Internal => Some(&self.change_descriptor),
+            _ => None,
+        }
+    }
+
+    /// Build a [`Wallet`] for the account, with its private keys
+    ///
+    /// The `database` isn't populated by the scan, so the wallet has to be synced before use.
+    pub fn build_wallet<D: BatchDatabase>(&self, database: D) -> Result<Wallet<D>, Error> {
+        Wallet::new(
+            &self.secret_descriptors.0,
+            Some(&self.secret_descriptors.1),
+            self.network,
+            database,
+        )
+    }
+}
+
+/// Scanner that looks for the standard derivations of a key that have some history
+///
+/// For every [`DerivationStandard`], accounts are scanned in order, starting from `0`, until
+/// one without transactions is found, following the account discovery of BIP44. At most
+/// [`max_accounts`](Self::max_accounts) accounts are scanned for every standard.
+///
+/// For a usage example see [this module](crate::wallet::recovery)'s documentation.
+pub struct RecoveryScanner<K> {
+    key: K,
+    network: Network,
+    standards: Vec<DerivationStandard>,
+    max_accounts: u32,
+}
+
+impl<K> RecoveryScanner<K>
+where
+    K: DerivableKey<Legacy> + DerivableKey<Segwitv0> + DerivableKey<Tap> + Clone,
+{
+    /// Create a new scanner for `key`, that scans every [`DerivationStandard`]
+    pub fn new(key: K, network: Network) -> Self {
+        RecoveryScanner {
+            key,
+            network,
+            standards: DerivationStandard::all(),
+            max_accounts: DEFAULT_MAX_ACCOUNTS,
+        }
+    }
+
+    /// Only scan the given derivation `standards`
+    pub fn standards(mut self, standards: Vec<DerivationStandard>) -> Self {
+        self.standards = standards;
+        self
+    }
+
+    /// Scan at most `max_accounts` accounts for every standard
+    ///
+    /// By default, [`DEFAULT_MAX_ACCOUNTS`] are scanned.
+    pub fn max_accounts(mut self, max_accounts: u32) -> Self {
+        self.max_accounts = max_accounts;
+        self
+    }
+
+    /// Scan the `blockchain` and return the accounts that have some history
+    ///
+    /// Every account is synced in a temporary in-memory wallet, using the default stop gap of the
+    /// `blockchain`.
+    #[maybe_async]
+    pub fn scan<B: WalletSync + GetHeight>(
+        &self,
+        blockchain: &B,
+    ) -> Result<Vec<RecoveredAccount>, Error> {
+        let mut recovered = vec![];
+        for standard in &self.standards {
+            for account in 0..self.max_accounts {
+                let (descriptor, keymap, _) = standard.descriptor(
+                    self.key.clone(),
+                    account,
+                    KeychainKind::External,
+                    self.network,
+                )?;
+                let (change_descriptor, change_keymap, _) = standard.descriptor(
+                    self.key.clone(),
+                    account,
+                    KeychainKind::Internal,
+                    self.network,
+                )?;
+
+                let secret_descriptors = (
+                    descriptor.to_string_with_secret(&keymap),
+                    change_descriptor.to_string_with_secret(&change_keymap),
+                );
+
+                let wallet = Wallet::new(
+                    &secret_descriptors.0,
+                    Some(&secret_descriptors.1),
+                    self.network,
+                    MemoryDatabase::new(),
+                )?;
+                maybe_await!(wallet.sync(blockchain, SyncOptions::default()))?;
+
+                let transactions = wallet.list_transactions(false)?.len();
+                debug!(
+                    "{:?} account #{} has {} transactions",
+                    standard, account, transactions
+                );
+                if transactions == 0 {
+                    break;
+                }
+
+                recovered.push(RecoveredAccount {
+                    standard: *standard,
+                    account,
+                    transactions,
+                    descriptor,
+                    change_descriptor,
+                    secret_descriptors,
+                    network: self.network,
+                });
+            }
+        }
+
+        Ok(recovered)
+    }
+}
+
+#[cfg(all(test, not(feature = "async-interface")))]
+mod test {
+    use std::str::FromStr;
+
+    use bitcoin::util::bip32::ExtendedPrivKey;
+    use bitcoin::{PackedLockTime, Script, Transaction, TxOut};
+
+    use super::*;
+    use crate::blockchain::Progress;
+    use crate::wallet::AddressIndex;
+    use crate::TransactionDetails;
+
+    /// A blockchain where only some scripts have received a transaction
+    struct UsedScripts(Vec<Script>);
+
+    impl GetHeight for UsedScripts {
+        fn get_height(&self) -> Result<u32, Error> {
+            Ok(100)
+        }
+    }
+
+    impl WalletSync for UsedScripts {
+        fn wallet_setup<D: BatchDatabase>(
+            &self,
+            database: &mut D,
+            _progress_update: Box<dyn Progress>,
+        ) -> Result<(), Error> {
+            for script in database.iter_script_pubkeys(None)? {
+                if self.0.contains(&script) {
+                    let transaction = Transaction {
+                        version: 1,
+                        lock_time: PackedLockTime(0),
+                        input: vec![],
+                        output: vec![TxOut {
+                            value: 50_000,
+                            script_pubkey: script,
+                        }],
+                    };
+                    database.set_tx(&TransactionDetails {
+                        txid: transaction.txid(),
+                        transaction: Some(transaction),
+                        received: 50_000,
+                        sent: 0,
+                        fee: Some(0),
+                        confirmation_time: None,
+                    })?;
+                }
+            }
+
+            Ok(())
+        }
+    }
+
+    fn script_at(
+        key: ExtendedPrivKey,
+        standard: DerivationStandard,
+        account: u32,
+        keychain: KeychainKind,
+        index: u32,
+    ) -> Script {
+        let (descriptor, _, _) = standard
+            .descriptor(key, account, keychain, Network::Regtest)
+            .unwrap();
+        descriptor.at_derivation_index(index).script_pubkey()
+    }
+
+    #[test]
+    fn test_recovery_scan() {
+        let key = ExtendedPrivKey::from_str("tprv8ZgxMBicQKsPeZRHk4rTG6orPS2CRNFX3njhUXx5vj9qGog5ZMH4uGReDWN5kCkY3jmWEtWause41CDvBRXD1shKknAMKxT99o9qUTRVC6m").unwrap();
+        let blockchain = UsedScripts(vec![
+            script_at(key, DerivationStandard::Bip84, 0, KeychainKind::External, 5),
+            script_at(key, DerivationStandard::Bip84, 1, KeychainKind::Internal, 0),
+            script_at(key, DerivationStandard::Bip86, 0, KeychainKind::External, 0),
+            // account #1 is unused, so account #2 isn't reached
+            script_at(key, DerivationStandard::Bip49, 2, KeychainKind::External, 0),
+        ]);
+
+        let recovered = RecoveryScanner::new(key, Network::Regtest)
+            .scan(&blockchain)
+            .unwrap();
+        assert_eq!(
+            recovered
+                .iter()
+                .map(|account| (account.standard, account.account, account.transactions))
+                .collect::<Vec<_>>(),
+            vec![
+                (DerivationStandard::Bip84, 0, 1),
+                (DerivationStandard::Bip84, 1, 1),
+                (DerivationStandard::Bip86, 0, 1),
+            ]
+        );
+
+        let wallet = recovered[2].build_wallet(MemoryDatabase::new()).unwrap();
+        assert_eq!(
+            wallet
+                .get_address(AddressIndex::Peek(0))
+                .unwrap()
+                .script_pubkey(),
+            script_at(key, DerivationStandard::Bip86, 0, KeychainKind::External, 0)
+        );
+        assert!(recovered[2].descriptor(KeychainKind::Imported).is_none());
+
+        let recovered = RecoveryScanner::new(key, Network::Regtest)
+            .standards(vec![DerivationStandard::Bip84])
+            .max_accounts(1)
+            .scan(&blockchain)
+            .unwrap();
+        assert_eq!(recovered.len(), 1);
+    }
+}
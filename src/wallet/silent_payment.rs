@@ -0,0 +1,722 @@
+// Bitcoin Dev Kit
+//
+// Copyright (c) 2020-2022 Bitcoin Dev Kit Developers
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Silent payments
+//!
+//! This module implements the sending and receiving sides of [BIP352] silent payments.
+//!
+//! A receiver publishes a single static [`SilentPaymentAddress`], made of a *scan* and a *spend*
+//! public key. A sender combines the private keys of the inputs it's spending with the receiver's
+//! scan key to derive a fresh taproot output that only the receiver can detect and spend: no
+//! interaction is required and no two payments ever share the same script.
+//!
+//! On the receiving side a [`Wallet`](super::Wallet) can be given a [`SilentPaymentKeychain`]
+//! with [`Wallet::set_silent_payment_keychain`](super::Wallet::set_silent_payment_keychain),
+//! after which it can hand out its address. Incoming payments pay scripts the wallet can't know
+//! in advance, so they are not found by [`Wallet::sync`](super::Wallet::sync): transactions
+//! fetched from a backend, along with the outputs they spend, have to be checked with
+//! [`scan_transaction`]. On the sending side, silent payment recipients can be added to a transaction
+//! with [`TxBuilder::add_silent_payment_recipient`](super::tx_builder::TxBuilder::add_silent_payment_recipient).
+//!
+//! Labels (`m > 0`) are not supported yet.
+//!
+//! ## Example
+//!
+//! ```
+//! # use std::str::FromStr;
+//! # use bdk::bitcoin::secp256k1::{Secp256k1, SecretKey};
+//! # use bdk::bitcoin::Network;
+//! # use bdk::wallet::silent_payment::*;
+//! let secp = Secp256k1::new();
+//! let scan_key = SecretKey::from_slice(&[0x11; 32])?;
+//! let spend_key = SecretKey::from_slice(&[0x22; 32])?.public_key(&secp);
+//!
+//! let keychain = SilentPaymentKeychain::new(scan_key, spend_key);
+//! let address = keychain.address(Network::Bitcoin, &secp);
+//! assert!(address.to_string().starts_with("sp1q"));
+//! assert_eq!(SilentPaymentAddress::from_str(&address.to_string())?, address);
+//! # Ok::<_, Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! [BIP352]: https://github.com/bitcoin/bips/blob/master/bip-0352.mediawiki
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use bitcoin::bech32::{self, FromBase32, ToBase32, Variant};
+use bitcoin::consensus::encode::serialize;
+use bitcoin::hashes::{hash160, sha256, Hash, HashEngine};
+use bitcoin::schnorr::TweakedPublicKey;
+use bitcoin::secp256k1::{self, KeyPair, Parity, PublicKey, Scalar, SecretKey, XOnlyPublicKey};
+use bitcoin::util::psbt;
+use bitcoin::{Network, OutPoint, Script, Transaction, TxIn, TxOut};
+
+use crate::wallet::utils::SecpCtx;
+
+const ADDRESS_VERSION: u8 = 0;
+// version (1 char) + scan key (33 bytes) + spend key (33 bytes) in base32
+const ADDRESS_DATA_LEN: usize = 1 + 106;
+
+// The "nothing up my sleeve" point from BIP341, used as taproot internal key when key-path
+// spending is disabled: inputs spending through a script path with this internal key are not
+// eligible.
+const NUMS_H: [u8; 32] = [
+    0x50, 0x92, 0x9b, 0x74, 0xc1, 0xa0, 0x49, 0x54, 0xb7, 0x8b, 0x4b, 0x60, 0x35, 0xe9, 0x7a, 0x5e,
+    0x07, 0x8a, 0x5a, 0x0f, 0x28, 0xec, 0x96, 0xd5, 0x47, 0xbf, 0xee, 0x9a, 0xce, 0x80, 0x3a, 0xc0,
+];
+
+/// Errors that can be thrown while working with silent payments
+#[derive(Debug)]
+pub enum SilentPaymentError {
+    /// The human readable part of the address is not one of `sp`, `tsp` or `sprt`
+    InvalidHrp(String),
+    /// The address is not encoded with bech32m
+    InvalidVariant,
+    /// The address doesn't contain exactly two public keys
+    InvalidLength,
+    /// The address version is not supported
+    UnsupportedVersion(u8),
+    /// The wallet doesn't have a [`SilentPaymentKeychain`]
+    MissingKeychain,
+    /// None of the inputs of the transaction can be used to derive a silent payment output
+    NoEligibleInputs,
+    /// The private key for an eligible input is not available in the wallet's signers
+    MissingInputKey(OutPoint),
+    /// Bech32 encoding error
+    Bech32(bech32::Error),
+    /// Secp256k1 error
+    Secp256k1(secp256k1::Error),
+}
+
+impl fmt::Display for SilentPaymentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidHrp(hrp) => write!(f, "Invalid silent payment address prefix: {}", hrp),
+            Self::InvalidVariant => write!(f, "Silent payment addresses must use bech32m"),
+            Self::InvalidLength => write!(f, "Invalid silent payment address length"),
+            Self::UnsupportedVersion(v) => {
+                write!(f, "Unsupported silent payment address version: {}", v)
+            }
+            Self::MissingKeychain => write!(f, "The wallet doesn't have a silent payment keychain"),
+            Self::NoEligibleInputs => write!(f, "No input is eligible for silent payments"),
+            Self::MissingInputKey(outpoint) => {
+                write!(f, "Missing private key for eligible input {}", outpoint)
+            }
+            Self::Bech32(e) => write!(f, "Bech32 error: {}", e),
+            Self::Secp256k1(e) => write!(f, "Secp256k1 error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SilentPaymentError {}
+
+impl_error!(bech32::Error, Bech32, SilentPaymentError);
+impl_error!(secp256k1::Error, Secp256k1, SilentPaymentError);
+
+/// A silent payment address
+///
+/// Encoded as bech32m with the `sp` prefix on mainnet, `tsp` on testnet and signet and `sprt` on
+/// regtest.
+///
+/// Since testnet and signet share the same prefix, a `tsp` address is always parsed as a testnet
+/// address: use [`SilentPaymentAddress::is_valid_for_network`] instead of comparing
+/// [`SilentPaymentAddress::network`] to check whether it can be used on a given network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SilentPaymentAddress {
+    scan_key: PublicKey,
+    spend_key: PublicKey,
+    network: Network,
+}
+
+impl SilentPaymentAddress {
+    /// Create a new address from the receiver's scan and spend public keys
+    pub fn new(scan_key: PublicKey, spend_key: PublicKey, network: Network) -> Self {
+        SilentPaymentAddress {
+            scan_key,
+            spend_key,
+            network,
+        }
+    }
+
+    /// Return the scan public key
+    pub fn scan_key(&self) -> PublicKey {
+        self.scan_key
+    }
+
+    /// Return the spend public key
+    pub fn spend_key(&self) -> PublicKey {
+        self.spend_key
+    }
+
+    /// Return the network of the address
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    /// Return whether the address can be used on `network`
+    ///
+    /// Testnet and signet are considered the same network, like
+    /// [`Address::is_valid_for_network`](bitcoin::Address::is_valid_for_network) does.
+    pub fn is_valid_for_network(&self, network: Network) -> bool {
+        Self::hrp(self.network) == Self::hrp(network)
+    }
+
+    // Script used as a stand-in for the real output while building a transaction, before the
+    // inputs are known. It has the same size as the final P2TR output, so it doesn't change the
+    // fee calculation.
+    pub(crate) fn placeholder_script(&self) -> Script {
+        let (x_only, _) = self.spend_key.x_only_public_key();
+        Script::new_v1_p2tr_tweaked(TweakedPublicKey::dangerous_assume_tweaked(x_only))
+    }
+
+    fn hrp(network: Network) -> &'static str {
+        match network {
+            Network::Bitcoin => "sp",
+            Network::Testnet | Network::Signet => "tsp",
+            Network::Regtest => "sprt",
+        }
+    }
+}
+
+impl fmt::Display for SilentPaymentAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut data = vec![bech32::u5::try_from_u8(ADDRESS_VERSION).expect("Valid u5")];
+        data.extend(
+            [self.scan_key.serialize(), self.spend_key.serialize()]
+                .concat()
+                .to_base32(),
+        );
+
+        let encoded = bech32::encode(Self::hrp(self.network), data, Variant::Bech32m)
+            .map_err(|_| fmt::Error)?;
+        write!(f, "{}", encoded)
+    }
+}
+
+impl FromStr for SilentPaymentAddress {
+    type Err = SilentPaymentError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (hrp, data, variant) = bech32::decode(s)?;
+        let network = match hrp.as_str() {
+            "sp" => Network::Bitcoin,
+            "tsp" => Network::Testnet,
+            "sprt" => Network::Regtest,
+            _ => return Err(SilentPaymentError::InvalidHrp(hrp)),
+        };
+        if variant != Variant::Bech32m {
+            return Err(SilentPaymentError::InvalidVariant);
+        }
+
+        let version = data
+            .first()
+            .ok_or(SilentPaymentError::InvalidLength)?
+            .to_u8();
+        if version != ADDRESS_VERSION {
+            return Err(SilentPaymentError::UnsupportedVersion(version));
+        }
+        if data.len() != ADDRESS_DATA_LEN {
+            return Err(SilentPaymentError::InvalidLength);
+        }
+
+        let keys = Vec::<u8>::from_base32(&data[1..])?;
+        Ok(SilentPaymentAddress {
+            scan_key: PublicKey::from_slice(&keys[..33])?,
+            spend_key: PublicKey::from_slice(&keys[33..])?,
+            network,
+        })
+    }
+}
+
+/// The keys a wallet needs to receive silent payments
+///
+/// The scan key is always private, since it's needed to detect incoming payments. The spend key
+/// can be public only: in that case the wallet is able to find payments, but the private key has
+/// to be provided separately to spend them (see [`SilentPaymentOutput::spending_key`]).
+#[derive(Debug, Clone)]
+pub struct SilentPaymentKeychain {
+    scan_key: SecretKey,
+    spend_key: PublicKey,
+}
+
+impl SilentPaymentKeychain {
+    /// Create a new keychain from the private scan key and the public spend key
+    pub fn new(scan_key: SecretKey, spend_key: PublicKey) -> Self {
+        SilentPaymentKeychain {
+            scan_key,
+            spend_key,
+        }
+    }
+
+    /// Return the silent payment address for this keychain
+    pub fn address(&self, network: Network, secp: &SecpCtx) -> SilentPaymentAddress {
+        SilentPaymentAddress::new(self.scan_key.public_key(secp), self.spend_key, network)
+    }
+}
+
+/// An output paid to a [`SilentPaymentKeychain`], found while scanning a transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SilentPaymentOutput {
+    /// Location of the output
+    pub outpoint: OutPoint,
+    /// The output itself
+    pub txout: TxOut,
+    /// The tweak that has to be added to the private spend key to spend this output
+    pub tweak: SecretKey,
+}
+
+impl SilentPaymentOutput {
+    /// Return the private key that can spend this output through the taproot key path
+    ///
+    /// The output key is used as is, without any further taproot tweak.
+    pub fn spending_key(&self, spend_key: &SecretKey) -> Result<SecretKey, SilentPaymentError> {
+        Ok(spend_key.add_tweak(&Scalar::from(self.tweak))?)
+    }
+}
+
+fn tagged_hash(tag: &str, data: &[&[u8]]) -> [u8; 32] {
+    let tag_hash = sha256::Hash::hash(tag.as_bytes());
+    let mut engine = sha256::Hash::engine();
+    engine.input(&tag_hash[..]);
+    engine.input(&tag_hash[..]);
+    for d in data {
+        engine.input(d);
+    }
+
+    sha256::Hash::from_engine(engine).into_inner()
+}
+
+// hash_BIP0352/Inputs(outpoint_L || A), where outpoint_L is the smallest serialized outpoint
+fn input_hash(
+    outpoints: &[OutPoint],
+    input_key_sum: &PublicKey,
+) -> Result<Scalar, SilentPaymentError> {
+    let smallest_outpoint = outpoints
+        .iter()
+        .map(serialize)
+        .min()
+        .ok_or(SilentPaymentError::NoEligibleInputs)?;
+    let hash = tagged_hash(
+        "BIP0352/Inputs",
+        &[&smallest_outpoint, &input_key_sum.serialize()],
+    );
+
+    Ok(Scalar::from(SecretKey::from_slice(&hash)?))
+}
+
+// t_k = hash_BIP0352/SharedSecret(serP(ecdh_shared_secret) || ser32(k))
+fn shared_secret_tweak(
+    ecdh_shared_secret: &PublicKey,
+    k: u32,
+) -> Result<SecretKey, SilentPaymentError> {
+    let hash = tagged_hash(
+        "BIP0352/SharedSecret",
+        &[&ecdh_shared_secret.serialize(), &k.to_be_bytes()],
+    );
+
+    Ok(SecretKey::from_slice(&hash)?)
+}
+
+/// Return the public key an input contributes to the shared secret, or `None` if the input is
+/// not eligible for silent payments
+///
+/// `prevout` is the output spent by `txin`.
+pub fn input_public_key(txin: &TxIn, prevout: &TxOut) -> Option<PublicKey> {
+    let script_pubkey = &prevout.script_pubkey;
+    let witness = txin.witness.to_vec();
+
+    let compressed_key = |bytes: &[u8]| match bytes.len() {
+        33 => PublicKey::from_slice(bytes).ok(),
+        _ => None,
+    };
+
+    if script_pubkey.is_p2pkh() {
+        // Look for the compressed key matching the hash in the spent script, starting from the
+        // end of the scriptSig
+        let script_sig = txin.script_sig.as_bytes();
+        let key_hash = &script_pubkey.as_bytes()[3..23];
+        (33..=script_sig.len())
+            .rev()
+            .map(|end| &script_sig[end - 33..end])
+            .find(|bytes| &hash160::Hash::hash(bytes)[..] == key_hash)
+            .and_then(compressed_key)
+    } else if script_pubkey.is_p2sh() {
+        let redeem_script = Script::from(txin.script_sig.as_bytes().get(1..)?.to_vec());
+        match redeem_script.is_v0_p2wpkh() {
+            true => witness.last().and_then(|bytes| compressed_key(bytes)),
+            false => None,
+        }
+    } else if script_pubkey.is_v0_p2wpkh() {
+        witness.last().and_then(|bytes| compressed_key(bytes))
+    } else if script_pubkey.is_v1_p2tr() {
+        let mut stack = witness.as_slice();
+        // Remove the annex, if present
+        if stack.len() > 1 && stack.last().and_then(|e| e.first()) == Some(&0x50) {
+            stack = &stack[..stack.len() - 1];
+        }
+        // Script path spends with the NUMS point as internal key are not eligible
+        if stack.len() > 1 && stack.last().and_then(|cb| cb.get(1..33)) == Some(&NUMS_H[..]) {
+            return None;
+        }
+
+        XOnlyPublicKey::from_slice(&script_pubkey.as_bytes()[2..34])
+            .ok()
+            .map(|x_only| PublicKey::from_x_only_public_key(x_only, Parity::Even))
+    } else {
+        None
+    }
+}
+
+// Whether the private key that signs for `psbt_input` contributes to silent payment outputs
+pub(crate) fn is_eligible_input(psbt_input: &psbt::Input, prevout: &TxOut) -> bool {
+    let script_pubkey = &prevout.script_pubkey;
+    if script_pubkey.is_v1_p2tr() {
+        psbt_input
+            .tap_internal_key
+            .map(|key| key.serialize() != NUMS_H)
+            .unwrap_or(true)
+    } else if script_pubkey.is_p2sh() {
+        psbt_input
+            .redeem_script
+            .as_ref()
+            .map(Script::is_v0_p2wpkh)
+            .unwrap_or(false)
+    } else {
+        script_pubkey.is_v0_p2wpkh() || script_pubkey.is_p2pkh()
+    }
+}
+
+/// Compute the output scripts for a list of silent payment recipients
+///
+/// `input_keys` contains the private key of every eligible input, along with a flag set to
+/// `true` for taproot inputs, while `outpoints` must contain **every** input of the transaction.
+/// Recipients sharing the same scan key get consecutive values of `k`, in the order in which
+/// they appear in `recipients`.
+pub(crate) fn output_scripts(
+    recipients: &[SilentPaymentAddress],
+    input_keys: &[(SecretKey, bool)],
+    outpoints: &[OutPoint],
+    secp: &SecpCtx,
+) -> Result<Vec<Script>, SilentPaymentError> {
+    let mut keys = input_keys.iter().map(|(key, is_taproot)| {
+        // Taproot keys are used with their even-y representation
+        match (is_taproot, key.x_only_public_key(secp).1) {
+            (true, Parity::Odd) => key.negate(),
+            _ => *key,
+        }
+    });
+    let first = keys.next().ok_or(SilentPaymentError::NoEligibleInputs)?;
+    let input_key_sum = keys.try_fold(first, |acc, key| acc.add_tweak(&Scalar::from(key)))?;
+
+    let input_hash = input_hash(outpoints, &input_key_sum.public_key(secp))?;
+    let partial_secret = input_key_sum.mul_tweak(&input_hash)?;
+
+    let mut counters: HashMap<PublicKey, u32> = HashMap::new();
+    recipients
+        .iter()
+        .map(|recipient| {
+            let k = counters.entry(recipient.scan_key).or_insert(0);
+            let ecdh_shared_secret = recipient
+                .scan_key
+                .mul_tweak(secp, &Scalar::from(partial_secret))?;
+            let tweak = shared_secret_tweak(&ecdh_shared_secret, *k)?;
+            *k += 1;
+
+            let output_key = recipient
+                .spend_key
+                .add_exp_tweak(secp, &Scalar::from(tweak))?;
+            let (x_only, _) = output_key.x_only_public_key();
+
+            Ok(Script::new_v1_p2tr_tweaked(
+                TweakedPublicKey::dangerous_assume_tweaked(x_only),
+            ))
+        })
+        .collect()
+}
+
+/// Return the private key an input contributes to a silent payment, given the private key that
+/// signs for it
+///
+/// For taproot inputs `secret_key` is the untweaked internal key, and the result is the key
+/// tweaked with `merkle_root` as in BIP341.
+pub(crate) fn input_secret_key(
+    secret_key: SecretKey,
+    merkle_root: Option<bitcoin::util::taproot::TapBranchHash>,
+    is_taproot: bool,
+    secp: &SecpCtx,
+) -> SecretKey {
+    use bitcoin::schnorr::TapTweak;
+
+    match is_taproot {
+        true => {
+            let keypair = KeyPair::from_secret_key(secp, &secret_key);
+            let tweaked = keypair.tap_tweak(secp, merkle_root).to_inner();
+            SecretKey::from_keypair(&tweaked)
+        }
+        false => secret_key,
+    }
+}
+
+/// Scan a transaction for outputs paid to `keychain`
+///
+/// `prevouts` must contain the outputs spent by the transaction, in the same order as its
+/// inputs.
+pub fn scan_transaction(
+    keychain: &SilentPaymentKeychain,
+    tx: &Transaction,
+    prevouts: &[TxOut],
+    secp: &SecpCtx,
+) -> Result<Vec<SilentPaymentOutput>, SilentPaymentError> {
+    if tx.is_coin_base() || !tx.output.iter().any(|o| o.script_pubkey.is_v1_p2tr()) {
+        return Ok(vec![]);
+    }
+
+    let input_keys = tx
+        .input
+        .iter()
+        .zip(prevouts)
+        .filter_map(|(txin, prevout)| input_public_key(txin, prevout))
+        .collect::<Vec<_>>();
+    if input_keys.is_empty() {
+        return Ok(vec![]);
+    }
+    let input_key_sum = PublicKey::combine_keys(&input_keys.iter().collect::<Vec<_>>())?;
+
+    let outpoints = tx
+        .input
+        .iter()
+        .map(|txin| txin.previous_output)
+        .collect::<Vec<_>>();
+    let input_hash = input_hash(&outpoints, &input_key_sum)?;
+    let ecdh_shared_secret = input_key_sum
+        .mul_tweak(secp, &input_hash)?
+        .mul_tweak(secp, &Scalar::from(keychain.scan_key))?;
+
+    let txid = tx.txid();
+    let mut found = vec![];
+    for k in 0.. {
+        let tweak = shared_secret_tweak(&ecdh_shared_secret, k)?;
+        let output_key = keychain
+            .spend_key
+            .add_exp_tweak(secp, &Scalar::from(tweak))?;
+        let (x_only, _) = output_key.x_only_public_key();
+        let expected_script =
+            Script::new_v1_p2tr_tweaked(TweakedPublicKey::dangerous_assume_tweaked(x_only));
+
+        match tx
+            .output
+            .iter()
+            .position(|o| o.script_pubkey == expected_script)
+        {
+            Some(vout) => found.push(SilentPaymentOutput {
+                outpoint: OutPoint::new(txid, vout as u32),
+                txout: tx.output[vout].clone(),
+                tweak,
+            }),
+            None => break,
+        }
+    }
+
+    Ok(found)
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::hashes::hex::FromHex;
+    use bitcoin::secp256k1::Secp256k1;
+    use bitcoin::{PackedLockTime, Sequence, Witness};
+
+    use super::*;
+
+    fn test_keychain(secp: &SecpCtx) -> SilentPaymentKeychain {
+        let scan_key = SecretKey::from_slice(&[0x11; 32]).unwrap();
+        let spend_key = SecretKey::from_slice(&[0x22; 32]).unwrap();
+        SilentPaymentKeychain::new(scan_key, spend_key.public_key(secp))
+    }
+
+    fn test_outpoints() -> Vec<OutPoint> {
+        vec![
+            OutPoint::from_str(
+                "0000000000000000000000000000000000000000000000000000000000000002:1",
+            )
+            .unwrap(),
+            OutPoint::from_str(
+                "0000000000000000000000000000000000000000000000000000000000000001:0",
+            )
+            .unwrap(),
+        ]
+    }
+
+    #[test]
+    fn test_address_encoding() {
+        let secp = Secp256k1::new();
+        let keychain = test_keychain(&secp);
+
+        let expected = [
+            (Network::Bitcoin, "sp1qqd8n2k7uklxq4aegau7vawtptkgxsja4kt99lpv6krctwpq8tpc65qjxd4lu4etruh9sngx3su9mtqp5fqzxz7re59y5nnez9p03ht3lyue8qjka"),
+            (Network::Testnet, "tsp1qqd8n2k7uklxq4aegau7vawtptkgxsja4kt99lpv6krctwpq8tpc65qjxd4lu4etruh9sngx3su9mtqp5fqzxz7re59y5nnez9p03ht3lyudgq8ux"),
+            (Network::Regtest, "sprt1qqd8n2k7uklxq4aegau7vawtptkgxsja4kt99lpv6krctwpq8tpc65qjxd4lu4etruh9sngx3su9mtqp5fqzxz7re59y5nnez9p03ht3lyult5ra5"),
+        ];
+        for (network, encoded) in expected {
+            let address = keychain.address(network, &secp);
+            assert_eq!(address.to_string(), encoded);
+            assert_eq!(SilentPaymentAddress::from_str(encoded).unwrap(), address);
+        }
+    }
+
+    #[test]
+    fn test_address_signet() {
+        let secp = Secp256k1::new();
+        let keychain = test_keychain(&secp);
+
+        let address = keychain.address(Network::Signet, &secp);
+        assert_eq!(
+            address.to_string(),
+            keychain.address(Network::Testnet, &secp).to_string()
+        );
+
+        let parsed = SilentPaymentAddress::from_str(&address.to_string()).unwrap();
+        assert_eq!(parsed.network(), Network::Testnet);
+        assert!(parsed.is_valid_for_network(Network::Signet));
+        assert!(parsed.is_valid_for_network(Network::Testnet));
+        assert!(!parsed.is_valid_for_network(Network::Regtest));
+        assert!(!parsed.is_valid_for_network(Network::Bitcoin));
+    }
+
+    #[test]
+    fn test_address_invalid() {
+        let secp = Secp256k1::new();
+        let address = test_keychain(&secp).address(Network::Bitcoin, &secp);
+
+        // same payload, but encoded with bech32 instead of bech32m
+        let (_, data, _) = bech32::decode(&address.to_string()).unwrap();
+        let bech32 = bech32::encode("sp", data.clone(), Variant::Bech32).unwrap();
+        assert!(matches!(
+            SilentPaymentAddress::from_str(&bech32),
+            Err(SilentPaymentError::InvalidVariant)
+        ));
+
+        let wrong_hrp = bech32::encode("bc", data, Variant::Bech32m).unwrap();
+        assert!(matches!(
+            SilentPaymentAddress::from_str(&wrong_hrp),
+            Err(SilentPaymentError::InvalidHrp(_))
+        ));
+    }
+
+    #[test]
+    fn test_output_scripts() {
+        let secp = Secp256k1::new();
+        let address = test_keychain(&secp).address(Network::Regtest, &secp);
+        let input_keys = vec![
+            (SecretKey::from_slice(&[0x33; 32]).unwrap(), false),
+            (SecretKey::from_slice(&[0x44; 32]).unwrap(), true),
+        ];
+
+        let scripts =
+            output_scripts(&[address, address], &input_keys, &test_outpoints(), &secp).unwrap();
+
+        let expected_keys = [
+            "c50dc5aea5dd2864c5cf0c930eb66d7ac55301d2b94d138777e51612e0f61d65",
+            "487f8492e6ca758b3b83cf725bc967f4766c315020d3c9c95bab780ef0eb8f22",
+        ];
+        for (script, key) in scripts.iter().zip(expected_keys) {
+            assert!(script.is_v1_p2tr());
+            assert_eq!(
+                script.as_bytes()[2..],
+                Vec::<u8>::from_hex(key).unwrap()[..]
+            );
+        }
+    }
+
+    #[test]
+    fn test_scan_transaction() {
+        let secp = Secp256k1::new();
+        let keychain = test_keychain(&secp);
+        let address = keychain.address(Network::Regtest, &secp);
+
+        let wpkh_key = SecretKey::from_slice(&[0x33; 32]).unwrap();
+        let tr_key = SecretKey::from_slice(&[0x44; 32]).unwrap();
+        let outpoints = test_outpoints();
+        let scripts = output_scripts(
+            &[address, address],
+            &[(wpkh_key, false), (tr_key, true)],
+            &outpoints,
+            &secp,
+        )
+        .unwrap();
+
+        let wpkh_pubkey = bitcoin::PublicKey::new(wpkh_key.public_key(&secp));
+        let prevouts = vec![
+            TxOut {
+                value: 10_000,
+                script_pubkey: Script::new_v0_p2wpkh(&wpkh_pubkey.wpubkey_hash().unwrap()),
+            },
+            TxOut {
+                value: 10_000,
+                script_pubkey: Script::new_v1_p2tr_tweaked(
+                    TweakedPublicKey::dangerous_assume_tweaked(tr_key.x_only_public_key(&secp).0),
+                ),
+            },
+        ];
+        let tx = Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: vec![
+                TxIn {
+                    previous_output: outpoints[0],
+                    script_sig: Script::new(),
+                    sequence: Sequence::MAX,
+                    witness: Witness::from_vec(vec![vec![0; 72], wpkh_pubkey.to_bytes()]),
+                },
+                TxIn {
+                    previous_output: outpoints[1],
+                    script_sig: Script::new(),
+                    sequence: Sequence::MAX,
+                    witness: Witness::from_vec(vec![vec![0; 64]]),
+                },
+            ],
+            output: vec![
+                TxOut {
+                    value: 5_000,
+                    script_pubkey: scripts[1].clone(),
+                },
+                TxOut {
+                    value: 4_000,
+                    script_pubkey: scripts[0].clone(),
+                },
+            ],
+        };
+
+        let found = scan_transaction(&keychain, &tx, &prevouts, &secp).unwrap();
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].outpoint.vout, 1);
+        assert_eq!(found[1].outpoint.vout, 0);
+
+        // The spending key matches the output key
+        let spend_key = SecretKey::from_slice(&[0x22; 32]).unwrap();
+        for output in found {
+            let key = output.spending_key(&spend_key).unwrap();
+            assert_eq!(
+                output.txout.script_pubkey.as_bytes()[2..],
+                key.x_only_public_key(&secp).0.serialize()[..]
+            );
+        }
+
+        // Another keychain doesn't find anything
+        let other = SilentPaymentKeychain::new(
+            SecretKey::from_slice(&[0x55; 32]).unwrap(),
+            spend_key.public_key(&secp),
+        );
+        assert!(scan_transaction(&other, &tx, &prevouts, &secp)
+            .unwrap()
+            .is_empty());
+    }
+}
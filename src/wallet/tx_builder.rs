@@ -37,19 +37,23 @@
 //! ```
 
 use std::collections::BTreeMap;
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashSet};
 use std::default::Default;
+use std::fmt;
 use std::marker::PhantomData;
+use std::rc::Rc;
 
 use bitcoin::util::psbt::{self, PartiallySignedTransaction as Psbt};
 use bitcoin::{LockTime, OutPoint, Script, Sequence, Transaction};
 
 use super::coin_selection::{CoinSelectionAlgorithm, DefaultCoinSelectionAlgorithm};
-use crate::{database::BatchDatabase, Error, Utxo, Wallet};
+use super::silent_payment::SilentPaymentAddress;
+use crate::database::{BatchDatabase, LabelKey};
 use crate::{
     types::{FeeRate, KeychainKind, LocalUtxo, WeightedUtxo},
     TransactionDetails,
 };
+use crate::{Error, Utxo, Wallet};
 /// Context in which the [`TxBuilder`] is valid
 pub trait TxBuilderContext: std::fmt::Debug + Default + Clone {}
 
@@ -127,13 +131,18 @@ pub struct TxBuilder<'a, D, Cs, Ctx> {
 #[derive(Default, Debug, Clone)]
 pub(crate) struct TxParams {
     pub(crate) recipients: Vec<(Script, u64)>,
+    pub(crate) fee_paying_recipients: BTreeSet<usize>,
+    pub(crate) fee_split: FeeSplit,
+    pub(crate) silent_payment_recipients: Vec<(SilentPaymentAddress, u64)>,
     pub(crate) drain_wallet: bool,
     pub(crate) drain_to: Option<Script>,
     pub(crate) fee_policy: Option<FeePolicy>,
     pub(crate) internal_policy_path: Option<BTreeMap<String, Vec<usize>>>,
     pub(crate) external_policy_path: Option<BTreeMap<String, Vec<usize>>>,
+    pub(crate) custom_policy_paths: BTreeMap<u32, BTreeMap<String, Vec<usize>>>,
     pub(crate) utxos: Vec<WeightedUtxo>,
     pub(crate) unspendable: HashSet<OutPoint>,
+    pub(crate) utxo_filters: Vec<UtxoFilter>,
     pub(crate) manually_selected_only: bool,
     pub(crate) sighash: Option<psbt::PsbtSighashType>,
     pub(crate) ordering: TxOrdering,
@@ -141,20 +150,43 @@ pub(crate) struct TxParams {
     pub(crate) rbf: Option<RbfValue>,
     pub(crate) version: Option<Version>,
     pub(crate) change_policy: ChangeSpendPolicy,
+    pub(crate) change_split: ChangeSplit,
+    pub(crate) ancestor_fee_policy: AncestorFeePolicy,
     pub(crate) only_witness_utxo: bool,
     pub(crate) add_global_xpubs: bool,
     pub(crate) include_output_redeem_witness_script: bool,
     pub(crate) bumping_fee: Option<PreviousFee>,
+    pub(crate) bumping_change: Option<Script>,
+    pub(crate) package_fee: Option<PackageFee>,
     pub(crate) current_height: Option<LockTime>,
     pub(crate) allow_dust: bool,
 }
 
+type UtxoFilterFn = dyn Fn(&LocalUtxo, u32) -> bool;
+
+/// Predicate over a wallet UTXO and the child number of the address it was received on
+#[derive(Clone)]
+pub(crate) struct UtxoFilter(pub Rc<UtxoFilterFn>);
+
+impl fmt::Debug for UtxoFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "UtxoFilter")
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub(crate) struct PreviousFee {
     pub absolute: u64,
     pub rate: f32,
 }
 
+/// Weight and fee of the unconfirmed ancestors that will be mined together with the transaction
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct PackageFee {
+    pub weight: usize,
+    pub fee: u64,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub(crate) enum FeePolicy {
     FeeRate(FeeRate),
@@ -261,6 +293,12 @@ impl<'a, D: BatchDatabase, Cs: CoinSelectionAlgorithm<D>, Ctx: TxBuilderContext>
         let to_update = match keychain {
             KeychainKind::Internal => &mut self.params.internal_policy_path,
             KeychainKind::External => &mut self.params.external_policy_path,
+            KeychainKind::Custom(id) => {
+                self.params.custom_policy_paths.insert(id, policy_path);
+                return self;
+            }
+            // imported scripts are never spent by the wallet
+            KeychainKind::Imported => return self,
         };
 
         *to_update = Some(policy_path);
@@ -276,7 +314,13 @@ impl<'a, D: BatchDatabase, Cs: CoinSelectionAlgorithm<D>, Ctx: TxBuilderContext>
     pub fn add_utxos(&mut self, outpoints: &[OutPoint]) -> Result<&mut Self, Error> {
         let utxos = outpoints
             .iter()
-            .map(|outpoint| self.wallet.get_utxo(*outpoint)?.ok_or(Error::UnknownUtxo))
+            .map(|outpoint| {
+                let utxo = self.wallet.get_utxo(*outpoint)?.ok_or(Error::UnknownUtxo)?;
+                match self.wallet.has_keychain(utxo.keychain) {
+                    true => Ok(utxo),
+                    false => Err(Error::UnknownKeychain(utxo.keychain)),
+                }
+            })
             .collect::<Result<Vec<_>, _>>()?;
 
         for utxo in utxos {
@@ -347,25 +391,7 @@ impl<'a, D: BatchDatabase, Cs: CoinSelectionAlgorithm<D>, Ctx: TxBuilderContext>
         psbt_input: psbt::Input,
         satisfaction_weight: usize,
     ) -> Result<&mut Self, Error> {
-        if psbt_input.witness_utxo.is_none() {
-            match psbt_input.non_witness_utxo.as_ref() {
-                Some(tx) => {
-                    if tx.txid() != outpoint.txid {
-                        return Err(Error::Generic(
-                            "Foreign utxo outpoint does not match PSBT input".into(),
-                        ));
-                    }
-                    if tx.output.len() <= outpoint.vout as usize {
-                        return Err(Error::InvalidOutpoint(outpoint));
-                    }
-                }
-                None => {
-                    return Err(Error::Generic(
-                        "Foreign utxo missing witness_utxo or non_witness_utxo".into(),
-                    ))
-                }
-            }
-        }
+        check_foreign_utxo(outpoint, &psbt_input)?;
 
         self.params.utxos.push(WeightedUtxo {
             satisfaction_weight,
@@ -402,11 +428,78 @@ impl<'a, D: BatchDatabase, Cs: CoinSelectionAlgorithm<D>, Ctx: TxBuilderContext>
     ///
     /// It's important to note that the "must-be-spent" utxos added with [`TxBuilder::add_utxo`]
     /// have priority over this. See the docs of the two linked methods for more details.
+    ///
+    /// To exclude a utxo from every future transaction, freeze it with
+    /// [`Wallet::freeze_utxo`](super::Wallet::freeze_utxo) instead.
     pub fn add_unspendable(&mut self, unspendable: OutPoint) -> &mut Self {
         self.params.unspendable.insert(unspendable);
         self
     }
 
+    /// Only consider the utxos accepted by `filter` when selecting coins
+    ///
+    /// The filter is called with every available utxo and the child number of the address it
+    /// was received on, within its keychain. Utxos whose address is not found in the database
+    /// are never selected by a filtered builder. Calling this method multiple times restricts the
+    /// selection to the utxos accepted by all of the filters.
+    ///
+    /// Filters are applied on top of the [`ChangeSpendPolicy`] and the "unspendable" list, while
+    /// the "must-be-spent" utxos added with [`TxBuilder::add_utxo`] are always spent.
+    ///
+    /// ## Example
+    ///
+    /// Only spend from the first 100 external addresses:
+    ///
+    /// ```
+    /// # use std::str::FromStr;
+    /// # use bitcoin::*;
+    /// # use bdk::*;
+    /// # let to_address = Address::from_str("2N4eQYCbKUHCCTUjBJeHcJp9ok6J2GZsTDt").unwrap();
+    /// # let wallet = doctest_wallet!();
+    /// let mut builder = wallet.build_tx();
+    /// builder
+    ///     .add_recipient(to_address.script_pubkey(), 50_000)
+    ///     .filter_utxos(|utxo, child| utxo.keychain == KeychainKind::External && child < 100);
+    /// # Ok::<(), bdk::Error>(())
+    /// ```
+    pub fn filter_utxos<F>(&mut self, filter: F) -> &mut Self
+    where
+        F: Fn(&LocalUtxo, u32) -> bool + 'static,
+    {
+        self.params.utxo_filters.push(UtxoFilter(Rc::new(filter)));
+        self
+    }
+
+    /// Only consider the utxos labeled with `label` when selecting coins
+    ///
+    /// A utxo matches if either the output itself or the address it was received on carries the
+    /// label. The labels are read from the database when this method is called. See
+    /// [`TxBuilder::filter_utxos`] for how filters interact with the other coin control options.
+    pub fn filter_by_label(&mut self, label: &str) -> Result<&mut Self, Error> {
+        let mut outpoints = HashSet::new();
+        let mut scripts = HashSet::new();
+        for (key, _) in self
+            .wallet
+            .list_labels()?
+            .into_iter()
+            .filter(|(_, l)| l == label)
+        {
+            match key {
+                LabelKey::Utxo(outpoint) => {
+                    outpoints.insert(outpoint);
+                }
+                LabelKey::Address(script) => {
+                    scripts.insert(script);
+                }
+                _ => {}
+            }
+        }
+
+        Ok(self.filter_utxos(move |utxo, _| {
+            outpoints.contains(&utxo.outpoint) || scripts.contains(&utxo.txout.script_pubkey)
+        }))
+    }
+
     /// Sign with a specific sig hash
     ///
     /// **Use this option very carefully**
@@ -463,6 +556,25 @@ impl<'a, D: BatchDatabase, Cs: CoinSelectionAlgorithm<D>, Ctx: TxBuilderContext>
         self
     }
 
+    /// Split the change of the transaction into multiple outputs. See [`ChangeSplit`].
+    ///
+    /// Every additional output is sent to a new address of the internal keychain. Splitting is
+    /// only done when the change goes to the wallet, it's ignored if [`TxBuilder::drain_to`] is
+    /// set.
+    pub fn change_split(&mut self, change_split: ChangeSplit) -> &mut Self {
+        self.params.change_split = change_split;
+        self
+    }
+
+    /// Set how to deal with unconfirmed UTXOs whose ancestors pay less than the requested fee rate.
+    /// See [`AncestorFeePolicy`].
+    ///
+    /// This only has an effect when the fee is set with [`TxBuilder::fee_rate`].
+    pub fn ancestor_fee_policy(&mut self, ancestor_fee_policy: AncestorFeePolicy) -> &mut Self {
+        self.params.ancestor_fee_policy = ancestor_fee_policy;
+        self
+    }
+
     /// Only Fill-in the [`psbt::Input::witness_utxo`](bitcoin::util::psbt::Input::witness_utxo) field when spending from
     /// SegWit descriptors.
     ///
@@ -549,12 +661,18 @@ impl<'a, D: BatchDatabase, Cs: CoinSelectionAlgorithm<D>, Ctx: TxBuilderContext>
     /// This will be used to:
     /// 1. Set the nLockTime for preventing fee sniping.
     /// **Note**: This will be ignored if you manually specify a nlocktime using [`TxBuilder::nlocktime`].
+    ///    When all the inputs are taproot, [BIP-326] is followed instead: either the nLockTime or the
+    ///    nSequence of a random input is used, and sometimes the value is randomly lowered. The
+    ///    nSequence is only used with [`TxBuilder::enable_rbf`], since it makes the transaction
+    ///    replaceable, and never overrides a value set with [`TxBuilder::enable_rbf_with_sequence`].
     /// 2. Decide whether coinbase outputs are mature or not. If the coinbase outputs are not
     ///    mature at `current_height`, we ignore them in the coin selection.
     ///    If you want to create a transaction that spends immature coinbase inputs, manually
     ///    add them using [`TxBuilder::add_utxos`].
     ///
     /// In both cases, if you don't provide a current height, we use the last sync height.
+    ///
+    /// [BIP-326]: https://github.com/bitcoin/bips/blob/master/bip-0326.mediawiki
     pub fn current_height(&mut self, height: u32) -> &mut Self {
         self.params.current_height = Some(LockTime::from_height(height).expect("Invalid height"));
         self
@@ -571,8 +689,11 @@ impl<'a, D: BatchDatabase, Cs: CoinSelectionAlgorithm<D>, Ctx: TxBuilderContext>
 
 impl<'a, D: BatchDatabase, Cs: CoinSelectionAlgorithm<D>> TxBuilder<'a, D, Cs, CreateTx> {
     /// Replace the recipients already added with a new list
+    ///
+    /// This also clears the recipients added with [`TxBuilder::add_fee_paying_recipient`].
     pub fn set_recipients(&mut self, recipients: Vec<(Script, u64)>) -> &mut Self {
         self.params.recipients = recipients;
+        self.params.fee_paying_recipients.clear();
         self
     }
 
@@ -582,6 +703,81 @@ impl<'a, D: BatchDatabase, Cs: CoinSelectionAlgorithm<D>> TxBuilder<'a, D, Cs, C
         self
     }
 
+    /// Add a recipient that pays for the fee of the transaction
+    ///
+    /// The fee is subtracted from the amount of the recipients added with this method instead of
+    /// being paid by the inputs, so `amount` is the most they can receive. When there is more than
+    /// one of them the fee is split according to [`TxBuilder::fee_split`], and any rounding
+    /// remainder is paid by the first one. This is the equivalent of Bitcoin Core's
+    /// `subtractfeefromoutputs`.
+    ///
+    /// Building the transaction fails with [`Error::OutputBelowDustLimit`] if an output ends up
+    /// below the dust limit after paying its share, unless [`TxBuilder::allow_dust`] is set, or
+    /// if its share is higher than its amount.
+    pub fn add_fee_paying_recipient(&mut self, script_pubkey: Script, amount: u64) -> &mut Self {
+        self.params
+            .fee_paying_recipients
+            .insert(self.params.recipients.len());
+        self.add_recipient(script_pubkey, amount)
+    }
+
+    /// Set how the fee is split among the recipients added with
+    /// [`TxBuilder::add_fee_paying_recipient`]
+    pub fn fee_split(&mut self, fee_split: FeeSplit) -> &mut Self {
+        self.params.fee_split = fee_split;
+        self
+    }
+
+    /// Compute the maximum amount that can be sent to `script_pubkey`, without building the
+    /// transaction
+    ///
+    /// This is the value of an output to `script_pubkey` added on top of the recipients already
+    /// in the builder, when spending every UTXO available to it at the fee set with
+    /// [`TxBuilder::fee_rate`] or [`TxBuilder::fee_absolute`]. The UTXOs are chosen honoring the
+    /// unspendable list, the [`ChangeSpendPolicy`], the [`UTXO filters`](TxBuilder::filter_utxos),
+    /// the policy paths and the manually selected UTXOs. UTXOs that cost more to spend than
+    /// their value are not counted, unless they are manually selected.
+    ///
+    /// Returns [`Error::InsufficientFunds`] if the amount would be below the dust limit for
+    /// `script_pubkey`, unless [`TxBuilder::allow_dust`] is set.
+    ///
+    /// ```
+    /// # use std::str::FromStr;
+    /// # use bitcoin::*;
+    /// # use bdk::*;
+    /// # let descriptor = "wpkh(tpubD6NzVbkrYhZ4Xferm7Pz4VnjdcDPFyjVu5K4iZXQ4pVN8Cks4pHVowTBXBKRhX64pkRyJZJN5xAKj4UDNnLPb5p2sSKXhewoYx5GbTdUFWq/*)";
+    /// # let wallet = doctest_wallet!();
+    /// # let to_address = Address::from_str("2N4eQYCbKUHCCTUjBJeHcJp9ok6J2GZsTDt").unwrap();
+    /// let mut builder = wallet.build_tx();
+    /// builder.fee_rate(FeeRate::from_sat_per_vb(5.0));
+    /// let max = builder.max_sendable(&to_address.script_pubkey())?;
+    /// # Ok::<(), bdk::Error>(())
+    /// ```
+    pub fn max_sendable(&self, script_pubkey: &Script) -> Result<u64, Error> {
+        self.wallet.max_sendable(&self.params, script_pubkey)
+    }
+
+    /// Add a [silent payment](super::silent_payment) recipient to the internal list
+    ///
+    /// The output script is derived from the private keys of the inputs of the transaction, so
+    /// the wallet must be able to sign for every eligible input: building the transaction fails
+    /// with [`SilentPaymentError::MissingInputKey`] otherwise.
+    ///
+    /// Since the output depends on the inputs, changing them later (for example by bumping the
+    /// fee) invalidates the payment.
+    ///
+    /// [`SilentPaymentError::MissingInputKey`]: super::silent_payment::SilentPaymentError::MissingInputKey
+    pub fn add_silent_payment_recipient(
+        &mut self,
+        address: SilentPaymentAddress,
+        amount: u64,
+    ) -> &mut Self {
+        self.params
+            .silent_payment_recipients
+            .push((address, amount));
+        self
+    }
+
     /// Add data as an output, using OP_RETURN
     pub fn add_data(&mut self, data: &[u8]) -> &mut Self {
         let script = Script::new_op_return(data);
@@ -669,6 +865,24 @@ impl<'a, D: BatchDatabase> TxBuilder<'a, D, DefaultCoinSelectionAlgorithm, BumpF
             ))),
         }
     }
+
+    /// Add a new recipient to the transaction we are bumping
+    ///
+    /// This allows batching new payments into a transaction that is still unconfirmed instead of
+    /// creating a chain of unconfirmed transactions. All the outputs of the original transaction
+    /// are kept intact, and unless a different one is set with [`TxBuilder::allow_shrinking`] the
+    /// change goes back to the change output of the original transaction. If the original inputs
+    /// are not enough to pay for the new recipients more confirmed UTXOs are added.
+    ///
+    /// **Note** that the fee rate of the replacement must still be higher than the original one,
+    /// see [`TxBuilder::fee_rate`].
+    pub fn add_recipient(&mut self, script_pubkey: Script, amount: u64) -> &mut Self {
+        self.params.recipients.push((script_pubkey, amount));
+        if self.params.drain_to.is_none() {
+            self.params.drain_to = self.params.bumping_change.clone();
+        }
+        self
+    }
 }
 
 /// Ordering of the transaction's inputs and outputs
@@ -768,11 +982,132 @@ impl ChangeSpendPolicy {
         match self {
             ChangeSpendPolicy::ChangeAllowed => true,
             ChangeSpendPolicy::OnlyChange => utxo.keychain == KeychainKind::Internal,
-            ChangeSpendPolicy::ChangeForbidden => utxo.keychain == KeychainKind::External,
+            ChangeSpendPolicy::ChangeForbidden => utxo.keychain != KeychainKind::Internal,
         }
     }
 }
 
+/// How to split the change of a transaction into multiple outputs
+///
+/// Having more than one change output lets the wallet make several payments later on without
+/// chaining them on the same unconfirmed change. Every additional output pays for its own weight:
+/// when the change isn't enough to create all of them above the dust limit, fewer outputs are
+/// created, down to a single one.
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Hash, Clone, Copy)]
+pub enum ChangeSplit {
+    /// Create a single change output (default)
+    Single,
+    /// Split the change into this number of outputs of the same value
+    Equal(usize),
+    /// Create outputs of a fixed value, plus one more output with the remainder
+    Denomination {
+        /// Value of each output
+        value: u64,
+        /// Maximum number of outputs of `value` to create
+        max_outputs: usize,
+    },
+    /// Split the change into this number of outputs of random values, rounded to two significant
+    /// digits so that they look like payments
+    Random(usize),
+}
+
+impl Default for ChangeSplit {
+    fn default() -> Self {
+        ChangeSplit::Single
+    }
+}
+
+/// Policy regarding unconfirmed UTXOs whose unconfirmed ancestors pay a low fee rate
+///
+/// A transaction spending an unconfirmed output can only be mined together with (or after) all of
+/// its unconfirmed ancestors, so what miners look at is the fee rate of the whole package. The
+/// weight and fee of the ancestors are taken from the [`TransactionDetails`](crate::TransactionDetails)
+/// stored in the database; ancestors with an unknown fee are assumed to pay no fee at all.
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Hash, Clone, Copy)]
+pub enum AncestorFeePolicy {
+    /// Only pay for the transaction being built (default)
+    Ignore,
+    /// Pay the extra fee needed for every unconfirmed package spent to reach the requested fee rate
+    ///
+    /// Coin selection takes this extra fee into account when evaluating each UTXO. If more than
+    /// one UTXO with the same unconfirmed ancestors ends up being selected, the ancestors may be
+    /// paid for more than once.
+    Bump,
+    /// Don't spend UTXOs whose unconfirmed package pays less than the requested fee rate, unless
+    /// they are manually selected
+    Avoid,
+}
+
+impl Default for AncestorFeePolicy {
+    fn default() -> Self {
+        AncestorFeePolicy::Ignore
+    }
+}
+
+/// How to split the fee among the recipients that pay for it
+///
+/// See [`TxBuilder::add_fee_paying_recipient`].
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Hash, Clone, Copy)]
+pub enum FeeSplit {
+    /// Every recipient pays the same share of the fee (default)
+    Equal,
+    /// Every recipient pays a share of the fee proportional to its amount
+    Proportional,
+}
+
+impl Default for FeeSplit {
+    fn default() -> Self {
+        FeeSplit::Equal
+    }
+}
+
+impl FeeSplit {
+    /// Split `fee` among outputs of the given `values`, the first one pays the rounding remainder
+    pub(crate) fn split(&self, fee: u64, values: &[u64]) -> Vec<u64> {
+        let mut shares = match self {
+            FeeSplit::Equal => vec![fee / values.len() as u64; values.len()],
+            FeeSplit::Proportional => {
+                let total = values.iter().sum::<u64>().max(1) as u128;
+                values
+                    .iter()
+                    .map(|value| (fee as u128 * *value as u128 / total) as u64)
+                    .collect()
+            }
+        };
+        shares[0] += fee - shares.iter().sum::<u64>();
+
+        shares
+    }
+}
+
+// Make sure a foreign utxo carries its previous output, and that it matches `outpoint`
+pub(crate) fn check_foreign_utxo(
+    outpoint: OutPoint,
+    psbt_input: &psbt::Input,
+) -> Result<(), Error> {
+    if psbt_input.witness_utxo.is_none() {
+        match psbt_input.non_witness_utxo.as_ref() {
+            Some(tx) => {
+                if tx.txid() != outpoint.txid {
+                    return Err(Error::Generic(
+                        "Foreign utxo outpoint does not match PSBT input".into(),
+                    ));
+                }
+                if tx.output.len() <= outpoint.vout as usize {
+                    return Err(Error::InvalidOutpoint(outpoint));
+                }
+            }
+            None => {
+                return Err(Error::Generic(
+                    "Foreign utxo missing witness_utxo or non_witness_utxo".into(),
+                ))
+            }
+        }
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod test {
     const ORDERING_TEST_TX: &str = "0200000003c26f3eb7932f7acddc5ddd26602b77e7516079b03090a16e2c2f54\
@@ -915,6 +1250,27 @@ mod test {
         assert_eq!(filtered[0].keychain, KeychainKind::Internal);
     }
 
+    #[test]
+    fn test_fee_split_equal() {
+        assert_eq!(FeeSplit::Equal.split(1_000, &[10_000]), vec![1_000]);
+        assert_eq!(
+            FeeSplit::Equal.split(1_000, &[10_000, 50_000, 20_000]),
+            vec![334, 333, 333]
+        );
+    }
+
+    #[test]
+    fn test_fee_split_proportional() {
+        assert_eq!(
+            FeeSplit::Proportional.split(1_000, &[10_000, 30_000]),
+            vec![250, 750]
+        );
+        assert_eq!(
+            FeeSplit::Proportional.split(1_000, &[10_000, 10_000, 10_000]),
+            vec![334, 333, 333]
+        );
+    }
+
     #[test]
     fn test_default_tx_version_1() {
         let version = Version::default();
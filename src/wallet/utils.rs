@@ -10,10 +10,12 @@
 // licenses.
 
 use bitcoin::secp256k1::{All, Secp256k1};
-use bitcoin::{LockTime, Script, Sequence};
+use bitcoin::{LockTime, PackedLockTime, Script, Sequence, Transaction};
 
 use miniscript::{MiniscriptKey, Satisfier, ToPublicKey};
 
+use rand::Rng;
+
 /// Trait to check if a value is below the dust limit.
 /// We are performing dust value calculation for a given script public key using rust-bitcoin to
 /// keep it compatible with network dust rate
@@ -64,6 +66,43 @@ pub(crate) fn check_nsequence_rbf(rbf: Sequence, csv: Sequence) -> bool {
     true
 }
 
+/// Set the fields of `tx` that prevent fee sniping as described in BIP-326
+///
+/// `confirmations` holds the number of confirmations of every input, when they can all be used
+/// as relative timelocks. In that case half of the times the nLockTime is disabled and the
+/// nSequence of a random input is set to its confirmations, otherwise the nLockTime is set to
+/// `current_height`. One time out of ten, a random offset lower than 100 is subtracted from the
+/// value.
+pub(crate) fn apply_anti_fee_sniping<R: Rng>(
+    rng: &mut R,
+    tx: &mut Transaction,
+    current_height: u32,
+    confirmations: Option<Vec<u32>>,
+) {
+    match confirmations {
+        Some(confirmations) if !confirmations.is_empty() && rng.gen_bool(0.5) => {
+            let index = rng.gen_range(0..confirmations.len());
+            let mut sequence = confirmations[index];
+            if rng.gen_ratio(1, 10) {
+                sequence = sequence.saturating_sub(rng.gen_range(0..100)).max(1);
+            }
+
+            // relative timelocks require version 2
+            tx.version = tx.version.max(2);
+            tx.lock_time = PackedLockTime::ZERO;
+            tx.input[index].sequence = Sequence(sequence);
+        }
+        _ => {
+            let mut height = current_height;
+            if rng.gen_ratio(1, 10) {
+                height = height.saturating_sub(rng.gen_range(0..100));
+            }
+
+            tx.lock_time = PackedLockTime(height);
+        }
+    }
+}
+
 impl<Pk: MiniscriptKey + ToPublicKey> Satisfier<Pk> for After {
     fn check_after(&self, n: LockTime) -> bool {
         if let Some(current_height) = self.current_height {
@@ -118,8 +157,9 @@ mod test {
     // otherwise it's time-based
     pub(crate) const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
 
-    use super::{check_nsequence_rbf, IsDust};
-    use crate::bitcoin::{Address, Sequence};
+    use super::{apply_anti_fee_sniping, check_nsequence_rbf, IsDust};
+    use crate::bitcoin::{Address, PackedLockTime, Sequence, Transaction, TxIn};
+    use rand::{rngs::StdRng, SeedableRng};
     use std::str::FromStr;
 
     #[test]
@@ -178,4 +218,74 @@ mod test {
         );
         assert!(result);
     }
+
+    #[test]
+    fn test_apply_anti_fee_sniping() {
+        let mut rng: StdRng = SeedableRng::from_seed([0; 32]);
+        let confirmations = vec![10, 500];
+        let current_height = 1_000;
+
+        let mut used_sequence = [false; 2];
+        let mut used_locktime = false;
+        let mut offsets = 0;
+        for _ in 0..1_000 {
+            let mut tx = Transaction {
+                version: 1,
+                lock_time: PackedLockTime::ZERO,
+                input: vec![TxIn::default(), TxIn::default()],
+                output: vec![],
+            };
+            apply_anti_fee_sniping(
+                &mut rng,
+                &mut tx,
+                current_height,
+                Some(confirmations.clone()),
+            );
+
+            match tx.lock_time {
+                PackedLockTime::ZERO => {
+                    assert_eq!(tx.version, 2);
+                    let index = tx
+                        .input
+                        .iter()
+                        .position(|txin| txin.sequence != Sequence::MAX)
+                        .unwrap();
+                    let sequence = tx.input[index].sequence.0;
+                    assert!(sequence <= confirmations[index]);
+                    assert!(sequence >= 1.max(confirmations[index].saturating_sub(99)));
+                    assert_eq!(tx.input[1 - index].sequence, Sequence::MAX);
+                    used_sequence[index] = true;
+                    offsets += (sequence != confirmations[index]) as usize;
+                }
+                PackedLockTime(height) => {
+                    assert!(height <= current_height && height > current_height - 100);
+                    assert_eq!(tx.version, 1);
+                    assert!(tx.input.iter().all(|txin| txin.sequence == Sequence::MAX));
+                    used_locktime = true;
+                    offsets += (height != current_height) as usize;
+                }
+            }
+        }
+
+        assert!(used_sequence[0] && used_sequence[1] && used_locktime);
+        // roughly one time out of ten
+        assert!(offsets > 50 && offsets < 150);
+    }
+
+    #[test]
+    fn test_apply_anti_fee_sniping_without_confirmations() {
+        let mut rng: StdRng = SeedableRng::from_seed([0; 32]);
+        for _ in 0..100 {
+            let mut tx = Transaction {
+                version: 1,
+                lock_time: PackedLockTime::ZERO,
+                input: vec![TxIn::default()],
+                output: vec![],
+            };
+            apply_anti_fee_sniping(&mut rng, &mut tx, 1_000, None);
+
+            assert!(tx.lock_time.0 > 900);
+            assert_eq!(tx.input[0].sequence, Sequence::MAX);
+        }
+    }
 }